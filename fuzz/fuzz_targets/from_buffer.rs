@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pvpn::packet::{HEADER_SIZE, Packet};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((p, len)) = Packet::from_buffer(data) {
+        assert_eq!(len, HEADER_SIZE);
+
+        //
+        // whatever parses has to encode back to the same bytes
+        //
+        let mut out = Vec::new();
+        p.encode(&mut out).unwrap();
+        assert_eq!(&out[..], &data[0..len]);
+    }
+});
@@ -0,0 +1,26 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pvpn::{error::Error, streams::TokenStreams};
+
+fuzz_target!(|data: &[u8]| {
+    let mut streams = TokenStreams::new();
+
+    //
+    // feed the input in two halves to go through the reassembly
+    //
+    let (first, second) = data.split_at(data.len() / 2);
+
+    for chunk in [first, second] {
+        streams.feed_tunnel_input(chunk);
+
+        loop {
+            match streams.read_packet() {
+                Ok((p, data)) => assert_eq!(data.len(), p.data_len as usize),
+                Err(Error::Empty) | Err(Error::NotEnoughData) => break,
+                // a typed error ends the session
+                Err(_) => return,
+            }
+        }
+    }
+});
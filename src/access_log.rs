@@ -0,0 +1,120 @@
+//
+// --access-log, one JSON line per forwarded connection once it's gone.
+// Opened for each session, a rotated file is picked up by the next one
+//
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{
+    api::{AccessRecord, to_json},
+    error::Result,
+    streams::ClosedConnReport,
+};
+
+#[derive(Debug)]
+pub struct AccessLog {
+    file: File,
+    // "server" or "client"
+    role: &'static str,
+    // the last write failed, logged once until one goes through again
+    failing: bool,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, role: &'static str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file,
+            role,
+            failing: false,
+        })
+    }
+
+    //
+    // Never fails, a full disk costs the access log not the connections
+    //
+    pub fn record(&mut self, report: &ClosedConnReport) {
+        let record = AccessRecord {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            role: self.role.to_string(),
+            peer: report.peer.map(|v| v.to_string()),
+            addr: report.addr as u16,
+            bytes_in: report.bytes_in,
+            bytes_out: report.bytes_out,
+            duration_ms: report.duration.as_millis() as u64,
+            reason: report.reason.to_string(),
+        };
+
+        let line = to_json(&record) + "\n";
+
+        // one write() per line, O_APPEND keeps them whole
+        match (self.file.write_all(line.as_bytes()), self.failing) {
+            (Ok(()), true) => {
+                info!("access log writable again");
+                self.failing = false;
+            }
+            (Ok(()), false) => {}
+            (Err(e), false) => {
+                warn!("access log write failure ({e}), further failures not logged");
+                self.failing = true;
+            }
+            (Err(_), true) => {}
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::streams::CloseReason;
+
+    #[test]
+    fn lines() {
+        let path = std::env::temp_dir().join(format!("pvpn-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = AccessLog::open(&path, "server").unwrap();
+
+        let mut report = ClosedConnReport {
+            addr: 4,
+            peer: Some("192.0.2.1:40000".parse().unwrap()),
+            reason: CloseReason::Finished,
+            bytes_in: 10,
+            bytes_out: 20,
+            unflushed: 0,
+            duration: Duration::from_millis(1500),
+        };
+        log.record(&report);
+
+        report.addr = 5;
+        report.peer = None;
+        report.reason = CloseReason::TunnelLost;
+        log.record(&report);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<serde_json::Value> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["peer"], "192.0.2.1:40000");
+        assert_eq!(lines[0]["addr"], 4);
+        assert_eq!(lines[0]["bytes_out"], 20);
+        assert_eq!(lines[0]["duration_ms"], 1500);
+        assert_eq!(lines[0]["reason"], "finished");
+        assert!(lines[1]["peer"].is_null());
+        assert_eq!(lines[1]["reason"], "tunnel-lost");
+    }
+}
@@ -0,0 +1,311 @@
+//
+// Who may reach the internet ports, --allow-cidr and --deny-cidr. A deny
+// entry wins over an allow one, no allow entry at all lets everyone else in
+//
+use log::{info, warn};
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    ratelimit::{AcceptRate, SourceLimiter},
+};
+
+// denied peers logged per window, the others only counted
+const DENY_LOG_BURST: usize = 10;
+const DENY_LOG_WINDOW: Duration = Duration::from_secs(60);
+// the connections over --accept-rate are summed up that often
+const RATE_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    //
+    // "10.0.0.0/8", "2001:db8::/32", a bare address is a /32 or a /128
+    //
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = || Error::InvalidCidr { spec: spec.to_string() };
+
+        let (addr, prefix) = match spec.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (spec.trim(), None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix = match prefix {
+            Some(v) => v.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn masked(bits: u128, width: u32, prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        p => bits >> (width - p as u32),
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // v4 peers of a dual stack listener show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u32::from(net) as u128, 32, self.prefix) == masked(u32::from(ip) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(u128::from(net), 128, self.prefix) == masked(u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Acl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+}
+
+//
+// A scanner gets DENY_LOG_BURST lines per window, what's left is summed up
+// when the next window starts
+//
+#[derive(Debug)]
+pub struct DenyLog {
+    window: Instant,
+    logged: usize,
+    suppressed: u64,
+}
+
+impl Default for DenyLog {
+    fn default() -> Self {
+        Self {
+            window: Instant::now(),
+            logged: 0,
+            suppressed: 0,
+        }
+    }
+}
+
+impl DenyLog {
+    //
+    // What to log for this one, None when it's only counted. The first
+    // line of a window tells how many the previous one didn't
+    //
+    pub fn on_denied(&mut self, peer: impl Display, now: Instant) -> Option<String> {
+        let mut line = String::new();
+
+        if now.duration_since(self.window) >= DENY_LOG_WINDOW {
+            if 0 != self.suppressed {
+                line = format!("{} denied peers not logged, ", self.suppressed);
+            }
+            self.window = now;
+            self.logged = 0;
+            self.suppressed = 0;
+        }
+
+        if self.logged >= DENY_LOG_BURST {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.logged += 1;
+        line.push_str(&format!("denied {peer}"));
+        Some(line)
+    }
+}
+
+//
+// A listener's Acl, its accept rate limit and the log of who they turned
+// away
+//
+#[derive(Debug)]
+pub struct Gate {
+    acl: Acl,
+    log: DenyLog,
+    limiter: Option<SourceLimiter>,
+    last_rate_log: Instant,
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self::new(Acl::default(), None)
+    }
+}
+
+impl Gate {
+    pub fn new(acl: Acl, accept_rate: Option<AcceptRate>) -> Self {
+        Self {
+            acl,
+            log: DenyLog::default(),
+            limiter: accept_rate.map(SourceLimiter::new),
+            last_rate_log: Instant::now(),
+        }
+    }
+
+    pub fn admits(&mut self, label: &str, peer: SocketAddr) -> bool {
+        if !self.acl.is_allowed(peer.ip()) {
+            if let Some(line) = self.log.on_denied(peer, Instant::now()) {
+                info!("[{label}] {line}");
+            }
+            return false;
+        }
+
+        // counted, tick() tells
+        match &mut self.limiter {
+            Some(l) => l.admits(peer.ip(), Instant::now()),
+            None => true,
+        }
+    }
+
+    //
+    // Once per loop tick, a single line for what the rate limit turned away
+    //
+    pub fn tick(&mut self, label: &str, now: Instant) {
+        let limiter = match &mut self.limiter {
+            Some(v) => v,
+            None => return,
+        };
+
+        if now.duration_since(self.last_rate_log) < RATE_LOG_INTERVAL {
+            return;
+        }
+
+        self.last_rate_log = now;
+        limiter.expire(now);
+
+        let dropped = limiter.take_dropped();
+
+        if let Some((ip, most)) = dropped.iter().max_by_key(|(_, n)| **n) {
+            let total: u64 = dropped.values().sum();
+            warn!(
+                "[{label}] {total} connections over the accept rate from {} sources, {most} from {ip}",
+                dropped.len()
+            );
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidrs(specs: &[&str]) -> Vec<Cidr> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("10.1.2.3".parse::<Cidr>().unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert_eq!("0.0.0.0/0".parse::<Cidr>().unwrap().to_string(), "0.0.0.0/0");
+
+        for bad in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/", "office", ""] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn v4() {
+        let c: Cidr = "192.168.10.0/23".parse().unwrap();
+
+        assert!(c.contains(ip("192.168.10.1")));
+        assert!(c.contains(ip("192.168.11.255")));
+        assert!(!c.contains(ip("192.168.12.0")));
+        assert!(c.contains(ip("::ffff:192.168.10.7")));
+        assert!(!c.contains(ip("::1")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn v6() {
+        let c: Cidr = "2001:db8:abcd::/48".parse().unwrap();
+
+        assert!(c.contains(ip("2001:db8:abcd:12::1")));
+        assert!(!c.contains(ip("2001:db8:abce::1")));
+        assert!(!c.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn deny_wins() {
+        let acl = Acl {
+            allow: cidrs(&["10.0.0.0/8", "2001:db8::/32"]),
+            deny: cidrs(&["10.6.6.0/24"]),
+        };
+
+        assert!(acl.is_allowed(ip("10.1.1.1")));
+        assert!(!acl.is_allowed(ip("10.6.6.6")));
+        assert!(acl.is_allowed(ip("2001:db8::5")));
+        assert!(!acl.is_allowed(ip("172.16.0.1")));
+
+        // deny only, the rest is in
+        let acl = Acl {
+            deny: cidrs(&["10.6.6.0/24"]),
+            ..Default::default()
+        };
+        assert!(acl.is_allowed(ip("172.16.0.1")));
+        assert!(!acl.is_allowed(ip("10.6.6.1")));
+
+        assert!(Acl::default().is_allowed(ip("10.6.6.1")));
+    }
+
+    #[test]
+    fn deny_log() {
+        let mut log = DenyLog::default();
+        let now = log.window;
+
+        let logged = (0..100).filter_map(|i| log.on_denied(i, now)).count();
+        assert_eq!(logged, DENY_LOG_BURST);
+
+        let line = log.on_denied("10.6.6.6", now + DENY_LOG_WINDOW).unwrap();
+        assert_eq!(line, "90 denied peers not logged, denied 10.6.6.6");
+    }
+}
@@ -0,0 +1,505 @@
+//
+// Machine readable documents the crate emits ( status file, control socket,
+// JSON logs... ), all of them carry `schema: OUTPUT_SCHEMA_VERSION`.
+//
+// Compatibility rule: within a schema version fields can be added, never
+// renamed, removed or change type. Anything else bumps the version. The
+// snapshot tests below fail on any change so the bump is a conscious one.
+//
+// The JSON is written by hand, flat documents of numbers and strings don't
+// justify serde in the default build.
+//
+use std::fmt::Write;
+
+use crate::{stats::TunnelStats, streams::BufferStats};
+
+pub const OUTPUT_SCHEMA_VERSION: u32 = 1;
+
+pub trait ToJson {
+    fn write_json(&self, out: &mut String);
+}
+
+//
+// A type whose fields are those of a JSON object, see document!
+//
+pub trait Document {
+    fn write_fields(&self, obj: &mut JsonObject);
+}
+
+//
+// Declares the struct and writes its fields in declaration order
+//
+macro_rules! document {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $($(#[$fmeta:meta])* pub $field:ident: $ty:ty,)*
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $($(#[$fmeta])* pub $field: $ty,)*
+        }
+
+        impl Document for $name {
+            fn write_fields(&self, obj: &mut JsonObject) {
+                $(obj.field(stringify!($field), &self.$field);)*
+            }
+        }
+
+        impl ToJson for $name {
+            fn write_json(&self, out: &mut String) {
+                let mut obj = JsonObject::default();
+                self.write_fields(&mut obj);
+                out.push_str(&obj.finish());
+            }
+        }
+    };
+}
+
+//
+// An object being written, one field after the other
+//
+#[derive(Debug, Default)]
+pub struct JsonObject {
+    out: String,
+}
+
+impl JsonObject {
+    pub fn field<T: ToJson + ?Sized>(&mut self, key: &str, value: &T) {
+        self.out.push(match self.out.is_empty() {
+            true => '{',
+            false => ',',
+        });
+        key.write_json(&mut self.out);
+        self.out.push(':');
+        value.write_json(&mut self.out);
+    }
+
+    pub fn finish(mut self) -> String {
+        match self.out.is_empty() {
+            true => "{}".to_string(),
+            false => {
+                self.out.push('}');
+                self.out
+            }
+        }
+    }
+}
+
+//
+// The document with the schema field first
+//
+pub fn to_json<T: Document>(doc: &T) -> String {
+    let mut obj = JsonObject::default();
+    obj.field("schema", &OUTPUT_SCHEMA_VERSION);
+    doc.write_fields(&mut obj);
+    obj.finish()
+}
+
+macro_rules! json_number {
+    ($($ty:ty),*) => {
+        $(impl ToJson for $ty {
+            fn write_json(&self, out: &mut String) {
+                let _ = write!(out, "{self}");
+            }
+        })*
+    };
+}
+
+json_number!(u16, u32, u64, usize);
+
+impl ToJson for f64 {
+    fn write_json(&self, out: &mut String) {
+        // {:?} keeps the ".0" of whole numbers, JSON has no NaN
+        match self.is_finite() {
+            true => {
+                let _ = write!(out, "{self:?}");
+            }
+            false => out.push_str("null"),
+        }
+    }
+}
+
+impl ToJson for bool {
+    fn write_json(&self, out: &mut String) {
+        let _ = write!(out, "{self}");
+    }
+}
+
+impl ToJson for str {
+    fn write_json(&self, out: &mut String) {
+        out.push('"');
+        for c in self.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c < ' ' => {
+                    let _ = write!(out, "\\u{:04x}", c as u32);
+                }
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+}
+
+impl ToJson for String {
+    fn write_json(&self, out: &mut String) {
+        self.as_str().write_json(out)
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Some(v) => v.write_json(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn write_json(&self, out: &mut String) {
+        out.push('[');
+        for (i, v) in self.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            v.write_json(out);
+        }
+        out.push(']');
+    }
+}
+
+//
+// The overrides of a `status` answer, for `pvpn ctl`. Reads what to_json()
+// writes and nothing else, names are plain words and expires_in a number
+//
+pub fn overrides(status: &str) -> Vec<OverrideStatus> {
+    let list = match status.split_once(r#""overrides":["#).and_then(|(_, v)| v.split_once(']')) {
+        Some((list, _)) => list,
+        None => return Vec::new(),
+    };
+
+    list.split("},")
+        .filter_map(|o| {
+            let name = o.split_once(r#""name":""#)?.1.split_once('"')?.0;
+            let expires_in = o.split_once(r#""expires_in":"#)?.1.trim_end_matches('}');
+
+            Some(OverrideStatus {
+                name: name.to_string(),
+                expires_in: expires_in.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+document! {
+    #[derive(Debug, Clone)]
+    pub struct TunnelStatus {
+        pub payload_in: u64,
+        pub payload_out: u64,
+        pub wire_in: u64,
+        pub wire_out: u64,
+        pub efficiency: f64,
+        // read from the tunnel and not parsed yet
+        pub backlog: usize,
+    }
+}
+
+impl From<&TunnelStats> for TunnelStatus {
+    fn from(s: &TunnelStats) -> Self {
+        Self {
+            payload_in: s.payload_in,
+            payload_out: s.payload_out,
+            wire_in: s.wire_in,
+            wire_out: s.wire_out,
+            efficiency: s.efficiency(),
+            backlog: 0,
+        }
+    }
+}
+
+document! {
+    #[derive(Debug, Clone)]
+    pub struct StreamStatus {
+        pub addr: u16,
+        pub to_local: usize,
+        pub to_tunnel: usize,
+        pub paused_window: bool,
+        pub paused_tunnel: bool,
+    }
+}
+
+impl From<&BufferStats> for StreamStatus {
+    fn from(s: &BufferStats) -> Self {
+        Self {
+            addr: s.addr as u16,
+            to_local: s.to_local,
+            to_tunnel: s.to_tunnel,
+            paused_window: s.paused_window,
+            paused_tunnel: s.paused_tunnel,
+        }
+    }
+}
+
+//
+// Backs both the status file and the control socket's `status` command
+//
+document! {
+    #[derive(Debug, Clone)]
+    pub struct Status {
+        // "server" or "client"
+        pub role: String,
+        // None between sessions
+        pub tunnel: Option<TunnelStatus>,
+        pub streams: Vec<StreamStatus>,
+        pub panics: usize,
+        // operator overrides in effect, `pvpn ctl` warns when there are any
+        pub overrides: Vec<OverrideStatus>,
+        // connections the session carries at once, the lower of both sides
+        pub max_connections: Option<usize>,
+    }
+}
+
+document! {
+    #[derive(Debug, Clone)]
+    pub struct OverrideStatus {
+        // stop-accepting, pause-timers...
+        pub name: String,
+        // seconds
+        pub expires_in: u64,
+    }
+}
+
+//
+// The control socket's `list`, the internet listeners of the server
+//
+document! {
+    #[derive(Debug, Clone)]
+    pub struct ForwardList {
+        pub forwards: Vec<ForwardStatus>,
+    }
+}
+
+document! {
+    #[derive(Debug, Clone)]
+    pub struct ForwardStatus {
+        pub label: String,
+        pub addr: String,
+        // "tcp" or "udp"
+        pub protocol: String,
+    }
+}
+
+//
+// Posted to the webhook, never carries the URL nor anything secret
+//
+document! {
+    #[derive(Debug, Clone)]
+    pub struct WebhookEvent {
+        // tunnel-up, tunnel-down...
+        pub event: String,
+        pub role: String,
+        // seconds since the epoch
+        pub time: u64,
+        // peer address, reason or forward label depending on the event
+        pub detail: String,
+    }
+}
+
+//
+// A line of the access log, for each forwarded connection once closed
+//
+document! {
+    #[derive(Debug, Clone)]
+    pub struct AccessRecord {
+        // seconds since the epoch
+        pub time: u64,
+        pub role: String,
+        // internet peer, None when the stream didn't know it
+        pub peer: Option<String>,
+        pub addr: u16,
+        // read from the socket
+        pub bytes_in: u64,
+        // written to the socket
+        pub bytes_out: u64,
+        pub duration_ms: u64,
+        // finished, local-error, peer-error, tunnel-lost... see CloseReason
+        pub reason: String,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //
+    // Changing these means a schema bump, unless only adding fields
+    //
+    #[test]
+    fn status_snapshot() {
+        let status = Status {
+            role: "server".to_string(),
+            tunnel: Some(TunnelStatus {
+                payload_in: 1,
+                payload_out: 2,
+                wire_in: 7,
+                wire_out: 8,
+                efficiency: 20.0,
+                backlog: 3,
+            }),
+            streams: vec![StreamStatus {
+                addr: 4,
+                to_local: 10,
+                to_tunnel: 20,
+                paused_window: false,
+                paused_tunnel: true,
+            }],
+            panics: 0,
+            overrides: vec![OverrideStatus {
+                name: "stop-accepting".to_string(),
+                expires_in: 900,
+            }],
+            max_connections: Some(200),
+        };
+
+        assert_eq!(
+            to_json(&status),
+            concat!(
+                r#"{"schema":1,"role":"server","#,
+                r#""tunnel":{"payload_in":1,"payload_out":2,"wire_in":7,"wire_out":8,"efficiency":20.0,"#,
+                r#""backlog":3},"#,
+                r#""streams":[{"addr":4,"to_local":10,"to_tunnel":20,"paused_window":false,"paused_tunnel":true}],"#,
+                r#""panics":0,"overrides":[{"name":"stop-accepting","expires_in":900}],"max_connections":200}"#
+            )
+        );
+
+        let idle = Status {
+            role: "client".to_string(),
+            tunnel: None,
+            streams: Vec::new(),
+            panics: 1,
+            overrides: Vec::new(),
+            max_connections: None,
+        };
+
+        assert_eq!(
+            to_json(&idle),
+            r#"{"schema":1,"role":"client","tunnel":null,"streams":[],"panics":1,"overrides":[],"max_connections":null}"#
+        );
+    }
+
+    #[test]
+    fn forward_list_snapshot() {
+        let list = ForwardList {
+            forwards: vec![ForwardStatus {
+                label: "web".to_string(),
+                addr: "0.0.0.0:8080".to_string(),
+                protocol: "tcp".to_string(),
+            }],
+        };
+
+        assert_eq!(
+            to_json(&list),
+            r#"{"schema":1,"forwards":[{"label":"web","addr":"0.0.0.0:8080","protocol":"tcp"}]}"#
+        );
+    }
+
+    #[test]
+    fn webhook_event_snapshot() {
+        let event = WebhookEvent {
+            event: "tunnel-up".to_string(),
+            role: "server".to_string(),
+            time: 1700000000,
+            detail: "192.0.2.1:1234".to_string(),
+        };
+
+        assert_eq!(
+            to_json(&event),
+            r#"{"schema":1,"event":"tunnel-up","role":"server","time":1700000000,"detail":"192.0.2.1:1234"}"#
+        );
+    }
+
+    #[test]
+    fn access_record_snapshot() {
+        let record = AccessRecord {
+            time: 1700000000,
+            role: "client".to_string(),
+            peer: Some("192.0.2.1:1234".to_string()),
+            addr: 4,
+            bytes_in: 10,
+            bytes_out: 20,
+            duration_ms: 1500,
+            reason: "finished".to_string(),
+        };
+
+        assert_eq!(
+            to_json(&record),
+            concat!(
+                r#"{"schema":1,"time":1700000000,"role":"client","peer":"192.0.2.1:1234","addr":4,"#,
+                r#""bytes_in":10,"bytes_out":20,"duration_ms":1500,"reason":"finished"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn escaped() {
+        let list = ForwardList {
+            forwards: vec![ForwardStatus {
+                label: "a\"b\\c\nd\te\u{1}é".to_string(),
+                addr: String::new(),
+                protocol: "tcp".to_string(),
+            }],
+        };
+        let json = to_json(&list);
+
+        assert!(json.contains(r#""label":"a\"b\\c\nd\te\u0001é""#), "{json}");
+
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(v["forwards"][0]["label"], list.forwards[0].label);
+
+        assert_eq!(
+            to_json(&ForwardList { forwards: Vec::new() }),
+            r#"{"schema":1,"forwards":[]}"#
+        );
+    }
+
+    #[test]
+    fn ctl_overrides() {
+        let mut status = Status {
+            role: "server".to_string(),
+            tunnel: None,
+            streams: Vec::new(),
+            panics: 0,
+            overrides: Vec::new(),
+            max_connections: None,
+        };
+
+        assert!(overrides(&to_json(&status)).is_empty());
+        assert!(overrides("error no tunnel").is_empty());
+
+        status.overrides = vec![
+            OverrideStatus {
+                name: "stop-accepting".to_string(),
+                expires_in: 900,
+            },
+            OverrideStatus {
+                name: "pause-timers".to_string(),
+                expires_in: 5,
+            },
+        ];
+
+        let found = overrides(&to_json(&status));
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].name.as_str(), found[0].expires_in), ("stop-accepting", 900));
+        assert_eq!((found[1].name.as_str(), found[1].expires_in), ("pause-timers", 5));
+    }
+}
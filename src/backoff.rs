@@ -0,0 +1,125 @@
+//
+// The client's reconnect delay. Doubles from the base after every attempt
+// that didn't last, up to the max, each one jittered down by up to half so
+// the clients of a server coming back don't all knock at once
+//
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEF_RECONNECT_MAX: Duration = Duration::from_secs(30);
+// a session that lasted that long starts over from the base
+pub const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+    // xorshift64*, never 0
+    rng: u64,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seed = now.as_nanos() as u64 ^ ((std::process::id() as u64) << 32);
+
+        Self::with_seed(base, max, seed)
+    }
+
+    pub fn with_seed(base: Duration, max: Duration, seed: u64) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+            attempt: 0,
+            rng: seed | 1,
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    //
+    // The upper bound of the next delay, before the jitter
+    //
+    pub fn ceiling(&self) -> Duration {
+        self.base.saturating_mul(1 << self.attempt.min(31)).min(self.max)
+    }
+
+    //
+    // Somewhere between half the ceiling and the ceiling
+    //
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        let half = ceiling / 2;
+
+        let jitter = match half.as_millis() as u64 {
+            0 => 0,
+            v => self.random() % (v + 1),
+        };
+
+        self.attempt = self.attempt.saturating_add(1);
+
+        half + Duration::from_millis(jitter)
+    }
+
+    //
+    // A session that made it to the server, a long one resets the delay
+    //
+    pub fn on_session(&mut self, lasted: Duration) {
+        if lasted >= STABLE_CONNECTION {
+            self.attempt = 0;
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence() {
+        let ms = Duration::from_millis;
+
+        let mut backoff = Backoff::with_seed(ms(500), Duration::from_secs(30), 42);
+
+        let ceilings: Vec<Duration> = (0..10)
+            .map(|_| {
+                let ceiling = backoff.ceiling();
+                let delay = backoff.next_delay();
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?} {ceiling:?}");
+                ceiling
+            })
+            .collect();
+
+        assert_eq!(
+            ceilings,
+            [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000, 30000].map(ms)
+        );
+
+        // same seed, same delays
+        let delays = |seed| {
+            let mut b = Backoff::with_seed(ms(500), Duration::from_secs(30), seed);
+            (0..10).map(|_| b.next_delay()).collect::<Vec<_>>()
+        };
+        assert_eq!(delays(7), delays(7));
+        assert_ne!(delays(7), delays(8));
+
+        // short sessions keep on backing off, a stable one starts over
+        backoff.on_session(Duration::from_secs(5));
+        assert_eq!(backoff.ceiling(), Duration::from_secs(30));
+        backoff.on_session(STABLE_CONNECTION);
+        assert_eq!(backoff.ceiling(), ms(500));
+
+        // max below the base
+        let mut backoff = Backoff::with_seed(ms(500), ms(100), 1);
+        backoff.next_delay();
+        assert_eq!(backoff.ceiling(), ms(500));
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    net::TcpListener,
+    sync::{Arc, mpsc},
+    thread,
+};
+
+use log::{error, info};
+
+use crate::{
+    error::{Error, Result},
+    tunnel_client::{ClientConfig, client_main},
+    tunnel_server::{Forward, ServerConfig, server_main},
+    unwind::catch_session,
+};
+
+//
+// Both roles in one process, the server's forwards end up on the client's
+// endpoints. There's no in-memory transport, ClientStream and the handshake
+// only know TCP sockets, so the two halves still talk over loopback: the
+// server gets the tunnel listener bound here, never a port to bind again
+//
+pub fn bridge_main(
+    mut server_config: ServerConfig,
+    mut client_config: ClientConfig,
+    forwards: Vec<Forward>,
+) -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let tunnel = listener.local_addr()?.to_string();
+
+    info!("bridge tunnel on {tunnel}");
+
+    server_config.tunnel = tunnel.clone();
+    server_config.tunnel_fd = Some(Arc::new(listener.into()));
+    client_config.tunnel = tunnel;
+
+    let (tx, rx) = mpsc::channel();
+    let client_tx = tx.clone();
+
+    thread::spawn(move || {
+        let _ = client_tx.send(("client", catch_session(|| client_main(&client_config))));
+    });
+    thread::spawn(move || {
+        let _ = tx.send(("server", catch_session(|| server_main(&server_config, forwards))));
+    });
+
+    //
+    // Neither returns while things work, the first one that does takes the
+    // bridge down with it
+    //
+    let (half, ret) = rx.recv().map_err(|_| Error::Internal {
+        payload: "bridge halves gone".to_string(),
+    })?;
+
+    match &ret {
+        Ok(_) => info!("bridge {half} stopped"),
+        Err(e) => error!("bridge {half} stopped ({e})"),
+    }
+
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        test_util::{TEST_TIMEOUT, connect_retry, endpoint},
+        tunnel_server::bind_forward,
+        udp::Protocol,
+    };
+
+    fn forward() -> (Vec<Forward>, String) {
+        let socket = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let listen = socket.local_addr().unwrap().to_string();
+
+        let forwards = vec![Forward {
+            label: "bridge".to_string(),
+            socket,
+        }];
+
+        (forwards, listen)
+    }
+
+    #[test]
+    fn loopback_bridge() {
+        let (listener, target) = endpoint();
+        let (forwards, listen) = forward();
+
+        let server_config = ServerConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let client_config = ClientConfig {
+            server: target,
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        thread::spawn(move || bridge_main(server_config, client_config, forwards));
+
+        let mut internet = connect_retry(&listen);
+        internet.write_all(b"across").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 6] = [0; 6];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"across");
+
+        local.write_all(b"back").unwrap();
+
+        let mut data: [u8; 4] = [0; 4];
+        internet.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"back");
+
+        // over --max-connections
+        let mut over = connect_retry(&listen);
+        let mut data: [u8; 1] = [0; 1];
+        let ret = over.read(&mut data);
+        assert!(
+            matches!(&ret, Ok(0)) || matches!(&ret, Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset),
+            "{ret:?}"
+        );
+    }
+
+    #[test]
+    fn client_failure() {
+        let (forwards, _) = forward();
+        let start = Instant::now();
+
+        let client_config = ClientConfig {
+            server: "pvpn.invalid:22".to_string(),
+            ..Default::default()
+        };
+
+        match bridge_main(ServerConfig::default(), client_config, forwards) {
+            Err(Error::Unresolved { .. }) => (),
+            v => panic!("{v:?}"),
+        }
+
+        assert!(start.elapsed() < TEST_TIMEOUT);
+    }
+}
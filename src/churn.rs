@@ -0,0 +1,255 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+//
+// Connection churn detection for one forward. Pure, fed with accept events
+// and clock ticks so it can be tuned without sockets
+//
+#[derive(Debug, Clone)]
+pub struct ChurnConfig {
+    // spike when the rate goes above baseline * factor, 0 disables
+    pub factor: f64,
+    // the rate is the number of accepts over this sliding window
+    pub window: Duration,
+    // the rate has to stay above the threshold that long to raise the alert
+    pub sustain: Duration,
+    // baseline EWMA half-life
+    pub half_life: Duration,
+    // rates below that are never a spike, whatever the baseline is
+    pub min_rate: usize,
+    // throttle the accepts to the threshold while alerting
+    pub protect: bool,
+}
+
+impl Default for ChurnConfig {
+    fn default() -> Self {
+        Self {
+            factor: 5.0,
+            window: Duration::from_secs(60),
+            sustain: Duration::from_secs(120),
+            half_life: Duration::from_secs(3600),
+            min_rate: 30,
+            protect: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChurnEvent {
+    Spike { rate: usize, baseline: f64 },
+    Normal { rate: usize, baseline: f64 },
+}
+
+impl Display for ChurnEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChurnEvent::Spike { rate, baseline } => write!(f, "event=churn_spike rate={rate} baseline={baseline:.1}"),
+            ChurnEvent::Normal { rate, baseline } => write!(f, "event=churn_normal rate={rate} baseline={baseline:.1}"),
+        }
+    }
+}
+
+pub struct ChurnDetector {
+    config: ChurnConfig,
+    accepts: VecDeque<Instant>,
+    // accepts per window, None until a first window completed
+    baseline: Option<f64>,
+    last_sample: Instant,
+    spike_since: Option<Instant>,
+    alerting: bool,
+}
+
+impl ChurnDetector {
+    pub fn new(config: ChurnConfig, now: Instant) -> Self {
+        Self {
+            config,
+            accepts: VecDeque::new(),
+            baseline: None,
+            last_sample: now,
+            spike_since: None,
+            alerting: false,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(t) = self.accepts.front() {
+            if now.duration_since(*t) <= self.config.window {
+                break;
+            }
+            self.accepts.pop_front();
+        }
+    }
+
+    pub fn rate(&self) -> usize {
+        self.accepts.len()
+    }
+
+    pub fn baseline(&self) -> f64 {
+        self.baseline.unwrap_or(0.0)
+    }
+
+    pub fn is_alerting(&self) -> bool {
+        self.alerting
+    }
+
+    fn threshold(&self) -> f64 {
+        (self.baseline() * self.config.factor).max(self.config.min_rate as f64)
+    }
+
+    pub fn on_accept(&mut self, now: Instant) {
+        if self.config.factor <= 0.0 {
+            return;
+        }
+
+        self.expire(now);
+        self.accepts.push_back(now);
+    }
+
+    //
+    // With protection on, accepts beyond the threshold get refused while the
+    // alert is raised
+    //
+    pub fn should_throttle(&self) -> bool {
+        self.config.protect && self.alerting && self.rate() as f64 > self.threshold()
+    }
+
+    //
+    // Called on every housekeeping tick, returns an event when the alert
+    // state changes
+    //
+    pub fn tick(&mut self, now: Instant) -> Option<ChurnEvent> {
+        if self.config.factor <= 0.0 {
+            return None;
+        }
+
+        self.expire(now);
+
+        let rate = self.rate();
+
+        //
+        // fold one sample per window into the baseline, not while alerting
+        // so an attack doesn't become the new normal
+        //
+        if now.duration_since(self.last_sample) >= self.config.window {
+            self.last_sample = now;
+
+            if !self.alerting {
+                let alpha =
+                    1.0 - (-self.config.window.as_secs_f64() / self.config.half_life.as_secs_f64() * 2f64.ln()).exp();
+
+                self.baseline = Some(match self.baseline {
+                    Some(v) => v + alpha * (rate as f64 - v),
+                    None => rate as f64,
+                });
+            }
+        }
+
+        // None while still learning
+        let baseline = self.baseline?;
+
+        if rate as f64 <= self.threshold() {
+            self.spike_since = None;
+
+            if self.alerting {
+                self.alerting = false;
+                return Some(ChurnEvent::Normal { rate, baseline });
+            }
+
+            return None;
+        }
+
+        let since = *self.spike_since.get_or_insert(now);
+
+        if !self.alerting && now.duration_since(since) >= self.config.sustain {
+            self.alerting = true;
+            return Some(ChurnEvent::Spike { rate, baseline });
+        }
+
+        None
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ChurnConfig {
+        ChurnConfig {
+            factor: 4.0,
+            window: Duration::from_secs(60),
+            sustain: Duration::from_secs(30),
+            half_life: Duration::from_secs(3600),
+            min_rate: 10,
+            protect: true,
+        }
+    }
+
+    //
+    // `per_minute` accepts evenly spread over `secs`, ticking every second
+    //
+    fn run(d: &mut ChurnDetector, start: Instant, secs: u64, per_minute: u64) -> (Instant, Vec<ChurnEvent>) {
+        let mut events = Vec::new();
+        let mut accepted = 0;
+
+        for s in 0..secs {
+            let now = start + Duration::from_secs(s);
+
+            while accepted * 60 < s * per_minute {
+                d.on_accept(now);
+                accepted += 1;
+            }
+
+            events.extend(d.tick(now));
+        }
+
+        (start + Duration::from_secs(secs), events)
+    }
+
+    #[test]
+    fn sustained_spike() {
+        let start = Instant::now();
+        let mut d = ChurnDetector::new(config(), start);
+
+        // two hours of 6/min
+        let (now, events) = run(&mut d, start, 7200, 6);
+        assert!(events.is_empty());
+        assert!((d.baseline() - 6.0).abs() < 1.0);
+
+        // a short burst isn't sustained
+        let (now, events) = run(&mut d, now, 20, 600);
+        assert!(events.is_empty());
+        let (now, _) = run(&mut d, now, 120, 6);
+
+        // 100/min for 3 minutes, a single event
+        let (now, events) = run(&mut d, now, 180, 100);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], ChurnEvent::Spike { .. }));
+        assert!(d.is_alerting());
+        assert!(d.should_throttle());
+
+        // the baseline didn't learn the spike
+        assert!(d.baseline() < 10.0);
+
+        let (_, events) = run(&mut d, now, 180, 6);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], ChurnEvent::Normal { .. }));
+        assert!(!d.should_throttle());
+    }
+
+    #[test]
+    fn below_min_rate() {
+        let start = Instant::now();
+        let mut d = ChurnDetector::new(config(), start);
+
+        // 0 -> 8/min is a big factor but below min_rate
+        let (now, _) = run(&mut d, start, 600, 0);
+        let (_, events) = run(&mut d, now, 600, 8);
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+//
+// Suspend and clock jump detection for the poll loops. The timers run on
+// Instant which stops while the host sleeps, the peer's don't, so whatever
+// expires right after a resume is the sleep's fault. Pure, fed with clock
+// samples so it can be tested without sleeping
+//
+
+// the loops wake up every TICK_INTERVAL, a gap that large isn't load
+pub const SUSPEND_GAP: Duration = Duration::from_secs(10);
+// timers don't expire anything that long after a resume
+pub const RESUME_GRACE: Duration = Duration::from_secs(15);
+// the wall clock drifting that much from the others was set
+pub const CLOCK_JUMP: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    // stops while the host sleeps
+    pub mono: Instant,
+    // keeps counting while the host sleeps, CLOCK_BOOTTIME where there's one
+    pub boot: Duration,
+    // since the epoch, may be set by anyone
+    pub wall: Duration,
+}
+
+#[cfg(target_os = "linux")]
+fn boot_time() -> Option<Duration> {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+
+    if 0 != unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } {
+        return None;
+    }
+
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boot_time() -> Option<Duration> {
+    None
+}
+
+impl ClockSample {
+    pub fn now() -> Self {
+        let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Self {
+            mono: Instant::now(),
+            // a wall clock jump then reads as a suspend
+            boot: boot_time().unwrap_or(wall),
+            wall,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockEvent {
+    // the host slept or the process was stopped
+    Resume { gap: Duration },
+    // someone set the wall clock
+    WallJump { forward: bool, by: Duration },
+}
+
+fn human(d: Duration) -> String {
+    match d.as_secs() {
+        s if s >= 3600 => format!("{} h", s / 3600),
+        s if s >= 60 => format!("{} min", s / 60),
+        s => format!("{s} s"),
+    }
+}
+
+impl Display for ClockEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClockEvent::Resume { gap } => write!(f, "resume detected, gap={}", human(*gap)),
+            ClockEvent::WallJump { forward, by } => {
+                let dir = match forward {
+                    true => "forward",
+                    false => "back",
+                };
+                write!(f, "wall clock jumped {dir} by {}", human(*by))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ResumeDetector {
+    last: Option<ClockSample>,
+    grace_until: Option<Instant>,
+}
+
+impl ResumeDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //
+    // Once per poll loop iteration
+    //
+    pub fn on_sample(&mut self, now: ClockSample) -> Option<ClockEvent> {
+        let last = self.last.replace(now)?;
+
+        let mono = now.mono.saturating_duration_since(last.mono);
+        let boot = now.boot.saturating_sub(last.boot);
+
+        // stopped the loop for that long, asleep or not
+        let gap = mono.max(boot);
+
+        if gap > SUSPEND_GAP {
+            self.grace_until = Some(now.mono + RESUME_GRACE);
+            return Some(ClockEvent::Resume { gap });
+        }
+
+        let (forward, by) = match now.wall.checked_sub(last.wall) {
+            Some(wall) => (wall >= boot, wall.abs_diff(boot)),
+            None => (false, last.wall - now.wall + boot),
+        };
+
+        match by > CLOCK_JUMP {
+            true => Some(ClockEvent::WallJump { forward, by }),
+            false => None,
+        }
+    }
+
+    //
+    // Expiry decisions are off meanwhile, the peer needs a chance to answer
+    //
+    pub fn in_grace(&self, now: Instant) -> bool {
+        matches!(self.grace_until, Some(t) if now < t)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeClock {
+        now: ClockSample,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {
+                now: ClockSample {
+                    mono: Instant::now(),
+                    boot: Duration::from_secs(1000),
+                    wall: Duration::from_secs(1_700_000_000),
+                },
+            }
+        }
+
+        // awake, every clock moves
+        fn run(&mut self, d: Duration) -> ClockSample {
+            self.now.mono += d;
+            self.now.boot += d;
+            self.now.wall += d;
+            self.now
+        }
+
+        // asleep, the monotonic clock doesn't move
+        fn sleep(&mut self, d: Duration) -> ClockSample {
+            self.now.boot += d;
+            self.now.wall += d;
+            self.now
+        }
+    }
+
+    #[test]
+    fn suspend() {
+        let tick = Duration::from_secs(1);
+        let mut clock = FakeClock::new();
+        let mut d = ResumeDetector::new();
+
+        for _ in 0..10 {
+            assert_eq!(d.on_sample(clock.run(tick)), None);
+        }
+
+        let gap = Duration::from_secs(37 * 60);
+        clock.sleep(gap);
+
+        let event = d.on_sample(clock.run(tick)).unwrap();
+        assert_eq!(event, ClockEvent::Resume { gap: gap + tick });
+        assert_eq!(event.to_string(), "resume detected, gap=37 min");
+
+        // the timers hold off for a while
+        assert!(d.in_grace(clock.now.mono));
+        assert_eq!(d.on_sample(clock.run(tick)), None);
+        assert!(d.in_grace(clock.now.mono));
+
+        clock.run(RESUME_GRACE);
+        assert!(!d.in_grace(clock.now.mono));
+    }
+
+    #[test]
+    fn stopped_process() {
+        let mut clock = FakeClock::new();
+        let mut d = ResumeDetector::new();
+
+        d.on_sample(clock.run(Duration::from_secs(1)));
+
+        // SIGSTOP, every clock moved
+        let event = d.on_sample(clock.run(Duration::from_secs(60)));
+        assert_eq!(
+            event,
+            Some(ClockEvent::Resume {
+                gap: Duration::from_secs(60)
+            })
+        );
+    }
+
+    #[test]
+    fn wall_jump() {
+        let tick = Duration::from_secs(1);
+        let mut clock = FakeClock::new();
+        let mut d = ResumeDetector::new();
+
+        d.on_sample(clock.run(tick));
+
+        clock.now.wall -= Duration::from_secs(3600);
+        let event = d.on_sample(clock.run(tick)).unwrap();
+
+        assert_eq!(
+            event,
+            ClockEvent::WallJump {
+                forward: false,
+                by: Duration::from_secs(3600)
+            }
+        );
+        assert_eq!(event.to_string(), "wall clock jumped back by 1 h");
+
+        // timers run on the monotonic clock, nothing to hold off
+        assert!(!d.in_grace(clock.now.mono));
+
+        // NTP sized adjustments go unnoticed
+        clock.now.wall += Duration::from_millis(500);
+        assert_eq!(d.on_sample(clock.run(tick)), None);
+    }
+}
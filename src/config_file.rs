@@ -0,0 +1,270 @@
+//
+// --config <path>, a TOML file with a [client] and a [server] table. The keys
+// are the flags' names with underscores ( tunnel_port = 1414 ), a list for
+// the ones that repeat. What the command line or the environment sets wins
+// over the file
+//
+use std::path::Path;
+
+use clap::{
+    ArgAction, ArgMatches, Command,
+    error::{ContextKind, ContextValue},
+    parser::ValueSource,
+};
+use toml_edit::{DocumentMut, Item, Value};
+
+use crate::error::{Error, Result};
+
+pub const SECTIONS: [&str; 2] = ["client", "server"];
+
+#[derive(Debug, Default)]
+pub struct FileArgs {
+    path: String,
+    // (section.key, --flag=value)
+    args: Vec<(String, String)>,
+}
+
+impl FileArgs {
+    //
+    // The [section] of the file as arguments for cmd, only for the flags
+    // matches didn't get from the command line or the environment
+    //
+    pub fn load(path: &Path, section: &str, cmd: &Command, matches: &ArgMatches) -> Result<Self> {
+        let name = path.display().to_string();
+
+        let text = std::fs::read_to_string(path).map_err(|e| Error::InvalidConfig {
+            path: name.clone(),
+            key: String::new(),
+            reason: e.to_string(),
+        })?;
+
+        Ok(Self {
+            args: args_from(&text, &name, section, cmd, matches)?,
+            path: name,
+        })
+    }
+
+    //
+    // The file gave flag id its value
+    //
+    pub fn sets(&self, id: &str) -> bool {
+        self.args.iter().any(|(k, _)| k.split_once('.').map(|(_, k)| k) == Some(id))
+    }
+
+    pub fn args(&self) -> impl Iterator<Item = &String> {
+        self.args.iter().map(|(_, v)| v)
+    }
+
+    //
+    // clap's error names a flag, the key of the file when it came from there
+    //
+    pub fn blame(&self, e: &clap::Error) -> Option<Error> {
+        let flag = match e.get(ContextKind::InvalidArg) {
+            Some(ContextValue::String(v)) => v.split_whitespace().next()?,
+            _ => return None,
+        };
+
+        let (key, _) = self.args.iter().find(|(_, arg)| arg.split('=').next() == Some(flag))?;
+
+        let reason = e.to_string();
+        let reason = reason.lines().next().unwrap_or_default();
+
+        Some(Error::InvalidConfig {
+            path: self.path.clone(),
+            key: key.clone(),
+            reason: reason.trim_start_matches("error: ").to_string(),
+        })
+    }
+}
+
+fn args_from(
+    text: &str,
+    path: &str,
+    section: &str,
+    cmd: &Command,
+    matches: &ArgMatches,
+) -> Result<Vec<(String, String)>> {
+    let invalid = |key: &str, reason: &str| Error::InvalidConfig {
+        path: path.to_string(),
+        key: key.to_string(),
+        reason: reason.trim().to_string(),
+    };
+
+    let doc: DocumentMut = text.parse().map_err(|e: toml_edit::TomlError| invalid("", &e.to_string()))?;
+
+    if let Some((key, _)) = doc.iter().find(|(k, _)| !SECTIONS.contains(k)) {
+        return Err(invalid(key, "unknown section"));
+    }
+
+    let table = match doc.get(section) {
+        Some(v) => v.as_table().ok_or_else(|| invalid(section, "expecting a table"))?,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut args = Vec::new();
+
+    for (key, item) in table.iter() {
+        let name = format!("{section}.{key}");
+
+        let arg = cmd
+            .get_arguments()
+            .find(|a| a.get_id() == key && a.get_long().is_some() && "config" != key)
+            .ok_or_else(|| invalid(&name, "unknown key"))?;
+
+        if let Some(ValueSource::CommandLine | ValueSource::EnvVariable) = matches.value_source(key) {
+            continue;
+        }
+
+        let long = format!("--{}", arg.get_long().unwrap_or(key));
+
+        let values: Vec<&Value> = match item {
+            Item::Value(Value::Array(v)) => v.iter().collect(),
+            Item::Value(v) => vec![v],
+            _ => return Err(invalid(&name, "expecting a value")),
+        };
+
+        match arg.get_action() {
+            ArgAction::SetTrue => match values[..] {
+                [Value::Boolean(v)] => {
+                    if *v.value() {
+                        args.push((name.clone(), long));
+                    }
+                }
+                _ => return Err(invalid(&name, "expecting true or false")),
+            },
+            ArgAction::Set | ArgAction::Append => {
+                if values.len() > 1 && matches!(arg.get_action(), ArgAction::Set) && arg.get_value_delimiter().is_none()
+                {
+                    return Err(invalid(&name, "expecting a single value"));
+                }
+
+                for v in values {
+                    let v = match v {
+                        Value::String(v) => v.value().clone(),
+                        Value::Integer(v) => v.value().to_string(),
+                        Value::Float(v) => v.value().to_string(),
+                        Value::Boolean(v) => v.value().to_string(),
+                        _ => return Err(invalid(&name, "expecting a string or a number")),
+                    };
+
+                    args.push((name.clone(), format!("{long}={v}")));
+                }
+            }
+            _ => return Err(invalid(&name, "unknown key")),
+        }
+    }
+
+    Ok(args)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, value_parser};
+
+    use super::*;
+
+    fn cmd() -> Command {
+        Command::new("client")
+            .arg(Arg::new("tunnel_port").long("tunnel-port").value_parser(value_parser!(u16)))
+            .arg(Arg::new("endpoint").long("endpoint").action(ArgAction::Append))
+            .arg(Arg::new("verbose").long("verbose").action(ArgAction::SetTrue))
+            .arg(Arg::new("config").long("config"))
+    }
+
+    fn load(text: &str, argv: &[&str]) -> Result<FileArgs> {
+        let cmd = cmd();
+        let matches = cmd
+            .clone()
+            .get_matches_from(std::iter::once("client").chain(argv.iter().copied()));
+
+        Ok(FileArgs {
+            path: "pvpn.toml".to_string(),
+            args: args_from(text, "pvpn.toml", "client", &cmd, &matches)?,
+        })
+    }
+
+    fn args(text: &str, argv: &[&str]) -> Result<Vec<String>> {
+        Ok(load(text, argv)?.args().cloned().collect())
+    }
+
+    //
+    // What the file is blamed for, by it or when clap parses what it gave
+    //
+    fn bad_key(text: &str) -> String {
+        let file = match load(text, &[]) {
+            Err(Error::InvalidConfig { key, .. }) => return key,
+            Err(e) => panic!("{e:?}"),
+            Ok(v) => v,
+        };
+
+        let e = cmd()
+            .try_get_matches_from(std::iter::once(&"client".to_string()).chain(file.args()))
+            .unwrap_err();
+
+        match file.blame(&e) {
+            Some(Error::InvalidConfig { key, .. }) => key,
+            v => panic!("{v:?}"),
+        }
+    }
+
+    #[test]
+    fn file() {
+        let text = r#"
+            [client]
+            tunnel_port = 4000
+            endpoint = ["ssh=127.0.0.1:22", "web=127.0.0.1:80"]
+            verbose = true
+
+            [server]
+            anything = "the client doesn't look"
+        "#;
+
+        assert_eq!(
+            args(text, &[]).unwrap(),
+            [
+                "--tunnel-port=4000",
+                "--endpoint=ssh=127.0.0.1:22",
+                "--endpoint=web=127.0.0.1:80",
+                "--verbose"
+            ]
+        );
+
+        // the command line wins
+        assert_eq!(
+            args(text, &["--tunnel-port", "5000", "--endpoint", "x=127.0.0.1:1"]).unwrap(),
+            ["--verbose"]
+        );
+
+        assert!(args("[server]\nverbose = true", &[]).unwrap().is_empty());
+        assert!(args("[client]\nverbose = false", &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(bad_key("[client]\ntunnel_prot = 1"), "client.tunnel_prot");
+        assert_eq!(bad_key("[client]\ntunnel_port = 70000"), "client.tunnel_port");
+        assert_eq!(bad_key("[client]\ntunnel_port = [1, 2]"), "client.tunnel_port");
+        assert_eq!(bad_key("[client]\nverbose = \"yes\""), "client.verbose");
+        assert_eq!(bad_key("[client]\nconfig = \"other.toml\""), "client.config");
+        assert_eq!(bad_key("[clinet]\nverbose = true"), "clinet");
+        assert_eq!(bad_key("client = 1"), "client");
+        assert_eq!(bad_key("[client]\ntunnel_port = "), "");
+
+        let file = load("[client]\ntunnel_port = 70000", &[]).unwrap();
+        let e = cmd().try_get_matches_from(["client", "--tunnel-port=70000"]).unwrap_err();
+        match file.blame(&e) {
+            Some(Error::InvalidConfig { path, reason, .. }) => {
+                assert_eq!(path, "pvpn.toml");
+                assert!(reason.contains("70000"), "{reason}");
+            }
+            v => panic!("{v:?}"),
+        }
+
+        // from the command line, clap's
+        let e = cmd().try_get_matches_from(["client", "--tunnel-port=x"]).unwrap_err();
+        assert!(load("", &[]).unwrap().blame(&e).is_none());
+    }
+}
@@ -0,0 +1,657 @@
+//
+// Operator control socket, a Unix socket speaking one command per
+// connection: the client sends a line, gets the answer and the server closes.
+// Access control is the socket file's permissions. Besides the overrides it
+// adds and removes internet forwards without a restart
+//
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{fs::FileTypeExt, net},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use mio::{
+    Interest, Registry, Token,
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    api::{ForwardList, ForwardStatus, OverrideStatus, Status, to_json},
+    error::{Error, Result},
+    stats::PeerStats,
+    streams::{ReadOutcome, read_outcome},
+};
+
+// a forgotten debug toggle goes away by itself after that
+pub const DEF_OVERRIDE_TTL: Duration = Duration::from_secs(15 * 60);
+
+// past the forward listeners, see tunnel_server
+const CONTROL_LISTENER: Token = Token(0x2_0000);
+const FIRST_CONNECTION: usize = 0x2_0001;
+// a command is a short line, anything longer is garbage
+const MAX_COMMAND: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Override {
+    // new internet connections wait in the listen backlog
+    StopAccepting,
+    // no keepalive probes, an idle client gets dropped by the tunnel timeout
+    StopKeepalives,
+    // no half-close, UDP nor tunnel timeouts
+    PauseTimers,
+}
+
+const ALL_OVERRIDES: &[Override] = &[Override::StopAccepting, Override::StopKeepalives, Override::PauseTimers];
+
+impl Display for Override {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Override::StopAccepting => write!(f, "stop-accepting"),
+            Override::StopKeepalives => write!(f, "stop-keepalives"),
+            Override::PauseTimers => write!(f, "pause-timers"),
+        }
+    }
+}
+
+impl FromStr for Override {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match ALL_OVERRIDES.iter().find(|o| o.to_string() == s) {
+            Some(v) => Ok(*v),
+            None => Err(format!("unknown override {s}")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Set(Override),
+    // None clears them all
+    Clear(Option<Override>),
+    // forces the client through a reconnect
+    DropTunnel,
+    Status,
+    // a new internet listener on that port, 0 for any
+    AddForward(u16),
+    RemoveForward(u16),
+    List,
+    // the session's totals, as in the Stats frames
+    Stats,
+}
+
+//
+// stop-accepting | stop-keepalives | pause-timers | clear [override] |
+// drop-tunnel | status | add-forward <port> | remove-forward <port> | list |
+// stats
+//
+pub fn parse_command(line: &str) -> Result<Command> {
+    let invalid = || Error::InvalidCommand { line: line.to_string() };
+
+    let mut words = line.split_whitespace();
+
+    let port = |p: &str| p.parse::<u16>().map_err(|_| invalid());
+
+    let cmd = match (words.next(), words.next()) {
+        (Some("status"), None) => Command::Status,
+        (Some("list"), None) => Command::List,
+        (Some("stats"), None) => Command::Stats,
+        (Some("add-forward"), Some(p)) => Command::AddForward(port(p)?),
+        (Some("remove-forward"), Some(p)) => Command::RemoveForward(port(p)?),
+        (Some("drop-tunnel"), None) => Command::DropTunnel,
+        (Some("clear"), None) => Command::Clear(None),
+        (Some("clear"), Some(o)) => Command::Clear(Some(o.parse().map_err(|_| invalid())?)),
+        (Some(o), None) => Command::Set(o.parse().map_err(|_| invalid())?),
+        _ => return Err(invalid()),
+    };
+
+    match words.next() {
+        Some(_) => Err(invalid()),
+        None => Ok(cmd),
+    }
+}
+
+//
+// Active overrides and when they expire
+//
+#[derive(Debug)]
+pub struct Overrides {
+    ttl: Duration,
+    active: Vec<(Override, Instant)>,
+    // cleared or expired since the last take_ended()
+    ended: Vec<Override>,
+}
+
+impl Overrides {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            active: Vec::new(),
+            ended: Vec::new(),
+        }
+    }
+
+    //
+    // Setting it again pushes the expiry back
+    //
+    pub fn set(&mut self, o: Override, now: Instant) {
+        self.active.retain(|(a, _)| *a != o);
+        self.active.push((o, now + self.ttl));
+    }
+
+    pub fn clear(&mut self, o: Override) -> bool {
+        let before = self.active.len();
+        self.active.retain(|(a, _)| *a != o);
+
+        match before != self.active.len() {
+            true => {
+                self.ended.push(o);
+                true
+            }
+            false => false,
+        }
+    }
+
+    pub fn is_active(&self, o: Override) -> bool {
+        self.active.iter().any(|(a, _)| *a == o)
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<Override> {
+        let expired: Vec<Override> = self
+            .active
+            .iter()
+            .filter(|(_, deadline)| *deadline <= now)
+            .map(|(o, _)| *o)
+            .collect();
+
+        self.active.retain(|(_, deadline)| *deadline > now);
+        self.ended.extend_from_slice(&expired);
+        expired
+    }
+
+    //
+    // The loops undo what the overrides held back, e.g. the pending accepts
+    //
+    pub fn take_ended(&mut self) -> Vec<Override> {
+        std::mem::take(&mut self.ended)
+    }
+
+    pub fn status(&self, now: Instant) -> Vec<OverrideStatus> {
+        self.active
+            .iter()
+            .map(|(o, deadline)| OverrideStatus {
+                name: o.to_string(),
+                expires_in: deadline.saturating_duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+}
+
+//
+// What the commands act on, the loop that owns the control socket
+//
+pub trait Target {
+    fn status(&self) -> Status;
+    // None between sessions
+    fn stats(&self) -> Option<PeerStats>;
+    fn forwards(&self) -> Vec<ForwardStatus>;
+    // where it listens
+    fn add_forward(&mut self, port: u16) -> Result<SocketAddr>;
+    fn remove_forward(&mut self, port: u16) -> Result<()>;
+}
+
+//
+// pid and uid of the process on the other end, for the operator records
+//
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: RawFd) -> Option<(i32, u32)> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match ret {
+        0 => Some((cred.pid, cred.uid)),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(_fd: RawFd) -> Option<(i32, u32)> {
+    None
+}
+
+struct Connection {
+    stream: UnixStream,
+    // "pid=.. uid=.." or "unknown"
+    peer: String,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // the answer is queued, close once it's out
+    answered: bool,
+}
+
+pub struct Control {
+    path: PathBuf,
+    listener: UnixListener,
+    connections: HashMap<Token, Connection>,
+    next: usize,
+    pub overrides: Overrides,
+}
+
+impl Control {
+    //
+    // A socket left behind by a previous run is replaced, not a live one nor
+    // something else at the path
+    //
+    pub fn bind(path: &Path, ttl: Duration) -> Result<Self> {
+        remove_stale(path)?;
+
+        let listener = UnixListener::bind(path)?;
+        info!("control socket on {}", path.display());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener,
+            connections: HashMap::new(),
+            next: FIRST_CONNECTION,
+            overrides: Overrides::new(ttl),
+        })
+    }
+
+    //
+    // Each loop has its own Poll, the control socket follows the loop that
+    // runs
+    //
+    pub fn register(&mut self, registry: &Registry) -> Result<()> {
+        registry.register(&mut self.listener, CONTROL_LISTENER, Interest::READABLE)?;
+
+        for (token, c) in self.connections.iter_mut() {
+            registry.register(&mut c.stream, *token, Interest::READABLE | Interest::WRITABLE)?;
+        }
+        Ok(())
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        registry.deregister(&mut self.listener)?;
+
+        for c in self.connections.values_mut() {
+            registry.deregister(&mut c.stream)?;
+        }
+        Ok(())
+    }
+
+    pub fn owns(&self, token: Token) -> bool {
+        CONTROL_LISTENER == token || self.connections.contains_key(&token)
+    }
+
+    pub fn is_active(&self, o: Override) -> bool {
+        self.overrides.is_active(o)
+    }
+
+    //
+    // Called on every tick
+    //
+    pub fn expire(&mut self) {
+        for o in self.overrides.expire(Instant::now()) {
+            warn!("operator override {o} expired");
+        }
+    }
+
+    fn accept(&mut self, registry: &Registry) {
+        loop {
+            let (mut stream, _) = match self.listener.accept() {
+                Ok(v) => v,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("control accept failed ({e})");
+                    return;
+                }
+            };
+
+            let peer = match peer_credentials(stream.as_raw_fd()) {
+                Some((pid, uid)) => format!("pid={pid} uid={uid}"),
+                None => "unknown".to_string(),
+            };
+
+            let token = Token(self.next);
+            self.next = match self.next >= usize::MAX - 1 {
+                true => FIRST_CONNECTION,
+                false => self.next + 1,
+            };
+
+            if let Err(e) = registry.register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                warn!("control register failed ({e})");
+                continue;
+            }
+
+            debug!("control connection from {peer}");
+
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    peer,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                    answered: false,
+                },
+            );
+        }
+    }
+
+    fn execute(&mut self, cmd: Command, peer: &str, target: &mut dyn Target) -> (String, bool) {
+        let now = Instant::now();
+
+        match cmd {
+            Command::Set(o) => {
+                warn!(
+                    "operator action={o} by {peer}, expires in {} s",
+                    self.overrides.ttl.as_secs()
+                );
+                self.overrides.set(o, now);
+                ("ok".to_string(), false)
+            }
+            Command::Clear(Some(o)) => match self.overrides.clear(o) {
+                true => {
+                    warn!("operator action=clear {o} by {peer}");
+                    ("ok".to_string(), false)
+                }
+                false => (format!("error {o} not active"), false),
+            },
+            Command::Clear(None) => {
+                for o in ALL_OVERRIDES {
+                    if self.overrides.clear(*o) {
+                        warn!("operator action=clear {o} by {peer}");
+                    }
+                }
+                ("ok".to_string(), false)
+            }
+            Command::DropTunnel => match target.status().tunnel {
+                Some(_) => {
+                    warn!("operator action=drop-tunnel by {peer}");
+                    ("ok".to_string(), true)
+                }
+                None => ("error no tunnel".to_string(), false),
+            },
+            Command::Status => {
+                let mut status = target.status();
+                status.overrides = self.overrides.status(now);
+
+                (to_json(&status), false)
+            }
+            Command::AddForward(port) => match target.add_forward(port) {
+                Ok(addr) => {
+                    warn!("operator action=add-forward {addr} by {peer}");
+                    (format!("ok {addr}"), false)
+                }
+                Err(e) => (format!("error {e}"), false),
+            },
+            Command::RemoveForward(port) => match target.remove_forward(port) {
+                Ok(()) => {
+                    warn!("operator action=remove-forward {port} by {peer}");
+                    ("ok".to_string(), false)
+                }
+                Err(e) => (format!("error {e}"), false),
+            },
+            Command::List => {
+                let list = ForwardList {
+                    forwards: target.forwards(),
+                };
+
+                (to_json(&list), false)
+            }
+            Command::Stats => match target.stats() {
+                Some(v) => (v.to_string(), false),
+                None => ("error no tunnel".to_string(), false),
+            },
+        }
+    }
+
+    //
+    // Control socket events, never fatal to the loop. True when the operator
+    // asked for the tunnel to be dropped
+    //
+    pub fn on_event(&mut self, registry: &Registry, token: Token, target: &mut dyn Target) -> bool {
+        if CONTROL_LISTENER == token {
+            self.accept(registry);
+            return false;
+        }
+
+        let mut c = match self.connections.remove(&token) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        let mut drop_tunnel = false;
+        let mut closed = false;
+
+        if !c.answered {
+            let mut buf = [0; 256];
+
+            loop {
+                match read_outcome(&mut c.stream, &mut buf) {
+                    Ok(ReadOutcome::Data(v)) => c.input.extend_from_slice(&buf[0..v]),
+                    Ok(ReadOutcome::WouldBlock) => break,
+                    Ok(ReadOutcome::Eof) | Err(_) => {
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+
+            let line = match c.input.iter().position(|b| b'\n' == *b) {
+                Some(i) => Some(String::from_utf8_lossy(&c.input[0..i]).trim().to_string()),
+                // a client that shut its side down without a newline
+                None if closed && !c.input.is_empty() => Some(String::from_utf8_lossy(&c.input).trim().to_string()),
+                None if c.input.len() > MAX_COMMAND => Some(String::new()),
+                None => None,
+            };
+
+            if let Some(line) = line {
+                let peer = c.peer.clone();
+
+                let answer = match parse_command(&line) {
+                    Ok(cmd) => {
+                        let (answer, drop) = self.execute(cmd, &peer, target);
+                        drop_tunnel = drop;
+                        answer
+                    }
+                    Err(e) => format!("error {e}"),
+                };
+
+                c.output.extend_from_slice(answer.as_bytes());
+                c.output.push(b'\n');
+                c.answered = true;
+                closed = false;
+            }
+        }
+
+        while !c.output.is_empty() {
+            match c.stream.write(&c.output) {
+                Ok(v) => {
+                    c.output.drain(0..v);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+
+        match closed || (c.answered && c.output.is_empty()) {
+            true => {
+                let _ = registry.deregister(&mut c.stream);
+            }
+            false => {
+                self.connections.insert(token, c);
+            }
+        }
+
+        drop_tunnel
+    }
+}
+
+//
+// Unlinks path if it's a socket nothing listens on anymore
+//
+fn remove_stale(path: &Path) -> Result<()> {
+    let exists = |reason: &str| Error::ControlSocketExists {
+        path: path.display().to_string(),
+        reason: reason.to_string(),
+    };
+
+    let meta = match fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(e) if ErrorKind::NotFound == e.kind() => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(exists("not a socket"));
+    }
+
+    match net::UnixStream::connect(path) {
+        Ok(_) => Err(exists("in use by another server")),
+        Err(e) if ErrorKind::ConnectionRefused == e.kind() => {
+            info!("removing the stale socket {}", path.display());
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(exists(&e.to_string())),
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+//
+// Client side, what `pvpn ctl` uses
+//
+pub fn command(path: &Path, line: &str) -> Result<String> {
+    let mut stream = net::UnixStream::connect(path)?;
+
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+
+    Ok(answer.trim_end().to_string())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            parse_command("stop-accepting").unwrap(),
+            Command::Set(Override::StopAccepting)
+        );
+        assert_eq!(
+            parse_command(" clear pause-timers ").unwrap(),
+            Command::Clear(Some(Override::PauseTimers))
+        );
+        assert_eq!(parse_command("clear").unwrap(), Command::Clear(None));
+        assert_eq!(parse_command("drop-tunnel").unwrap(), Command::DropTunnel);
+
+        assert!(parse_command("").is_err());
+        assert!(parse_command("stop-everything").is_err());
+        assert!(parse_command("status now").is_err());
+
+        assert_eq!(parse_command("add-forward 8080").unwrap(), Command::AddForward(8080));
+        assert_eq!(
+            parse_command("remove-forward 8080").unwrap(),
+            Command::RemoveForward(8080)
+        );
+        assert_eq!(parse_command("list").unwrap(), Command::List);
+        assert!(parse_command("add-forward").is_err());
+        assert!(parse_command("add-forward 70000").is_err());
+        assert!(parse_command("remove-forward 80 81").is_err());
+    }
+
+    #[test]
+    fn overrides_expire() {
+        let start = Instant::now();
+        let mut o = Overrides::new(Duration::from_secs(10));
+
+        o.set(Override::StopAccepting, start);
+        o.set(Override::PauseTimers, start + Duration::from_secs(5));
+        assert!(o.is_active(Override::StopAccepting));
+
+        assert!(o.expire(start + Duration::from_secs(9)).is_empty());
+        assert_eq!(o.expire(start + Duration::from_secs(10)), vec![Override::StopAccepting]);
+        assert!(!o.is_active(Override::StopAccepting));
+
+        let status = o.status(start + Duration::from_secs(11));
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].name, "pause-timers");
+        assert_eq!(status[0].expires_in, 4);
+
+        assert!(o.clear(Override::PauseTimers));
+        assert!(!o.clear(Override::PauseTimers));
+
+        assert_eq!(o.take_ended(), vec![Override::StopAccepting, Override::PauseTimers]);
+        assert!(o.take_ended().is_empty());
+    }
+
+    #[test]
+    fn stale_socket() {
+        let path = std::env::temp_dir().join(format!("pvpn-ctl-stale-{}.sock", std::process::id()));
+        let ttl = DEF_OVERRIDE_TTL;
+        let _ = fs::remove_file(&path);
+
+        // a mistyped path
+        fs::write(&path, "keep me").unwrap();
+        assert!(matches!(
+            Control::bind(&path, ttl),
+            Err(Error::ControlSocketExists { .. })
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+        fs::remove_file(&path).unwrap();
+
+        // a live server's
+        let live = Control::bind(&path, ttl).unwrap();
+        assert!(matches!(
+            Control::bind(&path, ttl),
+            Err(Error::ControlSocketExists { .. })
+        ));
+        assert!(net::UnixStream::connect(&path).is_ok());
+        drop(live);
+
+        // left behind, the listener is closed but the file stays
+        drop(net::UnixListener::bind(&path).unwrap());
+        let control = Control::bind(&path, ttl).unwrap();
+        assert!(net::UnixStream::connect(&path).is_ok());
+
+        drop(control);
+        assert!(!path.exists());
+    }
+}
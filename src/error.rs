@@ -2,6 +2,8 @@ pub type Result<T> = core::result::Result<T, Error>;
 
 use derive_more::From;
 
+use crate::handshake::GoodbyeReason;
+
 #[derive(Debug, From)]
 pub enum Error {
     ReadFailure,
@@ -9,6 +11,10 @@ pub enum Error {
     Empty,
     NotEnoughData,
     ConnectionRefused,
+    // a connection to an endpoint that won't complete
+    ConnectFailed {
+        kind: std::io::ErrorKind,
+    },
     ClientNotFound,
     BufferTooSmall {
         max: usize,
@@ -21,6 +27,90 @@ pub enum Error {
     InvalidMessageType {
         msg: u8,
     },
+    InvalidLabel {
+        label: String,
+    },
+    InvalidHandshake,
+    InvalidMotd,
+    InvalidPortList {
+        spec: String,
+    },
+    InvalidForward {
+        spec: String,
+    },
+    InvalidCidr {
+        spec: String,
+    },
+    // the local address or device of that flag can't be used
+    LocalBindFailed {
+        flag: String,
+        reason: String,
+    },
+    InvalidProxy {
+        spec: String,
+    },
+    // --config, key is empty when the file itself is the problem
+    InvalidConfig {
+        path: String,
+        key: String,
+        reason: String,
+    },
+    // the proxy didn't take the credentials
+    ProxyAuthFailed,
+    // the proxy couldn't or wouldn't reach the tunnel
+    ProxyRefused {
+        reason: String,
+    },
+    // a host name of that flag didn't resolve
+    Unresolved {
+        flag: &'static str,
+        spec: String,
+        reason: String,
+    },
+    // a local socket stopped draining what the tunnel sends it
+    BufferFull {
+        addr: usize,
+        buffered: usize,
+    },
+    // the tunnel went silent
+    TunnelTimeout,
+    // drop-tunnel on the control socket
+    TunnelDropped,
+    // a new client connected, --tunnel-takeover
+    TunnelReplaced,
+    InvalidCommand {
+        line: String,
+    },
+    // remove-forward of a port nothing listens on
+    UnknownForward {
+        port: u16,
+    },
+    // --lazy-listen binds the same forwards for every session
+    ForwardsFixed,
+    // --control-socket names something that isn't a stale socket
+    ControlSocketExists {
+        path: String,
+        reason: String,
+    },
+    // --max-tunnel-failures sessions or --max-reconnect-attempts in a row
+    // failed
+    TooManyFailures {
+        failures: usize,
+    },
+    InvalidWebhook {
+        url: String,
+    },
+    // non 2xx answer
+    WebhookRejected,
+    // the peer dropped the tunnel and said why
+    Goodbye {
+        reason: GoodbyeReason,
+        message: String,
+    },
+    // a session panicked
+    Internal {
+        payload: String,
+    },
     IoError,
     //
     // 2d party
@@ -41,6 +131,52 @@ pub enum Error {
     AddrError(std::net::AddrParseError),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    // the configuration or the host, the next attempt fails the same way
+    Fatal,
+    // the network or the peer, the next client may do better
+    Retryable,
+}
+
+impl core::fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ErrorClass::Fatal => write!(f, "fatal"),
+            ErrorClass::Retryable => write!(f, "retryable"),
+        }
+    }
+}
+
+impl Error {
+    //
+    // EACCES only, a firewall's EPERM on a send is about that one packet
+    //
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::Io(e) => match e.kind() {
+                std::io::ErrorKind::AddrInUse | std::io::ErrorKind::AddrNotAvailable => ErrorClass::Fatal,
+                _ if Some(libc::EACCES) == e.raw_os_error() => ErrorClass::Fatal,
+                _ => ErrorClass::Retryable,
+            },
+            Error::AddrError(_)
+            | Error::InvalidPortList { .. }
+            | Error::InvalidForward { .. }
+            | Error::InvalidCidr { .. }
+            | Error::Unresolved { .. }
+            | Error::LocalBindFailed { .. }
+            | Error::InvalidProxy { .. }
+            | Error::InvalidConfig { .. }
+            | Error::ControlSocketExists { .. }
+            | Error::ProxyAuthFailed
+            | Error::InvalidWebhook { .. }
+            | Error::LoggingError(_)
+            | Error::TooManyFailures { .. } => ErrorClass::Fatal,
+            _ => ErrorClass::Retryable,
+        }
+    }
+}
+
 impl core::fmt::Display for Error {
     fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
         match self {
@@ -51,3 +187,37 @@ impl core::fmt::Display for Error {
         }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let os = |errno| Error::from(std::io::Error::from_raw_os_error(errno));
+
+        assert_eq!(os(libc::EACCES).class(), ErrorClass::Fatal);
+        assert_eq!(os(libc::EADDRINUSE).class(), ErrorClass::Fatal);
+        assert_eq!(os(libc::EPERM).class(), ErrorClass::Retryable);
+        assert_eq!(os(libc::ECONNRESET).class(), ErrorClass::Retryable);
+        assert_eq!(os(libc::ETIMEDOUT).class(), ErrorClass::Retryable);
+
+        assert_eq!(
+            "x".parse::<std::net::SocketAddr>().map_err(Error::from).unwrap_err().class(),
+            ErrorClass::Fatal
+        );
+        assert_eq!(Error::Eof.class(), ErrorClass::Retryable);
+        assert_eq!(Error::TunnelTimeout.class(), ErrorClass::Retryable);
+
+        let goodbye = |reason| Error::Goodbye {
+            reason,
+            message: String::new(),
+        };
+        // whoever sends it, the peer's word doesn't take a server down
+        assert_eq!(goodbye(GoodbyeReason::AuthRevoked).class(), ErrorClass::Retryable);
+        assert_eq!(goodbye(GoodbyeReason::Replaced).class(), ErrorClass::Retryable);
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    fmt::Display,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    error::{Error, Result},
+    packet::{Address, PacketMessage},
+    streams::TokenStreams,
+};
+
+pub const MAX_LABEL_LEN: usize = 32;
+// Banner frames are capped, a larger file is truncated
+pub const MAX_MOTD_LEN: usize = 4096;
+
+// The peer can take Banner frames
+pub const FEATURE_BANNER: &str = "banner";
+// The peer can take Stats frames
+pub const FEATURE_STATS: &str = "stats";
+// The peer wants a Released once an address it handed out is done with
+pub const FEATURE_RELEASE: &str = "release";
+
+// most a Goodbye may hold up the teardown
+pub const GOODBYE_TIMEOUT: Duration = Duration::from_millis(200);
+
+//
+// Sent by the server on the control address as soon as the tunnel is up, the
+// client answers with its own once it knows its session parameters.
+//
+// The payload is a list of key=value lines so either side can add keys
+// without breaking the other, unknown keys are ignored
+//
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hello {
+    // forward labels, indexed by channel
+    pub forwards: Vec<String>,
+    // internet port the server ended up listening on
+    pub port: Option<u16>,
+    // largest Data payload the sender wants to get, from the path probe
+    pub mtu: Option<usize>,
+    // optional frames the sender understands
+    pub features: Vec<String>,
+    // most connections the sender can carry at once
+    pub max_connections: Option<usize>,
+}
+
+//
+// Each side may have a limit, the session goes with the lower one
+//
+pub fn session_max_connections(server: Option<usize>, client: Option<usize>) -> Option<usize> {
+    match (server, client) {
+        (Some(s), Some(c)) => Some(s.min(c)),
+        (s, c) => s.or(c),
+    }
+}
+
+pub fn validate_label(label: &str) -> Result<()> {
+    let valid = !label.is_empty()
+        && label.len() <= MAX_LABEL_LEN
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidLabel {
+            label: label.to_string(),
+        }),
+    }
+}
+
+//
+// Operator supplied banner, must be UTF-8 and gets truncated to
+// MAX_MOTD_LEN on a character boundary
+//
+pub fn load_motd(path: &Path) -> Result<String> {
+    let data = std::fs::read(path)?;
+
+    let mut motd = String::from_utf8(data).map_err(|_| Error::InvalidMotd)?;
+
+    if motd.len() > MAX_MOTD_LEN {
+        let mut end = MAX_MOTD_LEN;
+        while !motd.is_char_boundary(end) {
+            end -= 1;
+        }
+        warn!("{} is larger than {MAX_MOTD_LEN} bytes, truncated", path.display());
+        motd.truncate(end);
+    }
+
+    Ok(motd)
+}
+
+impl Hello {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = String::new();
+
+        for label in &self.forwards {
+            out.push_str(&format!("forward={label}\n"));
+        }
+
+        if let Some(port) = self.port {
+            out.push_str(&format!("port={port}\n"));
+        }
+
+        if let Some(mtu) = self.mtu {
+            out.push_str(&format!("mtu={mtu}\n"));
+        }
+
+        for feature in &self.features {
+            out.push_str(&format!("feature={feature}\n"));
+        }
+
+        if let Some(max) = self.max_connections {
+            out.push_str(&format!("max_connections={max}\n"));
+        }
+
+        out.into_bytes()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(buf).map_err(|_| Error::InvalidHandshake)?;
+
+        let mut hello = Hello::default();
+
+        for line in text.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some(v) => v,
+                None => return Err(Error::InvalidHandshake),
+            };
+
+            match key {
+                "forward" => {
+                    validate_label(value)?;
+                    hello.forwards.push(value.to_string());
+                }
+                "port" => hello.port = Some(value.parse().map_err(|_| Error::InvalidHandshake)?),
+                "mtu" => hello.mtu = Some(value.parse().map_err(|_| Error::InvalidHandshake)?),
+                "feature" => hello.features.push(value.to_string()),
+                "max_connections" => hello.max_connections = Some(value.parse().map_err(|_| Error::InvalidHandshake)?),
+                _ => {}
+            }
+        }
+
+        Ok(hello)
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub fn label(&self, channel: usize) -> &str {
+        match self.forwards.get(channel) {
+            Some(v) => v,
+            None => "?",
+        }
+    }
+}
+
+impl Display for Hello {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "forwards=[{}]", self.forwards.join(","))?;
+
+        if let Some(port) = self.port {
+            write!(f, " port={port}")?;
+        }
+
+        if let Some(mtu) = self.mtu {
+            write!(f, " mtu={mtu}")?;
+        }
+
+        if !self.features.is_empty() {
+            write!(f, " features=[{}]", self.features.join(","))?;
+        }
+
+        if let Some(max) = self.max_connections {
+            write!(f, " max_connections={max}")?;
+        }
+
+        Ok(())
+    }
+}
+
+//
+// Why a side is about to drop the tunnel, lets the other one pick a
+// reconnect policy instead of treating it as a network failure
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum GoodbyeReason {
+    // the session panicked or hit a bug
+    Internal,
+    // the peer sent something that makes no sense
+    Protocol,
+    // the sender's configuration can't work, retrying soon won't help
+    ConfigError,
+    // the peer isn't welcome anymore, don't come back
+    AuthRevoked,
+    // drop-tunnel on the control socket
+    Operator,
+    // nothing was heard from the peer
+    Timeout,
+    // the server already has a client
+    Busy,
+    // another client took over the tunnel ( --tunnel-takeover )
+    Replaced,
+    // a code from a newer peer
+    Other,
+}
+
+impl GoodbyeReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GoodbyeReason::Internal => "internal",
+            GoodbyeReason::Protocol => "protocol",
+            GoodbyeReason::ConfigError => "config-error",
+            GoodbyeReason::AuthRevoked => "auth-revoked",
+            GoodbyeReason::Operator => "operator",
+            GoodbyeReason::Timeout => "timeout",
+            GoodbyeReason::Busy => "busy",
+            GoodbyeReason::Replaced => "replaced",
+            GoodbyeReason::Other => "other",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "internal" => GoodbyeReason::Internal,
+            "protocol" => GoodbyeReason::Protocol,
+            "config-error" => GoodbyeReason::ConfigError,
+            "auth-revoked" => GoodbyeReason::AuthRevoked,
+            "operator" => GoodbyeReason::Operator,
+            "timeout" => GoodbyeReason::Timeout,
+            "busy" => GoodbyeReason::Busy,
+            "replaced" => GoodbyeReason::Replaced,
+            _ => GoodbyeReason::Other,
+        }
+    }
+
+    //
+    // What a session ending on `e` tells the peer, None when the tunnel
+    // itself is the problem or the peer is the one leaving
+    //
+    pub fn for_error(e: &Error) -> Option<Self> {
+        match e {
+            Error::Io(_) | Error::Eof | Error::Goodbye { .. } => None,
+            Error::Internal { .. } => Some(GoodbyeReason::Internal),
+            Error::TunnelDropped => Some(GoodbyeReason::Operator),
+            Error::TunnelTimeout => Some(GoodbyeReason::Timeout),
+            Error::TunnelReplaced => Some(GoodbyeReason::Replaced),
+            Error::AddrError(_) => Some(GoodbyeReason::ConfigError),
+            Error::InvalidVersion { .. }
+            | Error::InvalidMessageType { .. }
+            | Error::InvalidHandshake
+            | Error::InvalidLabel { .. }
+            | Error::BufferTooSmall { .. } => Some(GoodbyeReason::Protocol),
+            _ => Some(GoodbyeReason::Internal),
+        }
+    }
+}
+
+impl Display for GoodbyeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+//
+// Last frame before a side drops the tunnel on purpose, same key=value
+// lines as Hello
+//
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Goodbye {
+    pub reason: GoodbyeReason,
+    // for the peer's logs
+    pub message: String,
+}
+
+impl Goodbye {
+    pub fn encode(&self) -> Vec<u8> {
+        // one line per key
+        let message = self.message.replace(['\n', '\r'], " ");
+        format!("reason={}\nmessage={message}\n", self.reason).into_bytes()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(buf).map_err(|_| Error::InvalidHandshake)?;
+
+        let mut goodbye = Goodbye {
+            reason: GoodbyeReason::Other,
+            message: String::new(),
+        };
+
+        for line in text.lines() {
+            match line.split_once('=') {
+                Some(("reason", v)) => goodbye.reason = GoodbyeReason::parse(v),
+                Some(("message", v)) => goodbye.message = v.to_string(),
+                Some(_) => {}
+                None => return Err(Error::InvalidHandshake),
+            }
+        }
+
+        Ok(goodbye)
+    }
+}
+
+impl Display for Goodbye {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reason={} message={}", self.reason, self.message)
+    }
+}
+
+//
+// In the tunnel's buffer for a loop that flushes it on its own, false when
+// there's nothing to tell
+//
+pub fn queue_goodbye(streams: &mut TokenStreams, tunnel: Address, e: &Error) -> bool {
+    let reason = match GoodbyeReason::for_error(e) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    let goodbye = Goodbye {
+        reason,
+        message: e.to_string(),
+    };
+
+    if let Err(e) = streams.write_control(tunnel, PacketMessage::Goodbye, &goodbye.encode()) {
+        debug!("unable to queue the goodbye ({e})");
+        return false;
+    }
+
+    true
+}
+
+//
+// Best effort, the session is over either way. Never holds the teardown for
+// more than GOODBYE_TIMEOUT
+//
+pub fn send_goodbye(streams: &mut TokenStreams, tunnel: Address, e: &Error) {
+    if !queue_goodbye(streams, tunnel, e) {
+        return;
+    }
+
+    let deadline = Instant::now() + GOODBYE_TIMEOUT;
+
+    loop {
+        if let Err(e) = streams.flush(tunnel) {
+            debug!("unable to send the goodbye ({e})");
+            return;
+        }
+
+        match streams.buffered_len(tunnel) {
+            Some(0) | None => return,
+            Some(_) if Instant::now() >= deadline => {
+                debug!("goodbye not sent within {}ms", GOODBYE_TIMEOUT.as_millis());
+                return;
+            }
+            Some(_) => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        assert!(validate_label("ssh-prod").is_ok());
+        assert!(validate_label("web_1.internal").is_ok());
+        assert!(validate_label("").is_err());
+        assert!(validate_label("with space").is_err());
+        assert!(validate_label("x=y").is_err());
+        assert!(validate_label(&"a".repeat(MAX_LABEL_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello {
+            forwards: vec!["ssh-prod".to_string()],
+            port: Some(8081),
+            mtu: Some(1024),
+            features: vec![FEATURE_BANNER.to_string()],
+            max_connections: Some(200),
+        };
+
+        let decoded = Hello::decode(&hello.encode()).unwrap();
+        assert_eq!(hello, decoded);
+        assert_eq!(decoded.label(0), "ssh-prod");
+        assert_eq!(decoded.label(1), "?");
+        assert_eq!(decoded.port, Some(8081));
+        assert!(decoded.has_feature(FEATURE_BANNER));
+
+        // unknown keys from a newer peer are ignored
+        let decoded = Hello::decode(b"forward=web\nsomething=else\n").unwrap();
+        assert_eq!(decoded.forwards, vec!["web".to_string()]);
+    }
+
+    #[test]
+    fn goodbye_round_trip() {
+        let goodbye = Goodbye {
+            reason: GoodbyeReason::ConfigError,
+            message: "no endpoint\nfor ssh".to_string(),
+        };
+
+        let decoded = Goodbye::decode(&goodbye.encode()).unwrap();
+        assert_eq!(decoded.reason, GoodbyeReason::ConfigError);
+        assert_eq!(decoded.message, "no endpoint for ssh");
+
+        let decoded = Goodbye::decode(b"reason=moved-on\n").unwrap();
+        assert_eq!(decoded.reason, GoodbyeReason::Other);
+
+        assert_eq!(GoodbyeReason::for_error(&Error::Eof), None);
+        assert_eq!(
+            GoodbyeReason::for_error(&Error::Internal {
+                payload: "boom".to_string()
+            }),
+            Some(GoodbyeReason::Internal)
+        );
+    }
+
+    #[test]
+    fn max_connections() {
+        assert_eq!(session_max_connections(Some(1000), Some(200)), Some(200));
+        assert_eq!(session_max_connections(Some(50), Some(200)), Some(50));
+        assert_eq!(session_max_connections(None, Some(200)), Some(200));
+        assert_eq!(session_max_connections(Some(50), None), Some(50));
+        assert_eq!(session_max_connections(None, None), None);
+    }
+
+    #[test]
+    fn motd_truncated() {
+        let path = std::env::temp_dir().join(format!("pvpn-motd-{}", std::process::id()));
+
+        // multi byte characters straddling the limit
+        std::fs::write(&path, "é".repeat(MAX_MOTD_LEN)).unwrap();
+        let motd = load_motd(&path).unwrap();
+        assert_eq!(motd.len(), MAX_MOTD_LEN);
+        assert!(motd.chars().all(|c| c == 'é'));
+
+        std::fs::write(&path, "maintenance Saturday 02:00 UTC\n").unwrap();
+        assert_eq!(load_motd(&path).unwrap(), "maintenance Saturday 02:00 UTC\n");
+
+        std::fs::write(&path, [0xff, 0xfe]).unwrap();
+        assert!(matches!(load_motd(&path), Err(Error::InvalidMotd)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
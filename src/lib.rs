@@ -1,5 +1,36 @@
+pub mod access_log;
+pub mod acl;
+pub mod api;
+pub mod backoff;
+pub mod bridge;
+pub mod churn;
+pub mod clock;
+pub mod config_file;
+pub mod control;
 pub mod error;
+pub mod handshake;
+pub mod logging;
+pub mod outbound;
+pub mod overload;
 pub mod packet;
+pub mod probe;
+pub mod proxy;
+pub mod ratelimit;
+pub mod resolve;
+pub mod resource;
+pub mod signals;
+pub mod stats;
 pub mod streams;
+#[cfg(feature = "systemd")]
+pub mod systemd;
+pub mod timers;
+pub mod tokens;
 pub mod tunnel_client;
 pub mod tunnel_server;
+pub mod udp;
+pub mod unwind;
+pub mod watchdog;
+pub mod webhook;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,265 @@
+//
+// The same failure over and over, a server down for the night, is logged
+// once then summed up every interval instead of once per attempt. And the
+// lines of --log-format json
+//
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::api::JsonObject;
+
+// how often a repeated failure is summed up
+pub const REPEAT_SUMMARY: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+pub struct RepeatLog {
+    interval: Duration,
+    // the failure being repeated and when it was last logged
+    last: Option<String>,
+    logged: Option<Instant>,
+    // repeats of last not logged yet
+    repeats: u64,
+    // failures in a row, whatever they were
+    failures: u64,
+}
+
+impl RepeatLog {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+            logged: None,
+            repeats: 0,
+            failures: 0,
+        }
+    }
+
+    //
+    // What to log for this failure, None when it's the last one again and
+    // only counted. A different one is logged right away
+    //
+    pub fn on_failure(&mut self, what: &str, now: Instant) -> Option<String> {
+        self.failures += 1;
+
+        if self.last.as_deref() != Some(what) {
+            self.last = Some(what.to_string());
+            self.logged = Some(now);
+            self.repeats = 0;
+            return Some(what.to_string());
+        }
+
+        self.repeats += 1;
+
+        match self.logged {
+            Some(t) if now.duration_since(t) < self.interval => None,
+            _ => {
+                let line = format!(
+                    "{what}, {} attempts in the last {}",
+                    self.repeats,
+                    minutes(self.interval)
+                );
+                self.logged = Some(now);
+                self.repeats = 0;
+                Some(line)
+            }
+        }
+    }
+
+    //
+    // What to log once it works again, None if it never failed
+    //
+    pub fn on_success(&mut self) -> Option<String> {
+        let failures = std::mem::take(&mut self.failures);
+
+        self.last = None;
+        self.logged = None;
+        self.repeats = 0;
+
+        match failures {
+            0 => None,
+            n => Some(format!("back after {n} failed attempts")),
+        }
+    }
+}
+
+// --log-format
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expecting text or json".to_string()),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+// what a message's key=value pairs can't take over
+const JSON_KEYS: [&str; 6] = ["ts", "level", "target", "line", "message", "forward"];
+
+//
+// One record as a single line of JSON. The message's key=value pairs (
+// token=, bytes= ) are fields of their own, numbers as numbers, and the
+// "[label]" it starts with the forward
+//
+pub fn json_line(ts: &str, level: log::Level, target: &str, line: Option<u32>, message: &str) -> String {
+    let mut obj = JsonObject::default();
+
+    obj.field("ts", ts);
+    obj.field("level", level.as_str());
+    obj.field("target", target);
+    obj.field("line", &line);
+    obj.field("message", message);
+
+    if let Some(label) = message.strip_prefix('[').and_then(|m| m.split_once(']')).map(|(l, _)| l) {
+        obj.field("forward", label);
+    }
+
+    // the first of a key wins
+    let mut seen = Vec::new();
+
+    for (k, v) in fields(message) {
+        if JSON_KEYS.contains(&k) || seen.contains(&k) {
+            continue;
+        }
+        seen.push(k);
+
+        match v.parse::<u64>() {
+            Ok(n) => obj.field(k, &n),
+            Err(_) => obj.field(k, v),
+        }
+    }
+
+    obj.finish()
+}
+
+fn fields(message: &str) -> impl Iterator<Item = (&str, &str)> {
+    message.split_whitespace().filter_map(|word| {
+        let (k, v) = word.split_once('=')?;
+        let v = v.trim_end_matches([',', ';', ')']);
+
+        if k.is_empty() || v.is_empty() || !k.bytes().all(|b| b.is_ascii_lowercase() || b == b'_') {
+            return None;
+        }
+
+        Some((k, v))
+    })
+}
+
+fn minutes(d: Duration) -> String {
+    match d.as_secs() {
+        s if 0 == s % 60 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn repeats() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let refused = "Connection refused (os error 111)";
+
+        let mut log = RepeatLog::new(REPEAT_SUMMARY);
+        assert_eq!(log.on_success(), None);
+
+        assert_eq!(log.on_failure(refused, start).as_deref(), Some(refused));
+
+        let logged = (1..=1284)
+            .filter_map(|i| log.on_failure(refused, start + secs(i % 599)))
+            .count();
+        assert_eq!(logged, 0);
+
+        assert_eq!(
+            log.on_failure(refused, start + REPEAT_SUMMARY).unwrap(),
+            "Connection refused (os error 111), 1285 attempts in the last 10m"
+        );
+        assert_eq!(log.on_failure(refused, start + REPEAT_SUMMARY + secs(1)), None);
+
+        // something else, right away
+        assert_eq!(
+            log.on_failure("Connection timed out", start + REPEAT_SUMMARY + secs(2))
+                .as_deref(),
+            Some("Connection timed out")
+        );
+
+        assert_eq!(log.on_success().unwrap(), "back after 1288 failed attempts");
+        assert_eq!(log.on_success(), None);
+
+        // logged again after a success
+        assert_eq!(log.on_failure(refused, start + secs(700)).as_deref(), Some(refused));
+    }
+
+    fn parse(line: &str) -> Value {
+        assert!(!line.contains('\n'), "{line}");
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn json() {
+        let v = parse(&json_line(
+            "2026-10-15T10:00:00.123Z",
+            log::Level::Info,
+            "pvpn::tunnel_server",
+            Some(1269),
+            "[ssh] token=10.0.0.1:5555 read bytes=512 from internet",
+        ));
+
+        assert_eq!(v["ts"], "2026-10-15T10:00:00.123Z");
+        assert_eq!(v["level"], "INFO");
+        assert_eq!(v["target"], "pvpn::tunnel_server");
+        assert_eq!(v["line"], 1269);
+        assert_eq!(v["forward"], "ssh");
+        assert_eq!(v["token"], "10.0.0.1:5555");
+        assert_eq!(v["bytes"], 512);
+
+        // escaped, and no field takes a reserved key over
+        let message = "quote=\"x\" tab\there\nnew line level=debug \\ \u{1}, token=[::1]:22)";
+        let v = parse(&json_line("", log::Level::Warn, "t", None, message));
+
+        assert_eq!(v["message"], message);
+        assert_eq!(v["level"], "WARN");
+        assert_eq!(v["line"], Value::Null);
+        assert_eq!(v["quote"], "\"x\"");
+        assert_eq!(v["token"], "[::1]:22");
+        assert!(v.get("forward").is_none());
+
+        // streams' frame lines
+        let p = crate::packet::Packet::new(7, crate::packet::PacketMessage::Data, 42);
+        let v = parse(&json_line("", log::Level::Debug, "t", None, &format!("WRITE: {p}")));
+        assert_eq!(v["token"], 7);
+        assert_eq!(v["len"], 42);
+
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::default().to_string(), "text");
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,116 +1,1477 @@
-use pvpn::{error::Result, tunnel_client::client_main, tunnel_server::server_main};
+use pvpn::{
+    acl::{Acl, Cidr},
+    api,
+    backoff::DEF_RECONNECT_MAX,
+    bridge::bridge_main,
+    churn::ChurnConfig,
+    config_file::FileArgs,
+    control::{DEF_OVERRIDE_TTL, command},
+    error::{Error, ErrorClass, Result},
+    handshake::{GoodbyeReason, load_motd, validate_label},
+    logging::{LogFormat, json_line},
+    outbound::LocalBind,
+    proxy::Proxy,
+    ratelimit::AcceptRate,
+    resource::fd_capacity,
+    signals::install_sighup,
+    streams::{BUFFER_SIZE, CONNECT_TIMEOUT, DEF_MAX_BUFFERED},
+    tunnel_client::{ClientConfig, client_main},
+    tunnel_server::{
+        Forward, ForwardSocket, ServerConfig, WhenDown, bind_forward, bind_v6_only, host_port, parse_forward,
+        parse_port_list, server_main,
+    },
+    udp::{DEF_UDP_TIMEOUT, Protocol},
+    unwind::install_panic_hook,
+    watchdog::DEF_WATCHDOG_TIMEOUT,
+    webhook::{DEF_SPOOL_MAX, EventKind, WebhookConfig},
+};
 
-use clap::{Parser, Subcommand};
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    os::fd::OwnedFd,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, error::ErrorKind, parser::ValueSource};
 use rstaples::display::printkv;
 
 pub const DEF_SERVER_PORT: u16 = 1414;
-const DEF_INTERNET_PORT: u16 = 8080;
+const DEF_TUNNEL_TIMEOUT: u64 = 90;
+const DEF_INTERNET_PORT: &str = "8080";
 const DEF_LISTEN_ADDR: &str = "0.0.0.0";
+// --max-reconnect-attempts or --max-tunnel-failures ran out, the supervisor's
+// turn. Not the watchdog's 3
+const EXIT_GAVE_UP: i32 = 5;
+// the configuration or the host, restarting as is won't help
+const EXIT_FATAL: i32 = 4;
+// --tunnel-port is also PVPN_TUNNEL_PORT
+const ENV_PREFIX: &str = "PVPN_";
 
 #[derive(Parser, Debug)]
 #[command(name = "pvpn", color=clap::ColorChoice::Never)]
 struct UserArgs {
     #[command(subcommand)]
     command: Commands,
+
+    /// print the command line using the current flag names and exit
+    #[arg(long, global = true)]
+    print_migrated_command: bool,
+
+    // the flags not at their default, see settings()
+    #[arg(skip)]
+    settings: Vec<String>,
+}
+
+//
+// Flag names of the former tokio binaries, still accepted as hidden aliases
+//
+const DEPRECATED_FLAGS: &[(&str, &str)] = &[
+    ("--internet-address", "--server-address"),
+    ("--internet-port", "--server-port"),
+    ("--client-address", "--tunnel-address"),
+    ("--client-port", "--tunnel-port"),
+    ("--endpoint-address", "--server-address"),
+    ("--endpoint-port", "--server-port"),
+];
+
+//
+// Rewrites the deprecated flags, returns the new argv and the (old, new)
+// pairs that were used
+//
+fn migrate_args(args: &[String]) -> (Vec<String>, Vec<(&'static str, &'static str)>) {
+    let mut migrated = Vec::with_capacity(args.len());
+    let mut used = Vec::new();
+
+    for arg in args {
+        let (flag, value) = match arg.split_once('=') {
+            Some((f, v)) => (f, Some(v)),
+            None => (arg.as_str(), None),
+        };
+
+        match DEPRECATED_FLAGS.iter().find(|(old, _)| *old == flag) {
+            Some((old, new)) => {
+                if !used.iter().any(|(o, _)| o == old) {
+                    used.push((*old, *new));
+                }
+                match value {
+                    Some(v) => migrated.push(format!("{new}={v}")),
+                    None => migrated.push(new.to_string()),
+                }
+            }
+            None => migrated.push(arg.clone()),
+        }
+    }
+
+    (migrated, used)
 }
 
 #[derive(Parser, Debug)]
 struct ClientArgs {
-    /// tunnel server
-    #[arg(long)]
-    tunnel_address: String,
+    /// tunnel server, a host name or an address. Repeat or separate with commas for backups, tried in order
+    #[arg(long, required = true, value_delimiter = ',')]
+    tunnel_address: Vec<String>,
 
-    /// tunnel port
+    /// tunnel port, for the --tunnel-address entries without their own
     #[arg(long, default_value_t=DEF_SERVER_PORT)]
     tunnel_port: u16,
 
-    /// server address
-    #[arg(long)]
-    server_address: String,
+    /// server address, a host name or an address
+    #[arg(
+        long,
+        alias = "endpoint-address",
+        required_unless_present_any = ["endpoint", "forward"],
+        requires = "server_port"
+    )]
+    server_address: Option<String>,
 
     /// server port
-    #[arg(long)]
-    server_port: u16,
+    #[arg(long, alias = "endpoint-port", requires = "server_address")]
+    server_port: Option<u16>,
+
+    /// endpoint of a named forward ( e.g. ssh=127.0.0.1:22 ), can be repeated
+    #[arg(long, value_parser = parse_endpoint)]
+    endpoint: Vec<(String, String)>,
+
+    /// endpoint of the server's forward on that internet port ( e.g. 8080=127.0.0.1:3000 ), can be repeated
+    #[arg(long, value_parser = parse_port_endpoint)]
+    forward: Vec<(String, String)>,
 
     /// verbose
     #[arg(short, long)]
     verbose: bool,
 
-    /// reconnect delay in milliseconds
+    /// log lines as text or as one JSON object each
+    #[arg(long, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// TOML file with the flags of a [client] table, the command line wins
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// first reconnect delay in milliseconds, doubled after each failed attempt
     #[arg(short, long, default_value_t = 500)]
     reconnect_delay: u64,
+
+    /// longest reconnect delay in milliseconds
+    #[arg(long, default_value_t = DEF_RECONNECT_MAX.as_millis() as u64)]
+    reconnect_max: u64,
+
+    /// exit after that many failed attempts in a row ( 0 retries forever )
+    #[arg(long, default_value_t = 0)]
+    max_reconnect_attempts: u64,
+
+    /// reach the tunnel server through that proxy, socks5://host:port[:user:pass] or
+    /// http://[user:pass@]host:port
+    #[arg(long, value_parser = parse_proxy)]
+    proxy: Option<Proxy>,
+
+    /// local address the tunnel connection is made from
+    #[arg(long)]
+    tunnel_bind: Option<IpAddr>,
+
+    /// local address the endpoint connections are made from
+    #[arg(long)]
+    endpoint_bind: Option<IpAddr>,
+
+    /// interface the endpoint connections go through ( SO_BINDTODEVICE )
+    #[arg(long)]
+    endpoint_bind_device: Option<String>,
+
+    /// abort if the event loop is stuck for this many seconds ( 0 disables )
+    #[arg(long, default_value_t = DEF_WATCHDOG_TIMEOUT)]
+    watchdog_timeout: u64,
+
+    /// send a PROXY protocol v1 line to the server with the internet peer address
+    #[arg(long)]
+    proxy_protocol: bool,
+
+    /// skip probing the largest frame size that makes it through the tunnel
+    #[arg(long)]
+    no_path_probe: bool,
+
+    /// lower the frame size to what the path probe found
+    #[arg(long)]
+    probe_clamp: bool,
+
+    /// don't show the server's banner
+    #[arg(long)]
+    no_motd: bool,
+
+    /// protocol of the forwarded service, has to match the server's
+    #[arg(long, default_value_t = Protocol::Tcp)]
+    protocol: Protocol,
+
+    /// seconds before closing the socket of an idle UDP peer
+    #[arg(long, default_value_t = DEF_UDP_TIMEOUT.as_secs())]
+    udp_timeout: u64,
+
+    /// seconds before closing a connection nothing went through ( 0 disables )
+    #[arg(long, default_value_t = 0)]
+    idle_timeout: u64,
+
+    /// seconds an endpoint connection may take to complete, refused after that
+    #[arg(long, default_value_t = CONNECT_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    endpoint_connect_timeout: u64,
+
+    /// seconds before TCP keepalive probes start on the tunnel and the connections ( 0 disables )
+    #[arg(long, default_value_t = 0)]
+    tcp_keepalive: u64,
+
+    /// seconds without hearing from the server before reconnecting ( 0 disables )
+    #[arg(long, default_value_t = DEF_TUNNEL_TIMEOUT)]
+    tunnel_timeout: u64,
+
+    /// tunnel bandwidth limit in kbit/s
+    #[arg(long)]
+    max_rate: Option<u64>,
+
+    /// KiB waiting for a stalled connection before dropping it
+    #[arg(long, default_value_t = DEF_MAX_BUFFERED / 1024)]
+    max_buffered: usize,
+
+    /// bytes read from a socket at once
+    #[arg(long, default_value_t = BUFFER_SIZE)]
+    buffer_size: usize,
+
+    /// endpoint connections this host can carry, told to the server, the ones above are refused
+    /// ( defaults to what RLIMIT_NOFILE allows )
+    #[arg(long, alias = "max-endpoint-connections")]
+    client_max_connections: Option<usize>,
+
+    /// append a JSON line per endpoint connection to that file
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+
+    #[command(flatten)]
+    webhook: WebhookArgs,
 }
 
 #[derive(Parser, Debug)]
 struct ServerArgs {
     /// tunnel server
-    #[arg(long, default_value=DEF_LISTEN_ADDR)]
+    #[arg(long, default_value=DEF_LISTEN_ADDR, alias = "client-address")]
     tunnel_address: String,
 
     /// tunnel port
-    #[arg(long, default_value_t=DEF_SERVER_PORT)]
+    #[arg(long, default_value_t=DEF_SERVER_PORT, alias = "client-port")]
     tunnel_port: u16,
 
     /// server address
-    #[arg(long, default_value = DEF_LISTEN_ADDR)]
+    #[arg(long, default_value = DEF_LISTEN_ADDR, alias = "internet-address")]
     server_address: String,
 
-    /// server port
-    #[arg(long, default_value_t=DEF_INTERNET_PORT)]
-    server_port: u16,
+    /// also listen on [::] for the v6 peers, same ports as --server-address
+    #[arg(long)]
+    dual_stack: bool,
+
+    /// keep the internet ports closed while no client is attached
+    #[arg(long, conflicts_with = "dual_stack")]
+    lazy_listen: bool,
+
+    /// server port, or ports to try in order ( e.g. 8080,8081,8090-8099,0 )
+    #[arg(long, default_value = DEF_INTERNET_PORT, value_parser = parse_ports, alias = "internet-port")]
+    server_port: PortList,
+
+    /// internet port(s) and name of a forward ( e.g. 2222:ssh ), can be
+    /// repeated, replaces --server-port and --label
+    #[arg(long, value_parser = parse_forward_arg)]
+    forward: Vec<(Vec<u16>, String)>,
+
+    /// forward name used in logs ( defaults to the server port )
+    #[arg(long, value_parser = parse_label)]
+    label: Option<String>,
 
     /// verbose
     #[arg(short, long)]
     verbose: bool,
+
+    /// log lines as text or as one JSON object each
+    #[arg(long, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// TOML file with the flags of a [server] table, the command line wins
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// abort if the event loop is stuck for this many seconds ( 0 disables )
+    #[arg(long, default_value_t = DEF_WATCHDOG_TIMEOUT)]
+    watchdog_timeout: u64,
+
+    /// banner sent to the clients on connect, reloaded on SIGHUP
+    #[arg(long)]
+    motd: Option<PathBuf>,
+
+    /// warn when the connection rate goes above the baseline by this factor ( 0 disables )
+    #[arg(long, default_value_t = 5.0)]
+    churn_factor: f64,
+
+    /// seconds the connection rate has to stay above the threshold
+    #[arg(long, default_value_t = 120)]
+    churn_sustain: u64,
+
+    /// connections per minute that never count as a spike
+    #[arg(long, default_value_t = 30)]
+    churn_min_rate: usize,
+
+    /// refuse the connections above the threshold while a spike lasts
+    #[arg(long)]
+    churn_protect: bool,
+
+    /// protocol of the forwarded services
+    #[arg(long, default_value_t = Protocol::Tcp)]
+    protocol: Protocol,
+
+    /// seconds before forgetting an idle UDP peer
+    #[arg(long, default_value_t = DEF_UDP_TIMEOUT.as_secs())]
+    udp_timeout: u64,
+
+    /// seconds before closing an internet connection nothing went through ( 0 disables )
+    #[arg(long, alias = "client-idle-timeout", default_value_t = 0)]
+    idle_timeout: u64,
+
+    /// seconds before TCP keepalive probes start on the tunnel and the connections ( 0 disables )
+    #[arg(long, default_value_t = 0)]
+    tcp_keepalive: u64,
+
+    /// seconds without hearing from the client before dropping the tunnel ( 0 disables )
+    #[arg(long, default_value_t = DEF_TUNNEL_TIMEOUT)]
+    tunnel_timeout: u64,
+
+    /// tunnel bandwidth limit in kbit/s
+    #[arg(long)]
+    max_rate: Option<u64>,
+
+    /// KiB waiting for a stalled connection before dropping it
+    #[arg(long, default_value_t = DEF_MAX_BUFFERED / 1024)]
+    max_buffered: usize,
+
+    /// bytes read from a socket at once
+    #[arg(long, default_value_t = BUFFER_SIZE)]
+    buffer_size: usize,
+
+    /// unix socket taking operator commands ( see pvpn ctl )
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    /// seconds before an operator override reverts by itself
+    #[arg(long, default_value_t = DEF_OVERRIDE_TTL.as_secs())]
+    override_ttl: u64,
+
+    /// log an error when a session leaves file descriptors behind
+    #[arg(long)]
+    check_fds: bool,
+
+    /// append a JSON line per internet connection to that file
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+
+    /// internet connections carried at once, lowered to the client's if less
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// connections past --max-connections wait instead of being closed
+    #[arg(long, requires = "max_connections")]
+    queue_when_full: bool,
+
+    /// tunnel clients served at once, new connections go round-robin
+    #[arg(long, default_value_t = 1, conflicts_with_all = ["control_socket", "check_fds", "tunnel_takeover", "lazy_listen"])]
+    max_clients: usize,
+
+    /// internet connections while no client is connected, queue or refuse
+    #[arg(long, default_value_t = WhenDown::Queue)]
+    when_tunnel_down: WhenDown,
+
+    /// internet peers let in ( 203.0.113.0/24, 2001:db8::/32 ), everyone if none
+    #[arg(long, value_parser = parse_cidr)]
+    allow_cidr: Vec<Cidr>,
+
+    /// a new client replaces the connected one instead of being refused
+    #[arg(long)]
+    tunnel_takeover: bool,
+
+    /// internet peers turned away, even when an --allow-cidr matches
+    #[arg(long, value_parser = parse_cidr)]
+    deny_cidr: Vec<Cidr>,
+
+    /// new internet connections per second from a single address, the others are closed
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    accept_rate: Option<u64>,
+
+    /// connections an address may open at once before --accept-rate applies ( defaults to the rate )
+    #[arg(long, requires = "accept_rate", value_parser = clap::value_parser!(u64).range(1..))]
+    accept_burst: Option<u64>,
+
+    /// exit after that many failed tunnel sessions in a row, retry forever if not set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_tunnel_failures: Option<u64>,
+
+    #[command(flatten)]
+    webhook: WebhookArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct WebhookArgs {
+    /// POST a JSON event to this http:// URL when the tunnel state changes
+    #[arg(long)]
+    webhook: Option<String>,
+
+    /// events to send ( tunnel-up,tunnel-down,churn-spike,churn-normal )
+    #[arg(long, value_delimiter = ',', requires = "webhook")]
+    webhook_events: Vec<EventKind>,
+
+    /// keep the undelivered events in this file
+    #[arg(long, requires = "webhook")]
+    webhook_spool: Option<PathBuf>,
+
+    /// max undelivered events kept
+    #[arg(long, default_value_t = DEF_SPOOL_MAX)]
+    webhook_spool_max: usize,
+}
+
+impl WebhookArgs {
+    fn config(&self) -> Option<WebhookConfig> {
+        let url = self.webhook.clone()?;
+
+        let mut config = WebhookConfig {
+            url,
+            spool: self.webhook_spool.clone(),
+            spool_max: self.webhook_spool_max,
+            ..Default::default()
+        };
+
+        if !self.webhook_events.is_empty() {
+            config.events = self.webhook_events.clone();
+        }
+
+        Some(config)
+    }
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// client
-    Client(ClientArgs),
+    Client(Box<ClientArgs>),
 
     /// server
-    Server(ServerArgs),
+    Server(Box<ServerArgs>),
+
+    /// both roles in one process, forwards --listen to --target
+    Bridge(Box<BridgeArgs>),
+
+    /// send a command to a server's control socket
+    Ctl(CtlArgs),
+}
+
+#[derive(Parser, Debug)]
+struct CtlArgs {
+    /// the server's --control-socket
+    #[arg(long)]
+    socket: PathBuf,
+
+    /// status | stop-accepting | stop-keepalives | pause-timers | clear [override] | drop-tunnel |
+    /// add-forward <port> | remove-forward <port> | list | stats
+    #[arg(required = true)]
+    command: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+struct BridgeArgs {
+    /// internet facing address ( e.g. 10.0.0.1:8080 )
+    #[arg(long)]
+    listen: SocketAddr,
+
+    /// endpoint the connections are forwarded to
+    #[arg(long)]
+    target: SocketAddr,
+
+    /// forward name used in logs ( defaults to the listen port )
+    #[arg(long, value_parser = parse_label)]
+    label: Option<String>,
+
+    /// protocol of the forwarded service
+    #[arg(long, default_value_t = Protocol::Tcp)]
+    protocol: Protocol,
+
+    /// verbose
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// log lines as text or as one JSON object each
+    #[arg(long, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// seconds before forgetting an idle UDP peer
+    #[arg(long, default_value_t = DEF_UDP_TIMEOUT.as_secs())]
+    udp_timeout: u64,
+
+    /// seconds before closing a connection nothing went through ( 0 disables )
+    #[arg(long, default_value_t = 0)]
+    idle_timeout: u64,
+
+    /// seconds a --target connection may take to complete, refused after that
+    #[arg(long, default_value_t = CONNECT_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    target_connect_timeout: u64,
+
+    /// seconds before TCP keepalive probes start on the connections ( 0 disables )
+    #[arg(long, default_value_t = 0)]
+    tcp_keepalive: u64,
+
+    /// bandwidth limit in kbit/s
+    #[arg(long)]
+    max_rate: Option<u64>,
+
+    /// KiB waiting for a stalled connection before dropping it
+    #[arg(long, default_value_t = DEF_MAX_BUFFERED / 1024)]
+    max_buffered: usize,
+
+    /// bytes read from a socket at once
+    #[arg(long, default_value_t = BUFFER_SIZE)]
+    buffer_size: usize,
+
+    /// connections carried at once
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// connections past --max-connections wait instead of being closed
+    #[arg(long, requires = "max_connections")]
+    queue_when_full: bool,
+
+    /// peers let in ( 203.0.113.0/24, 2001:db8::/32 ), everyone if none
+    #[arg(long, value_parser = parse_cidr)]
+    allow_cidr: Vec<Cidr>,
+
+    /// peers turned away, even when an --allow-cidr matches
+    #[arg(long, value_parser = parse_cidr)]
+    deny_cidr: Vec<Cidr>,
+
+    /// new connections per second from a single address, the others are closed
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    accept_rate: Option<u64>,
+
+    /// connections an address may open at once before --accept-rate applies ( defaults to the rate )
+    #[arg(long, requires = "accept_rate", value_parser = clap::value_parser!(u64).range(1..))]
+    accept_burst: Option<u64>,
+
+    /// append a JSON line per connection to that file
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+
+    /// unix socket taking operator commands ( see pvpn ctl ), stats included
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+}
+
+fn parse_forward_arg(spec: &str) -> core::result::Result<(Vec<u16>, String), String> {
+    match parse_forward(spec) {
+        Ok(v) => Ok(v),
+        Err(_) => Err("expecting <ports>:<name>".to_string()),
+    }
+}
+
+//
+// "host", "host:port", "[v6]:port" or a bare v6 address
+//
+fn tunnel_spec(entry: &str, port: u16) -> String {
+    let has_port = match entry.rsplit_once(':') {
+        Some((host, p)) => p.parse::<u16>().is_ok() && (host.ends_with(']') || !host.contains(':')),
+        None => false,
+    };
+
+    match has_port {
+        true => entry.to_string(),
+        false => host_port(entry, port),
+    }
+}
+
+fn parse_endpoint(spec: &str) -> core::result::Result<(String, String), String> {
+    let invalid = || "expecting <name>=<host:port>".to_string();
+
+    let (label, addr) = spec.split_once('=').ok_or_else(invalid)?;
+
+    validate_label(label).map_err(|_| invalid())?;
+
+    // resolved by the client, names included
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return Err(invalid());
+    }
+
+    Ok((label.to_string(), addr.to_string()))
+}
+
+//
+// A server forward without a name goes by its port
+//
+fn parse_port_endpoint(spec: &str) -> core::result::Result<(String, String), String> {
+    let (port, _) = spec.split_once('=').unwrap_or_default();
+
+    match port.parse::<u16>() {
+        Ok(1..) => {}
+        _ => return Err(format!("expecting <port>=<host:port>, {port:?} isn't an internet port")),
+    }
+
+    parse_endpoint(spec).map_err(|_| "expecting <port>=<host:port>".to_string())
+}
+
+//
+// --endpoint and --forward, the same forward twice is most likely a typo
+//
+fn client_routes(opt: &ClientArgs) -> core::result::Result<HashMap<String, String>, String> {
+    let mut routes = HashMap::new();
+
+    for (label, endpoint) in opt.endpoint.iter().chain(&opt.forward) {
+        if let Some(other) = routes.insert(label.clone(), endpoint.clone()) {
+            return Err(format!("{label} is mapped twice, to {other} and {endpoint}"));
+        }
+    }
+
+    Ok(routes)
+}
+
+#[derive(Debug, Clone)]
+struct PortList(Vec<u16>);
+
+fn parse_ports(spec: &str) -> core::result::Result<PortList, String> {
+    match parse_port_list(spec) {
+        Ok(v) => Ok(PortList(v)),
+        Err(_) => Err("expecting a list of ports or port ranges, 0 last".to_string()),
+    }
+}
+
+fn parse_proxy(spec: &str) -> core::result::Result<Proxy, String> {
+    match spec.parse() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(
+            "expecting socks5://<host>:<port>[:<user>:<password>] or http://[<user>:<password>@]<host>:<port>"
+                .to_string(),
+        ),
+    }
+}
+
+fn parse_cidr(spec: &str) -> core::result::Result<Cidr, String> {
+    match spec.parse() {
+        Ok(v) => Ok(v),
+        Err(_) => Err("expecting <address>/<prefix length>".to_string()),
+    }
 }
 
-fn setup_logger(verbose: bool) {
+fn parse_label(label: &str) -> core::result::Result<String, String> {
+    match validate_label(label) {
+        Ok(_) => Ok(label.to_string()),
+        Err(_) => Err("expecting up to 32 alphanumeric, '-', '_' or '.' characters".to_string()),
+    }
+}
+
+fn kbps_to_bytes(kbps: u64) -> u64 {
+    kbps * 1000 / 8
+}
+
+fn setup_logger(verbose: bool, format: LogFormat) {
     let level = if verbose {
         log::LevelFilter::Info
     } else {
         log::LevelFilter::Error
     };
 
-    env_logger::Builder::new().filter_level(level).init();
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level);
+
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let line = json_line(
+                &buf.timestamp_millis().to_string(),
+                record.level(),
+                record.target(),
+                record.line(),
+                &record.args().to_string(),
+            );
+            writeln!(buf, "{line}")
+        });
+    }
+
+    builder.init();
+
+    install_panic_hook();
+}
+
+//
+// Listeners systemd handed over, (tunnel, internet ports). None of them
+// without the feature
+//
+#[cfg(feature = "systemd")]
+fn socket_activation() -> Result<(Option<OwnedFd>, Vec<OwnedFd>)> {
+    let activation = pvpn::systemd::listen_fds()?;
+    Ok((activation.tunnel, activation.internet))
+}
+
+#[cfg(not(feature = "systemd"))]
+fn socket_activation() -> Result<(Option<OwnedFd>, Vec<OwnedFd>)> {
+    Ok((None, Vec::new()))
+}
+
+//
+// The client and server flags are read from <prefix><NAME> too, PVPN_TUNNEL_PORT
+// for --tunnel-port
+//
+fn cli(env_prefix: &str) -> clap::Command {
+    let with_env = |cmd: clap::Command| {
+        cmd.mut_args(|arg| match arg.get_long() {
+            Some(_) => {
+                let name = format!("{env_prefix}{}", arg.get_id().as_str().to_uppercase());
+                arg.env(name)
+            }
+            None => arg,
+        })
+    };
+
+    UserArgs::command()
+        .mut_subcommand("client", with_env)
+        .mut_subcommand("server", with_env)
+}
+
+fn parse_from(cmd: clap::Command, argv: &[impl AsRef<str>]) -> UserArgs {
+    let matches = cmd.clone().get_matches_from(argv.iter().map(|v| v.as_ref()));
+    let mut args = UserArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.settings = settings(&cmd, &matches, &FileArgs::default());
+    args
+}
+
+//
+// What the flags not at their default were set to, "--name=value ( where )",
+// the merged configuration
+//
+fn settings(cmd: &clap::Command, matches: &ArgMatches, file: &FileArgs) -> Vec<String> {
+    let (sub, sub_cmd) = match matches.subcommand() {
+        Some((name, sub)) => match cmd.find_subcommand(name) {
+            Some(v) => (sub, v),
+            None => return Vec::new(),
+        },
+        None => return Vec::new(),
+    };
+
+    let mut settings = Vec::new();
+
+    for arg in sub_cmd.get_arguments() {
+        let (id, long) = match arg.get_long() {
+            Some(v) => (arg.get_id().as_str(), v),
+            None => continue,
+        };
+
+        let source = match sub.value_source(id) {
+            Some(ValueSource::EnvVariable) => "environment",
+            Some(ValueSource::CommandLine) if file.sets(id) => "config file",
+            Some(ValueSource::CommandLine) => "command line",
+            _ => continue,
+        };
+
+        let values: Vec<String> = sub
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(|v| v.to_string_lossy().to_string())
+            .collect();
+
+        settings.push(format!("--{long}={} ( {source} )", values.join(",")));
+    }
+
+    settings
+}
+
+//
+// The command line, then the environment, then --config for what neither
+// set, then the defaults
+//
+fn parse_args(argv: &[String], env_prefix: &str) -> Result<UserArgs> {
+    let cmd = cli(env_prefix);
+
+    // missing flags may be in the file, parsed for real below
+    let matches = match cmd.clone().ignore_errors(true).try_get_matches_from(argv) {
+        Ok(v) => v,
+        Err(_) => return Ok(parse_from(cmd, argv)),
+    };
+
+    let (name, sub) = match matches.subcommand() {
+        Some(v) => v,
+        None => return Ok(parse_from(cmd, argv)),
+    };
+
+    let file = match (sub.try_get_one::<PathBuf>("config"), cmd.find_subcommand(name)) {
+        (Ok(Some(path)), Some(sub_cmd)) => FileArgs::load(path, name, sub_cmd, sub)?,
+        _ => return Ok(parse_from(cmd, argv)),
+    };
+
+    let argv: Vec<&String> = argv.iter().chain(file.args()).collect();
+
+    let matches = cmd.clone().try_get_matches_from(argv);
+
+    match matches.and_then(|m| UserArgs::from_arg_matches(&m).map(|v| (v, m))) {
+        Ok((mut args, matches)) => {
+            args.settings = settings(&cmd, &matches, &file);
+            Ok(args)
+        }
+        Err(e) => match file.blame(&e) {
+            Some(v) => Err(v),
+            None => e.exit(),
+        },
+    }
+}
+
+//
+// Out of attempts and a configuration that can't work are told apart from
+// the other errors by their exit code
+//
+fn exit_status(ret: Result<()>) -> Result<()> {
+    let code = match &ret {
+        Err(Error::TooManyFailures { .. }) => EXIT_GAVE_UP,
+        // a revoked client, reconnect_policy() stopped it
+        Err(Error::Goodbye {
+            reason: GoodbyeReason::AuthRevoked,
+            ..
+        }) => EXIT_FATAL,
+        Err(e) if ErrorClass::Fatal == e.class() => EXIT_FATAL,
+        _ => return ret,
+    };
+
+    if let Err(e) = ret {
+        eprintln!("Error: {e}");
+    }
+    std::process::exit(code)
 }
 
 fn main() -> Result<()> {
-    let args = UserArgs::parse();
+    let argv: Vec<String> = std::env::args().collect();
+    let (migrated, deprecated) = migrate_args(&argv);
+
+    for (old, new) in deprecated {
+        eprintln!("warning: {old} is deprecated, use {new}");
+    }
+
+    let args = match parse_args(&migrated, ENV_PREFIX) {
+        Ok(v) => v,
+        Err(e) => return exit_status(Err(e)),
+    };
+
+    if args.print_migrated_command {
+        let cmd: Vec<&str> = migrated
+            .iter()
+            .map(|a| a.as_str())
+            .filter(|a| *a != "--print-migrated-command")
+            .collect();
+        println!("{}", cmd.join(" "));
+        return Ok(());
+    }
 
     match &args.command {
         Commands::Client(opt) => {
-            let tunnel = format!("{}:{}", opt.tunnel_address, opt.tunnel_port);
-            let server = format!("{}:{}", opt.server_address, opt.server_port);
+            let mut tunnels = opt.tunnel_address.iter().map(|v| tunnel_spec(v, opt.tunnel_port));
+
+            let config = ClientConfig {
+                tunnel: tunnels.next().unwrap_or_default(),
+                tunnel_failover: tunnels.collect(),
+                server: match (&opt.server_address, opt.server_port) {
+                    (Some(address), Some(port)) => host_port(address, port),
+                    _ => String::new(),
+                },
+                endpoints: client_routes(opt)
+                    .unwrap_or_else(|e| cli(ENV_PREFIX).error(ErrorKind::ArgumentConflict, e).exit()),
+                reconnect_delay: Duration::from_millis(opt.reconnect_delay),
+                reconnect_max: Some(Duration::from_millis(opt.reconnect_max)),
+                max_reconnect_attempts: match opt.max_reconnect_attempts {
+                    0 => None,
+                    v => Some(v as usize),
+                },
+                proxy: opt.proxy.clone(),
+                tunnel_bind: LocalBind {
+                    addr: opt.tunnel_bind,
+                    device: None,
+                },
+                endpoint_bind: LocalBind {
+                    addr: opt.endpoint_bind,
+                    device: opt.endpoint_bind_device.clone(),
+                },
+                proxy_protocol: opt.proxy_protocol,
+                watchdog_timeout: Duration::from_secs(opt.watchdog_timeout),
+                path_probe: !opt.no_path_probe,
+                probe_clamp: opt.probe_clamp,
+                motd: !opt.no_motd,
+                protocol: opt.protocol,
+                udp_timeout: Some(Duration::from_secs(opt.udp_timeout)),
+                idle_timeout: match opt.idle_timeout {
+                    0 => None,
+                    v => Some(Duration::from_secs(v)),
+                },
+                connect_timeout: Some(Duration::from_secs(opt.endpoint_connect_timeout)),
+                tcp_keepalive: match opt.tcp_keepalive {
+                    0 => None,
+                    v => Some(Duration::from_secs(v)),
+                },
+                tunnel_timeout: Duration::from_secs(opt.tunnel_timeout),
+                webhook: opt.webhook.config(),
+                max_rate: opt.max_rate.map(kbps_to_bytes),
+                max_buffered: Some(opt.max_buffered * 1024),
+                buffer_size: Some(opt.buffer_size),
+                max_connections: opt.client_max_connections.or_else(fd_capacity),
+                access_log: opt.access_log.clone(),
+            };
 
             println!("Port VPN Client:");
-            printkv("Tunnel Server", &tunnel);
-            printkv("Server", &server);
-            printkv("Reconnect", format!("{} ms", opt.reconnect_delay));
+            if let Some(path) = &opt.config {
+                printkv("Config File", path.display());
+            }
+            printkv("Tunnel Server", &config.tunnel);
+            for v in &config.tunnel_failover {
+                printkv("Backup Server", v);
+            }
+            if !config.server.is_empty() {
+                printkv("Server", &config.server);
+            }
+            for (label, endpoint) in &opt.endpoint {
+                printkv("Endpoint", format!("{label} -> {endpoint}"));
+            }
+            for (port, endpoint) in &opt.forward {
+                printkv("Forward", format!("{port} -> {endpoint}"));
+            }
+            printkv(
+                "Reconnect",
+                format!("{} ms, up to {} ms", opt.reconnect_delay, opt.reconnect_max),
+            );
+            printkv("Connect Timeout", format!("{} s", opt.endpoint_connect_timeout));
+            if !config.tunnel_timeout.is_zero() {
+                printkv("Tunnel Timeout", format!("{} s", opt.tunnel_timeout));
+            }
+            printkv("Proxy Protocol", config.proxy_protocol);
+            printkv("Protocol", config.protocol);
+            if let Some(max) = config.max_connections {
+                printkv("Max Connections", max);
+            }
+            if let Some(path) = &config.access_log {
+                printkv("Access Log", path.display());
+            }
+
+            if let Some(v) = config.max_reconnect_attempts {
+                printkv("Max Reconnect Attempts", v);
+            }
+            if let Some(v) = &config.proxy {
+                printkv("Proxy", v);
+            }
+            if let Some(v) = opt.tunnel_bind {
+                printkv("Tunnel Bind", v);
+            }
+            if let Some(v) = opt.endpoint_bind {
+                printkv("Endpoint Bind", v);
+            }
+            if let Some(v) = &opt.endpoint_bind_device {
+                printkv("Endpoint Device", v);
+            }
+            for v in &args.settings {
+                printkv("Setting", v);
+            }
 
-            setup_logger(opt.verbose);
+            setup_logger(opt.verbose, opt.log_format);
 
-            client_main(&tunnel, &server, opt.reconnect_delay)
+            exit_status(client_main(&config))
         }
         Commands::Server(opt) => {
-            let tunnel = format!("{}:{}", opt.tunnel_address, opt.tunnel_port);
-            let server = format!("{}:{}", opt.server_address, opt.server_port);
+            setup_logger(opt.verbose, opt.log_format);
+
+            let mut specs = opt.forward.clone();
+
+            if specs.is_empty() {
+                // label picked once bound
+                specs.push((opt.server_port.0.clone(), opt.label.clone().unwrap_or_default()));
+            }
+
+            let (tunnel_fd, internet_fds) = socket_activation()?;
+
+            let mut forwards = Vec::new();
+
+            // systemd's sockets replace --forward and --server-port
+            for fd in internet_fds {
+                let socket = ForwardSocket::from_fd(fd, opt.protocol)?;
+
+                let label = match &opt.label {
+                    Some(v) => v.clone(),
+                    None => socket.local_addr()?.port().to_string(),
+                };
+
+                forwards.push(Forward { label, socket });
+            }
+
+            if !forwards.is_empty() {
+                specs.clear();
+            }
+
+            for (ports, label) in specs {
+                let socket = bind_forward(&opt.server_address, &ports, opt.protocol)?;
+
+                let label = match label.is_empty() {
+                    true => socket.local_addr()?.port().to_string(),
+                    false => label,
+                };
+
+                let port = socket.local_addr()?.port();
+
+                forwards.push(Forward {
+                    label: label.clone(),
+                    socket,
+                });
+
+                // another listener under the same label, the client can't tell
+                if opt.dual_stack {
+                    let socket = bind_v6_only(port, opt.protocol)?;
+                    forwards.push(Forward { label, socket });
+                }
+            }
+
+            let config = ServerConfig {
+                tunnel: host_port(&opt.tunnel_address, opt.tunnel_port),
+                server_address: opt.server_address.clone(),
+                watchdog_timeout: Duration::from_secs(opt.watchdog_timeout),
+                motd_path: opt.motd.clone(),
+                motd: match &opt.motd {
+                    Some(path) => Some(load_motd(path)?),
+                    None => None,
+                },
+                churn: ChurnConfig {
+                    factor: opt.churn_factor,
+                    sustain: Duration::from_secs(opt.churn_sustain),
+                    min_rate: opt.churn_min_rate,
+                    protect: opt.churn_protect,
+                    ..Default::default()
+                },
+                udp_timeout: Some(Duration::from_secs(opt.udp_timeout)),
+                idle_timeout: match opt.idle_timeout {
+                    0 => None,
+                    v => Some(Duration::from_secs(v)),
+                },
+                tcp_keepalive: match opt.tcp_keepalive {
+                    0 => None,
+                    v => Some(Duration::from_secs(v)),
+                },
+                tunnel_timeout: Duration::from_secs(opt.tunnel_timeout),
+                webhook: opt.webhook.config(),
+                max_rate: opt.max_rate.map(kbps_to_bytes),
+                max_buffered: Some(opt.max_buffered * 1024),
+                buffer_size: Some(opt.buffer_size),
+                control_socket: opt.control_socket.clone(),
+                override_ttl: Some(Duration::from_secs(opt.override_ttl)),
+                check_fds: opt.check_fds,
+                max_connections: opt.max_connections,
+                queue_when_full: opt.queue_when_full,
+                max_clients: opt.max_clients,
+                when_down: opt.when_tunnel_down,
+                tunnel_takeover: opt.tunnel_takeover,
+                acl: Acl {
+                    allow: opt.allow_cidr.clone(),
+                    deny: opt.deny_cidr.clone(),
+                },
+                access_log: opt.access_log.clone(),
+                tunnel_fd: tunnel_fd.map(Arc::new),
+                accept_rate: opt.accept_rate.map(|rate| AcceptRate {
+                    rate,
+                    burst: opt.accept_burst.unwrap_or(rate),
+                }),
+                lazy_listen: opt.lazy_listen,
+                max_tunnel_failures: opt.max_tunnel_failures.map(|v| v as usize),
+            };
+
+            install_sighup();
 
             println!("Port VPN Server:");
-            printkv("Tunnel Address", &tunnel);
-            printkv("Server Address", &server);
+            if let Some(path) = &opt.config {
+                printkv("Config File", path.display());
+            }
+            match &config.tunnel_fd {
+                Some(_) => printkv("Tunnel Address", "systemd socket"),
+                None => printkv("Tunnel Address", &config.tunnel),
+            }
+            printkv("Protocol", opt.protocol);
+            for forward in &forwards {
+                printkv(
+                    "Forward",
+                    format!("{} on {}", forward.label, forward.socket.local_addr()?),
+                );
+            }
+            for cidr in &config.acl.allow {
+                printkv("Allow", cidr);
+            }
+            for cidr in &config.acl.deny {
+                printkv("Deny", cidr);
+            }
+            if let Some(v) = &config.accept_rate {
+                printkv("Accept Rate", format!("{}/s burst {} per address", v.rate, v.burst));
+            }
+            if let Some(path) = &config.access_log {
+                printkv("Access Log", path.display());
+            }
+            if let Some(v) = config.max_tunnel_failures {
+                printkv("Max Tunnel Failures", v);
+            }
+            for v in &args.settings {
+                printkv("Setting", v);
+            }
+
+            exit_status(server_main(&config, forwards))
+        }
+        Commands::Bridge(opt) => {
+            setup_logger(opt.verbose, opt.log_format);
+
+            let address = match opt.listen {
+                SocketAddr::V4(v) => v.ip().to_string(),
+                SocketAddr::V6(v) => format!("[{}]", v.ip()),
+            };
+
+            let socket = bind_forward(&address, &[opt.listen.port()], opt.protocol)?;
+
+            let label = match &opt.label {
+                Some(v) => v.clone(),
+                None => socket.local_addr()?.port().to_string(),
+            };
+
+            let idle_timeout = match opt.idle_timeout {
+                0 => None,
+                v => Some(Duration::from_secs(v)),
+            };
+            let tcp_keepalive = match opt.tcp_keepalive {
+                0 => None,
+                v => Some(Duration::from_secs(v)),
+            };
+
+            //
+            // The limits and the policy apply where the connections come
+            // in, the rate and the buffers to both halves
+            //
+            let server_config = ServerConfig {
+                server_address: address,
+                udp_timeout: Some(Duration::from_secs(opt.udp_timeout)),
+                idle_timeout,
+                tcp_keepalive,
+                max_rate: opt.max_rate.map(kbps_to_bytes),
+                max_buffered: Some(opt.max_buffered * 1024),
+                buffer_size: Some(opt.buffer_size),
+                max_connections: opt.max_connections,
+                queue_when_full: opt.queue_when_full,
+                acl: Acl {
+                    allow: opt.allow_cidr.clone(),
+                    deny: opt.deny_cidr.clone(),
+                },
+                accept_rate: opt.accept_rate.map(|rate| AcceptRate {
+                    rate,
+                    burst: opt.accept_burst.unwrap_or(rate),
+                }),
+                access_log: opt.access_log.clone(),
+                control_socket: opt.control_socket.clone(),
+                ..Default::default()
+            };
+
+            let client_config = ClientConfig {
+                server: opt.target.to_string(),
+                reconnect_delay: Duration::from_millis(500),
+                protocol: opt.protocol,
+                udp_timeout: Some(Duration::from_secs(opt.udp_timeout)),
+                idle_timeout,
+                connect_timeout: Some(Duration::from_secs(opt.target_connect_timeout)),
+                tcp_keepalive,
+                max_rate: opt.max_rate.map(kbps_to_bytes),
+                max_buffered: Some(opt.max_buffered * 1024),
+                buffer_size: Some(opt.buffer_size),
+                ..Default::default()
+            };
+
+            println!("Port VPN Bridge:");
+            printkv("Listen", socket.local_addr()?);
+            printkv("Target", opt.target);
+            printkv("Protocol", opt.protocol);
+            if let Some(max) = server_config.max_connections {
+                printkv("Max Connections", max);
+            }
+            for cidr in &server_config.acl.allow {
+                printkv("Allow", cidr);
+            }
+            for cidr in &server_config.acl.deny {
+                printkv("Deny", cidr);
+            }
+            if let Some(v) = &server_config.accept_rate {
+                printkv("Accept Rate", format!("{}/s burst {} per address", v.rate, v.burst));
+            }
+            if let Some(path) = &server_config.access_log {
+                printkv("Access Log", path.display());
+            }
+            if let Some(path) = &server_config.control_socket {
+                printkv("Control Socket", path.display());
+            }
+            for v in &args.settings {
+                printkv("Setting", v);
+            }
+
+            bridge_main(server_config, client_config, vec![Forward { label, socket }])
+        }
+        Commands::Ctl(opt) => {
+            let answer = command(&opt.socket, &opt.command.join(" "))?;
+
+            //
+            // a forgotten override degrades the service, make it obvious
+            //
+            for o in api::overrides(&answer) {
+                eprintln!("WARNING: override {} active, expires in {} s", o.name, o.expires_in);
+            }
+
+            println!("{answer}");
+
+            match answer.starts_with("error") {
+                true => std::process::exit(1),
+                false => Ok(()),
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(cmd: &str) -> Vec<String> {
+        cmd.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    fn parsed(cmd: &str) -> String {
+        format!("{:?}", UserArgs::try_parse_from(argv(cmd)).unwrap().command)
+    }
+
+    #[test]
+    fn max_clients_conflicts() {
+        assert!(UserArgs::try_parse_from(argv("pvpn server --max-clients 2 --when-tunnel-down refuse")).is_ok());
+
+        for flag in [
+            "--control-socket /tmp/pvpn.sock",
+            "--check-fds",
+            "--tunnel-takeover",
+            "--lazy-listen",
+        ] {
+            let cmd = format!("pvpn server --max-clients 2 {flag}");
+            assert!(UserArgs::try_parse_from(argv(&cmd)).is_err(), "{flag}");
+        }
+    }
+
+    #[test]
+    fn old_flags() {
+        let old = "pvpn server --internet-port 9090 --client-address 10.0.0.1 --internet-address=127.0.0.1";
+        let new = "pvpn server --server-port 9090 --tunnel-address 10.0.0.1 --server-address=127.0.0.1";
+
+        // clap aliases on their own
+        assert_eq!(parsed(old), parsed(new));
+
+        let (migrated, used) = migrate_args(&argv(old));
+        assert_eq!(migrated, argv(new));
+        assert_eq!(used.len(), 3);
+        assert!(used.contains(&("--internet-port", "--server-port")));
+
+        let old = "pvpn client --tunnel-address a --endpoint-address 127.0.0.1 --endpoint-port 22";
+        let new = "pvpn client --tunnel-address a --server-address 127.0.0.1 --server-port 22";
+
+        assert_eq!(parsed(old), parsed(new));
+        assert_eq!(migrate_args(&argv(old)).0, argv(new));
+
+        // nothing to report for current flags
+        assert!(migrate_args(&argv(new)).1.is_empty());
+    }
+
+    #[test]
+    fn tunnel_specs() {
+        assert_eq!(tunnel_spec("vpn.example.com", 4000), "vpn.example.com:4000");
+        assert_eq!(tunnel_spec("vpn.example.com:5000", 4000), "vpn.example.com:5000");
+        assert_eq!(tunnel_spec("::1", 4000), "[::1]:4000");
+        assert_eq!(tunnel_spec("[::1]:5000", 4000), "[::1]:5000");
+
+        let cmd =
+            "pvpn client --tunnel-address a,b:5000 --tunnel-address c --server-address 127.0.0.1 --server-port 22";
+        match UserArgs::try_parse_from(argv(cmd)).unwrap().command {
+            Commands::Client(opt) => assert_eq!(opt.tunnel_address, ["a", "b:5000", "c"]),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    #[test]
+    fn config_file() {
+        let path = std::env::temp_dir().join(format!("pvpn-config-{}.toml", std::process::id()));
+        let cmd = |extra: &str| argv(&format!("pvpn client --config {} {extra}", path.display()));
+
+        std::fs::write(
+            &path,
+            r#"
+            [client]
+            tunnel_address = "vpn.example.com"
+            tunnel_port = 4000
+            server_address = "127.0.0.1"
+            server_port = 22
+            verbose = true
+            reconnect_delay = 250
+            "#,
+        )
+        .unwrap();
+
+        let client = |args: UserArgs| match args.command {
+            Commands::Client(v) => v,
+            v => panic!("{v:?}"),
+        };
+
+        let opt = client(parse_args(&cmd(""), "PVPN_TEST_FILE_").unwrap());
+        assert_eq!(opt.tunnel_address, ["vpn.example.com"]);
+        assert_eq!(
+            (opt.tunnel_port, opt.server_port, opt.reconnect_delay),
+            (4000, Some(22), 250)
+        );
+        assert!(opt.verbose);
+
+        // the command line wins
+        let args = parse_args(&cmd("--tunnel-port 5000 --tunnel-address a,b"), "PVPN_TEST_FILE_").unwrap();
+        let settings = args.settings.clone();
+        let opt = client(args);
+        assert_eq!(opt.tunnel_address, ["a", "b"]);
+        assert_eq!((opt.tunnel_port, opt.reconnect_delay), (5000, 250));
+
+        // what's merged, the defaults left out
+        for v in [
+            "--tunnel-port=5000 ( command line )",
+            "--tunnel-address=a,b ( command line )",
+            "--reconnect-delay=250 ( config file )",
+            "--verbose=true ( config file )",
+        ] {
+            assert!(settings.iter().any(|s| s == v), "{v} {settings:?}");
+        }
+        assert!(!settings.iter().any(|s| s.starts_with("--log-format")), "{settings:?}");
+
+        std::fs::write(&path, "[client]\ntunnel_address = \"a\"\nreconnect_delay = \"soon\"\n").unwrap();
+        let ret = parse_args(&cmd(""), "PVPN_TEST_FILE_");
+        std::fs::remove_file(&path).unwrap();
+
+        match ret {
+            Err(Error::InvalidConfig { key, .. }) => assert_eq!(key, "client.reconnect_delay"),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    //
+    // The command line, then the environment, then the file, then the
+    // defaults. The variables are set on a child, this same test run again,
+    // set_var() would race with the tests resolving names alongside
+    //
+    #[test]
+    fn env_vars() {
+        const PREFIX: &str = "PVPN_TEST_ENV_";
+
+        if std::env::var_os("PVPN_TEST_ENV_CHILD").is_none() {
+            let out = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::env_vars"])
+                .env("PVPN_TEST_ENV_CHILD", "1")
+                .env("PVPN_TEST_ENV_TUNNEL_ADDRESS", "env.example.com")
+                .env("PVPN_TEST_ENV_TUNNEL_PORT", "4500")
+                .env("PVPN_TEST_ENV_SERVER_ADDRESS", "127.0.0.1")
+                .env("PVPN_TEST_ENV_SERVER_PORT", "22")
+                .env("PVPN_TEST_ENV_VERBOSE", "true")
+                .output()
+                .unwrap();
+
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            assert!(out.status.success() && stdout.contains("1 passed"), "{stdout}");
+            return;
+        }
+
+        let path = std::env::temp_dir().join(format!("pvpn-env-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[client]\ntunnel_address = \"file.example.com\"\ntunnel_port = 4000\nreconnect_delay = 250\n",
+        )
+        .unwrap();
+
+        let client = |cmd: &str| match parse_args(&argv(cmd), PREFIX).unwrap().command {
+            Commands::Client(v) => v,
+            v => panic!("{v:?}"),
+        };
+        let with_file = format!("pvpn client --config {}", path.display());
+
+        let opt = client(&with_file);
+        assert_eq!(opt.tunnel_address, ["env.example.com"]);
+        assert_eq!(
+            (opt.tunnel_port, opt.server_port, opt.reconnect_delay),
+            (4500, Some(22), 250)
+        );
+        assert!(opt.verbose);
+
+        let opt = client(&format!("{with_file} --tunnel-port 5000"));
+        assert_eq!((opt.tunnel_port, opt.reconnect_delay), (5000, 250));
+
+        let opt = client("pvpn client");
+        assert_eq!((opt.tunnel_port, opt.reconnect_delay), (4500, 500));
+
+        let settings = parse_args(&argv(&with_file), PREFIX).unwrap().settings;
+        assert!(settings.contains(&"--tunnel-port=4500 ( environment )".to_string()));
+        assert!(settings.contains(&"--reconnect-delay=250 ( config file )".to_string()));
+
+        std::fs::remove_file(&path).unwrap();
+
+        // the server's own flags
+        match parse_args(&argv("pvpn server"), PREFIX).unwrap().command {
+            Commands::Server(v) => assert!(v.verbose),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    #[test]
+    fn port_forwards() {
+        assert_eq!(
+            parse_port_endpoint("8080=127.0.0.1:3000"),
+            Ok(("8080".to_string(), "127.0.0.1:3000".to_string()))
+        );
+        assert_eq!(
+            parse_port_endpoint("8443=localhost:8443"),
+            Ok(("8443".to_string(), "localhost:8443".to_string()))
+        );
+
+        for bad in [
+            "ssh=127.0.0.1:22",
+            "0=127.0.0.1:22",
+            "70000=127.0.0.1:22",
+            "8080=127.0.0.1",
+            "8080",
+            "",
+        ] {
+            assert!(parse_port_endpoint(bad).is_err(), "{bad}");
+        }
+
+        let client = |cmd: &str| match UserArgs::try_parse_from(argv(cmd)).unwrap().command {
+            Commands::Client(v) => v,
+            v => panic!("{v:?}"),
+        };
+
+        // no --server-address needed
+        let opt = client("pvpn client --tunnel-address vps --forward 8080=127.0.0.1:3000 --endpoint ssh=127.0.0.1:22");
+        let routes = client_routes(&opt).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes["8080"], "127.0.0.1:3000");
+
+        let opt =
+            client("pvpn client --tunnel-address vps --forward 8080=127.0.0.1:3000 --forward 8080=127.0.0.1:3001");
+        assert_eq!(
+            client_routes(&opt).unwrap_err(),
+            "8080 is mapped twice, to 127.0.0.1:3000 and 127.0.0.1:3001"
+        );
+    }
 
-            setup_logger(opt.verbose);
+    #[test]
+    fn exit_codes() {
+        let codes = [EXIT_GAVE_UP, EXIT_FATAL, pvpn::watchdog::EXIT_WATCHDOG];
 
-            server_main(&server, &tunnel)
+        for (i, c) in codes.iter().enumerate() {
+            assert!(![0, 1, 2].contains(c), "{c}");
+            assert!(!codes[i + 1..].contains(c), "{c}");
         }
     }
 }
@@ -0,0 +1,166 @@
+//
+// Outgoing TCP connections from a given local address or interface,
+// --tunnel-bind, --endpoint-bind and --endpoint-bind-device. std and mio only
+// connect from wherever the routing table says
+//
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalBind {
+    pub addr: Option<IpAddr>,
+    // SO_BINDTODEVICE, Linux only
+    pub device: Option<String>,
+}
+
+impl LocalBind {
+    pub fn is_set(&self) -> bool {
+        self.addr.is_some() || self.device.is_some()
+    }
+}
+
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(v) => {
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*v.ip()).to_be();
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v.port().to_be();
+            sin6.sin6_addr.s6_addr = v.ip().octets();
+            sin6.sin6_scope_id = v.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+//
+// Bound, non blocking and connecting, mio takes it from there. A failed
+// bind names the flag
+//
+pub fn connect_from(local: &LocalBind, flag: &str, remote: SocketAddr) -> Result<TcpStream> {
+    let failed = |flag: String| Error::LocalBindFailed {
+        flag,
+        reason: io::Error::last_os_error().to_string(),
+    };
+
+    let family = match remote {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    if let Some(device) = &local.device {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_ptr() as *const libc::c_void,
+                device.len() as libc::socklen_t,
+            )
+        };
+        if 0 != ret {
+            return Err(failed(format!("{flag}-device")));
+        }
+    }
+
+    if let Some(ip) = local.addr {
+        let (addr, len) = sockaddr(SocketAddr::new(ip, 0));
+        let ret = unsafe { libc::bind(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, len) };
+        if 0 != ret {
+            return Err(failed(flag.to_string()));
+        }
+    }
+
+    let (addr, len) = sockaddr(remote);
+    let ret = unsafe { libc::connect(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, len) };
+    if 0 != ret {
+        let e = io::Error::last_os_error();
+        if Some(libc::EINPROGRESS) != e.raw_os_error() {
+            return Err(e.into());
+        }
+    }
+
+    Ok(TcpStream::from(fd))
+}
+
+//
+// Same, blocking up to timeout for it to complete
+//
+pub fn connect_from_timeout(local: &LocalBind, flag: &str, remote: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    let stream = connect_from(local, flag, remote)?;
+
+    let mut pfd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+
+    match unsafe { libc::poll(&mut pfd, 1, timeout.as_millis().min(i32::MAX as u128) as libc::c_int) } {
+        0 => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        v if v < 0 => return Err(io::Error::last_os_error().into()),
+        _ => {}
+    }
+
+    if let Some(e) = stream.take_error()? {
+        return Err(e.into());
+    }
+
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+
+        let local = LocalBind {
+            addr: Some("127.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let stream = connect_from_timeout(&local, "--tunnel-bind", remote, Duration::from_secs(1)).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), local.addr.unwrap());
+        assert_eq!(stream.local_addr().unwrap(), peer);
+
+        // not an address of this host
+        let local = LocalBind {
+            addr: Some("192.0.2.1".parse().unwrap()),
+            ..Default::default()
+        };
+        match connect_from(&local, "--endpoint-bind", remote) {
+            Err(Error::LocalBindFailed { flag, .. }) => assert_eq!(flag, "--endpoint-bind"),
+            v => panic!("{v:?}"),
+        }
+    }
+}
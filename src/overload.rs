@@ -0,0 +1,194 @@
+use std::{fmt::Display, time::Duration};
+
+// an iteration taking longer is slow
+pub const SLOW_ITERATION: Duration = Duration::from_millis(100);
+// consecutive slow iterations before shedding
+pub const SHED_AFTER: usize = 3;
+// per event budgets while shedding, the rest waits for the next iteration
+pub const SHED_ACCEPTS: usize = 4;
+pub const SHED_READS: usize = 4;
+
+// upper bounds in ms, the last bucket takes the rest
+const BUCKETS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+//
+// Wall time of the poll loop iterations, the wait in poll() excluded
+//
+#[derive(Debug, Default, Clone)]
+pub struct IterationHistogram {
+    counts: [u64; BUCKETS.len() + 1],
+    max: Duration,
+}
+
+impl IterationHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let idx = BUCKETS.iter().position(|b| ms < *b).unwrap_or(BUCKETS.len());
+
+        self.counts[idx] += 1;
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+}
+
+impl Display for IterationHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (i, count) in self.counts.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            match BUCKETS.get(i) {
+                Some(b) => write!(f, "<{b}ms={count}")?,
+                None => write!(f, ">={}ms={count}", BUCKETS[BUCKETS.len() - 1])?,
+            }
+        }
+        write!(f, "] max={}ms", self.max.as_millis())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverloadEvent {
+    Start { streak: usize, last: Duration },
+    End,
+}
+
+impl Display for OverloadEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverloadEvent::Start { streak, last } => {
+                write!(
+                    f,
+                    "event=overload slow_iterations={streak} last_ms={}",
+                    last.as_millis()
+                )
+            }
+            OverloadEvent::End => write!(f, "event=overload_end"),
+        }
+    }
+}
+
+//
+// Fed with every iteration's duration, decides when the loop sheds work and
+// how much of the elapsed time was the loop's own fault
+//
+#[derive(Debug, Default)]
+pub struct Overload {
+    histogram: IterationHistogram,
+    streak: usize,
+    shedding: bool,
+    // time spent above SLOW_ITERATION since the last reset
+    stalled: Duration,
+}
+
+impl Overload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_iteration(&mut self, elapsed: Duration) -> Option<OverloadEvent> {
+        self.histogram.record(elapsed);
+
+        if elapsed <= SLOW_ITERATION {
+            self.streak = 0;
+
+            if self.shedding {
+                self.shedding = false;
+                return Some(OverloadEvent::End);
+            }
+            return None;
+        }
+
+        self.stalled += elapsed - SLOW_ITERATION;
+        self.streak += 1;
+
+        if !self.shedding && self.streak >= SHED_AFTER {
+            self.shedding = true;
+            return Some(OverloadEvent::Start {
+                streak: self.streak,
+                last: elapsed,
+            });
+        }
+
+        None
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.shedding
+    }
+
+    pub fn accept_budget(&self) -> usize {
+        match self.shedding {
+            true => SHED_ACCEPTS,
+            false => usize::MAX,
+        }
+    }
+
+    pub fn read_budget(&self) -> usize {
+        match self.shedding {
+            true => SHED_READS,
+            false => usize::MAX,
+        }
+    }
+
+    //
+    // What a deadline measured from the last reset should not count, the
+    // peer can't be blamed while the loop wasn't looking
+    //
+    pub fn stalled(&self) -> Duration {
+        self.stalled
+    }
+
+    pub fn reset_stalled(&mut self) {
+        self.stalled = Duration::ZERO;
+    }
+
+    pub fn histogram(&self) -> &IterationHistogram {
+        &self.histogram
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shedding() {
+        let mut o = Overload::new();
+        let slow = SLOW_ITERATION * 3;
+
+        assert_eq!(o.on_iteration(Duration::from_millis(1)), None);
+        assert_eq!(o.on_iteration(slow), None);
+        assert_eq!(o.on_iteration(slow), None);
+        assert!(!o.is_shedding());
+
+        // a single event when it starts
+        assert!(matches!(
+            o.on_iteration(slow),
+            Some(OverloadEvent::Start { streak: 3, .. })
+        ));
+        assert_eq!(o.on_iteration(slow), None);
+        assert!(o.is_shedding());
+        assert_eq!(o.accept_budget(), SHED_ACCEPTS);
+
+        assert_eq!(o.stalled(), SLOW_ITERATION * 8);
+        o.reset_stalled();
+        assert_eq!(o.stalled(), Duration::ZERO);
+
+        assert_eq!(o.on_iteration(Duration::from_millis(1)), Some(OverloadEvent::End));
+        assert_eq!(o.accept_budget(), usize::MAX);
+
+        assert_eq!(o.histogram().count(), 6);
+        assert_eq!(o.histogram().max(), slow);
+    }
+}
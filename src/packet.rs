@@ -1,6 +1,7 @@
 use std::{
     fmt::Display,
-    io::{Cursor, ErrorKind},
+    io::{Cursor, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
@@ -8,10 +9,12 @@ use derive_more::Display;
 
 use crate::error::{Error, Result};
 
-const PACKET_VERSION: u8 = 1;
+pub const PACKET_VERSION: u8 = 1;
 pub const HEADER_SIZE: usize = 6;
 
-#[derive(Display, Debug, Clone, Copy, PartialEq)]
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 #[repr(u8)]
 pub enum PacketMessage {
     Data,
@@ -21,6 +24,24 @@ pub enum PacketMessage {
     ReadFailure,
     WriteFailure,
     IoFailure,
+    CloseWrite,
+    WindowUpdate,
+    Hello,
+    Connect,
+    // path probe, echoed back as ProbeReply with the same payload
+    Probe,
+    ProbeReply,
+    // operator message, only sent to clients advertising FEATURE_BANNER
+    Banner,
+    // exactly one UDP datagram
+    Datagram,
+    // sender's session totals, only sent to peers advertising FEATURE_STATS
+    Stats,
+    // the sender is dropping the tunnel, the payload says why
+    Goodbye,
+    // the sender is done with the address, only sent to peers advertising
+    // FEATURE_RELEASE
+    Released,
 }
 
 impl TryFrom<u8> for PacketMessage {
@@ -35,6 +56,17 @@ impl TryFrom<u8> for PacketMessage {
             4 => Ok(Self::ReadFailure),
             5 => Ok(Self::WriteFailure),
             6 => Ok(Self::IoFailure),
+            7 => Ok(Self::CloseWrite),
+            8 => Ok(Self::WindowUpdate),
+            9 => Ok(Self::Hello),
+            10 => Ok(Self::Connect),
+            11 => Ok(Self::Probe),
+            12 => Ok(Self::ProbeReply),
+            13 => Ok(Self::Banner),
+            14 => Ok(Self::Datagram),
+            15 => Ok(Self::Stats),
+            16 => Ok(Self::Goodbye),
+            17 => Ok(Self::Released),
             _ => Err(Error::InvalidMessageType { msg: value }),
         }
     }
@@ -51,8 +83,16 @@ impl From<&PacketMessage> for Error {
 
 impl From<Error> for PacketMessage {
     fn from(value: Error) -> Self {
+        (&value).into()
+    }
+}
+
+impl From<&Error> for PacketMessage {
+    fn from(value: &Error) -> Self {
         match value {
-            Error::Eof => PacketMessage::Disconnected,
+            Error::Eof => PacketMessage::CloseWrite,
+            Error::BufferFull { .. } => PacketMessage::Disconnected,
+            Error::ConnectFailed { .. } => PacketMessage::ConnectionRefused,
             Error::Io(e) => match e.kind() {
                 ErrorKind::ConnectionRefused => PacketMessage::ConnectionRefused,
                 _ => PacketMessage::IoFailure,
@@ -62,9 +102,50 @@ impl From<Error> for PacketMessage {
     }
 }
 
+//
+// Optional payload of a ConnectionRefused, why the client turned the
+// connection down
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum RefuseReason {
+    // the client has no endpoint for the forward
+    NoEndpoint,
+    // the client is at its --client-max-connections
+    CapacityExceeded,
+}
+
+impl RefuseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefuseReason::NoEndpoint => "no-endpoint",
+            RefuseReason::CapacityExceeded => "capacity-exceeded",
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match buf {
+            b"no-endpoint" => Some(RefuseReason::NoEndpoint),
+            b"capacity-exceeded" => Some(RefuseReason::CapacityExceeded),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RefuseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 pub type Address = usize;
 
+// Not a connection, tunnel level messages like Hello are sent to it
+pub const CONTROL_ADDRESS: Address = 0;
+
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     pub ver: u8,
     pub msg: PacketMessage,
@@ -76,7 +157,7 @@ impl Display for Packet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "ver={} msg={} addr={} len={}",
+            "ver={} msg={} token={} len={}",
             self.ver, self.msg, self.addr, self.data_len
         )
     }
@@ -110,7 +191,26 @@ impl Packet {
         }
     }
 
-    pub fn encode(&self, buf: &mut [u8]) -> Result<()> {
+    //
+    // Returns the number of bytes written
+    //
+    pub fn encode<W: Write>(&self, w: &mut W) -> Result<usize> {
+        let mut hdr: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        let used_size = self.encode_slice(&mut hdr)?;
+
+        w.write_all(&hdr[0..used_size])?;
+
+        Ok(used_size)
+    }
+
+    pub fn encode_slice(&self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::BufferTooSmall {
+                max: buf.len(),
+                actual: HEADER_SIZE,
+            });
+        }
+
         let mut cur = Cursor::new(buf);
 
         cur.write_u8(self.ver)?;
@@ -121,10 +221,17 @@ impl Packet {
         cur.write_u16::<LittleEndian>(addr_16)?;
         cur.write_u16::<LittleEndian>(self.data_len)?;
 
-        Ok(())
+        Ok(cur.position() as usize)
     }
 
-    pub fn from_buffer(buf: &[u8]) -> Result<Packet> {
+    //
+    // Returns the packet and the size of the header it was decoded from
+    //
+    pub fn from_buffer(buf: &[u8]) -> Result<(Packet, usize)> {
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::NotEnoughData);
+        }
+
         let mut cur = Cursor::new(buf);
 
         let ver = cur.read_u8()?;
@@ -141,7 +248,173 @@ impl Packet {
         let addr: u16 = cur.read_u16::<LittleEndian>()?;
         let data_len = cur.read_u16::<LittleEndian>()?;
 
-        Ok(Packet::new(addr as Address, msg, data_len))
+        let p = Packet::new(addr as Address, msg, data_len);
+
+        Ok((p, cur.position() as usize))
+    }
+}
+
+//
+// Same frames over tokio streams, for async users of the wire format
+//
+#[cfg(feature = "tokio")]
+pub struct PacketStream<S> {
+    stream: S,
+}
+
+#[cfg(feature = "tokio")]
+impl<S> PacketStream<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    //
+    // Reads one frame, the payload goes to `buf`. Returns the packet and the
+    // payload length
+    //
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<(Packet, usize)> {
+        use tokio::io::AsyncReadExt;
+
+        let mut hdr: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+        match self.stream.read_exact(&mut hdr).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(Error::Eof),
+            Err(e) => return Err(e.into()),
+        }
+
+        let (p, _) = Packet::from_buffer(&hdr)?;
+        let data_len = p.data_len as usize;
+
+        if data_len > buf.len() {
+            return Err(Error::BufferTooSmall {
+                max: buf.len(),
+                actual: data_len,
+            });
+        }
+
+        // a frame cut short is the peer going away all the same
+        match self.stream.read_exact(&mut buf[0..data_len]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(Error::Eof),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok((p, data_len))
+    }
+
+    pub async fn write_packet(&mut self, addr: Address, msg: PacketMessage, data: &[u8]) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let p = Packet::new(addr, msg, data.len().try_into()?);
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + data.len());
+        p.encode(&mut frame)?;
+        frame.extend_from_slice(data);
+
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+//
+// Payload of a Connect message, where the internet connection comes from
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectInfo {
+    // internet peer
+    pub peer: SocketAddr,
+    // address the peer connected to
+    pub local: SocketAddr,
+    // forward the connection came through, index in the Hello forwards
+    pub channel: u16,
+}
+
+fn write_sockaddr(cur: &mut Cursor<&mut Vec<u8>>, addr: &SocketAddr) -> Result<()> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            cur.write_u8(4)?;
+            cur.write_all(&ip.octets())?;
+        }
+        IpAddr::V6(ip) => {
+            cur.write_u8(6)?;
+            cur.write_all(&ip.octets())?;
+        }
+    }
+
+    cur.write_u16::<LittleEndian>(addr.port())?;
+    Ok(())
+}
+
+fn read_sockaddr(cur: &mut Cursor<&[u8]>) -> Result<SocketAddr> {
+    let ip = match cur.read_u8()? {
+        4 => {
+            let mut octets: [u8; 4] = [0; 4];
+            cur.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        6 => {
+            let mut octets: [u8; 16] = [0; 16];
+            cur.read_exact(&mut octets)?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(Error::InvalidHandshake),
+    };
+
+    let port = cur.read_u16::<LittleEndian>()?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+impl ConnectInfo {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut cur = Cursor::new(&mut out);
+
+        write_sockaddr(&mut cur, &self.peer)?;
+        write_sockaddr(&mut cur, &self.local)?;
+        cur.write_u16::<LittleEndian>(self.channel)?;
+
+        Ok(out)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let mut cur = Cursor::new(buf);
+
+        let peer = read_sockaddr(&mut cur)?;
+        let local = read_sockaddr(&mut cur)?;
+
+        // servers with a single forward didn't send it
+        let channel = cur.read_u16::<LittleEndian>().unwrap_or_default();
+
+        Ok(Self { peer, local, channel })
+    }
+
+    //
+    // https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt
+    //
+    pub fn proxy_v1_header(&self) -> String {
+        let proto = match (self.peer, self.local) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+            (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+            _ => return "PROXY UNKNOWN\r\n".to_string(),
+        };
+
+        format!(
+            "PROXY {proto} {} {} {} {}\r\n",
+            self.peer.ip(),
+            self.local.ip(),
+            self.peer.port(),
+            self.local.port()
+        )
     }
 }
 
@@ -154,14 +427,315 @@ impl Packet {
 ////////////////////////////////////////////////////////////////////////////////
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
     fn encode_decode() {
         let p = Packet::new(1, PacketMessage::IoFailure, 10);
         let mut buf: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
-        let _enc_len = p.encode(&mut buf).unwrap();
-        let p2 = Packet::from_buffer(&buf).unwrap();
+        let enc_len = p.encode_slice(&mut buf).unwrap();
+        assert_eq!(enc_len, HEADER_SIZE);
+        let (p2, dec_len) = Packet::from_buffer(&buf).unwrap();
         assert_eq!(p, p2);
+        assert_eq!(dec_len, enc_len);
+
+        let mut out = Vec::new();
+        assert_eq!(p.encode(&mut out).unwrap(), HEADER_SIZE);
+        assert_eq!(out, buf);
+    }
+
+    #[test]
+    fn short_buffers() {
+        let p = Packet::new_data(1, 10);
+
+        let mut buf: [u8; HEADER_SIZE - 1] = [0; HEADER_SIZE - 1];
+        match p.encode_slice(&mut buf) {
+            Err(Error::BufferTooSmall { max, actual }) => {
+                assert_eq!(max, HEADER_SIZE - 1);
+                assert_eq!(actual, HEADER_SIZE);
+            }
+            _ => panic!("expecting BufferTooSmall"),
+        }
+
+        assert!(p.encode(&mut &mut buf[..]).is_err());
+
+        assert!(matches!(Packet::from_buffer(&buf), Err(Error::NotEnoughData)));
+    }
+
+    #[test]
+    fn connect_info() {
+        let v4 = ConnectInfo {
+            peer: "192.0.2.10:51234".parse().unwrap(),
+            local: "198.51.100.1:8080".parse().unwrap(),
+            channel: 0,
+        };
+
+        assert_eq!(ConnectInfo::decode(&v4.encode().unwrap()).unwrap(), v4);
+        assert_eq!(
+            v4.proxy_v1_header(),
+            "PROXY TCP4 192.0.2.10 198.51.100.1 51234 8080\r\n"
+        );
+
+        let v6 = ConnectInfo {
+            peer: "[2001:db8::10]:51234".parse().unwrap(),
+            local: "[2001:db8::1]:8080".parse().unwrap(),
+            channel: 3,
+        };
+
+        assert_eq!(ConnectInfo::decode(&v6.encode().unwrap()).unwrap(), v6);
+        assert_eq!(
+            v6.proxy_v1_header(),
+            "PROXY TCP6 2001:db8::10 2001:db8::1 51234 8080\r\n"
+        );
+
+        let mixed = ConnectInfo {
+            peer: v4.peer,
+            local: v6.local,
+            channel: 0,
+        };
+        assert_eq!(mixed.proxy_v1_header(), "PROXY UNKNOWN\r\n");
+
+        assert!(ConnectInfo::decode(&[4, 1, 2]).is_err());
+
+        // no channel, single forward server
+        let legacy = v6.encode().unwrap();
+        assert_eq!(ConnectInfo::decode(&legacy[..legacy.len() - 2]).unwrap().channel, 0);
+    }
+
+    //
+    // Tooling reads these, renames break it
+    //
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_snapshot() {
+        use crate::{
+            handshake::{Goodbye, GoodbyeReason, Hello},
+            streams::CloseReason,
+        };
+
+        fn round_trip<T>(v: &T, json: &str)
+        where
+            T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+        {
+            assert_eq!(serde_json::to_string(v).unwrap(), json);
+            assert_eq!(&serde_json::from_str::<T>(json).unwrap(), v);
+        }
+
+        round_trip(
+            &Packet::new(7, PacketMessage::WindowUpdate, 4),
+            r#"{"ver":1,"msg":"window_update","addr":7,"data_len":4}"#,
+        );
+        round_trip(&PacketMessage::ProbeReply, r#""probe_reply""#);
+        round_trip(&RefuseReason::CapacityExceeded, r#""capacity-exceeded""#);
+        round_trip(&CloseReason::HalfCloseTimeout, r#""half-close-timeout""#);
+
+        round_trip(
+            &ConnectInfo {
+                peer: "192.0.2.10:51234".parse().unwrap(),
+                local: "[2001:db8::1]:8080".parse().unwrap(),
+                channel: 2,
+            },
+            r#"{"peer":"192.0.2.10:51234","local":"[2001:db8::1]:8080","channel":2}"#,
+        );
+
+        round_trip(
+            &Hello {
+                forwards: vec!["web".to_string()],
+                port: Some(8080),
+                mtu: None,
+                features: vec!["stats".to_string()],
+                max_connections: Some(100),
+            },
+            r#"{"forwards":["web"],"port":8080,"mtu":null,"features":["stats"],"max_connections":100}"#,
+        );
+
+        round_trip(
+            &Goodbye {
+                reason: GoodbyeReason::ConfigError,
+                message: "bad forward".to_string(),
+            },
+            r#"{"reason":"config-error","message":"bad forward"}"#,
+        );
+
+        // snake_case names, never the wire ids
+        for m in (0..=17).map(|v| PacketMessage::try_from(v).unwrap()) {
+            let name = serde_json::to_string(&m).unwrap();
+            assert!(
+                name.chars().all(|c| c == '"' || c == '_' || c.is_ascii_lowercase()),
+                "{name}"
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            msg in any::<u8>().prop_filter_map("message type", |v| PacketMessage::try_from(v).ok()),
+            addr in 0..=u16::MAX as Address,
+            data_len in any::<u16>(),
+        ) {
+            let p = Packet::new(addr, msg, data_len);
+
+            let mut out = Vec::new();
+            let enc_len = p.encode(&mut out).unwrap();
+            prop_assert_eq!(enc_len, out.len());
+
+            let (p2, dec_len) = Packet::from_buffer(&out).unwrap();
+            prop_assert_eq!(p, p2);
+            prop_assert_eq!(dec_len, enc_len);
+        }
+    }
+
+    //
+    // Version 1 layout, changing any of these breaks old peers
+    //
+    #[test]
+    fn golden_v1() {
+        let mut out = Vec::new();
+        Packet::new(0x0102, PacketMessage::Data, 0x0304).encode(&mut out).unwrap();
+        assert_eq!(out, [0x01, 0x00, 0x02, 0x01, 0x04, 0x03]);
+
+        let mut out = Vec::new();
+        Packet::new(u16::MAX as Address, PacketMessage::Connect, u16::MAX)
+            .encode(&mut out)
+            .unwrap();
+        assert_eq!(out, [0x01, 0x0a, 0xff, 0xff, 0xff, 0xff]);
+
+        let wire_ids = [
+            (PacketMessage::Data, 0),
+            (PacketMessage::ConnectionRefused, 1),
+            (PacketMessage::Disconnected, 2),
+            (PacketMessage::Eof, 3),
+            (PacketMessage::ReadFailure, 4),
+            (PacketMessage::WriteFailure, 5),
+            (PacketMessage::IoFailure, 6),
+            (PacketMessage::CloseWrite, 7),
+            (PacketMessage::WindowUpdate, 8),
+            (PacketMessage::Hello, 9),
+            (PacketMessage::Connect, 10),
+            (PacketMessage::Probe, 11),
+            (PacketMessage::ProbeReply, 12),
+            (PacketMessage::Banner, 13),
+            (PacketMessage::Datagram, 14),
+            (PacketMessage::Stats, 15),
+            (PacketMessage::Goodbye, 16),
+            (PacketMessage::Released, 17),
+        ];
+
+        for (msg, id) in wire_ids {
+            let mut out = Vec::new();
+            Packet::new_message(0, msg).encode(&mut out).unwrap();
+            assert_eq!(out, [0x01, id, 0x00, 0x00, 0x00, 0x00], "{msg}");
+        }
+
+        // addresses past the u16 range aren't encodable
+        assert!(
+            Packet::new_message(u16::MAX as Address + 1, PacketMessage::Data)
+                .encode(&mut Vec::new())
+                .is_err()
+        );
+    }
+
+    //
+    // Seed corpus for the fuzz targets, see fuzz/README.md
+    //
+    #[test]
+    #[ignore]
+    fn write_fuzz_corpus() {
+        let root = std::path::PathBuf::from(std::env::var("PVPN_FUZZ_CORPUS").unwrap());
+
+        for target in ["from_buffer", "read_packet"] {
+            std::fs::create_dir_all(root.join(target)).unwrap();
+        }
+
+        for msg in (0..=u8::MAX).filter_map(|v| PacketMessage::try_from(v).ok()) {
+            let payload: Vec<u8> = match msg {
+                PacketMessage::Data | PacketMessage::Probe => vec![0x41; 16],
+                PacketMessage::WindowUpdate => 4096u32.to_le_bytes().to_vec(),
+                PacketMessage::Connect => ConnectInfo {
+                    peer: "1.2.3.4:5678".parse().unwrap(),
+                    local: "[::1]:8080".parse().unwrap(),
+                    channel: 0,
+                }
+                .encode()
+                .unwrap(),
+                _ => Vec::new(),
+            };
+
+            let p = Packet::new(4, msg, payload.len() as u16);
+
+            let mut frame = Vec::new();
+            p.encode(&mut frame).unwrap();
+            std::fs::write(root.join("from_buffer").join(format!("{msg}")), &frame).unwrap();
+
+            frame.extend_from_slice(&payload);
+            std::fs::write(root.join("read_packet").join(format!("{msg}")), &frame).unwrap();
+        }
+    }
+
+    //
+    // The mio server and a tokio client agree on the frames
+    //
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_client() {
+        use crate::{
+            test_util::{TEST_TIMEOUT, connect_retry, free_port},
+            tunnel_server::{Forward, ServerConfig, bind_forward, server_main},
+            udp::Protocol,
+        };
+
+        let tunnel = format!("127.0.0.1:{}", free_port());
+        let socket = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            ..Default::default()
+        };
+
+        let forward = Forward {
+            label: "test".to_string(),
+            socket,
+        };
+
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        // the blocking retry doubles as the wait for the server
+        let tstream = connect_retry(&tunnel);
+        tstream.set_nonblocking(true).unwrap();
+        let mut client = PacketStream::new(tokio::net::TcpStream::from_std(tstream).unwrap());
+
+        let mut buf = vec![0; 0x10000];
+
+        let (p, _) = client.read_packet(&mut buf).await.unwrap();
+        assert_eq!(p.msg, PacketMessage::Hello);
+
+        let internet = std::thread::spawn(move || {
+            let mut internet = connect_retry(&server);
+            internet.write_all(b"ping").unwrap();
+
+            let mut data: [u8; 4] = [0; 4];
+            internet.read_exact(&mut data).unwrap();
+            data
+        });
+
+        let read = async {
+            let (p, _) = client.read_packet(&mut buf).await.unwrap();
+            assert_eq!(p.msg, PacketMessage::Connect);
+
+            let (data, len) = client.read_packet(&mut buf).await.unwrap();
+            assert_eq!(data.msg, PacketMessage::Data);
+            assert_eq!(data.addr, p.addr);
+            assert_eq!(&buf[0..len], b"ping");
+
+            client.write_packet(p.addr, PacketMessage::Data, b"pong").await.unwrap();
+        };
+
+        tokio::time::timeout(TEST_TIMEOUT, read).await.unwrap();
+
+        assert_eq!(&internet.join().unwrap(), b"pong");
     }
 }
@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant};
+
+// Smallest probe, anything below that and the tunnel isn't usable anyway
+const PROBE_MIN: usize = 256;
+// How long to wait for a single echo
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+// Whole probe phase, bounds both the time and the bytes
+const PROBE_BUDGET: Duration = Duration::from_secs(10);
+const PROBE_MAX_BYTES: usize = 256 * 1024;
+
+//
+// Path probe run by the client right after the handshake. Probe frames of
+// doubling sizes are echoed by the server, the largest one that made it back
+// is the usable frame size. On a PMTU blackhole the bigger frames never come
+// back and the probe times out
+//
+pub struct PathProbe {
+    max: usize,
+    next: usize,
+    // size of the probe waiting for its echo
+    pending: Option<(usize, Instant)>,
+    largest: Option<usize>,
+    started: Instant,
+    sent_bytes: usize,
+    done: bool,
+}
+
+impl PathProbe {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            next: PROBE_MIN.min(max),
+            pending: None,
+            largest: None,
+            started: Instant::now(),
+            sent_bytes: 0,
+            done: false,
+        }
+    }
+
+    //
+    // Size of the next probe to send, None once the probe is over
+    //
+    pub fn next_probe(&mut self) -> Option<usize> {
+        if self.done || self.pending.is_some() {
+            return None;
+        }
+
+        if self.next > self.max
+            || self.sent_bytes + self.next > PROBE_MAX_BYTES
+            || self.started.elapsed() > PROBE_BUDGET
+        {
+            self.done = true;
+            return None;
+        }
+
+        let size = self.next;
+
+        self.pending = Some((size, Instant::now()));
+        self.sent_bytes += size;
+
+        self.next = match size == self.max {
+            true => self.max + 1,
+            false => (size * 2).min(self.max),
+        };
+
+        Some(size)
+    }
+
+    pub fn on_reply(&mut self, size: usize) {
+        match self.pending {
+            Some((pending, _)) if pending == size => {
+                self.largest = Some(size);
+                self.pending = None;
+            }
+            // stale or garbage, the timeout will deal with it
+            _ => {}
+        }
+    }
+
+    //
+    // An echo that doesn't show up ends the probe
+    //
+    pub fn check_timeout(&mut self, timeout: Duration) {
+        if let Some((_, sent)) = self.pending
+            && sent.elapsed() > timeout
+        {
+            self.pending = None;
+            self.done = true;
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn largest(&self) -> Option<usize> {
+        self.largest
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blackhole() {
+        let mut probe = PathProbe::new(32 * 1024);
+        let mut sizes = Vec::new();
+
+        //
+        // anything above 1400 vanishes
+        //
+        while let Some(size) = probe.next_probe() {
+            sizes.push(size);
+
+            if size <= 1400 {
+                probe.on_reply(size);
+            } else {
+                probe.check_timeout(Duration::ZERO);
+            }
+        }
+
+        assert!(probe.is_done());
+        assert_eq!(sizes, vec![256, 512, 1024, 2048]);
+        assert_eq!(probe.largest(), Some(1024));
+    }
+
+    #[test]
+    fn clean_path() {
+        let mut probe = PathProbe::new(3000);
+        let mut sizes = Vec::new();
+
+        while let Some(size) = probe.next_probe() {
+            sizes.push(size);
+            probe.on_reply(size);
+        }
+
+        assert_eq!(sizes, vec![256, 512, 1024, 2048, 3000]);
+        assert_eq!(probe.largest(), Some(3000));
+    }
+}
@@ -0,0 +1,580 @@
+//
+// --proxy, the tunnel connection made through a proxy. The handshake runs on
+// the blocking stream, before it's handed over to mio. The proxy resolves the
+// tunnel's host name, the client may not be able to
+//
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    net::{IpAddr, TcpStream},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::error::{Error, Result};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USER_PASS: u8 = 2;
+const SOCKS_NO_METHOD: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Proxy {
+    // RFC 1928, user and password ( RFC 1929 ) if any
+    Socks5 {
+        server: String,
+        auth: Option<(String, String)>,
+    },
+    // CONNECT, basic auth if any
+    Http {
+        server: String,
+        auth: Option<(String, String)>,
+    },
+}
+
+// the CONNECT answer's headers, at most
+const MAX_HTTP_ANSWER: usize = 8192;
+
+//
+// "host:port" and "[v6]:port" as (host, port), the brackets removed
+//
+fn split_host_port(spec: &str) -> Option<(&str, u16)> {
+    let (host, port) = spec.rsplit_once(':')?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+
+    match host.is_empty() {
+        true => None,
+        false => Some((host, port.parse().ok()?)),
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = Error;
+
+    //
+    // socks5://host:port[:user:pass] or http://[user:pass@]host:port
+    //
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = || Error::InvalidProxy { spec: spec.to_string() };
+
+        if let Some(rest) = spec.strip_prefix("http://") {
+            let rest = rest.strip_suffix('/').unwrap_or(rest);
+
+            let (auth, server) = match rest.rsplit_once('@') {
+                Some((credentials, server)) => {
+                    let (user, pass) = credentials.split_once(':').ok_or_else(invalid)?;
+                    (Some((user.to_string(), pass.to_string())), server)
+                }
+                None => (None, rest),
+            };
+
+            split_host_port(server).ok_or_else(invalid)?;
+
+            return Ok(Proxy::Http {
+                server: server.to_string(),
+                auth,
+            });
+        }
+
+        let rest = spec.strip_prefix("socks5://").ok_or_else(invalid)?;
+
+        // the v6 literal's colons aren't separators
+        let (host, rest) = match rest.strip_prefix('[') {
+            Some(v) => {
+                let (host, rest) = v.split_once(']').ok_or_else(invalid)?;
+                (format!("[{host}]"), rest.strip_prefix(':').ok_or_else(invalid)?)
+            }
+            None => {
+                let (host, rest) = rest.split_once(':').ok_or_else(invalid)?;
+                (host.to_string(), rest)
+            }
+        };
+
+        let mut fields = rest.splitn(3, ':');
+
+        let port: u16 = fields.next().and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+
+        let auth = match (fields.next(), fields.next()) {
+            (None, None) => None,
+            (Some(user), Some(pass)) if !user.is_empty() && user.len() <= 255 && pass.len() <= 255 => {
+                Some((user.to_string(), pass.to_string()))
+            }
+            _ => return Err(invalid()),
+        };
+
+        if host.is_empty() || "[]" == host {
+            return Err(invalid());
+        }
+
+        Ok(Proxy::Socks5 {
+            server: format!("{host}:{port}"),
+            auth,
+        })
+    }
+}
+
+impl Display for Proxy {
+    // the password left out, it ends up in the logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Proxy::Socks5 { server, auth: None } => write!(f, "socks5://{server}"),
+            Proxy::Socks5 {
+                server,
+                auth: Some((user, _)),
+            } => write!(f, "socks5://{server}:{user}:***"),
+            Proxy::Http { server, auth: None } => write!(f, "http://{server}"),
+            Proxy::Http {
+                server,
+                auth: Some((user, _)),
+            } => write!(f, "http://{user}:***@{server}"),
+        }
+    }
+}
+
+fn socks_reply(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by the ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown reply",
+    }
+}
+
+impl Proxy {
+    // where the client connects
+    pub fn server(&self) -> &str {
+        match self {
+            Proxy::Socks5 { server, .. } | Proxy::Http { server, .. } => server,
+        }
+    }
+
+    //
+    // Asks the proxy for a connection to target ( host:port ), stream is the
+    // tunnel once it returns
+    //
+    pub fn handshake(&self, stream: &mut TcpStream, target: &str, timeout: Duration) -> Result<()> {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let ret = match self {
+            Proxy::Socks5 { auth, .. } => socks5(stream, auth.as_ref(), target),
+            Proxy::Http { auth, .. } => http_connect(stream, auth.as_ref(), target),
+        };
+
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        ret
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::new();
+
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+
+    out
+}
+
+//
+// The answer is read a byte at a time up to the blank line, whatever a
+// proxy sends past it stays in the socket for the session
+//
+fn http_connect(stream: &mut TcpStream, auth: Option<&(String, String)>, target: &str) -> Result<()> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+
+    if let Some((user, pass)) = auth {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(format!("{user}:{pass}").as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes())?;
+
+    let mut answer = Vec::new();
+    let mut byte: [u8; 1] = [0; 1];
+
+    while !answer.ends_with(b"\r\n\r\n") {
+        if answer.len() >= MAX_HTTP_ANSWER {
+            return Err(Error::ProxyRefused {
+                reason: "answer too long".to_string(),
+            });
+        }
+
+        if 0 == stream.read(&mut byte)? {
+            return Err(Error::Eof);
+        }
+        answer.push(byte[0]);
+    }
+
+    let answer = String::from_utf8_lossy(&answer);
+    let status = answer.lines().next().unwrap_or_default();
+
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        Some("407") => Err(Error::ProxyAuthFailed),
+        _ => Err(Error::ProxyRefused {
+            reason: status.to_string(),
+        }),
+    }
+}
+
+fn socks5(stream: &mut TcpStream, auth: Option<&(String, String)>, target: &str) -> Result<()> {
+    let (host, port) = split_host_port(target).ok_or_else(|| Error::InvalidProxy {
+        spec: target.to_string(),
+    })?;
+
+    let refused = |reason: &str| Error::ProxyRefused {
+        reason: reason.to_string(),
+    };
+
+    //
+    // greeting
+    //
+    let methods: &[u8] = match auth {
+        Some(_) => &[SOCKS_NO_AUTH, SOCKS_USER_PASS],
+        None => &[SOCKS_NO_AUTH],
+    };
+
+    let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting)?;
+
+    let mut choice: [u8; 2] = [0; 2];
+    stream.read_exact(&mut choice)?;
+
+    if SOCKS_VERSION != choice[0] {
+        return Err(refused("not a SOCKS5 proxy"));
+    }
+
+    match (choice[1], auth) {
+        (SOCKS_NO_AUTH, _) => {}
+        (SOCKS_USER_PASS, Some((user, pass))) => {
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            stream.write_all(&request)?;
+
+            let mut status: [u8; 2] = [0; 2];
+            stream.read_exact(&mut status)?;
+
+            if 0 != status[1] {
+                return Err(Error::ProxyAuthFailed);
+            }
+        }
+        // credentials needed, none or not the right kind
+        (SOCKS_NO_METHOD, _) | (SOCKS_USER_PASS, None) => return Err(Error::ProxyAuthFailed),
+        _ => return Err(refused("unexpected authentication method")),
+    }
+
+    //
+    // CONNECT, names go as they are
+    //
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.len() <= 255 => {
+            request.push(SOCKS_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+        Err(_) => return Err(refused("host name too long")),
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply: [u8; 4] = [0; 4];
+    stream.read_exact(&mut reply)?;
+
+    if SOCKS_VERSION != reply[0] {
+        return Err(refused("not a SOCKS5 proxy"));
+    }
+
+    if 0 != reply[1] {
+        return Err(refused(socks_reply(reply[1])));
+    }
+
+    // the address the proxy connected from, not needed
+    let len = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => {
+            let mut len: [u8; 1] = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(refused("unexpected address type")),
+    };
+
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound)?;
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //
+    // A SOCKS5 proxy that is also the target, it echoes once connected
+    //
+    fn stub(credentials: Option<(&'static str, &'static str)>, reply: u8) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+
+            let mut hdr: [u8; 2] = [0; 2];
+            s.read_exact(&mut hdr).unwrap();
+            let mut methods = vec![0; hdr[1] as usize];
+            s.read_exact(&mut methods).unwrap();
+
+            let (user, pass) = match credentials {
+                None => {
+                    s.write_all(&[5, 0]).unwrap();
+                    (String::new(), String::new())
+                }
+                Some(_) if !methods.contains(&SOCKS_USER_PASS) => {
+                    s.write_all(&[5, SOCKS_NO_METHOD]).unwrap();
+                    return;
+                }
+                Some(_) => {
+                    s.write_all(&[5, SOCKS_USER_PASS]).unwrap();
+                    let field = |s: &mut std::net::TcpStream| {
+                        let mut len: [u8; 1] = [0; 1];
+                        s.read_exact(&mut len).unwrap();
+                        let mut v = vec![0; len[0] as usize];
+                        s.read_exact(&mut v).unwrap();
+                        String::from_utf8(v).unwrap()
+                    };
+                    let mut ver: [u8; 1] = [0; 1];
+                    s.read_exact(&mut ver).unwrap();
+                    (field(&mut s), field(&mut s))
+                }
+            };
+
+            if let Some((u, p)) = credentials {
+                let ok = u == user && p == pass;
+                s.write_all(&[1, !ok as u8]).unwrap();
+                if !ok {
+                    return;
+                }
+            }
+
+            // CONNECT to tunnel.example:1414
+            let mut request: [u8; 5] = [0; 5];
+            s.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..4], &[5, SOCKS_CONNECT, 0, SOCKS_DOMAIN]);
+            let mut host = vec![0; request[4] as usize + 2];
+            s.read_exact(&mut host).unwrap();
+            assert_eq!(&host[..host.len() - 2], b"tunnel.example");
+            assert_eq!(&host[host.len() - 2..], &1414u16.to_be_bytes());
+
+            s.write_all(&[5, reply, 0, SOCKS_IPV4, 127, 0, 0, 1, 0x12, 0x34]).unwrap();
+
+            let mut data: [u8; 4] = [0; 4];
+            if s.read_exact(&mut data).is_ok() {
+                s.write_all(&data).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn through(proxy: &str) -> Result<TcpStream> {
+        let proxy: Proxy = proxy.parse().unwrap();
+        let mut stream = TcpStream::connect(proxy.server()).unwrap();
+        proxy.handshake(&mut stream, "tunnel.example:1414", Duration::from_secs(5))?;
+        Ok(stream)
+    }
+
+    #[test]
+    fn parse() {
+        let p: Proxy = "socks5://proxy.corp:1080".parse().unwrap();
+        assert_eq!(
+            p,
+            Proxy::Socks5 {
+                server: "proxy.corp:1080".to_string(),
+                auth: None
+            }
+        );
+
+        let p: Proxy = "socks5://[::1]:1080:me:se:cret".parse().unwrap();
+        assert_eq!(p.server(), "[::1]:1080");
+        assert_eq!(p.to_string(), "socks5://[::1]:1080:me:***");
+
+        for bad in [
+            "https://proxy:8080",
+            "socks5://proxy",
+            "socks5://:1080",
+            "socks5://proxy:x",
+            "socks5://proxy:1080:me",
+        ] {
+            assert!(bad.parse::<Proxy>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn socks5_no_auth() {
+        let mut stream = through(&format!("socks5://{}", stub(None, 0))).unwrap();
+
+        stream.write_all(b"ping").unwrap();
+        let mut data: [u8; 4] = [0; 4];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"ping");
+    }
+
+    #[test]
+    fn socks5_user_pass() {
+        let addr = stub(Some(("me", "secret")), 0);
+        let mut stream = through(&format!("socks5://{addr}:me:secret")).unwrap();
+
+        stream.write_all(b"ping").unwrap();
+        let mut data: [u8; 4] = [0; 4];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"ping");
+
+        let addr = stub(Some(("me", "secret")), 0);
+        assert!(matches!(
+            through(&format!("socks5://{addr}:me:wrong")),
+            Err(Error::ProxyAuthFailed)
+        ));
+
+        // credentials expected, none given
+        let addr = stub(Some(("me", "secret")), 0);
+        assert!(matches!(
+            through(&format!("socks5://{addr}")),
+            Err(Error::ProxyAuthFailed)
+        ));
+    }
+
+    #[test]
+    fn socks5_unreachable() {
+        match through(&format!("socks5://{}", stub(None, 4))) {
+            Err(Error::ProxyRefused { reason }) => assert_eq!(reason, "host unreachable"),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    //
+    // An HTTP proxy answering with status, extra headers and, on a 200, the
+    // tunnel's first bytes in the same segment. Returns what it was asked
+    //
+    fn http_stub(status: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+
+            let mut request = Vec::new();
+            let mut byte: [u8; 1] = [0; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                s.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            tx.send(String::from_utf8(request).unwrap()).unwrap();
+
+            let mut answer = format!("HTTP/1.1 {status}\r\nVia: stub\r\nX-Extra: 1\r\n\r\n");
+            if status.starts_with("200") {
+                answer.push_str("early");
+            }
+            s.write_all(answer.as_bytes()).unwrap();
+        });
+
+        (addr, rx)
+    }
+
+    #[test]
+    fn http_parse() {
+        let p: Proxy = "http://proxy.corp:3128".parse().unwrap();
+        assert_eq!(p.server(), "proxy.corp:3128");
+        assert_eq!(p.to_string(), "http://proxy.corp:3128");
+
+        let p: Proxy = "http://me:p@ss@[::1]:8080/".parse().unwrap();
+        assert_eq!(
+            p,
+            Proxy::Http {
+                server: "[::1]:8080".to_string(),
+                auth: Some(("me".to_string(), "p@ss".to_string()))
+            }
+        );
+        assert_eq!(p.to_string(), "http://me:***@[::1]:8080");
+
+        for bad in ["http://proxy", "http://me@proxy:3128", "http://:3128"] {
+            assert!(bad.parse::<Proxy>().is_err(), "{bad}");
+        }
+
+        assert_eq!(base64(b"Aladdin:open sesame"), "QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b""), "");
+    }
+
+    #[test]
+    fn http_connect_ok() {
+        let (addr, request) = http_stub("200 Connection established");
+        let mut stream = through(&format!("http://me:secret@{addr}")).unwrap();
+
+        let request = request.recv().unwrap();
+        assert!(request.starts_with("CONNECT tunnel.example:1414 HTTP/1.1\r\n"));
+        assert!(request.contains("Host: tunnel.example:1414\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic bWU6c2VjcmV0\r\n"));
+
+        // pipelined by the proxy, not lost
+        let mut data: [u8; 5] = [0; 5];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"early");
+    }
+
+    #[test]
+    fn http_connect_refused() {
+        let (addr, _request) = http_stub("407 Proxy Authentication Required");
+        assert!(matches!(
+            through(&format!("http://{addr}")),
+            Err(Error::ProxyAuthFailed)
+        ));
+
+        let (addr, _request) = http_stub("502 Bad Gateway");
+        match through(&format!("http://{addr}")) {
+            Err(Error::ProxyRefused { reason }) => assert_eq!(reason, "HTTP/1.1 502 Bad Gateway"),
+            v => panic!("{v:?}"),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// how often a throttled tunnel gets another chance to write
+pub const REFILL_INTERVAL: Duration = Duration::from_millis(10);
+// smallest burst, a few frames worth
+const MIN_BURST: u64 = 4096;
+
+//
+// Bytes per second with a burst of 100ms worth of tokens
+//
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let burst = (rate / 10).max(MIN_BURST);
+
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    //
+    // Whatever the rate, starting full at `now`
+    //
+    pub fn with_burst(rate: u64, burst: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+    }
+
+    pub fn available_at(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        self.tokens as usize
+    }
+
+    pub fn available(&mut self) -> usize {
+        self.available_at(Instant::now())
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.tokens = (self.tokens - len as f64).max(0.0);
+    }
+}
+
+//
+// --accept-rate and --accept-burst, new connections per source address
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AcceptRate {
+    // per second
+    pub rate: u64,
+    pub burst: u64,
+}
+
+//
+// A bucket of accepts per source address. A full bucket is forgotten, the
+// source would get a full one back anyway
+//
+#[derive(Debug)]
+pub struct SourceLimiter {
+    limit: AcceptRate,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    // turned away since the last take_dropped()
+    dropped: HashMap<IpAddr, u64>,
+}
+
+impl SourceLimiter {
+    pub fn new(limit: AcceptRate) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+            dropped: HashMap::new(),
+        }
+    }
+
+    pub fn admits(&mut self, ip: IpAddr, now: Instant) -> bool {
+        // v4 peers of a dual stack listener show up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+
+        let bucket = self
+            .buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::with_burst(self.limit.rate, self.limit.burst, now));
+
+        if bucket.available_at(now) >= 1 {
+            bucket.consume(1);
+            return true;
+        }
+
+        *self.dropped.entry(ip).or_default() += 1;
+        false
+    }
+
+    //
+    // Keeps the table to the sources still over their burst
+    //
+    pub fn expire(&mut self, now: Instant) {
+        self.buckets.retain(|_, b| b.available_at(now) < b.burst as usize);
+    }
+
+    pub fn sources(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn take_dropped(&mut self) -> HashMap<IpAddr, u64> {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill() {
+        let start = Instant::now();
+        let mut b = TokenBucket::new(100_000);
+
+        // starts with a full burst
+        assert_eq!(b.available_at(start), 10_000);
+        b.consume(10_000);
+        assert_eq!(b.available_at(start), 0);
+
+        let half = b.available_at(start + Duration::from_millis(50));
+        assert!((4_999..=5_000).contains(&half));
+
+        // never more than the burst
+        assert_eq!(b.available_at(start + Duration::from_secs(10)), 10_000);
+    }
+
+    #[test]
+    fn per_source() {
+        let start = Instant::now();
+        let mut l = SourceLimiter::new(AcceptRate { rate: 2, burst: 5 });
+
+        let noisy: IpAddr = "192.0.2.1".parse().unwrap();
+        let quiet: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let admitted = (0..100).filter(|_| l.admits(noisy, start)).count();
+        assert_eq!(admitted, 5);
+
+        // its own bucket
+        assert!(l.admits(quiet, start));
+
+        // 2 a second from then on
+        let later = start + Duration::from_secs(1);
+        assert_eq!((0..100).filter(|_| l.admits(noisy, later)).count(), 2);
+
+        // the mapped form is the same source
+        assert!(!l.admits("::ffff:192.0.2.1".parse().unwrap(), later));
+
+        assert_eq!(l.take_dropped().get(&noisy), Some(&(95 + 98 + 1)));
+        assert!(l.take_dropped().is_empty());
+
+        // back to a full burst, forgotten
+        assert_eq!(l.sources(), 2);
+        l.expire(later + Duration::from_secs(1));
+        assert_eq!(l.sources(), 1);
+        l.expire(later + Duration::from_secs(3));
+        assert_eq!(l.sources(), 0);
+    }
+}
@@ -0,0 +1,153 @@
+//
+// Host names for the tunnel and the endpoints, IP literals pass through.
+// Looked up at startup so a typo fails there, naming the flag, then again
+// once the answer is RESOLVE_TTL old. A lookup failing later on keeps the
+// previous answer
+//
+use std::{
+    io,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    error::{Error, Result},
+    outbound::{LocalBind, connect_from_timeout},
+};
+
+pub const RESOLVE_TTL: Duration = Duration::from_secs(300);
+
+pub fn resolve(flag: &'static str, spec: &str) -> Result<Vec<SocketAddr>> {
+    let unresolved = |reason: String| Error::Unresolved {
+        flag,
+        spec: spec.to_string(),
+        reason,
+    };
+
+    let addrs: Vec<SocketAddr> = spec.to_socket_addrs().map_err(|e| unresolved(e.to_string()))?.collect();
+
+    match addrs.is_empty() {
+        true => Err(unresolved("no address".to_string())),
+        false => Ok(addrs),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    // the command line option it came from, for the errors
+    flag: &'static str,
+    spec: String,
+    // never empty
+    addrs: Vec<SocketAddr>,
+    at: Instant,
+}
+
+impl Resolved {
+    pub fn new(flag: &'static str, spec: &str) -> Result<Self> {
+        Ok(Self {
+            flag,
+            spec: spec.to_string(),
+            addrs: resolve(flag, spec)?,
+            at: Instant::now(),
+        })
+    }
+
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+
+    //
+    // In the resolver's order, looked up again once stale
+    //
+    pub fn addrs(&mut self, now: Instant) -> &[SocketAddr] {
+        if now.duration_since(self.at) >= RESOLVE_TTL {
+            self.at = now;
+
+            match resolve(self.flag, &self.spec) {
+                Ok(v) if v != self.addrs => {
+                    info!("{} now resolves to {v:?}", self.spec);
+                    self.addrs = v;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("{e}, keeping {:?}", self.addrs),
+            }
+        }
+
+        &self.addrs
+    }
+
+    pub fn first(&mut self, now: Instant) -> SocketAddr {
+        self.addrs(now)[0]
+    }
+}
+
+//
+// The first address that answers within timeout, the last error otherwise.
+// From --tunnel-bind if set
+//
+pub fn connect_first(addrs: &[SocketAddr], local: &LocalBind, timeout: Duration) -> Result<TcpStream> {
+    let mut last = Error::from(io::Error::from(io::ErrorKind::InvalidInput));
+
+    for addr in addrs {
+        let ret = match local.is_set() {
+            true => connect_from_timeout(local, "--tunnel-bind", *addr, timeout),
+            false => TcpStream::connect_timeout(addr, timeout).map_err(Error::from),
+        };
+
+        match ret {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                info!("unable to connect to {addr} ({e})");
+                last = e;
+            }
+        }
+    }
+
+    Err(last)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let mut r = Resolved::new("--server-address", "localhost:22").unwrap();
+        assert!(r.addrs(Instant::now()).iter().all(|a| a.ip().is_loopback() && 22 == a.port()));
+
+        assert_eq!(
+            resolve("--server-address", "127.0.0.1:22").unwrap(),
+            vec!["127.0.0.1:22".parse::<SocketAddr>().unwrap()]
+        );
+
+        match resolve("--tunnel-address", "pvpn.invalid:22") {
+            Err(Error::Unresolved { flag, spec, .. }) => {
+                assert_eq!(flag, "--tunnel-address");
+                assert_eq!(spec, "pvpn.invalid:22");
+            }
+            v => panic!("{v:?}"),
+        }
+
+        // no port
+        assert!(resolve("--server-address", "localhost").is_err());
+    }
+
+    #[test]
+    fn first_reachable() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let none = LocalBind::default();
+
+        let stream = connect_first(&[closed, open], &none, Duration::from_secs(1)).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+
+        assert!(connect_first(&[closed], &none, Duration::from_secs(1)).is_err());
+    }
+}
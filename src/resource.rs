@@ -0,0 +1,174 @@
+//
+// What the process holds, sampled on the stats timer and at the end of a
+// session. Anything the platform can't tell is None and shows as "?"
+//
+use std::{fmt::Display, fs, sync::Mutex, time::Duration};
+
+// fds the process may open on top of one per connection before it looks like
+// a leak ( webhook, logs, the other threads... )
+pub const FD_SLACK: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub fds: Option<usize>,
+    // bytes
+    pub peak_rss: Option<u64>,
+    // user + system
+    pub cpu: Option<Duration>,
+}
+
+#[cfg(target_os = "linux")]
+const FD_DIR: Option<&str> = Some("/proc/self/fd");
+#[cfg(target_os = "macos")]
+const FD_DIR: Option<&str> = Some("/dev/fd");
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const FD_DIR: Option<&str> = None;
+
+pub fn fd_count() -> Option<usize> {
+    let entries = fs::read_dir(FD_DIR?).ok()?;
+
+    // minus the one read_dir() holds
+    Some(entries.count().saturating_sub(1))
+}
+
+//
+// Connections the process can open before running out of fds, what is left
+// of RLIMIT_NOFILE once the slack is set aside. None when unlimited
+//
+pub fn fd_capacity() -> Option<usize> {
+    let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+
+    if 0 != unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        return None;
+    }
+
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+
+    let soft: usize = limit.rlim_cur.try_into().ok()?;
+
+    Some(soft.saturating_sub(FD_SLACK).max(1))
+}
+
+fn rusage() -> Option<(u64, Duration)> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    if 0 != unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } {
+        return None;
+    }
+
+    // kilobytes everywhere but on macOS
+    let peak_rss = match cfg!(target_os = "macos") {
+        true => usage.ru_maxrss as u64,
+        false => usage.ru_maxrss as u64 * 1024,
+    };
+
+    let tv = |t: libc::timeval| Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64);
+
+    Some((peak_rss, tv(usage.ru_utime) + tv(usage.ru_stime)))
+}
+
+impl Usage {
+    pub fn sample() -> Self {
+        let (peak_rss, cpu) = match rusage() {
+            Some((rss, cpu)) => (Some(rss), Some(cpu)),
+            None => (None, None),
+        };
+
+        Self {
+            fds: fd_count(),
+            peak_rss,
+            cpu,
+        }
+    }
+
+    //
+    // More fds than the connections explain, relative to what the session
+    // started with
+    //
+    pub fn fd_leak(&self, baseline: &Usage, connections: usize) -> bool {
+        match (self.fds, baseline.fds) {
+            (Some(now), Some(base)) => now > base + connections + FD_SLACK,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.fds {
+            Some(v) => write!(f, "fds={v}")?,
+            None => write!(f, "fds=?")?,
+        }
+        match self.peak_rss {
+            Some(v) => write!(f, " peak_rss={}KB", v / 1024)?,
+            None => write!(f, " peak_rss=?")?,
+        }
+        match self.cpu {
+            Some(v) => write!(f, " cpu={:.2}s", v.as_secs_f64()),
+            None => write!(f, " cpu=?"),
+        }
+    }
+}
+
+// (before, after) of the last checked session
+static LAST_FD_CHECK: Mutex<Option<(usize, usize)>> = Mutex::new(None);
+
+//
+// Check mode: the fds a session opened are all gone once it ended. Returns
+// false on a leak
+//
+pub fn check_fds(before: Option<usize>) -> bool {
+    let (before, after) = match (before, fd_count()) {
+        (Some(b), Some(a)) => (b, a),
+        _ => return true,
+    };
+
+    if let Ok(mut last) = LAST_FD_CHECK.lock() {
+        *last = Some((before, after));
+    }
+
+    after <= before + FD_SLACK
+}
+
+pub fn last_fd_check() -> Option<(usize, usize)> {
+    LAST_FD_CHECK.lock().ok().and_then(|v| *v)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage() {
+        let u = Usage::sample();
+
+        if cfg!(target_os = "linux") {
+            assert!(u.fds.unwrap() >= 3);
+            assert!(u.peak_rss.unwrap() > 0);
+        }
+
+        let base = Usage {
+            fds: Some(10),
+            ..Default::default()
+        };
+        let now = Usage {
+            fds: Some(10 + 12 + FD_SLACK + 1),
+            ..Default::default()
+        };
+
+        assert!(now.fd_leak(&base, 12));
+        assert!(!now.fd_leak(&base, 13));
+        assert!(!Usage::default().fd_leak(&base, 0));
+
+        assert_eq!(Usage::default().to_string(), "fds=? peak_rss=? cpu=?");
+
+        if let Some(v) = fd_capacity() {
+            assert!(v >= 1);
+        }
+    }
+}
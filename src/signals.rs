@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SIGHUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    SIGHUP.store(true, Ordering::Relaxed);
+}
+
+//
+// The handler only sets a flag, the loops pick it up when convenient
+//
+pub fn install_sighup() {
+    let handler = on_sighup as extern "C" fn(libc::c_int);
+
+    unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+}
+
+//
+// True once per SIGHUP received
+//
+pub fn take_sighup() -> bool {
+    SIGHUP.swap(false, Ordering::Relaxed)
+}
@@ -0,0 +1,243 @@
+use std::{collections::HashMap, fmt::Display, io::Cursor, ops::AddAssign, time::Duration};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    error::Result,
+    packet::{Address, PacketMessage},
+};
+
+// How often each side sends its totals to the peer
+pub const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+//
+// Per session accounting of what goes through the tunnel socket.
+//
+// `payload` is the forwarded application data, `wire` is everything that
+// was enqueued to / dequeued from the tunnel ( headers, control frames... )
+//
+#[derive(Debug, Default, Clone)]
+pub struct TunnelStats {
+    pub payload_in: u64,
+    pub payload_out: u64,
+    pub wire_in: u64,
+    pub wire_out: u64,
+    pub control_in: HashMap<PacketMessage, u64>,
+    pub control_out: HashMap<PacketMessage, u64>,
+}
+
+fn efficiency(payload: u64, wire: u64) -> f64 {
+    if 0 == wire {
+        return 100.0;
+    }
+    payload as f64 * 100.0 / wire as f64
+}
+
+fn fmt_control(f: &mut std::fmt::Formatter<'_>, frames: &HashMap<PacketMessage, u64>) -> std::fmt::Result {
+    let mut frames: Vec<(&PacketMessage, &u64)> = frames.iter().collect();
+    frames.sort_by_key(|(m, _)| **m as u8);
+
+    write!(f, "[")?;
+    for (i, (msg, count)) in frames.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{msg}={count}")?;
+    }
+    write!(f, "]")
+}
+
+impl TunnelStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_enqueue(&mut self, msg: PacketMessage, header_len: usize, data_len: usize) {
+        self.wire_out += (header_len + data_len) as u64;
+
+        match msg {
+            PacketMessage::Data => self.payload_out += data_len as u64,
+            _ => *self.control_out.entry(msg).or_default() += 1,
+        }
+    }
+
+    pub fn on_dequeue(&mut self, msg: PacketMessage, header_len: usize, data_len: usize) {
+        self.wire_in += (header_len + data_len) as u64;
+
+        match msg {
+            PacketMessage::Data => self.payload_in += data_len as u64,
+            _ => *self.control_in.entry(msg).or_default() += 1,
+        }
+    }
+
+    //
+    // payload bytes / wire bytes in percent, both directions combined
+    //
+    pub fn efficiency(&self) -> f64 {
+        efficiency(self.payload_in + self.payload_out, self.wire_in + self.wire_out)
+    }
+
+    pub fn efficiency_in(&self) -> f64 {
+        efficiency(self.payload_in, self.wire_in)
+    }
+
+    pub fn efficiency_out(&self) -> f64 {
+        efficiency(self.payload_out, self.wire_out)
+    }
+}
+
+impl Display for TunnelStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "payload_in={} wire_in={} payload_out={} wire_out={} efficiency={:.1}% (in={:.1}% out={:.1}%) control_in=",
+            self.payload_in,
+            self.wire_in,
+            self.payload_out,
+            self.wire_out,
+            self.efficiency(),
+            self.efficiency_in(),
+            self.efficiency_out(),
+        )?;
+        fmt_control(f, &self.control_in)?;
+        write!(f, " control_out=")?;
+        fmt_control(f, &self.control_out)
+    }
+}
+
+//
+// Traffic of one forwarded connection, seen from its local socket.
+//
+// `bytes_in` was read from the socket and sent through the tunnel,
+// `bytes_out` came from the tunnel and was written to the socket
+//
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StreamCounters {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub frames_in: u64,
+    pub frames_out: u64,
+}
+
+impl AddAssign for StreamCounters {
+    fn add_assign(&mut self, rhs: Self) {
+        self.bytes_in += rhs.bytes_in;
+        self.bytes_out += rhs.bytes_out;
+        self.frames_in += rhs.frames_in;
+        self.frames_out += rhs.frames_out;
+    }
+}
+
+impl Display for StreamCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bytes_in={} bytes_out={} frames_in={} frames_out={}",
+            self.bytes_in, self.bytes_out, self.frames_in, self.frames_out
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamStats {
+    pub addr: Address,
+    pub age: Duration,
+    pub counters: StreamCounters,
+}
+
+//
+// Totals of a session, also the payload of the Stats frames
+//
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerStats {
+    // still open
+    pub streams: u32,
+    // closed since the session started
+    pub closed: u64,
+    // open and closed ones
+    pub counters: StreamCounters,
+}
+
+impl PeerStats {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut cur = Cursor::new(&mut out);
+
+        cur.write_u32::<LittleEndian>(self.streams)?;
+        cur.write_u64::<LittleEndian>(self.closed)?;
+        cur.write_u64::<LittleEndian>(self.counters.bytes_in)?;
+        cur.write_u64::<LittleEndian>(self.counters.bytes_out)?;
+        cur.write_u64::<LittleEndian>(self.counters.frames_in)?;
+        cur.write_u64::<LittleEndian>(self.counters.frames_out)?;
+
+        Ok(out)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let mut cur = Cursor::new(buf);
+
+        Ok(Self {
+            streams: cur.read_u32::<LittleEndian>()?,
+            closed: cur.read_u64::<LittleEndian>()?,
+            counters: StreamCounters {
+                bytes_in: cur.read_u64::<LittleEndian>()?,
+                bytes_out: cur.read_u64::<LittleEndian>()?,
+                frames_in: cur.read_u64::<LittleEndian>()?,
+                frames_out: cur.read_u64::<LittleEndian>()?,
+            },
+        })
+    }
+}
+
+impl Display for PeerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "streams={} closed={} {}", self.streams, self.closed, self.counters)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::HEADER_SIZE;
+
+    #[test]
+    fn efficiency_accounting() {
+        let mut stats = TunnelStats::new();
+
+        assert_eq!(stats.efficiency(), 100.0);
+
+        stats.on_enqueue(PacketMessage::Data, HEADER_SIZE, 94);
+        stats.on_enqueue(PacketMessage::Disconnected, HEADER_SIZE, 0);
+        stats.on_dequeue(PacketMessage::Data, HEADER_SIZE, 194);
+
+        assert_eq!(stats.payload_out, 94);
+        assert_eq!(stats.wire_out, 94 + 2 * HEADER_SIZE as u64);
+        assert_eq!(stats.payload_in, 194);
+        assert_eq!(stats.wire_in, 200);
+        assert_eq!(stats.control_out[&PacketMessage::Disconnected], 1);
+        assert!(stats.control_in.is_empty());
+
+        assert_eq!(stats.efficiency_in(), 97.0);
+        assert_eq!(stats.efficiency(), 288.0 * 100.0 / 306.0);
+    }
+
+    #[test]
+    fn peer_stats_encoding() {
+        let stats = PeerStats {
+            streams: 2,
+            closed: 10,
+            counters: StreamCounters {
+                bytes_in: 1 << 40,
+                bytes_out: 5,
+                frames_in: 1,
+                frames_out: 2,
+            },
+        };
+
+        assert_eq!(PeerStats::decode(&stats.encode().unwrap()).unwrap(), stats);
+        assert!(PeerStats::decode(&[0; 4]).is_err());
+    }
+}
@@ -1,24 +1,350 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
     io::{ErrorKind, IoSlice, Read, Write},
+    net::{Shutdown, SocketAddr},
+    time::{Duration, Instant},
 };
 
-use bytes::{Buf, BytesMut};
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use log::{debug, error, info, warn};
-use mio::net::TcpStream;
+use mio::{Interest, Registry, Token, net::TcpStream};
 
 use crate::{
+    access_log::AccessLog,
     error::{Error, Result},
-    packet::{Address, HEADER_SIZE, Packet, PacketMessage},
+    packet::{Address, CONTROL_ADDRESS, HEADER_SIZE, Packet, PacketMessage, RefuseReason},
+    ratelimit::TokenBucket,
+    stats::{PeerStats, StreamCounters, StreamStats, TunnelStats},
+    tokens::TokenAllocator,
 };
 
+//
+// What a read from a non-blocking socket means for the caller
+//
+#[derive(Debug, PartialEq)]
+pub enum ReadOutcome {
+    Data(usize),
+    // nothing for now, wait for the next readable event
+    WouldBlock,
+    // the peer shut its side down
+    Eof,
+}
+
+//
+// The one place interpreting a socket read, every read path goes through it.
+// Interrupted reads are retried, `buf` can't be empty or 0 would be ambiguous
+//
+pub fn read_outcome<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<ReadOutcome> {
+    if buf.is_empty() {
+        return Err(Error::BufferTooSmall { max: 0, actual: 1 });
+    }
+
+    loop {
+        return match r.read(buf) {
+            Ok(0) => Ok(ReadOutcome::Eof),
+            Ok(v) => Ok(ReadOutcome::Data(v)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(ReadOutcome::WouldBlock),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => Err(e.into()),
+        };
+    }
+}
+
+//
+// read_outcome() into the uninitialized tail of `dst`, what was read is
+// appended to it
+//
+fn read_spare<R: Read>(r: &mut R, dst: &mut BytesMut, size: usize) -> Result<ReadOutcome> {
+    dst.reserve(size);
+
+    // reserve() may leave more than asked for, a read stays `size`
+    let spare = &mut dst.spare_capacity_mut()[..size];
+
+    // SAFETY: read() only writes into the buffer and returns how much of it
+    // it wrote, the bytes past that are never looked at
+    let buf = unsafe { std::slice::from_raw_parts_mut(spare.as_mut_ptr() as *mut u8, spare.len()) };
+
+    let outcome = read_outcome(r, buf)?;
+
+    if let ReadOutcome::Data(v) = outcome {
+        // SAFETY: the first v bytes of the spare capacity were just written
+        unsafe { dst.set_len(dst.len() + v) };
+    }
+
+    Ok(outcome)
+}
+
 pub struct ClientStream {
     stream: TcpStream,
     buffered: BytesMut,
     pub is_connected: bool,
+    // the local socket returned EOF, the peer was sent a CloseWrite
+    read_closed: bool,
+    // the peer sent a CloseWrite, shutdown(Write) once buffered is flushed
+    write_closed: bool,
+    write_shutdown: bool,
+    last_activity: Instant,
+    // None while there's nothing to wait for, the socket is deregistered then
+    interest: Option<Interest>,
+    // bytes sent through the tunnel that the peer didn't give credit for
+    in_flight: usize,
+    // reads are paused until the peer returns some credit
+    paused: bool,
+    // bytes written to the socket that the peer wasn't credited for yet
+    credit: usize,
+    // bytes of this stream's frames still queued in the tunnel's buffer
+    tunnel_queued: usize,
+    // reads are paused until the tunnel drained that queue
+    tunnel_paused: bool,
+    created: Instant,
+    counters: StreamCounters,
+    // caps what goes to the socket, the rest waits in buffered
+    limit: Option<TokenBucket>,
+    // the tunnel only, reads are paused until tun_input drained
+    backlog_paused: bool,
+    // the tunnel only, not read while a local socket is congested
+    congestion_paused: bool,
+    // the peer sent Disconnected with data still buffered, since when. Only
+    // flushed from then on, see drain()
+    draining: Option<Instant>,
+    // the internet connection this stream carries, for the access log
+    peer: Option<SocketAddr>,
+}
+
+//
+// Why a stream went away
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum CloseReason {
+    // both halves closed
+    Finished,
+    // the peer sent Disconnected
+    Disconnected,
+    // the local socket failed
+    LocalError,
+    // the peer reported a failure ( IoFailure... )
+    PeerError,
+    // the peer's endpoint turned the connection down, ConnectionRefused
+    Refused,
+    // the local socket stopped draining, see set_max_buffered()
+    Stalled,
+    // the endpoint never answered, see CONNECT_TIMEOUT
+    ConnectTimeout,
+    // nothing went through for longer than the idle timeout
+    Idle,
+    // half-closed and idle for too long
+    HalfCloseTimeout,
+    // the session ended with the stream still open
+    TunnelLost,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CloseReason::Finished => "finished",
+            CloseReason::Disconnected => "disconnected",
+            CloseReason::LocalError => "local-error",
+            CloseReason::PeerError => "peer-error",
+            CloseReason::Refused => "refused",
+            CloseReason::Stalled => "stalled",
+            CloseReason::ConnectTimeout => "connect-timeout",
+            CloseReason::Idle => "idle",
+            CloseReason::HalfCloseTimeout => "half-close-timeout",
+            CloseReason::TunnelLost => "tunnel-lost",
+        };
+        write!(f, "{s}")
+    }
+}
+
+//
+// What close() tells about a stream, one access log line
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosedConnReport {
+    pub addr: Address,
+    // internet peer, see set_peer()
+    pub peer: Option<SocketAddr>,
+    pub reason: CloseReason,
+    // read from the socket, toward the tunnel
+    pub bytes_in: u64,
+    // written to the socket
+    pub bytes_out: u64,
+    // for the socket and never written
+    pub unflushed: usize,
+    pub duration: Duration,
+}
+
+impl Display for ClosedConnReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "token={} reason={} duration={}ms bytes_in={} bytes_out={} unflushed={}",
+            self.addr,
+            self.reason,
+            self.duration.as_millis(),
+            self.bytes_in,
+            self.bytes_out,
+            self.unflushed
+        )
+    }
+}
+
+//
+// One stream as snapshot() sees it, for debugging stuck connections
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub addr: Address,
+    // false while the connect is in progress
+    pub connected: bool,
+    // waiting for the local socket
+    pub buffered_bytes: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // since anything went through
+    pub idle_secs: u64,
+    // disconnected by the peer, flushing what's left
+    pub draining: bool,
+}
+
+impl Display for StreamInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match (self.connected, self.draining) {
+            (false, _) => "connecting",
+            (true, true) => "draining",
+            (true, false) => "connected",
+        };
+
+        write!(
+            f,
+            "token={} state={state} buffered={} bytes_in={} bytes_out={} idle={}s",
+            self.addr, self.buffered_bytes, self.bytes_in, self.bytes_out, self.idle_secs
+        )
+    }
+}
+
+//
+// Per stream buffering, one number per direction
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferStats {
+    pub addr: Address,
+    // from the tunnel, waiting for the local socket
+    pub to_local: usize,
+    // from the local socket, waiting for the tunnel
+    pub to_tunnel: usize,
+    // no credit left from the peer
+    pub paused_window: bool,
+    // the tunnel queue is over its high watermark
+    pub paused_tunnel: bool,
 }
 
+// Bytes read from a socket at once, see set_buffer_size()
 pub const BUFFER_SIZE: usize = 32 * 1024;
+pub const MIN_BUFFER_SIZE: usize = 1024;
+pub const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+// Data frames are split to that, well under the u16 data_len whatever the
+// buffer size
+pub const DEF_MTU: usize = 32 * 1024;
+// Max bytes in flight per address before reading from its socket pauses
+pub const WINDOW_SIZE: usize = 1024 * 1024;
+// Credit is returned in chunks to avoid a WindowUpdate per write
+const CREDIT_THRESHOLD: usize = WINDOW_SIZE / 4;
+//
+// Toward the local socket the buffering is capped by the window, toward the
+// tunnel a stream stops being read past the high watermark and resumes once
+// its share of the tunnel queue went under the low one
+//
+pub const TUNNEL_HIGH_WATER: usize = 256 * 1024;
+pub const TUNNEL_LOW_WATER: usize = 64 * 1024;
+// Event loops wake up at least this often to run their housekeeping
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+// An endpoint that didn't answer by then is refused
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a half-closed stream can stay idle before it gets dropped
+pub const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+// How long a disconnected stream gets to flush what it was sent
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// When the tunnel is gone, how long the streams get to flush what it brought
+pub const TEARDOWN_FLUSH: Duration = Duration::from_millis(500);
+// Data waiting for a local socket past that means the socket stopped
+// draining, WINDOW_SIZE keeps a well behaved peer far below
+pub const DEF_MAX_BUFFERED: usize = 4 * WINDOW_SIZE;
+// Read from the tunnel and not parsed yet. Past the cap the tunnel isn't read
+// anymore, it resumes under half of it, where the warning is logged too
+pub const DEF_MAX_TUN_INPUT: usize = 4 * WINDOW_SIZE;
+
+fn setsockopt_int(stream: &TcpStream, level: libc::c_int, opt: libc::c_int, value: libc::c_int) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            opt,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TCP_KEEPIDLE: Option<libc::c_int> = Some(libc::TCP_KEEPIDLE);
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const TCP_KEEPIDLE: Option<libc::c_int> = Some(libc::TCP_KEEPALIVE);
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+const TCP_KEEPIDLE: Option<libc::c_int> = None;
+
+//
+// SO_KEEPALIVE, the first probe after `idle` where the platform lets us
+// pick, its default elsewhere
+//
+pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> std::io::Result<()> {
+    setsockopt_int(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+
+    if let Some(opt) = TCP_KEEPIDLE {
+        let secs = idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+        setsockopt_int(stream, libc::IPPROTO_TCP, opt, secs)?;
+    }
+
+    Ok(())
+}
+
+//
+// SO_LINGER 0, close() sends a RST instead of a FIN
+//
+fn set_reset_on_close(stream: &TcpStream) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
 
 impl ClientStream {
     pub fn new(stream: TcpStream) -> Result<Self> {
@@ -29,30 +355,114 @@ impl ClientStream {
             stream,
             buffered: BytesMut::new(),
             is_connected: false,
+            read_closed: false,
+            write_closed: false,
+            write_shutdown: false,
+            last_activity: Instant::now(),
+            // WRITABLE tells when the connection completes
+            interest: Some(Interest::READABLE | Interest::WRITABLE),
+            in_flight: 0,
+            paused: false,
+            credit: 0,
+            tunnel_queued: 0,
+            tunnel_paused: false,
+            created: Instant::now(),
+            counters: StreamCounters::default(),
+            limit: None,
+            backlog_paused: false,
+            congestion_paused: false,
+            draining: None,
+            peer: None,
         })
     }
 
-    fn flush_buffer(&mut self) -> Result<usize> {
-        if self.buffered.is_empty() {
-            return Ok(0);
+    //
+    // WRITABLE only while something waits for the socket, an idle stream
+    // doesn't wake the loop up
+    //
+    fn wanted_interest(&self) -> Option<Interest> {
+        let readable = !(self.paused
+            || self.tunnel_paused
+            || self.backlog_paused
+            || self.congestion_paused
+            || self.read_closed
+            || self.draining.is_some());
+        let writable = !self.is_connected || !self.buffered.is_empty();
+
+        match (readable, writable) {
+            (true, true) => Some(Interest::READABLE | Interest::WRITABLE),
+            (true, false) => Some(Interest::READABLE),
+            (false, true) => Some(Interest::WRITABLE),
+            (false, false) => None,
+        }
+    }
+
+    pub fn is_half_closed(&self) -> bool {
+        self.read_closed || self.write_closed
+    }
+
+    fn is_closed(&self) -> bool {
+        self.read_closed && self.write_closed && self.buffered.is_empty()
+    }
+
+    fn shutdown_write(&mut self) {
+        if !self.write_closed || self.write_shutdown || !self.buffered.is_empty() {
+            return;
         }
 
-        let buffered = self.buffered.len();
+        if let Err(e) = self.stream.shutdown(Shutdown::Write) {
+            warn!("shutdown failure ({e})");
+        }
 
-        let written_len = match self.stream.write(&self.buffered) {
-            Ok(v) => {
-                debug!("{v} / {buffered}");
-                self.buffered.advance(v);
-                v
+        self.write_shutdown = true;
+    }
+
+    //
+    // Writes until the socket would block or there's nothing left, returns
+    // what went out. Edge triggered, a socket left writable won't tell again
+    //
+    fn flush_buffer(&mut self) -> Result<usize> {
+        let mut written_len = 0;
+
+        while !self.buffered.is_empty() {
+            let buffered = self.buffered.len();
+
+            let allowed = match &mut self.limit {
+                Some(b) => b.available().min(buffered),
+                None => buffered,
+            };
+
+            if 0 == allowed {
+                break;
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                //
-                // that's expected
-                //
-                0
+
+            match self.stream.write(&self.buffered[0..allowed]) {
+                Ok(0) => break,
+                Ok(v) => {
+                    debug!("{v} / {buffered}");
+                    if let Some(b) = &mut self.limit {
+                        b.consume(v);
+                    }
+                    self.buffered.advance(v);
+                    self.credit += v;
+                    written_len += v;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    //
+                    // that's expected
+                    //
+                    break;
+                }
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(e.into()),
-        };
+        }
+
+        if 0 == written_len {
+            return Ok(0);
+        }
+
+        self.last_activity = Instant::now();
 
         if let Err(e) = self.stream.flush() {
             // not fatal ?
@@ -66,7 +476,23 @@ impl ClientStream {
         self.buffered.extend_from_slice(data)
     }
 
+    //
+    // Where the internet connection comes from, on either side
+    //
+    pub fn set_peer(&mut self, peer: SocketAddr) {
+        self.peer = Some(peer);
+    }
+
     fn write_chained(&mut self, slices: &[&[u8]]) -> Result<()> {
+        if self.limit.is_some() {
+            // the bucket decides how much goes out
+            for s in slices {
+                self.buffered.extend_from_slice(s);
+            }
+            self.flush_buffer()?;
+            return Ok(());
+        }
+
         let buf_len = self.buffered.len();
 
         let mut io_slices: Vec<IoSlice<'_>> = Vec::with_capacity(slices.len() + 1);
@@ -84,7 +510,11 @@ impl ClientStream {
         }
 
         let written = match self.stream.write_vectored(&io_slices) {
-            Ok(v) => v,
+            Ok(v) => {
+                self.last_activity = Instant::now();
+                self.credit += v;
+                v
+            }
             Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
             Err(e) => return Err(e.into()),
         };
@@ -110,6 +540,26 @@ impl ClientStream {
         Ok(())
     }
 
+    //
+    // Returns the size of the encoded header. With data already queued the
+    // frame has to go behind it anyway so the header is encoded straight
+    // into the buffer
+    //
+    fn write_frame(&mut self, p: &Packet, data: &[u8]) -> Result<usize> {
+        if !self.buffered.is_empty() {
+            let hdr_len = p.encode(&mut (&mut self.buffered).writer())?;
+            self.buffered.extend_from_slice(data);
+            self.flush_buffer()?;
+            return Ok(hdr_len);
+        }
+
+        let mut hdr: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        let hdr_len = p.encode_slice(&mut hdr)?;
+
+        self.write_chained(&[&hdr[0..hdr_len], data])?;
+        Ok(hdr_len)
+    }
+
     pub fn complete_connect(&mut self) -> Result<usize> {
         if self.is_connected {
             // nothing to do
@@ -121,17 +571,22 @@ impl ClientStream {
         //
         let err = self.stream.take_error()?;
 
+        // refused, reset, timed out... it won't get any better
         if let Some(e) = err {
-            return Err(e.into());
+            return Err(Error::ConnectFailed { kind: e.kind() });
         }
 
         if let Err(e) = self.stream.peer_addr() {
             //
-            // might still return
+            // still in progress while
             // * libc::EINPROGRESS
             // * ErrorKind::NotConnected
-            warn!("{e}");
-            return Ok(0);
+            //
+            if e.kind() == ErrorKind::NotConnected || e.raw_os_error() == Some(libc::EINPROGRESS) {
+                debug!("connect in progress ({e})");
+                return Ok(0);
+            }
+            return Err(Error::ConnectFailed { kind: e.kind() });
         }
 
         self.is_connected = true;
@@ -144,6 +599,44 @@ impl ClientStream {
 pub struct TokenStreams {
     map: HashMap<Address, ClientStream>,
     tun_input: BytesMut,
+    tunnel_stats: TunnelStats,
+    registry: Option<Registry>,
+    tunnel: Option<Address>,
+    // largest Data payload sent in a single frame
+    mtu: usize,
+    // owner and size of each frame (part) in the tunnel's buffer, in order
+    tunnel_queue: VecDeque<(Address, usize)>,
+    // streams removed so far and what they moved
+    closed: u64,
+    closed_counters: StreamCounters,
+    // bytes per second into the tunnel
+    max_rate: Option<u64>,
+    // per stream cap of the data waiting for the local socket
+    max_buffered: usize,
+    // addresses this side hands out
+    tokens: TokenAllocator,
+    // the peer wants a Released for every address this side is done with
+    send_releases: bool,
+    // waiting for send_releases()
+    releases: Vec<Address>,
+    // cap of tun_input, see DEF_MAX_TUN_INPUT
+    max_tun_input: usize,
+    // tun_input went past the high watermark, logged once per crossing
+    backlog_warned: bool,
+    // SO_KEEPALIVE idle time of every stream added
+    keepalive: Option<Duration>,
+    // bytes read from the tunnel at once, the loops size theirs the same
+    buffer_size: usize,
+    // a line for every stream closed
+    access_log: Option<AccessLog>,
+    // see set_backpressure()
+    backpressure: bool,
+    // local sockets past the high watermark
+    congested: HashSet<Address>,
+    // the congestion cleared, tun_input waits for the loop
+    resumed: bool,
+    // added to the addresses for the registry's tokens, see set_token_base()
+    token_base: usize,
 }
 
 impl TokenStreams {
@@ -153,179 +646,2307 @@ impl TokenStreams {
         Self {
             map: HashMap::new(),
             tun_input,
+            tunnel_stats: TunnelStats::new(),
+            registry: None,
+            tunnel: None,
+            mtu: DEF_MTU,
+            tunnel_queue: VecDeque::new(),
+            closed: 0,
+            closed_counters: StreamCounters::default(),
+            max_rate: None,
+            max_buffered: DEF_MAX_BUFFERED,
+            tokens: TokenAllocator::default(),
+            send_releases: false,
+            releases: Vec::new(),
+            max_tun_input: DEF_MAX_TUN_INPUT,
+            backlog_warned: false,
+            keepalive: None,
+            buffer_size: BUFFER_SIZE,
+            access_log: None,
+            backpressure: false,
+            congested: HashSet::new(),
+            resumed: false,
+            token_base: 0,
         }
     }
 
-    pub fn add(&mut self, addr: Address, client: ClientStream) {
-        self.map.insert(addr, client);
+    //
+    // A fresh address for a new connection, None when they're all in use
+    //
+    pub fn allocate_address(&mut self) -> Option<Address> {
+        self.tokens.allocate()
     }
 
-    pub fn remove(&mut self, addr: Address) {
-        info!("removing token={addr}");
-        self.map.remove(&addr);
+    pub fn held_addresses(&self) -> usize {
+        self.tokens.held()
     }
 
-    pub fn contains_token(&self, addr: Address) -> bool {
-        self.map.contains_key(&addr)
+    //
+    // Set once the peer said it understands Released
+    //
+    pub fn set_send_releases(&mut self, enabled: bool) {
+        self.send_releases = enabled;
     }
 
-    pub fn flush(&mut self, addr: Address) -> Result<()> {
-        let client = match self.map.get_mut(&addr) {
+    //
+    // This side is done with the address, remove() calls it for the streams.
+    // The peer learns about it on the next send_releases()
+    //
+    pub fn release_address(&mut self, addr: Address) {
+        self.tokens.release_local(addr, Instant::now());
+
+        if self.send_releases {
+            self.releases.push(addr);
+        }
+    }
+
+    //
+    // After everything else for these addresses was queued, nothing about
+    // them can follow
+    //
+    pub fn send_releases(&mut self, tunnel: Address) -> Result<()> {
+        for addr in std::mem::take(&mut self.releases) {
+            self.write_message(tunnel, addr, PacketMessage::Released)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Limits the bytes per second written to the tunnel, the streams feeding
+    // it get paused by the tunnel watermarks meanwhile
+    //
+    pub fn set_max_rate(&mut self, rate: Option<u64>) {
+        self.max_rate = rate;
+
+        if let Some(tunnel) = self.tunnel
+            && let Some(client) = self.map.get_mut(&tunnel)
+        {
+            client.limit = rate.map(TokenBucket::new);
+        }
+    }
+
+    //
+    // A stream going past it is dropped, the tunnel is exempt, its watermarks
+    // pause the streams feeding it instead
+    //
+    pub fn set_max_buffered(&mut self, max: usize) {
+        self.max_buffered = max;
+    }
+
+    //
+    // The tunnel isn't read while a local socket holds more than half of
+    // max_buffered, until they're all under a quarter. Coarser than the
+    // window, for a peer that doesn't keep to it. The loop has to come back
+    // for tun_input once take_resumed() says so
+    //
+    pub fn set_backpressure(&mut self, enabled: bool) {
+        self.backpressure = enabled;
+    }
+
+    pub fn is_congested(&self) -> bool {
+        !self.congested.is_empty()
+    }
+
+    pub fn take_resumed(&mut self) -> bool {
+        std::mem::take(&mut self.resumed)
+    }
+
+    fn update_congestion(&mut self, addr: Address) -> Result<()> {
+        if !self.backpressure || Some(addr) == self.tunnel {
+            return Ok(());
+        }
+
+        let buffered = self.map.get(&addr).map_or(0, |c| c.buffered.len());
+        let before = self.is_congested();
+
+        if buffered > self.max_buffered / 2 {
+            if self.congested.insert(addr) {
+                debug!("token={addr} {buffered} bytes buffered, congested");
+            }
+        } else if buffered <= self.max_buffered / 4 && self.congested.remove(&addr) {
+            debug!("token={addr} {buffered} bytes buffered, drained");
+        }
+
+        let congested = self.is_congested();
+
+        if before == congested {
+            return Ok(());
+        }
+
+        match congested {
+            true => debug!("local sockets congested, pausing the tunnel reads"),
+            false => {
+                debug!("local sockets drained, resuming the tunnel reads");
+                self.resumed = true;
+            }
+        }
+
+        let tunnel = match self.tunnel {
             Some(v) => v,
-            None => return Err(Error::ClientNotFound),
+            None => return Ok(()),
         };
 
-        if !client.is_connected {
-            client.complete_connect()?;
+        if let Some(client) = self.map.get_mut(&tunnel) {
+            client.congestion_paused = congested;
+            self.update_interest(tunnel)?;
         }
 
-        if client.is_connected {
-            client.flush_buffer()?;
+        Ok(())
+    }
+
+    //
+    // Never below a frame or the tunnel could stop for good
+    //
+    pub fn set_max_tun_input(&mut self, max: usize) {
+        self.max_tun_input = max.max(HEADER_SIZE + u16::MAX as usize);
+    }
+
+    //
+    // Read from the tunnel, waiting for read_packet()
+    //
+    pub fn tun_backlog(&self) -> usize {
+        self.tun_input.len()
+    }
+
+    //
+    // For the streams added from now on, the tunnel included
+    //
+    pub fn set_keepalive(&mut self, idle: Option<Duration>) {
+        self.keepalive = idle;
+    }
+
+    pub fn set_access_log(&mut self, log: Option<AccessLog>) {
+        self.access_log = log;
+    }
+
+    //
+    // Clamped to MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE. Larger reads are split
+    // into frames by write_packet(), nothing else depends on it
+    //
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buffer_size = size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE);
+
+        if self.buffer_size != size {
+            warn!("buffer size {size} out of range, using {}", self.buffer_size);
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn buffered_len(&self, addr: Address) -> Option<usize> {
+        self.map.get(&addr).map(|c| c.buffered.len())
+    }
+
+    //
+    // The tunnel has data waiting for tokens, flush() it again after
+    // REFILL_INTERVAL
+    //
+    pub fn is_throttled(&self) -> bool {
+        match self.tunnel.and_then(|t| self.map.get(&t)) {
+            Some(client) => client.limit.is_some() && !client.buffered.is_empty(),
+            None => false,
+        }
+    }
+
+    //
+    // Capped to what the u16 length field can carry
+    //
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu.clamp(1, u16::MAX as usize);
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    //
+    // Streams get (re)registered with this registry as they are added and
+    // their interest changes
+    //
+    pub fn set_registry(&mut self, registry: Registry) {
+        self.registry = Some(registry);
+    }
+
+    //
+    // Several of them sharing a Poll ( --max-clients ) register their
+    // streams at base + address, each in its own range
+    //
+    pub fn set_token_base(&mut self, base: usize) {
+        self.token_base = base;
+    }
+
+    pub fn tunnel_stats(&self) -> &TunnelStats {
+        &self.tunnel_stats
+    }
+
+    //
+    // What flush_read() would append, for the fuzz targets
+    //
+    #[doc(hidden)]
+    pub fn feed_tunnel_input(&mut self, data: &[u8]) {
+        self.tun_input.extend_from_slice(data);
+    }
+
+    pub fn add(&mut self, addr: Address, mut client: ClientStream) -> Result<()> {
+        if let Some(idle) = self.keepalive
+            && let Err(e) = set_keepalive(&client.stream, idle)
+        {
+            warn!("token={addr} keepalive failed ({e})");
+        }
+
+        if let Some(registry) = &self.registry
+            && let Some(interest) = client.interest
+        {
+            registry.register(&mut client.stream, Token(self.token_base + addr), interest)?;
         }
 
+        self.map.insert(addr, client);
         Ok(())
     }
 
-    pub fn write(&mut self, addr: Address, buffer: &[u8]) -> Result<()> {
-        let client = match self.map.get_mut(&addr) {
-            Some(v) => v,
-            None => return Err(Error::ClientNotFound),
-        };
+    pub fn add_tunnel(&mut self, addr: Address, mut client: ClientStream) -> Result<()> {
+        client.limit = self.max_rate.map(TokenBucket::new);
+        self.add(addr, client)?;
+        self.tunnel = Some(addr);
+        Ok(())
+    }
 
-        client.write_chained(&[buffer])
+    fn tunnel_addr(&self) -> Result<Address> {
+        self.tunnel.ok_or(Error::ClientNotFound)
     }
 
-    pub fn write_message(&mut self, src: Address, dst: Address, msg: PacketMessage) -> Result<()> {
-        let client = match self.map.get_mut(&src) {
+    fn update_interest(&mut self, addr: Address) -> Result<()> {
+        let client = match self.map.get_mut(&addr) {
             Some(v) => v,
             None => return Err(Error::ClientNotFound),
         };
 
-        let p = Packet::new_message(dst, msg);
+        let interest = client.wanted_interest();
 
-        debug!("WRITE: {p}");
+        if interest == client.interest {
+            return Ok(());
+        }
 
-        let mut hdr: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
-        p.encode(&mut hdr)?;
+        let token = Token(self.token_base + addr);
+
+        if let Some(registry) = &self.registry {
+            match (client.interest, interest) {
+                (Some(_), Some(v)) => registry.reregister(&mut client.stream, token, v)?,
+                (None, Some(v)) => registry.register(&mut client.stream, token, v)?,
+                (Some(_), None) => registry.deregister(&mut client.stream)?,
+                (None, None) => {}
+            }
+        }
 
-        client.write_chained(&[&hdr])
+        client.interest = interest;
+        Ok(())
     }
 
-    pub fn write_packet(&mut self, src: Address, dst: Address, data: &[u8]) -> Result<()> {
-        let client = match self.map.get_mut(&src) {
-            Some(v) => v,
-            None => return Err(Error::ClientNotFound),
+    //
+    // Tell the peer how much of the data it sent for this address made it to
+    // the socket so it can keep sending
+    //
+    fn return_credit(&mut self, addr: Address) -> Result<()> {
+        let credit = match self.map.get_mut(&addr) {
+            Some(v) if v.credit >= CREDIT_THRESHOLD => std::mem::take(&mut v.credit),
+            _ => return Ok(()),
         };
 
-        let data_len: u16 = data.len().try_into()?;
+        let credit: u32 = credit.try_into()?;
+        let mut data: [u8; 4] = [0; 4];
+        LittleEndian::write_u32(&mut data, credit);
 
-        let p = Packet::new_data(dst, data_len);
+        let tunnel = self.tunnel_addr()?;
+        self.write_frame(tunnel, Packet::new(addr, PacketMessage::WindowUpdate, 4), &data)
+    }
 
-        debug!("WRITE: {p}");
+    fn window_update(&mut self, addr: Address, credit: usize) -> Result<()> {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) => v,
+            // already gone
+            None => return Ok(()),
+        };
 
-        let mut hdr: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
-        p.encode(&mut hdr)?;
+        client.in_flight = client.in_flight.saturating_sub(credit);
+
+        if client.paused && client.in_flight < WINDOW_SIZE {
+            debug!("resuming token={addr} in_flight={}", client.in_flight);
+            client.paused = false;
+            self.update_interest(addr)?;
+        }
 
-        client.write_chained(&[&hdr, data])
+        Ok(())
     }
 
-    pub fn read_packet(&mut self, buf: &mut [u8]) -> Result<(usize, Address)> {
-        if self.tun_input.len() < HEADER_SIZE {
-            // nothing to read
-            return Err(Error::Empty);
-        }
+    //
+    // Takes the stream out, as is. close() is the orderly way
+    //
+    pub fn remove(&mut self, addr: Address) -> Option<ClientStream> {
+        info!("removing token={addr}");
 
-        let p = Packet::from_buffer(&self.tun_input)?;
+        let mut client = self.map.remove(&addr)?;
 
         //
-        // Do we also have the data available
+        // out of the poll before the drop closes it, no stale events for a
+        // token that's gone or about to be handed out again
         //
-        let data_len: usize = p.data_len.into();
-        let total_length = HEADER_SIZE + data_len;
-
-        if total_length > self.tun_input.len() {
-            //
-            // Not enough data
-            //
-            debug!("not enough data {} < {total_length}", self.tun_input.len());
-            return Err(Error::NotEnoughData);
+        if let Some(registry) = &self.registry
+            && client.interest.is_some()
+            && let Err(e) = registry.deregister(&mut client.stream)
+        {
+            debug!("token={addr} deregister failure ({e})");
         }
 
-        debug!("READ:  {p}");
+        if Some(addr) != self.tunnel {
+            self.release_address(addr);
 
-        self.tun_input.advance(HEADER_SIZE);
-
-        match p.msg {
-            PacketMessage::Data => {
-                if data_len > buf.len() {
-                    return Err(Error::BufferTooSmall {
-                        max: buf.len(),
-                        actual: data_len,
-                    });
-                }
+            self.closed += 1;
+            self.closed_counters += client.counters;
 
-                if data_len > 0 {
-                    buf[0..data_len].copy_from_slice(&self.tun_input[0..data_len]);
-                }
+            if let Err(e) = self.update_congestion(addr) {
+                debug!("token={addr} congestion update failure ({e})");
+            }
+        }
 
-                self.tun_input.advance(data_len);
+        Some(client)
+    }
 
-                Ok((data_len, p.addr))
+    //
+    // Last flush attempt, shutdown and removal. The report is logged and
+    // handed back
+    //
+    pub fn close(&mut self, addr: Address, reason: CloseReason) -> Option<ClosedConnReport> {
+        let mut client = self.remove(addr)?;
+
+        if CloseReason::Refused == reason {
+            // the internet peer fails right away, like with nothing listening
+            if let Err(e) = set_reset_on_close(&client.stream) {
+                debug!("token={addr} SO_LINGER failure ({e})");
             }
-            PacketMessage::Disconnected => Err(Error::Eof),
-            _ => {
-                let e: Error = (&p.msg).into();
-                error!("{e}");
-                self.remove(p.addr);
-                Err(e)
+        } else if client.is_connected {
+            // best effort, the stream is going away whatever happens
+            if let Err(e) = client.flush_buffer() {
+                debug!("token={addr} final flush failure ({e})");
+            }
+
+            if let Err(e) = client.stream.shutdown(Shutdown::Both) {
+                debug!("token={addr} shutdown failure ({e})");
             }
         }
+
+        let report = ClosedConnReport {
+            addr,
+            peer: client.peer,
+            reason,
+            bytes_in: client.counters.bytes_in,
+            bytes_out: client.counters.bytes_out,
+            unflushed: client.buffered.len(),
+            duration: client.created.elapsed(),
+        };
+
+        info!("closed {report}");
+
+        if let Some(log) = &mut self.access_log {
+            log.record(&report);
+        }
+
+        Some(report)
     }
 
-    pub fn flush_read(&mut self, src: Address, buf: &mut [u8]) -> Result<()> {
-        let client = match self.map.get_mut(&src) {
+    //
+    // close() and the peer is sent `msg` ( Disconnected, IoFailure... ) for
+    // the address. Nothing happens for an address already gone, the peer was
+    // told then
+    //
+    pub fn close_notify(
+        &mut self,
+        addr: Address,
+        reason: CloseReason,
+        msg: PacketMessage,
+    ) -> Result<Option<ClosedConnReport>> {
+        let report = match self.close(addr, reason) {
             Some(v) => v,
-            None => return Err(Error::ClientNotFound),
+            None => return Ok(None),
         };
 
+        if let Some(tunnel) = self.tunnel {
+            self.write_message(tunnel, addr, msg)?;
+        }
+
+        Ok(Some(report))
+    }
+
+    //
+    // What the peer sent before going away still reaches the sockets that
+    // take it within `deadline`, close() only tries once. Returns the bytes
+    // left behind
+    //
+    pub fn flush_all(&mut self, deadline: Duration) -> usize {
+        let start = Instant::now();
+
         loop {
-            let read_len = match client.stream.read(buf) {
-                Ok(v) => v,
-                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
-                Err(e) => return Err(e.into()),
-            };
+            let mut pending = 0;
+
+            for (addr, client) in self.map.iter_mut() {
+                if Some(*addr) == self.tunnel || client.buffered.is_empty() {
+                    continue;
+                }
 
-            if 0 == read_len {
-                break Err(Error::Eof);
+                // accepted before WRITABLE was seen, or still connecting
+                if let Err(e) = client.complete_connect() {
+                    debug!("token={addr} connect failure ({e})");
+                    continue;
+                }
+
+                if !client.is_connected {
+                    continue;
+                }
+
+                match client.flush_buffer() {
+                    Ok(_) => pending += client.buffered.len(),
+                    // close() gives up on it
+                    Err(e) => debug!("token={addr} flush failure ({e})"),
+                }
+            }
+
+            if 0 == pending || start.elapsed() >= deadline {
+                return pending;
             }
 
-            self.tun_input.extend_from_slice(&buf[0..read_len]);
+            std::thread::sleep(Duration::from_millis(10));
         }
     }
 
-    pub fn read(&mut self, addr: Address, buffer: &mut [u8]) -> Result<usize> {
-        let client = match self.map.get_mut(&addr) {
-            Some(v) => v,
-            None => return Err(Error::ClientNotFound),
+    //
+    // Every stream but the tunnel, when the session ends
+    //
+    pub fn close_all(&mut self, reason: CloseReason) -> Vec<ClosedConnReport> {
+        let mut addrs: Vec<Address> = self.map.keys().filter(|a| Some(**a) != self.tunnel).copied().collect();
+        addrs.sort();
+
+        addrs.into_iter().filter_map(|a| self.close(a, reason)).collect()
+    }
+
+    //
+    // Per stream counters, the tunnel excluded
+    //
+    pub fn stats(&self) -> Vec<StreamStats> {
+        let mut stats: Vec<StreamStats> = self
+            .map
+            .iter()
+            .filter(|(addr, _)| Some(**addr) != self.tunnel)
+            .map(|(addr, client)| StreamStats {
+                addr: *addr,
+                age: client.created.elapsed(),
+                counters: client.counters,
+            })
+            .collect();
+
+        stats.sort_by_key(|s| s.addr);
+        stats
+    }
+
+    //
+    // Everything the session's streams moved, open and closed ones
+    //
+    pub fn totals(&self) -> PeerStats {
+        let mut totals = PeerStats {
+            closed: self.closed,
+            counters: self.closed_counters,
+            ..Default::default()
         };
 
-        let read_len = match client.stream.read(buffer) {
-            Ok(v) => {
-                if 0 == v {
-                    debug!("received EOF for token={addr}");
-                    self.remove(addr);
-                    return Err(Error::Eof);
+        for s in self.stats() {
+            totals.streams += 1;
+            totals.counters += s.counters;
+        }
+
+        totals
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains_token(&self, addr: Address) -> bool {
+        self.map.contains_key(&addr)
+    }
+
+    //
+    // Tear down the stream once both sides are done with it
+    //
+    fn try_finish(&mut self, addr: Address) {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) => v,
+            None => return,
+        };
+
+        client.shutdown_write();
+
+        if client.is_closed() {
+            self.close(addr, CloseReason::Finished);
+        }
+    }
+
+    //
+    // The peer won't send any more data for this address
+    //
+    pub fn close_write(&mut self, addr: Address) {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) => v,
+            None => return,
+        };
+
+        debug!("close write for token={addr}");
+        client.write_closed = true;
+        client.last_activity = Instant::now();
+
+        if client.is_connected {
+            self.try_finish(addr);
+        }
+    }
+
+    //
+    // Drops the half-closed streams that stayed idle for too long, the peer
+    // is told. Returns their addresses
+    //
+    pub fn prune_half_closed(&mut self, timeout: Duration) -> Result<Vec<Address>> {
+        let expired: Vec<Address> = self
+            .map
+            .iter()
+            .filter(|(_, c)| c.is_half_closed() && c.last_activity.elapsed() > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &expired {
+            warn!("half-closed token={addr} timed out");
+            self.close_notify(*addr, CloseReason::HalfCloseTimeout, PacketMessage::Disconnected)?;
+        }
+
+        Ok(expired)
+    }
+
+    //
+    // The peer is gone, what it sent before still goes to the socket. The
+    // stream isn't read nor written to anymore and flush() closes it once
+    // empty. Closed right away when there's nothing to wait for
+    //
+    fn drain(&mut self, addr: Address) -> Result<()> {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        if client.buffered.is_empty() || client.draining.is_some() {
+            self.close(addr, CloseReason::Disconnected);
+            return Ok(());
+        }
+
+        debug!("token={addr} draining {} bytes", client.buffered.len());
+        client.draining = Some(Instant::now());
+        self.update_interest(addr)
+    }
+
+    //
+    // Closes the draining streams the socket didn't take everything from in
+    // `timeout`, the peer isn't told, it's the one that left. Returns their
+    // addresses
+    //
+    pub fn prune_draining(&mut self, timeout: Duration) -> Vec<Address> {
+        let expired: Vec<Address> = self
+            .map
+            .iter()
+            .filter(|(_, c)| matches!(c.draining, Some(t) if t.elapsed() > timeout))
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &expired {
+            warn!("token={addr} drain timed out");
+            self.close(*addr, CloseReason::Disconnected);
+        }
+
+        expired
+    }
+
+    //
+    // Drops the streams nothing went through for longer than `max_idle`, the
+    // peer is told. Returns their addresses
+    //
+    pub fn prune_idle(&mut self, max_idle: Duration) -> Result<Vec<Address>> {
+        let expired: Vec<Address> = self
+            .map
+            .iter()
+            .filter(|(addr, c)| {
+                Some(**addr) != self.tunnel && c.draining.is_none() && c.last_activity.elapsed() > max_idle
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &expired {
+            info!("token={addr} idle for more than {}s", max_idle.as_secs());
+            self.close_notify(*addr, CloseReason::Idle, PacketMessage::Disconnected)?;
+        }
+
+        Ok(expired)
+    }
+
+    //
+    // Drops the streams still connecting after `timeout`, the peer is told
+    // the connection was refused. Returns their addresses
+    //
+    pub fn prune_connecting(&mut self, timeout: Duration) -> Result<Vec<Address>> {
+        let expired: Vec<Address> = self
+            .map
+            .iter()
+            .filter(|(addr, c)| Some(**addr) != self.tunnel && !c.is_connected && c.created.elapsed() > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &expired {
+            warn!("token={addr} connect timed out");
+            self.close_notify(*addr, CloseReason::ConnectTimeout, PacketMessage::ConnectionRefused)?;
+        }
+
+        Ok(expired)
+    }
+
+    //
+    // A stream failing is closed and the peer told, only the tunnel failing
+    // is the caller's problem
+    //
+    pub fn flush(&mut self, addr: Address) -> Result<()> {
+        let draining = self.map.get(&addr).is_some_and(|c| c.draining.is_some());
+
+        match self.flush_stream(addr) {
+            // the peer already forgot about it
+            Err(e) if draining => {
+                self.close(addr, CloseReason::LocalError);
+                Err(e)
+            }
+            Err(e) if Some(addr) != self.tunnel => {
+                self.close_notify(addr, CloseReason::LocalError, (&e).into())?;
+                Err(e)
+            }
+            Ok(()) if draining && self.buffered_len(addr) == Some(0) => {
+                self.close(addr, CloseReason::Disconnected);
+                Ok(())
+            }
+            ret => ret,
+        }
+    }
+
+    fn flush_stream(&mut self, addr: Address) -> Result<()> {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) => v,
+            None => return Err(Error::ClientNotFound),
+        };
+
+        let before = client.buffered.len();
+
+        if !client.is_connected {
+            client.complete_connect()?;
+        }
+
+        if client.is_connected {
+            client.flush_buffer()?;
+            let after = client.buffered.len();
+
+            if Some(addr) == self.tunnel {
+                self.tunnel_drained(before - after)?;
+            }
+
+            self.return_credit(addr)?;
+            self.try_finish(addr);
+        }
+
+        if self.map.contains_key(&addr) {
+            self.update_interest(addr)?;
+        }
+
+        self.update_congestion(addr)
+    }
+
+    //
+    // `len` bytes of the tunnel's buffer made it to the socket, credit the
+    // streams they belong to
+    //
+    fn tunnel_drained(&mut self, mut len: usize) -> Result<()> {
+        while len > 0 {
+            let (owner, queued) = match self.tunnel_queue.front_mut() {
+                Some(v) => v,
+                None => break,
+            };
+
+            let drained = len.min(*queued);
+            let owner = *owner;
+
+            *queued -= drained;
+            len -= drained;
+
+            if 0 == *queued {
+                self.tunnel_queue.pop_front();
+            }
+
+            if let Some(client) = self.map.get_mut(&owner) {
+                client.tunnel_queued = client.tunnel_queued.saturating_sub(drained);
+
+                if client.tunnel_paused && client.tunnel_queued <= TUNNEL_LOW_WATER {
+                    debug!("resuming token={owner} tunnel_queued={}", client.tunnel_queued);
+                    client.tunnel_paused = false;
+                    self.update_interest(owner)?;
                 }
-                v
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
+        }
+
+        Ok(())
+    }
+
+    fn tunnel_queued(&mut self, owner: Address, len: usize) -> Result<()> {
+        if 0 == len {
+            return Ok(());
+        }
+
+        self.tunnel_queue.push_back((owner, len));
+
+        let client = match self.map.get_mut(&owner) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        client.tunnel_queued += len;
+
+        if !client.tunnel_paused && client.tunnel_queued > TUNNEL_HIGH_WATER {
+            debug!("pausing token={owner} tunnel_queued={}", client.tunnel_queued);
+            client.tunnel_paused = true;
+            self.update_interest(owner)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn buffer_stats(&self) -> Vec<BufferStats> {
+        let mut stats: Vec<BufferStats> = self
+            .map
+            .iter()
+            .filter(|(addr, _)| Some(**addr) != self.tunnel)
+            .map(|(addr, client)| BufferStats {
+                addr: *addr,
+                to_local: client.buffered.len(),
+                to_tunnel: client.tunnel_queued,
+                paused_window: client.paused,
+                paused_tunnel: client.tunnel_paused,
+            })
+            .collect();
+
+        stats.sort_by_key(|s| s.addr);
+        stats
+    }
+
+    //
+    // Every stream but the tunnel, sorted by address. Only looks
+    //
+    pub fn snapshot(&self) -> Vec<StreamInfo> {
+        let mut info: Vec<StreamInfo> = self
+            .map
+            .iter()
+            .filter(|(addr, _)| Some(**addr) != self.tunnel)
+            .map(|(addr, client)| StreamInfo {
+                addr: *addr,
+                connected: client.is_connected,
+                buffered_bytes: client.buffered.len(),
+                bytes_in: client.counters.bytes_in,
+                bytes_out: client.counters.bytes_out,
+                idle_secs: client.last_activity.elapsed().as_secs(),
+                draining: client.draining.is_some(),
+            })
+            .collect();
+
+        info.sort_by_key(|i| i.addr);
+        info
+    }
+
+    pub fn write(&mut self, addr: Address, buffer: &[u8]) -> Result<()> {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) if v.draining.is_none() => v,
+            _ => return Err(Error::ClientNotFound),
+        };
+
+        //
+        // the peer ignores the window or the socket is wedged, either way
+        // more memory won't help
+        //
+        let buffered = client.buffered.len();
+
+        if Some(addr) != self.tunnel && buffered + buffer.len() > self.max_buffered {
+            warn!("token={addr} not draining, {buffered} bytes buffered");
+            self.close_notify(addr, CloseReason::Stalled, PacketMessage::Disconnected)?;
+            return Err(Error::BufferFull { addr, buffered });
+        }
+
+        if let Err(e) = client.write_chained(&[buffer]) {
+            if Some(addr) != self.tunnel {
+                self.close_notify(addr, CloseReason::LocalError, (&e).into())?;
+            }
+            return Err(e);
+        }
+
+        client.counters.bytes_out += buffer.len() as u64;
+        client.counters.frames_in += 1;
+        self.update_interest(addr)?;
+        self.update_congestion(addr)?;
+        self.return_credit(addr)
+    }
+
+    //
+    // Single place where frames get enqueued to the tunnel
+    //
+    fn write_frame(&mut self, src: Address, p: Packet, data: &[u8]) -> Result<()> {
+        let client = match self.map.get_mut(&src) {
+            Some(v) => v,
+            None => return Err(Error::ClientNotFound),
+        };
+
+        debug!("WRITE: {p}");
+
+        let before = client.buffered.len();
+        let hdr_len = client.write_frame(&p, data)?;
+        let after = client.buffered.len();
+
+        self.tunnel_stats.on_enqueue(p.msg, hdr_len, data.len());
+
+        if after != before {
+            self.update_interest(src)?;
+        }
+
+        if Some(src) != self.tunnel {
+            return Ok(());
+        }
+
+        //
+        // the frame went out after whatever was queued, what's left of it
+        // sits at the tail
+        //
+        let queued = after.min(hdr_len + data.len());
+        self.tunnel_drained(before - (after - queued))?;
+        self.tunnel_queued(p.addr, queued)
+    }
+
+    pub fn write_message(&mut self, src: Address, dst: Address, msg: PacketMessage) -> Result<()> {
+        self.write_frame(src, Packet::new_message(dst, msg), &[])
+    }
+
+    pub fn write_message_data(&mut self, src: Address, dst: Address, msg: PacketMessage, data: &[u8]) -> Result<()> {
+        let data_len: u16 = data.len().try_into()?;
+        self.write_frame(src, Packet::new(dst, msg, data_len), data)
+    }
+
+    pub fn write_control(&mut self, src: Address, msg: PacketMessage, data: &[u8]) -> Result<()> {
+        self.write_message_data(src, CONTROL_ADDRESS, msg, data)
+    }
+
+    //
+    // Payloads larger than the mtu go out as several Data frames, in order.
+    // An empty one sends nothing, a zero-length Data frame never goes out
+    //
+    pub fn write_packet(&mut self, src: Address, dst: Address, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(self.mtu) {
+            let data_len: u16 = chunk.len().try_into()?;
+            self.write_frame(src, Packet::new_data(dst, data_len), chunk)?;
+        }
+
+        if let Some(client) = self.map.get_mut(&dst) {
+            client.in_flight += data.len();
+            client.counters.bytes_in += data.len() as u64;
+            client.counters.frames_out += data.len().div_ceil(self.mtu) as u64;
+
+            if !client.paused && client.in_flight >= WINDOW_SIZE {
+                debug!("pausing token={dst} in_flight={}", client.in_flight);
+                client.paused = true;
+                self.update_interest(dst)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    //
+    // Returns the next Data or tunnel level packet with its payload, split
+    // off tun_input without a copy. The per-stream messages are handled here
+    //
+    pub fn read_packet(&mut self) -> Result<(Packet, Bytes)> {
+        loop {
+            // the rest waits for take_resumed()
+            if self.is_congested() {
+                return Err(Error::Empty);
+            }
+
+            if self.tun_input.len() < HEADER_SIZE {
+                // nothing to read
+                self.drained()?;
+                return Err(Error::Empty);
+            }
+
+            let (p, hdr_len) = Packet::from_buffer(&self.tun_input)?;
+
+            //
+            // Do we also have the data available
+            //
+            let data_len: usize = p.data_len.into();
+            let total_length = hdr_len + data_len;
+
+            if total_length > self.tun_input.len() {
+                //
+                // Not enough data
+                //
+                debug!("not enough data {} < {total_length}", self.tun_input.len());
+                self.drained()?;
+                return Err(Error::NotEnoughData);
+            }
+
+            debug!("READ:  {p}");
+
+            //
+            // everything sent to the control address is for the loops
+            //
+            let surfaced = CONTROL_ADDRESS == p.addr
+                || matches!(
+                    p.msg,
+                    PacketMessage::Data | PacketMessage::Datagram | PacketMessage::Hello | PacketMessage::Connect
+                );
+
+            self.tunnel_stats.on_dequeue(p.msg, hdr_len, data_len);
+            self.tun_input.advance(hdr_len);
+
+            match p.msg {
+                _ if surfaced => {
+                    let data = self.tun_input.split_to(data_len).freeze();
+                    return Ok((p, data));
+                }
+                PacketMessage::CloseWrite => {
+                    self.tun_input.advance(data_len);
+                    self.close_write(p.addr);
+                }
+                PacketMessage::WindowUpdate => {
+                    if data_len < 4 {
+                        self.tun_input.advance(data_len);
+                        warn!("token={} invalid window update", p.addr);
+                        continue;
+                    }
+
+                    let credit = LittleEndian::read_u32(&self.tun_input[0..4]);
+                    self.tun_input.advance(data_len);
+                    self.window_update(p.addr, credit as usize)?;
+                }
+                PacketMessage::Disconnected => {
+                    self.tun_input.advance(data_len);
+                    self.drain(p.addr)?;
+                }
+                PacketMessage::Released => {
+                    self.tun_input.advance(data_len);
+                    self.tokens.release_peer(p.addr);
+                }
+                _ => {
+                    let reason = RefuseReason::decode(&self.tun_input[0..data_len]);
+                    self.tun_input.advance(data_len);
+                    let e: Error = (&p.msg).into();
+                    match reason {
+                        Some(r) => error!("token={} {e} ({r})", p.addr),
+                        None => error!("token={} {e}", p.addr),
+                    }
+                    let reason = match p.msg {
+                        PacketMessage::ConnectionRefused => CloseReason::Refused,
+                        _ => CloseReason::PeerError,
+                    };
+                    self.close(p.addr, reason);
+                }
+            }
+        }
+    }
+
+    //
+    // Reads straight into tun_input's spare capacity, buffer_size at a time
+    //
+    pub fn flush_read(&mut self, src: Address) -> Result<()> {
+        let client = match self.map.get_mut(&src) {
+            Some(v) => v,
+            None => return Err(Error::ClientNotFound),
+        };
+
+        if client.backlog_paused || client.congestion_paused {
+            return Ok(());
+        }
+
+        //
+        // the tunnel going away ends the session, hence the error
+        //
+        loop {
+            if self.tun_input.len() >= self.max_tun_input {
+                //
+                // Not read to WouldBlock, no new event would come. Adding
+                // READABLE back once drained re-arms it
+                //
+                debug!("tunnel backlog at {} bytes, pausing the reads", self.tun_input.len());
+                client.backlog_paused = true;
+                self.update_interest(src)?;
+                break;
+            }
+
+            match read_spare(&mut client.stream, &mut self.tun_input, self.buffer_size)? {
+                ReadOutcome::Data(_) => {}
+                ReadOutcome::WouldBlock => break,
+                ReadOutcome::Eof => return Err(Error::Eof),
+            }
+        }
+
+        let backlog = self.tun_input.len();
+
+        if !self.backlog_warned && backlog > self.max_tun_input / 2 {
+            warn!("tunnel backlog at {backlog} bytes");
+            self.backlog_warned = true;
+        }
+
+        Ok(())
+    }
+
+    //
+    // read_packet() is done for now, the tunnel reads resume once the backlog
+    // went under the high watermark
+    //
+    fn drained(&mut self) -> Result<()> {
+        if self.tun_input.len() > self.max_tun_input / 2 {
+            return Ok(());
+        }
+
+        self.backlog_warned = false;
+
+        let tunnel = match self.tunnel {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        if let Some(client) = self.map.get_mut(&tunnel)
+            && client.backlog_paused
+        {
+            debug!("tunnel backlog at {} bytes, resuming the reads", self.tun_input.len());
+            client.backlog_paused = false;
+            self.update_interest(tunnel)?;
+        }
+
+        Ok(())
+    }
+
+    //
+    // For the error and hang up events. A paused stream isn't read and one
+    // with nothing buffered isn't written to, a reset would go unnoticed
+    // until the next timeout. A pending error closes the stream and the peer
+    // is told, returns true then
+    //
+    pub fn socket_error(&mut self, addr: Address) -> Result<bool> {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+
+        let err = match client.stream.take_error() {
+            Ok(Some(e)) => e,
+            Ok(None) => return Ok(false),
+            Err(e) => e,
+        };
+
+        let e = match client.is_connected {
+            true => Error::from(err),
+            false => Error::ConnectFailed { kind: err.kind() },
+        };
+
+        warn!("token={addr} socket error ({e})");
+
+        match client.draining.is_some() {
+            // the peer already forgot about it
+            true => {
+                self.close(addr, CloseReason::LocalError);
+            }
+            false => {
+                self.close_notify(addr, CloseReason::LocalError, (&e).into())?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    //
+    // A paused or read closed stream reads as WouldBlock, its interest says
+    // when to come back. Eof leaves the write side alone, the caller tells
+    // the peer with a CloseWrite
+    //
+    pub fn read(&mut self, addr: Address, buffer: &mut [u8]) -> Result<ReadOutcome> {
+        let client = match self.map.get_mut(&addr) {
+            Some(v) => v,
+            None => return Err(Error::ClientNotFound),
+        };
+
+        if client.read_closed || client.paused || client.tunnel_paused || client.draining.is_some() {
+            return Ok(ReadOutcome::WouldBlock);
+        }
+
+        let outcome = match read_outcome(&mut client.stream, buffer) {
+            Ok(v) => v,
             Err(e) => {
-                error!("read failure ({e})");
-                self.remove(addr);
-                return Err(e.into());
+                error!("token={addr} read failure ({e})");
+                self.close_notify(addr, CloseReason::LocalError, (&e).into())?;
+                return Err(e);
             }
         };
 
-        Ok(read_len)
+        match outcome {
+            ReadOutcome::Data(_) => client.last_activity = Instant::now(),
+            ReadOutcome::WouldBlock => {}
+            ReadOutcome::Eof => {
+                debug!("received EOF for token={addr}");
+                client.read_closed = true;
+                self.update_interest(addr)?;
+                self.try_finish(addr);
+            }
+        }
+
+        Ok(outcome)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    //
+    // Two TokenStreams whose tunnels are both ends of a loopback connection
+    //
+    fn tunnel_pair(tunnel: Address) -> (TokenStreams, TokenStreams) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+
+        let mut tx = TokenStreams::new();
+        let mut rx = TokenStreams::new();
+
+        tx.add_tunnel(tunnel, ClientStream::new(TcpStream::from_std(a)).unwrap())
+            .unwrap();
+        rx.add_tunnel(tunnel, ClientStream::new(TcpStream::from_std(b)).unwrap())
+            .unwrap();
+
+        (tx, rx)
+    }
+
+    #[test]
+    fn oversized_payload_split() {
+        const TUNNEL: Address = 1;
+
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+
+        let data: Vec<u8> = (0..200 * 1024).map(|i| i as u8).collect();
+        tx.write_packet(TUNNEL, 42, &data).unwrap();
+
+        let mut received = Vec::new();
+        let start = Instant::now();
+
+        while received.len() < data.len() && start.elapsed() < Duration::from_secs(10) {
+            tx.flush(TUNNEL).unwrap();
+            rx.flush_read(TUNNEL).unwrap();
+
+            loop {
+                match rx.read_packet() {
+                    Ok((p, payload)) => {
+                        assert_eq!(p.addr, 42);
+                        assert!(payload.len() <= DEF_MTU);
+                        received.extend_from_slice(&payload);
+                    }
+                    Err(Error::Empty) | Err(Error::NotEnoughData) => break,
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        }
+
+        assert!(received == data);
+    }
+
+    //
+    // Local socket -> tunnel -> read_packet() with reads smaller and larger
+    // than a frame
+    //
+    #[test]
+    fn buffer_sizes() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let data: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+
+        for size in [1024, 64 * 1024] {
+            let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+            tx.set_buffer_size(size);
+            rx.set_buffer_size(size);
+
+            let (local, mut peer) = local_pair();
+            tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+            let sent = data.clone();
+            let writer = std::thread::spawn(move || peer.write_all(&sent).unwrap());
+
+            let mut buf = vec![0; tx.buffer_size()];
+            let mut received = Vec::new();
+            let start = Instant::now();
+
+            while received.len() < data.len() && start.elapsed() < Duration::from_secs(10) {
+                while let ReadOutcome::Data(v) = tx.read(STREAM, &mut buf).unwrap() {
+                    assert!(v <= size);
+                    tx.write_packet(TUNNEL, STREAM, &buf[..v]).unwrap();
+                }
+
+                tx.flush(TUNNEL).unwrap();
+                rx.flush_read(TUNNEL).unwrap();
+
+                loop {
+                    match rx.read_packet() {
+                        Ok((p, payload)) => {
+                            assert_eq!(p.addr, STREAM);
+                            assert!(payload.len() <= DEF_MTU);
+                            received.extend_from_slice(&payload);
+                        }
+                        Err(Error::Empty) | Err(Error::NotEnoughData) => break,
+                        Err(e) => panic!("{e}"),
+                    }
+                }
+            }
+
+            writer.join().unwrap();
+            assert!(received == data, "{size}");
+        }
+    }
+
+    #[test]
+    fn garbage_input() {
+        let mut seed: u32 = 0x1234_5678;
+
+        //
+        // valid version byte most of the time so it goes past the header
+        //
+        for _ in 0..2000 {
+            let mut streams = TokenStreams::new();
+
+            let len = (seed % 64) as usize;
+            let mut input = Vec::with_capacity(len);
+            for _ in 0..len {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                input.push((seed >> 16) as u8);
+            }
+            if !input.is_empty() && !seed.is_multiple_of(4) {
+                input[0] = crate::packet::PACKET_VERSION;
+            }
+
+            streams.feed_tunnel_input(&input);
+
+            while let Ok((p, payload)) = streams.read_packet() {
+                assert_eq!(payload.len(), p.data_len as usize);
+            }
+        }
+    }
+
+    type Frame = (Address, PacketMessage, Vec<u8>);
+
+    fn wire(frames: &[Frame]) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for (addr, msg, data) in frames {
+            Packet::new(*addr, *msg, data.len() as u16).encode(&mut out).unwrap();
+            out.extend_from_slice(data);
+        }
+
+        out
+    }
+
+    //
+    // Feeds the chunks one after the other, read_packet() until it wants more
+    // after each of them
+    //
+    fn reassemble<'a>(chunks: impl Iterator<Item = &'a [u8]>) -> Vec<Frame> {
+        let mut streams = TokenStreams::new();
+        let mut out = Vec::new();
+
+        for chunk in chunks {
+            streams.feed_tunnel_input(chunk);
+
+            loop {
+                match streams.read_packet() {
+                    Ok((p, data)) => out.push((p.addr, p.msg, data.to_vec())),
+                    Err(Error::Empty) | Err(Error::NotEnoughData) => break,
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        }
+
+        assert_eq!(streams.tun_backlog(), 0);
+        out
+    }
+
+    #[test]
+    fn split_frames() {
+        let frames: Vec<Frame> = vec![
+            (5, PacketMessage::Data, (0..300).map(|i| i as u8).collect()),
+            (CONTROL_ADDRESS, PacketMessage::Probe, vec![]),
+            (6, PacketMessage::Connect, vec![1, 2, 3]),
+            (5, PacketMessage::Data, vec![0x41]),
+            (7, PacketMessage::Datagram, vec![0x42; 17]),
+            (CONTROL_ADDRESS, PacketMessage::Hello, vec![0x43; 5]),
+            (5, PacketMessage::Data, vec![0x44; u16::MAX as usize]),
+        ];
+        let input = wire(&frames);
+
+        // everything at once
+        assert_eq!(reassemble(std::iter::once(&input[..])), frames);
+
+        // a byte at a time, headers included
+        assert_eq!(reassemble(input.chunks(1)), frames);
+
+        // every split point of the small frames, headers cut anywhere and
+        // payloads ending with the start of the next header
+        let small = wire(&frames[..6]);
+
+        for at in 0..=small.len() {
+            let (a, b) = small.split_at(at);
+            assert_eq!(reassemble([a, b].into_iter()), frames[..6], "split at {at}");
+        }
+
+        // odd sized segments
+        for size in [2, 3, HEADER_SIZE - 1, HEADER_SIZE + 1, 1000] {
+            assert_eq!(reassemble(input.chunks(size)), frames, "{size}");
+        }
+    }
+
+    fn local_pair() -> (TcpStream, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+
+        a.set_nonblocking(true).unwrap();
+        (TcpStream::from_std(a), b)
+    }
+
+    #[test]
+    fn tunnel_congestion_pauses_one_direction() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        // rx never reads, the tunnel backs up
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+
+        let (local, _peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        let chunk = vec![0x41; DEF_MTU];
+        let mut pushed = 0;
+
+        //
+        // credit every write back so the window never kicks in
+        //
+        while !tx.map[&STREAM].tunnel_paused && pushed < 256 * 1024 * 1024 {
+            tx.write_packet(TUNNEL, STREAM, &chunk).unwrap();
+            tx.window_update(STREAM, chunk.len()).unwrap();
+            pushed += chunk.len();
+        }
+
+        let stats = &tx.buffer_stats()[0];
+        assert!(stats.paused_tunnel);
+        assert!(!stats.paused_window);
+        assert!(stats.to_tunnel > TUNNEL_HIGH_WATER);
+
+        let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        assert_eq!(tx.read(STREAM, &mut buf).unwrap(), ReadOutcome::WouldBlock);
+        assert_eq!(tx.map[&STREAM].interest, Some(Interest::WRITABLE));
+
+        // the other direction still flows
+        tx.write(STREAM, b"toward local").unwrap();
+        assert_eq!(tx.buffer_stats()[0].to_local, 0);
+
+        //
+        // draining the tunnel resumes the reads
+        //
+        let start = Instant::now();
+
+        while tx.map[&STREAM].tunnel_paused && start.elapsed() < Duration::from_secs(10) {
+            let _ = rx.flush_read(TUNNEL);
+            while rx.read_packet().is_ok() {}
+            tx.flush(TUNNEL).unwrap();
+        }
+
+        let stats = &tx.buffer_stats()[0];
+        assert!(!stats.paused_tunnel);
+        assert!(stats.to_tunnel <= TUNNEL_LOW_WATER);
+    }
+
+    #[test]
+    fn local_congestion_pauses_tunnel() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+        const MAX: usize = 256 * 1024;
+
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+        rx.set_backpressure(true);
+        rx.set_max_buffered(MAX);
+
+        // not read for now
+        let (local, mut peer) = local_pair();
+        rx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        let chunk = vec![0x41; DEF_MTU];
+        let mut written = 0;
+
+        while !rx.is_congested() && written < 64 * 1024 * 1024 {
+            rx.write(STREAM, &chunk).unwrap();
+            written += chunk.len();
+        }
+
+        assert!(rx.is_congested());
+        assert!(rx.buffered_len(STREAM).unwrap() <= MAX);
+        assert!(!rx.map[&TUNNEL].interest.is_some_and(|i| i.is_readable()));
+
+        // left in the socket
+        tx.write_message(TUNNEL, STREAM, PacketMessage::Disconnected).unwrap();
+        tx.flush(TUNNEL).unwrap();
+        sleep(Duration::from_millis(50));
+        rx.flush_read(TUNNEL).unwrap();
+        assert_eq!(rx.tun_backlog(), 0);
+
+        //
+        // the peer catches up, the tunnel reads resume
+        //
+        let start = Instant::now();
+        let mut buf = vec![0; 64 * 1024];
+
+        while rx.is_congested() && start.elapsed() < Duration::from_secs(10) {
+            let _ = peer.read(&mut buf).unwrap();
+            rx.flush(STREAM).unwrap();
+        }
+
+        assert!(!rx.is_congested());
+        assert!(rx.take_resumed());
+        assert!(!rx.take_resumed());
+        assert!(rx.map[&TUNNEL].interest.is_some_and(|i| i.is_readable()));
+
+        rx.flush_read(TUNNEL).unwrap();
+        assert!(rx.tun_backlog() > 0);
+    }
+
+    #[test]
+    fn stream_counters() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+
+        let (local, _peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        // two frames to the tunnel, one from it
+        tx.write_packet(TUNNEL, STREAM, &vec![0; DEF_MTU + 1]).unwrap();
+        tx.write(STREAM, b"hello").unwrap();
+
+        let stats = tx.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            stats[0].counters,
+            StreamCounters {
+                bytes_in: DEF_MTU as u64 + 1,
+                bytes_out: 5,
+                frames_in: 1,
+                frames_out: 2,
+            }
+        );
+
+        // the totals outlive the stream
+        tx.remove(STREAM);
+        assert!(tx.stats().is_empty());
+
+        let totals = tx.totals();
+        assert_eq!(totals.streams, 0);
+        assert_eq!(totals.closed, 1);
+        assert_eq!(totals.counters.bytes_out, 5);
+    }
+
+    #[test]
+    fn stalled_sink_bounded() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+        const MAX: usize = 256 * 1024;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+        tx.set_max_buffered(MAX);
+
+        // the peer never reads
+        let (local, _peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        let chunk = vec![0x41; DEF_MTU];
+        let mut err = None;
+
+        // way more than the socket buffers can take
+        for _ in 0..4096 {
+            if let Err(e) = tx.write(STREAM, &chunk) {
+                err = Some(e);
+                break;
+            }
+            assert!(tx.buffered_len(STREAM).unwrap() <= MAX);
+        }
+
+        assert!(matches!(err, Some(Error::BufferFull { addr: STREAM, .. })));
+        assert_eq!(tx.buffered_len(STREAM), None);
+        assert_eq!(PacketMessage::from(err.unwrap()), PacketMessage::Disconnected);
+    }
+
+    //
+    // Readable events with nothing behind them put nothing on the wire
+    //
+    #[test]
+    fn no_empty_data_frames() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let mut buf = [0; 64];
+
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+        let (local, _peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        for _ in 0..100 {
+            match tx.read(STREAM, &mut buf).unwrap() {
+                ReadOutcome::Data(v) => tx.write_packet(TUNNEL, STREAM, &buf[0..v]).unwrap(),
+                ReadOutcome::WouldBlock => {}
+                ReadOutcome::Eof => panic!("unexpected EOF"),
+            }
+        }
+
+        // even asked to
+        tx.write_packet(TUNNEL, STREAM, &[]).unwrap();
+
+        tx.flush(TUNNEL).unwrap();
+        sleep(Duration::from_millis(50));
+        rx.flush_read(TUNNEL).unwrap();
+
+        assert!(matches!(rx.read_packet(), Err(Error::Empty)));
+    }
+
+    #[test]
+    fn close_reports() {
+        use std::os::fd::AsRawFd;
+
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+
+        // clean, everything made it to the peer
+        let (local, mut peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+        tx.write(STREAM, b"hello").unwrap();
+
+        let report = tx.close(STREAM, CloseReason::Finished).unwrap();
+        assert_eq!(report.addr, STREAM);
+        assert_eq!(report.reason, CloseReason::Finished);
+        assert_eq!((report.bytes_in, report.bytes_out, report.unflushed), (0, 5, 0));
+        assert!(tx.close(STREAM, CloseReason::Finished).is_none());
+
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"hello");
+
+        // error, the peer never read what was buffered for it
+        let (local, peer) = local_pair();
+        set_buffer_size(local.as_raw_fd(), libc::SO_SNDBUF, 4096);
+        set_buffer_size(peer.as_raw_fd(), libc::SO_RCVBUF, 4096);
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+        tx.write(STREAM, &vec![0x41; 256 * 1024]).unwrap();
+
+        let buffered = tx.buffered_len(STREAM).unwrap();
+        assert!(buffered > 0);
+
+        let report = tx.close(STREAM, CloseReason::LocalError).unwrap();
+        assert_eq!(report.reason, CloseReason::LocalError);
+        assert!(report.unflushed > 0 && report.unflushed <= buffered);
+        assert_eq!(report.bytes_out, 256 * 1024);
+
+        // the tunnel went away, only the tunnel is left
+        let (a, _peer_a) = local_pair();
+        let (b, _peer_b) = local_pair();
+        tx.add(6, ClientStream::new(a).unwrap()).unwrap();
+        tx.add(7, ClientStream::new(b).unwrap()).unwrap();
+
+        let reports = tx.close_all(CloseReason::TunnelLost);
+        assert_eq!(reports.iter().map(|r| r.addr).collect::<Vec<_>>(), vec![6, 7]);
+        assert!(reports.iter().all(|r| r.reason == CloseReason::TunnelLost));
+        assert!(tx.stats().is_empty());
+        assert_eq!(tx.totals().closed, 4);
+
+        assert_eq!(
+            reports[0].to_string(),
+            format!(
+                "token=6 reason=tunnel-lost duration={}ms bytes_in=0 bytes_out=0 unflushed=0",
+                reports[0].duration.as_millis()
+            )
+        );
+    }
+
+    //
+    // Nothing consumes what the tunnel brings in, the reads stop at the cap
+    // and resume once read_packet() drained the backlog
+    //
+    #[test]
+    fn tunnel_backlog_capped() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+        const MAX: usize = 128 * 1024;
+        const TOTAL: usize = 1024 * 1024;
+
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+        rx.set_max_tun_input(MAX);
+
+        tx.write_packet(TUNNEL, STREAM, &vec![0x41; TOTAL]).unwrap();
+
+        let readable = |rx: &TokenStreams| rx.map[&TUNNEL].interest.is_some_and(|i| i.is_readable());
+
+        let start = Instant::now();
+        while rx.tun_backlog() < MAX && start.elapsed() < Duration::from_secs(10) {
+            tx.flush(TUNNEL).unwrap();
+            rx.flush_read(TUNNEL).unwrap();
+        }
+
+        let backlog = rx.tun_backlog();
+        assert!((MAX..MAX + BUFFER_SIZE).contains(&backlog), "{backlog}");
+        assert!(!readable(&rx));
+
+        // paused, the socket isn't read anymore
+        tx.flush(TUNNEL).unwrap();
+        rx.flush_read(TUNNEL).unwrap();
+        assert_eq!(rx.tun_backlog(), backlog);
+
+        let mut received = 0;
+        let start = Instant::now();
+
+        while received < TOTAL && start.elapsed() < Duration::from_secs(10) {
+            loop {
+                match rx.read_packet() {
+                    Ok((_, payload)) => received += payload.len(),
+                    Err(Error::Empty) | Err(Error::NotEnoughData) => break,
+                    Err(e) => panic!("{e}"),
+                }
+            }
+
+            assert!(readable(&rx));
+            assert!(rx.tun_backlog() <= MAX / 2);
+
+            tx.flush(TUNNEL).unwrap();
+            rx.flush_read(TUNNEL).unwrap();
+            assert!(rx.tun_backlog() < MAX + BUFFER_SIZE);
+        }
+
+        assert_eq!(received, TOTAL);
+    }
+
+    #[test]
+    fn connect_failures() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+
+        // nothing listens there anymore
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        tx.add(STREAM, ClientStream::new(TcpStream::connect(closed).unwrap()).unwrap())
+            .unwrap();
+
+        let start = Instant::now();
+        let err = loop {
+            match tx.flush(STREAM) {
+                Ok(()) if start.elapsed() < Duration::from_secs(5) => sleep(Duration::from_millis(10)),
+                Ok(()) => panic!("still connecting"),
+                Err(e) => break e,
+            }
+        };
+
+        assert!(
+            matches!(
+                err,
+                Error::ConnectFailed {
+                    kind: ErrorKind::ConnectionRefused
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(PacketMessage::from(err), PacketMessage::ConnectionRefused);
+        assert!(!tx.contains_token(STREAM));
+
+        // blackholed, the connect never completes
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        tx.add(STREAM + 1, ClientStream::new(stream).unwrap()).unwrap();
+
+        assert!(tx.prune_connecting(CONNECT_TIMEOUT).unwrap().is_empty());
+        assert_eq!(tx.prune_connecting(Duration::ZERO).unwrap(), vec![STREAM + 1]);
+        assert!(!tx.contains_token(STREAM + 1));
+        assert!(tx.contains_token(TUNNEL));
+    }
+
+    //
+    // Whoever closes first tells the peer, the others find nothing to do
+    //
+    #[test]
+    fn double_close() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+
+        let (local, _peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        let msg = PacketMessage::Disconnected;
+        assert!(tx.close_notify(STREAM, CloseReason::Stalled, msg).unwrap().is_some());
+        assert!(tx.close_notify(STREAM, CloseReason::LocalError, msg).unwrap().is_none());
+        assert!(tx.close_notify(42, CloseReason::LocalError, msg).unwrap().is_none());
+        assert!(tx.write(STREAM, b"late").is_err());
+        tx.flush(TUNNEL).unwrap();
+
+        let start = Instant::now();
+
+        while start.elapsed() < Duration::from_millis(200) {
+            rx.flush_read(TUNNEL).unwrap();
+            while rx.read_packet().is_ok() {}
+            sleep(Duration::from_millis(10));
+        }
+
+        let control_in = &rx.tunnel_stats().control_in;
+        assert_eq!(control_in.get(&PacketMessage::Disconnected), Some(&1));
+        assert_eq!(control_in.len(), 1);
+    }
+
+    //
+    // The peer sends a large response and disconnects right after, the local
+    // socket is slower than both
+    //
+    #[test]
+    fn drained_before_close() {
+        use std::os::fd::AsRawFd;
+
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+
+        let (local, mut peer) = local_pair();
+        set_buffer_size(local.as_raw_fd(), libc::SO_SNDBUF, 16 * 1024);
+        set_buffer_size(peer.as_raw_fd(), libc::SO_RCVBUF, 16 * 1024);
+        rx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        tx.write_packet(TUNNEL, STREAM, &data).unwrap();
+        tx.write_message(TUNNEL, STREAM, PacketMessage::Disconnected).unwrap();
+
+        let start = Instant::now();
+
+        while rx.tunnel_stats().control_in.is_empty() && start.elapsed() < Duration::from_secs(10) {
+            tx.flush(TUNNEL).unwrap();
+            rx.flush_read(TUNNEL).unwrap();
+
+            while let Ok((_, payload)) = rx.read_packet() {
+                rx.write(STREAM, &payload).unwrap();
+            }
+        }
+
+        // disconnected, still there for what the socket didn't take yet
+        assert!(rx.buffered_len(STREAM).unwrap() > 0);
+        assert!(matches!(rx.write(STREAM, b"late"), Err(Error::ClientNotFound)));
+        assert!(rx.prune_draining(DRAIN_TIMEOUT).is_empty());
+
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let start = Instant::now();
+
+        while rx.contains_token(STREAM) && start.elapsed() < Duration::from_secs(10) {
+            rx.flush(STREAM).unwrap();
+            sleep(Duration::from_millis(1));
+        }
+
+        assert!(!rx.contains_token(STREAM));
+        assert!(reader.join().unwrap() == data);
+    }
+
+    #[test]
+    fn snapshot() {
+        const TUNNEL: Address = 1;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+
+        let (a, _peer_a) = local_pair();
+        let (b, _peer_b) = local_pair();
+        tx.add(6, ClientStream::new(a).unwrap()).unwrap();
+        tx.add(5, ClientStream::new(b).unwrap()).unwrap();
+        tx.write(6, b"hello").unwrap();
+
+        let snapshot = tx.snapshot();
+        assert_eq!(snapshot.iter().map(|i| i.addr).collect::<Vec<_>>(), vec![5, 6]);
+        assert_eq!((snapshot[1].bytes_out, snapshot[1].draining), (5, false));
+        assert_eq!(
+            snapshot[0].to_string(),
+            "token=5 state=connecting buffered=0 bytes_in=0 bytes_out=0 idle=0s"
+        );
+
+        // only looks
+        assert_eq!(tx.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn reset_noticed() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+
+        let (local, peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+        tx.flush(STREAM).unwrap();
+
+        assert!(!tx.socket_error(STREAM).unwrap());
+
+        // what the error event says, without reading nor writing
+        crate::test_util::reset(peer);
+        sleep(Duration::from_millis(50));
+
+        assert!(tx.socket_error(STREAM).unwrap());
+        assert!(!tx.contains_token(STREAM));
+        assert!(!tx.socket_error(STREAM).unwrap());
+        tx.flush(TUNNEL).unwrap();
+
+        let start = Instant::now();
+        while rx.tunnel_stats().control_in.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            rx.flush_read(TUNNEL).unwrap();
+            while rx.read_packet().is_ok() {}
+        }
+
+        assert_eq!(rx.tunnel_stats().control_in.get(&PacketMessage::IoFailure), Some(&1));
+    }
+
+    #[test]
+    fn idle_reaped() {
+        const TUNNEL: Address = 1;
+        const IDLE: Address = 5;
+        const ACTIVE: Address = 6;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+
+        let (a, _peer_a) = local_pair();
+        let (b, _peer_b) = local_pair();
+        tx.add(IDLE, ClientStream::new(a).unwrap()).unwrap();
+        tx.add(ACTIVE, ClientStream::new(b).unwrap()).unwrap();
+
+        sleep(Duration::from_millis(100));
+        tx.write(ACTIVE, b"still here").unwrap();
+
+        assert_eq!(tx.prune_idle(Duration::from_millis(50)).unwrap(), vec![IDLE]);
+        assert!(tx.contains_token(ACTIVE));
+        assert!(tx.contains_token(TUNNEL));
+    }
+
+    fn get_sockopt(fd: std::os::fd::RawFd, level: libc::c_int, opt: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                opt,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        value
+    }
+
+    #[test]
+    fn socket_options() {
+        use std::os::fd::AsRawFd;
+
+        const STREAM: Address = 5;
+
+        let mut tx = TokenStreams::new();
+
+        // off by default
+        let (local, _peer) = local_pair();
+        let fd = local.as_raw_fd();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+        assert_eq!(get_sockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+
+        tx.set_keepalive(Some(Duration::from_secs(45)));
+
+        let (local, _peer) = local_pair();
+        let fd = local.as_raw_fd();
+        tx.add(STREAM + 1, ClientStream::new(local).unwrap()).unwrap();
+
+        assert_ne!(get_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY), 0);
+        assert_ne!(get_sockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+        if let Some(opt) = TCP_KEEPIDLE {
+            assert_eq!(get_sockopt(fd, libc::IPPROTO_TCP, opt), 45);
+        }
+    }
+
+    //
+    // Loopback bulk transfer through the tunnel read path, flush_read() then
+    // read_packet() until the payload is out. Numbers only, run with
+    // cargo test --release -- --ignored read_throughput --nocapture
+    //
+    #[test]
+    #[ignore]
+    fn read_throughput() {
+        const TUNNEL: Address = 1;
+        const TOTAL: usize = 1024 * 1024 * 1024;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+
+        let writer = std::thread::spawn(move || {
+            let mut tx = TokenStreams::new();
+            tx.add_tunnel(TUNNEL, ClientStream::new(TcpStream::from_std(a)).unwrap())
+                .unwrap();
+
+            let chunk = vec![0x41; 4 * WINDOW_SIZE];
+            let mut sent = 0;
+
+            while sent < TOTAL {
+                tx.write_packet(TUNNEL, 5, &chunk).unwrap();
+                sent += chunk.len();
+
+                while tx.buffered_len(TUNNEL).unwrap() > 0 {
+                    tx.flush(TUNNEL).unwrap();
+                    std::thread::yield_now();
+                }
+            }
+            tx
+        });
+
+        // the reader sleeps in poll(), its CPU time is the read path's
+        let mut poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(8);
+        let mut rx = TokenStreams::new();
+        rx.set_registry(poll.registry().try_clone().unwrap());
+        rx.add_tunnel(TUNNEL, ClientStream::new(TcpStream::from_std(b)).unwrap())
+            .unwrap();
+
+        let cpu = || {
+            let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) }, 0);
+            let tv = |t: libc::timeval| Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64);
+            tv(usage.ru_utime) + tv(usage.ru_stime)
+        };
+
+        let mut received = 0;
+        let start = Instant::now();
+        let cpu_start = cpu();
+
+        while received < TOTAL {
+            poll.poll(&mut events, None).unwrap();
+            rx.flush_read(TUNNEL).unwrap();
+
+            while let Ok((_, payload)) = rx.read_packet() {
+                received += payload.len();
+            }
+        }
+
+        let elapsed = start.elapsed();
+        let busy = cpu() - cpu_start;
+        drop(writer.join().unwrap());
+
+        println!(
+            "read path: {:.0} MB/s, reader CPU {}ms per GB",
+            TOTAL as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0),
+            busy.as_millis()
+        );
+    }
+
+    //
+    // Loopback throughput of the vectored header + payload write against
+    // copying both into `buffered` first. Numbers only, run with
+    // cargo test --release -- --ignored write_throughput --nocapture
+    //
+    #[test]
+    #[ignore]
+    fn write_throughput() {
+        const TOTAL: usize = 1024 * 1024 * 1024;
+        const CHUNK: usize = 16 * 1024;
+
+        let run = |vectored: bool| {
+            let (local, mut peer) = local_pair();
+
+            let reader = std::thread::spawn(move || {
+                let mut buf = vec![0; 256 * 1024];
+                let mut total = 0;
+                while total < TOTAL + (TOTAL / CHUNK) * HEADER_SIZE {
+                    total += peer.read(&mut buf).unwrap();
+                }
+            });
+
+            let mut client = ClientStream::new(local).unwrap();
+            client.is_connected = true;
+
+            let chunk = vec![0x41; CHUNK];
+            let p = Packet::new_data(5, CHUNK as u16);
+            let mut hdr = [0; HEADER_SIZE];
+            p.encode_slice(&mut hdr).unwrap();
+
+            let start = Instant::now();
+
+            for _ in 0..TOTAL / CHUNK {
+                match vectored {
+                    true => {
+                        client.write_frame(&p, &chunk).unwrap();
+                    }
+                    false => {
+                        client.push_data(&hdr);
+                        client.push_data(&chunk);
+                        client.flush_buffer().unwrap();
+                    }
+                }
+
+                while client.buffered.len() > WINDOW_SIZE {
+                    client.flush_buffer().unwrap();
+                    std::thread::yield_now();
+                }
+            }
+
+            while !client.buffered.is_empty() {
+                client.flush_buffer().unwrap();
+            }
+
+            reader.join().unwrap();
+            TOTAL as f64 / start.elapsed().as_secs_f64() / (1024.0 * 1024.0)
+        };
+
+        let copied = run(false);
+        let vectored = run(true);
+
+        println!("copied: {copied:.0} MB/s vectored: {vectored:.0} MB/s");
+    }
+
+    fn set_buffer_size(fd: std::os::fd::RawFd, opt: libc::c_int, size: libc::c_int) {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                opt,
+                &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn flush_until_would_block() {
+        use std::os::fd::AsRawFd;
+
+        let (local, mut peer) = local_pair();
+        set_buffer_size(local.as_raw_fd(), libc::SO_SNDBUF, 4096);
+        set_buffer_size(peer.as_raw_fd(), libc::SO_RCVBUF, 4096);
+
+        let mut client = ClientStream::new(local).unwrap();
+        client.is_connected = true;
+
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        client.push_data(&data);
+
+        let mut received = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+
+        peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        while received.len() < data.len() {
+            let before = client.buffered.len();
+            let written = client.flush_buffer().unwrap();
+            assert_eq!(before - client.buffered.len(), written);
+
+            //
+            // stopped because the socket is full, not after the first
+            // partial write
+            //
+            if !client.buffered.is_empty() {
+                let err = client.stream.write(&[0]).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::WouldBlock);
+            }
+
+            match peer.read(&mut buf) {
+                Ok(v) => received.extend_from_slice(&buf[0..v]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{e}"),
+            }
+        }
+
+        assert!(received == data);
+    }
+
+    #[test]
+    fn writable_only_when_pending() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+        let (local, mut peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        // connected and idle, only the reads matter
+        tx.flush(STREAM).unwrap();
+        assert_eq!(tx.map[&STREAM].interest, Some(Interest::READABLE));
+
+        let chunk = vec![0x41; DEF_MTU];
+        while 0 == tx.buffered_len(STREAM).unwrap() {
+            tx.write(STREAM, &chunk).unwrap();
+        }
+        assert_eq!(tx.map[&STREAM].interest, Some(Interest::READABLE | Interest::WRITABLE));
+
+        let mut buf = vec![0; 1024 * 1024];
+        while tx.buffered_len(STREAM).unwrap() > 0 {
+            let _ = peer.read(&mut buf).unwrap();
+            tx.flush(STREAM).unwrap();
+        }
+        assert_eq!(tx.map[&STREAM].interest, Some(Interest::READABLE));
+    }
+
+    //
+    // Replays canned read results
+    //
+    struct Scripted(VecDeque<std::io::Result<usize>>);
+
+    impl Read for Scripted {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.pop_front().unwrap()
+        }
+    }
+
+    #[test]
+    fn read_outcomes() {
+        use std::io;
+
+        let table: Vec<(Vec<io::Result<usize>>, Option<ReadOutcome>)> = vec![
+            (vec![Ok(5)], Some(ReadOutcome::Data(5))),
+            (vec![Ok(0)], Some(ReadOutcome::Eof)),
+            (vec![Err(ErrorKind::WouldBlock.into())], Some(ReadOutcome::WouldBlock)),
+            (
+                vec![Err(ErrorKind::Interrupted.into()), Ok(3)],
+                Some(ReadOutcome::Data(3)),
+            ),
+            (vec![Err(ErrorKind::Interrupted.into()), Ok(0)], Some(ReadOutcome::Eof)),
+            (vec![Err(ErrorKind::ConnectionReset.into())], None),
+        ];
+
+        let mut buf = [0; 8];
+
+        for (script, expected) in table {
+            let mut r = Scripted(script.into());
+
+            match expected {
+                Some(v) => assert_eq!(read_outcome(&mut r, &mut buf).unwrap(), v),
+                None => assert!(read_outcome(&mut r, &mut buf).is_err()),
+            }
+        }
+
+        // 0 would mean both EOF and nothing read
+        assert!(read_outcome(&mut Scripted(VecDeque::new()), &mut []).is_err());
+    }
+
+    //
+    // Same socket states seen through each call site
+    //
+    #[test]
+    fn read_call_sites() {
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let mut buf = [0; 64];
+
+        // stream: nothing, data, then EOF
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+        let (local, mut peer) = local_pair();
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+
+        assert_eq!(tx.read(STREAM, &mut buf).unwrap(), ReadOutcome::WouldBlock);
+
+        peer.write_all(b"data").unwrap();
+        peer.shutdown(Shutdown::Write).unwrap();
+        sleep(Duration::from_millis(50));
+
+        assert_eq!(tx.read(STREAM, &mut buf).unwrap(), ReadOutcome::Data(4));
+        assert_eq!(tx.read(STREAM, &mut buf).unwrap(), ReadOutcome::Eof);
+        // read closed from then on
+        assert_eq!(tx.read(STREAM, &mut buf).unwrap(), ReadOutcome::WouldBlock);
+
+        // tunnel: nothing and data are fine, EOF ends the session
+        let (mut tx, mut rx) = tunnel_pair(TUNNEL);
+
+        tx.flush_read(TUNNEL).unwrap();
+
+        rx.write_packet(TUNNEL, STREAM, b"data").unwrap();
+        sleep(Duration::from_millis(50));
+        tx.flush_read(TUNNEL).unwrap();
+
+        drop(rx);
+        sleep(Duration::from_millis(50));
+        assert!(matches!(tx.flush_read(TUNNEL), Err(Error::Eof)));
+    }
+
+    //
+    // A slow reader gets everything before the teardown closes it
+    //
+    #[test]
+    fn teardown_flush() {
+        use std::os::fd::AsRawFd;
+
+        const TUNNEL: Address = 1;
+        const STREAM: Address = 5;
+
+        let (mut tx, _rx) = tunnel_pair(TUNNEL);
+
+        let (local, mut peer) = local_pair();
+        set_buffer_size(local.as_raw_fd(), libc::SO_SNDBUF, 4096);
+        tx.add(STREAM, ClientStream::new(local).unwrap()).unwrap();
+        tx.write(STREAM, &vec![0x41; 256 * 1024]).unwrap();
+        assert!(tx.buffered_len(STREAM).unwrap() > 0);
+
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).unwrap();
+            received.len()
+        });
+
+        assert_eq!(tx.flush_all(Duration::from_secs(10)), 0);
+
+        let report = tx.close_all(CloseReason::TunnelLost);
+        assert_eq!(report[0].unflushed, 0);
+        assert_eq!(reader.join().unwrap(), 256 * 1024);
     }
 }
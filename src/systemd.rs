@@ -0,0 +1,179 @@
+//
+// Socket activation, systemd owns the listening sockets and hands them over
+// through LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES ( sd_listen_fds(3) ). A
+// restart of the service doesn't close the internet port. The sockets are
+// told apart by their FileDescriptorName=, "tunnel" or "internet"
+//
+use std::{
+    env,
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+};
+
+use log::{info, warn};
+
+use crate::error::Result;
+
+// the first inherited fd, the others follow
+pub const LISTEN_FDS_START: RawFd = 3;
+
+pub const TUNNEL_NAME: &str = "tunnel";
+pub const INTERNET_NAME: &str = "internet";
+
+#[derive(Debug, Default)]
+pub struct Activation {
+    // where the pvpn clients connect
+    pub tunnel: Option<OwnedFd>,
+    // one forward each, in the order systemd passed them
+    pub internet: Vec<OwnedFd>,
+}
+
+//
+// The (fd, name) pairs the variables describe, None when they're not there
+// or meant for another process. Without names every fd is "internet"
+//
+pub fn parse_env(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Option<Vec<(RawFd, String)>> {
+    if pid?.trim().parse::<u32>().ok()? != own_pid {
+        return None;
+    }
+
+    let count: RawFd = fds?.trim().parse().ok()?;
+
+    let mut names = names.unwrap_or_default().split(':');
+
+    let pairs = (0..count)
+        .map(|i| {
+            let name = match names.next() {
+                Some(v) if !v.is_empty() => v,
+                _ => INTERNET_NAME,
+            };
+            (LISTEN_FDS_START + i, name.to_string())
+        })
+        .collect();
+
+    Some(pairs)
+}
+
+impl Activation {
+    //
+    // Takes ownership of the fds. Unknown names are closed
+    //
+    pub fn from_fds(fds: Vec<(RawFd, String)>) -> Result<Self> {
+        let mut activation = Activation::default();
+
+        for (fd, name) in fds {
+            // SAFETY: inherited for this process and owned by nothing else
+            let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            // nothing of it should leak into what the process runs
+            if -1 == unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            match name.as_str() {
+                TUNNEL_NAME if activation.tunnel.is_none() => activation.tunnel = Some(owned),
+                INTERNET_NAME => activation.internet.push(owned),
+                _ => warn!("socket activation: ignoring fd={fd} name={name}"),
+            }
+        }
+
+        Ok(activation)
+    }
+}
+
+//
+// What systemd passed, empty when started some other way. The variables are
+// removed so nothing started from here picks them up
+//
+pub fn listen_fds() -> Result<Activation> {
+    let var = |k: &str| env::var(k).ok();
+
+    let fds = parse_env(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    );
+
+    // SAFETY: called from main() before any thread is started
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let fds = match fds {
+        Some(v) => v,
+        None => return Ok(Activation::default()),
+    };
+
+    info!("socket activation: {} fds", fds.len());
+
+    Activation::from_fds(fds)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env() {
+        let pairs = |v: Option<Vec<(RawFd, String)>>| {
+            v.map(|v| v.into_iter().map(|(fd, n)| format!("{fd}={n}")).collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            pairs(parse_env(Some("42"), Some("2"), Some("tunnel:internet"), 42)),
+            Some(vec!["3=tunnel".to_string(), "4=internet".to_string()])
+        );
+
+        // no names, the internet ports
+        assert_eq!(
+            pairs(parse_env(Some("42"), Some("2"), None, 42)),
+            Some(vec!["3=internet".to_string(), "4=internet".to_string()])
+        );
+
+        // for someone else, the parent's maybe
+        assert_eq!(parse_env(Some("41"), Some("2"), None, 42), None);
+        assert_eq!(parse_env(None, Some("2"), None, 42), None);
+        assert_eq!(parse_env(Some("42"), None, None, 42), None);
+        assert_eq!(parse_env(Some("42"), Some("x"), None, 42), None);
+    }
+
+    #[test]
+    fn handed_over() {
+        use std::os::fd::{AsRawFd, IntoRawFd};
+
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let internet = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let addr = internet.local_addr().unwrap();
+
+        let activation = Activation::from_fds(vec![
+            (tunnel.into_raw_fd(), "tunnel".to_string()),
+            (other.into_raw_fd(), "metrics".to_string()),
+            (internet.into_raw_fd(), "internet".to_string()),
+        ])
+        .unwrap();
+
+        assert!(activation.tunnel.is_some());
+        assert_eq!(activation.internet.len(), 1);
+
+        let fd = activation.internet[0].as_raw_fd();
+        assert_eq!(
+            unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC,
+            libc::FD_CLOEXEC
+        );
+
+        let internet = std::net::TcpListener::from(activation.internet.into_iter().next().unwrap());
+        assert_eq!(internet.local_addr().unwrap(), addr);
+    }
+}
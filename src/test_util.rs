@@ -0,0 +1,179 @@
+//
+// Helpers to run a server and a client in-process over loopback
+//
+use std::{
+    net::{TcpListener, TcpStream},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    tunnel_client::{ClientConfig, client_main},
+    tunnel_server::{Forward, ServerConfig, bind_forward, server_main},
+};
+
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+pub struct Tunnel {
+    // internet address of the first forward
+    pub server: String,
+    // all of them, in forward order
+    pub servers: Vec<String>,
+    // where the client connects
+    pub tunnel: String,
+}
+
+//
+// Starts a server and a client forwarding to `endpoint`, the threads are
+// never joined, they live as long as the test process
+//
+pub fn start_tunnel(endpoint: &str) -> Tunnel {
+    start_tunnel_with(endpoint, ServerConfig::default(), ClientConfig::default())
+}
+
+//
+// Same with custom options, the addresses are filled in
+//
+pub fn start_tunnel_with(endpoint: &str, server_config: ServerConfig, client_config: ClientConfig) -> Tunnel {
+    start_services(&[("test", endpoint)], server_config, client_config)
+}
+
+//
+// One forward per (label, endpoint), each with its own internet port. An
+// empty label is the port, like an unnamed --forward and the client's
+// --forward <port>=
+//
+pub fn start_services(
+    services: &[(&str, &str)],
+    mut server_config: ServerConfig,
+    mut client_config: ClientConfig,
+) -> Tunnel {
+    let mut forwards = Vec::new();
+    let mut servers = Vec::new();
+
+    for (label, endpoint) in services {
+        let socket = bind_forward("127.0.0.1", &[0], client_config.protocol).unwrap();
+        let addr = socket.local_addr().unwrap();
+        servers.push(addr.to_string());
+
+        let label = match label.is_empty() {
+            true => addr.port().to_string(),
+            false => label.to_string(),
+        };
+
+        forwards.push(Forward {
+            label: label.clone(),
+            socket,
+        });
+
+        client_config.endpoints.insert(label, endpoint.to_string());
+    }
+
+    let tunnel = format!("127.0.0.1:{}", free_port());
+
+    server_config.tunnel = tunnel.clone();
+
+    client_config.tunnel = tunnel.clone();
+    if client_config.reconnect_delay.is_zero() {
+        client_config.reconnect_delay = Duration::from_millis(50);
+    }
+
+    thread::spawn(move || server_main(&server_config, forwards));
+    thread::spawn(move || client_main(&client_config));
+
+    Tunnel {
+        server: servers[0].clone(),
+        servers,
+        tunnel,
+    }
+}
+
+//
+// The internet port only exists once the tunnel is up so keep on trying
+//
+pub fn connect_retry(addr: &str) -> TcpStream {
+    let start = Instant::now();
+
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(v) => {
+                v.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+                return v;
+            }
+            Err(e) => {
+                if start.elapsed() > TEST_TIMEOUT {
+                    panic!("unable to connect to {addr} ({e})");
+                }
+                sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+pub fn endpoint() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (listener, addr)
+}
+
+//
+// SO_LINGER 0 then close(), the peer gets a RST instead of a FIN
+//
+pub fn reset(stream: impl std::os::fd::AsRawFd) {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(ret, 0);
+}
+
+//
+// From another loopback address, 127.0.0.2 for instance
+//
+pub fn connect_from(src: std::net::Ipv4Addr, addr: &str) -> TcpStream {
+    use std::os::fd::FromRawFd;
+
+    let dst: std::net::SocketAddrV4 = addr.parse().unwrap();
+
+    let sin = |ip: std::net::Ipv4Addr, port: u16| libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: port.to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(ip).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+
+    let len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+    assert!(fd >= 0);
+
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let local = sin(src, 0);
+    let ret = unsafe { libc::bind(fd, &local as *const libc::sockaddr_in as *const libc::sockaddr, len) };
+    assert_eq!(ret, 0);
+
+    let remote = sin(*dst.ip(), dst.port());
+    let ret = unsafe { libc::connect(fd, &remote as *const libc::sockaddr_in as *const libc::sockaddr, len) };
+    assert_eq!(ret, 0);
+
+    stream.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+    stream
+}
@@ -0,0 +1,126 @@
+//
+// The poll loops' housekeeping. Each timer is due every interval, poll()
+// sleeps until the closest one so nothing waits on traffic to wake the loop
+// up and a busy loop doesn't starve them either
+//
+use std::time::{Duration, Instant};
+
+// what both loops run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Housekeeping {
+    // pruning, keepalives, the per listener bookkeeping
+    Tick,
+    // the resources line and the peer's Stats
+    Stats,
+}
+
+#[derive(Debug)]
+struct Timer<K> {
+    kind: K,
+    interval: Duration,
+    next: Instant,
+}
+
+#[derive(Debug)]
+pub struct Timers<K> {
+    timers: Vec<Timer<K>>,
+}
+
+impl<K> Default for Timers<K> {
+    fn default() -> Self {
+        Self { timers: Vec::new() }
+    }
+}
+
+impl<K: Copy + PartialEq> Timers<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //
+    // Due every interval from now on, replaces what kind had
+    //
+    pub fn every(&mut self, kind: K, interval: Duration, now: Instant) {
+        self.timers.retain(|t| t.kind != kind);
+        self.timers.push(Timer {
+            kind,
+            interval,
+            next: now + interval,
+        });
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().map(|t| t.next).min()
+    }
+
+    //
+    // How long poll() may sleep, never more than max
+    //
+    pub fn wait(&self, now: Instant, max: Duration) -> Duration {
+        match self.next_deadline() {
+            Some(t) => t.saturating_duration_since(now).min(max),
+            None => max,
+        }
+    }
+
+    //
+    // The timers that expired, each rescheduled an interval from now. A loop
+    // that fell behind runs them once, not once per missed interval
+    //
+    pub fn due(&mut self, now: Instant) -> Vec<K> {
+        let mut due = Vec::new();
+
+        for t in self.timers.iter_mut().filter(|t| t.next <= now) {
+            t.next = now + t.interval;
+            due.push(t.kind);
+        }
+
+        due
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+
+        let mut timers = Timers::new();
+        assert_eq!(timers.wait(start, secs(1)), secs(1));
+
+        timers.every(Housekeeping::Tick, secs(1), start);
+        timers.every(Housekeeping::Stats, secs(60), start);
+
+        assert_eq!(timers.next_deadline(), Some(start + secs(1)));
+        assert_eq!(timers.wait(start, secs(5)), secs(1));
+        assert_eq!(
+            timers.wait(start, Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+        assert!(timers.due(start).is_empty());
+
+        // late by a lot, once
+        assert_eq!(timers.due(start + secs(10)), vec![Housekeeping::Tick]);
+        assert!(timers.due(start + secs(10)).is_empty());
+        assert_eq!(timers.wait(start + secs(12), secs(5)), Duration::ZERO);
+
+        assert_eq!(
+            timers.due(start + secs(60)),
+            vec![Housekeeping::Tick, Housekeeping::Stats]
+        );
+        assert_eq!(timers.next_deadline(), Some(start + secs(61)));
+
+        // rescheduled
+        timers.every(Housekeeping::Stats, secs(2), start + secs(60));
+        assert_eq!(
+            timers.due(start + secs(62)),
+            vec![Housekeeping::Tick, Housekeeping::Stats]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//
+// Tunnel addresses handed out by the server. An address comes back once both
+// sides are done with it, removed here and Released by the peer, so a late
+// frame for the old connection never lands on a new one. Peers that don't
+// send Released get their addresses back after QUARANTINE, and only when
+// nothing else is left
+//
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::packet::Address;
+
+// Below are CONTROL_ADDRESS and the tokens of the server's own sockets
+pub const FIRST_ADDRESS: Address = 4;
+// Addresses travel as u16
+pub const LAST_ADDRESS: Address = u16::MAX as Address;
+// Longer than the peers keep a UDP socket around ( DEF_UDP_TIMEOUT )
+pub const QUARANTINE: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Hold {
+    // removed on this side, since when
+    local: Option<Instant>,
+    // the peer sent Released
+    peer: bool,
+}
+
+#[derive(Debug)]
+pub struct TokenAllocator {
+    last: Address,
+    // never handed out from there on
+    next: Address,
+    // released by both sides, oldest first
+    free: VecDeque<Address>,
+    // handed out and not back yet
+    held: HashMap<Address, Hold>,
+}
+
+impl Default for TokenAllocator {
+    fn default() -> Self {
+        Self::new(FIRST_ADDRESS, LAST_ADDRESS)
+    }
+}
+
+impl TokenAllocator {
+    pub fn new(first: Address, last: Address) -> Self {
+        Self {
+            last,
+            next: first,
+            free: VecDeque::new(),
+            held: HashMap::new(),
+        }
+    }
+
+    //
+    // None once every address is held
+    //
+    pub fn allocate(&mut self) -> Option<Address> {
+        if self.free.is_empty() && self.next > self.last {
+            self.expire(Instant::now(), QUARANTINE);
+        }
+
+        let addr = match self.free.pop_front() {
+            Some(v) => v,
+            None if self.next <= self.last => {
+                self.next += 1;
+                self.next - 1
+            }
+            None => return None,
+        };
+
+        self.held.insert(addr, Hold::default());
+        Some(addr)
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_held(&self, addr: Address) -> bool {
+        self.held.contains_key(&addr)
+    }
+
+    //
+    // The address isn't used on this side anymore
+    //
+    pub fn release_local(&mut self, addr: Address, now: Instant) {
+        if let Some(hold) = self.held.get_mut(&addr) {
+            hold.local.get_or_insert(now);
+            self.settle(addr);
+        }
+    }
+
+    //
+    // The peer won't send anything for the address anymore
+    //
+    pub fn release_peer(&mut self, addr: Address) {
+        if let Some(hold) = self.held.get_mut(&addr) {
+            hold.peer = true;
+            self.settle(addr);
+        }
+    }
+
+    fn settle(&mut self, addr: Address) {
+        if let Some(Hold {
+            local: Some(_),
+            peer: true,
+        }) = self.held.get(&addr)
+        {
+            self.held.remove(&addr);
+            self.free.push_back(addr);
+        }
+    }
+
+    //
+    // Takes back what was released here and never acknowledged by the peer
+    // for `timeout`. Returns how many
+    //
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> usize {
+        let expired: Vec<Address> = self
+            .held
+            .iter()
+            .filter(|(_, h)| matches!(h.local, Some(t) if now.duration_since(t) >= timeout))
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &expired {
+            self.held.remove(addr);
+            self.free.push_back(*addr);
+        }
+
+        expired.len()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn allocation() {
+        let mut a = TokenAllocator::new(4, 6);
+
+        assert_eq!(a.allocate(), Some(4));
+        assert_eq!(a.allocate(), Some(5));
+        assert_eq!(a.allocate(), Some(6));
+        assert_eq!(a.allocate(), None);
+        assert_eq!(a.held(), 3);
+    }
+
+    #[test]
+    fn recycling() {
+        let now = Instant::now();
+        let mut a = TokenAllocator::new(4, 5);
+
+        let x = a.allocate().unwrap();
+        let _y = a.allocate().unwrap();
+
+        // gone here, the peer may still have frames in flight
+        a.release_local(x, now);
+        assert!(a.is_held(x));
+        assert_eq!(a.allocate(), None);
+
+        a.release_peer(x);
+        assert!(!a.is_held(x));
+        assert_eq!(a.allocate(), Some(x));
+
+        // either order
+        a.release_peer(x);
+        assert!(a.is_held(x));
+        a.release_local(x, now);
+        assert_eq!(a.allocate(), Some(x));
+    }
+
+    #[test]
+    fn quarantine() {
+        let now = Instant::now();
+        let mut a = TokenAllocator::new(4, 5);
+
+        let x = a.allocate().unwrap();
+        let y = a.allocate().unwrap();
+
+        // a peer that never acknowledges
+        a.release_local(x, now);
+        assert_eq!(a.expire(now + QUARANTINE / 2, QUARANTINE), 0);
+        assert_eq!(a.expire(now + QUARANTINE, QUARANTINE), 1);
+
+        // still in use on this side, never expires
+        assert!(a.is_held(y));
+        assert_eq!(a.allocate(), Some(x));
+    }
+
+    #[test]
+    fn reserved_range() {
+        let mut a = TokenAllocator::default();
+
+        let mut seen = 0;
+        while let Some(addr) = a.allocate() {
+            assert!((FIRST_ADDRESS..=LAST_ADDRESS).contains(&addr), "{addr}");
+            seen += 1;
+        }
+
+        assert_eq!(seen, LAST_ADDRESS - FIRST_ADDRESS + 1);
+    }
+
+    //
+    // Sequential connections, the peer's Released trailing by a few of them
+    //
+    #[test]
+    fn soak() {
+        const CONNECTIONS: usize = 50_000;
+        const LAG: usize = 8;
+
+        let now = Instant::now();
+        let mut a = TokenAllocator::new(FIRST_ADDRESS, FIRST_ADDRESS + 63);
+
+        let mut live: HashSet<Address> = HashSet::new();
+        let mut unacked: VecDeque<Address> = VecDeque::new();
+
+        for _ in 0..CONNECTIONS {
+            let addr = a.allocate().unwrap();
+
+            assert!(addr >= FIRST_ADDRESS);
+            assert!(live.insert(addr), "{addr} handed out twice");
+
+            a.release_local(addr, now);
+            unacked.push_back(addr);
+
+            if unacked.len() > LAG {
+                let addr = unacked.pop_front().unwrap();
+                a.release_peer(addr);
+                live.remove(&addr);
+            }
+        }
+
+        assert_eq!(a.held(), LAG);
+    }
+}
@@ -1,44 +1,643 @@
-use std::{thread::sleep, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
-use mio::{Events, Interest, Poll, Token, net::TcpStream};
+use mio::{
+    Events, Interest, Poll, Token,
+    net::{TcpStream, UdpSocket},
+};
 
-use log::{error, info, warn};
+use log::{Level, debug, error, info, log_enabled, warn};
 
 use crate::{
-    error::{Error, Result},
-    streams::{BUFFER_SIZE, ClientStream, TokenStreams},
+    access_log::AccessLog,
+    backoff::{Backoff, DEF_RECONNECT_MAX, STABLE_CONNECTION},
+    clock::{ClockEvent, ClockSample, ResumeDetector},
+    error::{Error, ErrorClass, Result},
+    handshake::{
+        FEATURE_BANNER, FEATURE_RELEASE, FEATURE_STATS, Goodbye, GoodbyeReason, Hello, send_goodbye,
+        session_max_connections,
+    },
+    logging::{REPEAT_SUMMARY, RepeatLog},
+    outbound::{LocalBind, connect_from},
+    packet::{Address, CONTROL_ADDRESS, ConnectInfo, Packet, PacketMessage, RefuseReason},
+    probe::{PROBE_TIMEOUT, PathProbe},
+    proxy::Proxy,
+    ratelimit::REFILL_INTERVAL,
+    resolve::{Resolved, connect_first},
+    resource::Usage,
+    stats::{PeerStats, STATS_INTERVAL},
+    streams::{
+        CONNECT_TIMEOUT, ClientStream, CloseReason, DRAIN_TIMEOUT, HALF_CLOSE_TIMEOUT, ReadOutcome, TEARDOWN_FLUSH,
+        TICK_INTERVAL, TokenStreams,
+    },
+    timers::{Housekeeping, Timers},
+    udp::{DEF_UDP_TIMEOUT, MAX_DATAGRAM, Protocol},
+    unwind::{catch_session, panic_count},
+    watchdog::{Activity, Watchdog},
+    webhook::{EventKind, Webhook, WebhookConfig},
 };
 
 const TUNNEL_STREAM: Token = Token(1);
+// reconnect_delay multipliers when the server said why it dropped the tunnel
+const INTERNAL_BACKOFF: u32 = 10;
+const CONFIG_ERROR_BACKOFF: u32 = 60;
+const BUSY_BACKOFF: u32 = 10;
+// two live clients taking over from each other, not too often
+const REPLACED_BACKOFF: u32 = 60;
+// the connections refused at capacity are summed up that often
+const CAPACITY_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
-fn read_loop(mut tstream: TcpStream, server: &str) -> Result<()> {
-    let mut poll = Poll::new()?;
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    // pvpn server
+    pub tunnel: String,
+    // tried in that order when tunnel can't be reached
+    pub tunnel_failover: Vec<String>,
+    // local endpoint the connections are forwarded to, unless the forward has
+    // its own in endpoints
+    pub server: String,
+    // forward label -> endpoint
+    pub endpoints: HashMap<String, String>,
+    // the first reconnect delay, doubled up to reconnect_max
+    pub reconnect_delay: Duration,
+    // DEF_RECONNECT_MAX if None
+    pub reconnect_max: Option<Duration>,
+    // give up after that many failed attempts in a row, never if None
+    pub max_reconnect_attempts: Option<usize>,
+    // the tunnel goes through it if set
+    pub proxy: Option<Proxy>,
+    // where the tunnel and the endpoint connections are made from
+    pub tunnel_bind: LocalBind,
+    pub endpoint_bind: LocalBind,
+    // prepend a PROXY protocol v1 line to what is sent to the endpoint
+    pub proxy_protocol: bool,
+    // 0 disables the watchdog
+    pub watchdog_timeout: Duration,
+    // probe the largest frame size that makes it through after the handshake
+    pub path_probe: bool,
+    // lower the session's frame size to the probed one
+    pub probe_clamp: bool,
+    // ask for and log the operator's banner
+    pub motd: bool,
+    // has to match the server's
+    pub protocol: Protocol,
+    // idle UDP sockets are closed after that, DEF_UDP_TIMEOUT if None
+    pub udp_timeout: Option<Duration>,
+    // idle TCP connections are closed after that, never if None
+    pub idle_timeout: Option<Duration>,
+    // endpoint connections not established by then are refused,
+    // CONNECT_TIMEOUT if None
+    pub connect_timeout: Option<Duration>,
+    // SO_KEEPALIVE idle time of the tunnel and the connections, off if None
+    pub tcp_keepalive: Option<Duration>,
+    // nothing from the server for that long drops the tunnel, 0 disables
+    pub tunnel_timeout: Duration,
+    pub webhook: Option<WebhookConfig>,
+    // bytes per second written to the tunnel
+    pub max_rate: Option<u64>,
+    // per stream data waiting for its socket, DEF_MAX_BUFFERED if None
+    pub max_buffered: Option<usize>,
+    // bytes read from a socket at once, BUFFER_SIZE if None
+    pub buffer_size: Option<usize>,
+    // endpoint connections carried at once, the server is told and the ones
+    // above are refused. No limit if None
+    pub max_connections: Option<usize>,
+    // JSON lines, one per endpoint connection
+    pub access_log: Option<PathBuf>,
+}
 
-    let mut events = Events::with_capacity(128);
+//
+// The config's endpoints, resolved once for the process
+//
+#[derive(Debug, Default)]
+struct Endpoints {
+    // --server-address, the forwards without their own
+    server: Option<Resolved>,
+    by_label: HashMap<String, Resolved>,
+}
+
+impl Endpoints {
+    fn resolve(config: &ClientConfig) -> Result<Self> {
+        let server = match config.server.is_empty() {
+            true => None,
+            false => Some(Resolved::new("--server-address", &config.server)?),
+        };
+
+        let by_label = config
+            .endpoints
+            .iter()
+            .map(|(label, spec)| Ok((label.clone(), Resolved::new("--endpoint", spec)?)))
+            .collect::<Result<_>>()?;
 
-    poll.registry()
-        .register(&mut tstream, TUNNEL_STREAM, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(Self { server, by_label })
+    }
+
+    fn get(&mut self, label: &str) -> Option<&mut Resolved> {
+        match self.by_label.get_mut(label) {
+            Some(v) => Some(v),
+            None => self.server.as_mut(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    hello: Hello,
+    probe: Option<PathProbe>,
+    // forward of each stream, for the logs
+    channels: HashMap<Address, usize>,
+    // UDP mode, one socket toward the endpoint per tunnel address
+    udp: HashMap<Address, (UdpSocket, Instant)>,
+    webhook: Webhook,
+    // the server of the list this session is attached to
+    tunnel: String,
+    // refused at capacity and not logged yet, when that was last logged
+    refused: u64,
+    refused_logged: Option<Instant>,
+}
+
+//
+// One line per CAPACITY_LOG_INTERVAL at most, however many are turned away
+//
+fn log_refused(config: &ClientConfig, session: &mut Session, now: Instant) {
+    if 0 == session.refused {
+        return;
+    }
+
+    if let Some(t) = session.refused_logged
+        && now.duration_since(t) < CAPACITY_LOG_INTERVAL
+    {
+        return;
+    }
+
+    warn!(
+        "{} connections refused at capacity ({} connections)",
+        session.refused,
+        config.max_connections.unwrap_or_default()
+    );
+    session.refused = 0;
+    session.refused_logged = Some(now);
+}
+
+fn read_loop(
+    tstream: TcpStream,
+    tunnel: &str,
+    config: &ClientConfig,
+    endpoints: &mut Endpoints,
+    watchdog: &Watchdog,
+    webhook: &Webhook,
+) -> Result<()> {
+    //
+    // Everything of a session starts here and ends with it, only the
+    // resolved endpoints carry over to the next one
+    //
+    let mut poll = Poll::new()?;
 
     let mut streams = TokenStreams::new();
 
-    streams.add(TUNNEL_STREAM.0, ClientStream::new(tstream)?);
+    streams.set_registry(poll.registry().try_clone()?);
+    streams.set_max_rate(config.max_rate);
+    streams.set_keepalive(config.tcp_keepalive);
+    if let Some(max) = config.max_buffered {
+        streams.set_max_buffered(max);
+    }
+    if let Some(size) = config.buffer_size {
+        streams.set_buffer_size(size);
+    }
+    if let Some(path) = &config.access_log {
+        match AccessLog::open(path, "client") {
+            Ok(v) => streams.set_access_log(Some(v)),
+            Err(e) => warn!("unable to open the access log {} ({e})", path.display()),
+        }
+    }
 
-    let mut read_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    streams.add_tunnel(TUNNEL_STREAM.0, ClientStream::new(tstream)?)?;
 
     info!("-----------------------------CLIENT-----------------------------");
 
+    let ret = catch_session(|| event_loop(&mut poll, &mut streams, tunnel, config, endpoints, watchdog, webhook));
+
+    if let Err(e) = &ret {
+        send_goodbye(&mut streams, TUNNEL_STREAM.0, e);
+    }
+
+    // whatever is still open won't hear from the peer again
+    let left = streams.flush_all(TEARDOWN_FLUSH);
+    if 0 != left {
+        warn!("{left} bytes for the connections never went out");
+    }
+    streams.close_all(CloseReason::TunnelLost);
+
+    info!("session summary: {}", streams.tunnel_stats());
+    info!("session streams: {}", streams.totals());
+    info!("session resources: {} connections={}", Usage::sample(), streams.len());
+
+    ret
+}
+
+//
+// What the client tells the server about itself after the server's Hello
+//
+fn client_hello(config: &ClientConfig) -> Hello {
+    let mut hello = Hello::default();
+
+    if config.motd {
+        hello.features.push(FEATURE_BANNER.to_string());
+    }
+
+    hello.features.push(FEATURE_STATS.to_string());
+    hello.max_connections = config.max_connections;
+
+    hello
+}
+
+//
+// Endpoint connections and UDP sockets, the tunnel doesn't count
+//
+fn connection_count(streams: &TokenStreams, session: &Session) -> usize {
+    streams.len().saturating_sub(1) + session.udp.len()
+}
+
+//
+// Tunnel level messages from the server
+//
+fn control_message(
+    p: &Packet,
+    data: &[u8],
+    streams: &mut TokenStreams,
+    config: &ClientConfig,
+    session: &mut Session,
+) -> Result<()> {
+    match p.msg {
+        PacketMessage::Hello => {
+            session.hello = Hello::decode(data)?;
+            info!("connected to the server: {}", session.hello);
+
+            streams.set_send_releases(session.hello.has_feature(FEATURE_RELEASE));
+            session.webhook.notify(EventKind::TunnelUp, &session.tunnel);
+
+            let params = client_hello(config);
+            if !params.features.is_empty() {
+                streams.write_control(TUNNEL_STREAM.0, PacketMessage::Hello, &params.encode())?;
+            }
+
+            match session_max_connections(session.hello.max_connections, config.max_connections) {
+                Some(v) => info!("session parameters: max_connections={v}"),
+                None => info!("session parameters: max_connections=unlimited"),
+            }
+
+            if config.path_probe {
+                session.probe = Some(PathProbe::new(streams.mtu()));
+                probe_step(streams, config, session)?;
+            }
+        }
+        PacketMessage::Banner => {
+            if !config.motd {
+                return Ok(());
+            }
+
+            match std::str::from_utf8(data) {
+                Ok(v) => info!(
+                    "----- message from the server -----\n{}\n-----------------------------------",
+                    v.trim_end()
+                ),
+                Err(_) => warn!("invalid banner"),
+            }
+        }
+        PacketMessage::Stats => info!("server stats: {}", PeerStats::decode(data)?),
+        PacketMessage::Goodbye => {
+            let goodbye = Goodbye::decode(data)?;
+            warn!("the server is dropping the tunnel: {goodbye}");
+            return Err(Error::Goodbye {
+                reason: goodbye.reason,
+                message: goodbye.message,
+            });
+        }
+        // server keepalive
+        PacketMessage::Probe => streams.write_control(TUNNEL_STREAM.0, PacketMessage::ProbeReply, data)?,
+        PacketMessage::ProbeReply => {
+            if let Some(probe) = &mut session.probe {
+                probe.on_reply(data.len());
+            }
+            probe_step(streams, config, session)?;
+        }
+        _ => warn!("unexpected control message {}", p.msg),
+    }
+
+    Ok(())
+}
+
+//
+// Sends the next probe or wraps up the probe phase
+//
+fn probe_step(streams: &mut TokenStreams, config: &ClientConfig, session: &mut Session) -> Result<()> {
+    let probe = match &mut session.probe {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    probe.check_timeout(PROBE_TIMEOUT);
+
+    if let Some(size) = probe.next_probe() {
+        return streams.write_control(TUNNEL_STREAM.0, PacketMessage::Probe, &vec![0; size]);
+    }
+
+    if !probe.is_done() {
+        // waiting for an echo
+        return Ok(());
+    }
+
+    let mtu = streams.mtu();
+    let largest = probe.largest().unwrap_or(0);
+    session.probe = None;
+
+    if largest >= mtu {
+        info!("path probe: frames up to {mtu} bytes go through");
+        return Ok(());
+    }
+
+    warn!("path probe: frames above {largest} bytes don't make it through, max frame size is {mtu}");
+
+    if config.probe_clamp && largest > 0 {
+        streams.set_mtu(largest);
+
+        let params = Hello {
+            mtu: Some(largest),
+            ..Default::default()
+        };
+        streams.write_control(TUNNEL_STREAM.0, PacketMessage::Hello, &params.encode())?;
+    }
+
+    Ok(())
+}
+
+//
+// UDP mode, the endpoint sees the datagrams of every internet peer coming
+// from a distinct local port
+//
+fn udp_connect(poll: &Poll, addr: Address, server: SocketAddr, session: &mut Session) -> Result<()> {
+    let local = match server.is_ipv4() {
+        true => "0.0.0.0:0",
+        false => "[::]:0",
+    };
+
+    let mut socket = UdpSocket::bind(local.parse()?)?;
+    socket.connect(server)?;
+
+    poll.registry().register(&mut socket, Token(addr), Interest::READABLE)?;
+
+    session.udp.insert(addr, (socket, Instant::now()));
+    Ok(())
+}
+
+//
+// The endpoint connection of addr, established later on, see
+// prune_connecting()
+//
+fn tcp_connect(
+    streams: &mut TokenStreams,
+    addr: Address,
+    server: SocketAddr,
+    info: &ConnectInfo,
+    config: &ClientConfig,
+) -> Result<()> {
+    let local = &config.endpoint_bind;
+
+    let stream = match local.is_set() {
+        true => TcpStream::from_std(connect_from(local, "--endpoint-bind", server)?),
+        false => TcpStream::connect(server)?,
+    };
+
+    let mut client = ClientStream::new(stream)?;
+
+    client.set_peer(info.peer);
+
+    if config.proxy_protocol {
+        client.push_data(info.proxy_v1_header().as_bytes());
+    }
+
+    streams.add(addr, client)
+}
+
+//
+// Every datagram the endpoint sent back, one Datagram frame each
+//
+fn udp_read(addr: Address, streams: &mut TokenStreams, session: &mut Session, datagram: &mut [u8]) -> Result<()> {
+    let (socket, last_seen) = match session.udp.get_mut(&addr) {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
     loop {
-        if let Err(e) = poll.poll(&mut events, None) {
+        let len = match socket.recv(datagram) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => {
+                // e.g. ICMP port unreachable, the next send may work
+                debug!("recv() failure for token={addr} ({e})");
+                return Ok(());
+            }
+        };
+
+        *last_seen = Instant::now();
+
+        if len > streams.mtu() {
+            warn!("dropping datagram token={addr} bytes={len}, above {}", streams.mtu());
+            continue;
+        }
+
+        streams.write_message_data(TUNNEL_STREAM.0, addr, PacketMessage::Datagram, &datagram[0..len])?;
+    }
+}
+
+fn udp_reap(poll: &Poll, timeout: Duration, streams: &mut TokenStreams, session: &mut Session) -> Result<()> {
+    let idle: Vec<Address> = session
+        .udp
+        .iter()
+        .filter(|(_, (_, last_seen))| last_seen.elapsed() > timeout)
+        .map(|(addr, _)| *addr)
+        .collect();
+
+    for addr in idle {
+        if let Some((mut socket, _)) = session.udp.remove(&addr) {
+            debug!("udp flow token={addr} expired");
+            poll.registry().deregister(&mut socket)?;
+        }
+        streams.release_address(addr);
+        session.channels.remove(&addr);
+    }
+
+    Ok(())
+}
+
+fn event_loop(
+    poll: &mut Poll,
+    streams: &mut TokenStreams,
+    tunnel: &str,
+    config: &ClientConfig,
+    endpoints: &mut Endpoints,
+    watchdog: &Watchdog,
+    webhook: &Webhook,
+) -> Result<()> {
+    let mut events = Events::with_capacity(128);
+
+    let mut read_buffer = vec![0; streams.buffer_size()];
+
+    let mut housekeeping = Timers::new();
+    housekeeping.every(Housekeeping::Tick, TICK_INTERVAL, Instant::now());
+    housekeeping.every(Housekeeping::Stats, STATS_INTERVAL, Instant::now());
+    // what the process held when the session started
+    let baseline = Usage::sample();
+
+    let mut session = Session {
+        webhook: webhook.clone(),
+        tunnel: tunnel.to_string(),
+        ..Default::default()
+    };
+
+    let mut datagram = vec![0; MAX_DATAGRAM];
+
+    let mut clock = ResumeDetector::new();
+
+    // --tunnel-timeout
+    let mut last_read = Instant::now();
+    let mut last_keepalive = Instant::now();
+
+    loop {
+        // until the next housekeeping deadline at most
+        let wait = housekeeping.wait(Instant::now(), TICK_INTERVAL);
+
+        let timeout = match streams.is_throttled() {
+            true => REFILL_INTERVAL.min(wait),
+            false => wait,
+        };
+
+        if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
             error!("poll() failure {e}");
             return Err(e.into());
         }
 
+        watchdog.ping();
+        watchdog.set_streams(streams.len());
+
+        match clock.on_sample(ClockSample::now()) {
+            Some(e @ ClockEvent::Resume { .. }) => {
+                warn!("{e}");
+                // is the tunnel still there
+                streams.write_control(TUNNEL_STREAM.0, PacketMessage::Probe, &[])?;
+            }
+            Some(e) => warn!("{e}"),
+            None => {}
+        }
+
+        if streams.is_throttled() {
+            streams.flush(TUNNEL_STREAM.0)?;
+        }
+
+        let now = Instant::now();
+        let due = housekeeping.due(now);
+
+        if due.contains(&Housekeeping::Tick) {
+            // nothing expires because the host slept
+            let timers = !clock.in_grace(now);
+
+            if timers {
+                streams.prune_half_closed(HALF_CLOSE_TIMEOUT)?;
+                streams.prune_draining(DRAIN_TIMEOUT);
+                streams.prune_connecting(config.connect_timeout.unwrap_or(CONNECT_TIMEOUT))?;
+
+                if let Some(max_idle) = config.idle_timeout {
+                    streams.prune_idle(max_idle)?;
+                }
+
+                probe_step(streams, config, &mut session)?;
+                log_refused(config, &mut session, Instant::now());
+            }
+
+            if timers && !config.tunnel_timeout.is_zero() {
+                let silent = last_read.elapsed();
+
+                if silent > config.tunnel_timeout {
+                    warn!(
+                        "nothing from the server for {} s, dropping the tunnel",
+                        silent.as_secs()
+                    );
+                    return Err(Error::TunnelTimeout);
+                }
+
+                //
+                // keeps the NAT mapping alive, the server echoes. Not while
+                // the path probe waits for its own echoes
+                //
+                let interval = config.tunnel_timeout / 3;
+                if silent > interval && last_keepalive.elapsed() > interval && session.probe.is_none() {
+                    last_keepalive = Instant::now();
+                    streams.write_control(TUNNEL_STREAM.0, PacketMessage::Probe, &[])?;
+                }
+            }
+
+            if timers {
+                udp_reap(
+                    poll,
+                    config.udp_timeout.unwrap_or(DEF_UDP_TIMEOUT),
+                    streams,
+                    &mut session,
+                )?;
+            }
+
+            session
+                .channels
+                .retain(|addr, _| streams.contains_token(*addr) || session.udp.contains_key(addr));
+        }
+
+        if due.contains(&Housekeeping::Stats) {
+            let usage = Usage::sample();
+            match usage.fd_leak(&baseline, streams.len()) {
+                true => warn!("resources: {usage} connections={} fd leak?", streams.len()),
+                false => info!("resources: {usage} connections={}", streams.len()),
+            }
+
+            if log_enabled!(Level::Debug) {
+                for info in streams.snapshot() {
+                    debug!("stream {info}");
+                }
+            }
+
+            if session.hello.has_feature(FEATURE_STATS) {
+                streams.write_control(TUNNEL_STREAM.0, PacketMessage::Stats, &streams.totals().encode()?)?;
+            }
+        }
+
         for event in events.iter() {
+            watchdog.record(Activity::Event {
+                token: event.token().0,
+                readable: event.is_readable(),
+                writable: event.is_writable(),
+            });
+
             if TUNNEL_STREAM == event.token() && event.is_readable() {
-                streams.flush_read(TUNNEL_STREAM.0, &mut read_buffer)?;
+                //
+                // the frames that came with the EOF are handled first, the
+                // peer's last Data may be among them
+                //
+                let eof = match streams.flush_read(TUNNEL_STREAM.0) {
+                    Err(Error::Eof) => true,
+                    ret => {
+                        ret?;
+                        false
+                    }
+                };
+                last_read = Instant::now();
 
                 loop {
-                    let (read_len, dst_addr) = match streams.read_packet(&mut read_buffer) {
+                    let (p, data) = match streams.read_packet() {
                         Ok(v) => v,
                         Err(Error::Empty) => {
                             break;
@@ -51,98 +650,1221 @@ fn read_loop(mut tstream: TcpStream, server: &str) -> Result<()> {
                             break;
                         }
                         Err(e) => {
+                            // the input can't be resynchronized, start over
                             error!("{e}");
-                            break;
+                            return Err(e);
                         }
                     };
 
-                    info!("{read_len} bytes for addr={dst_addr}");
+                    watchdog.record(Activity::Frame {
+                        msg: p.msg,
+                        addr: p.addr,
+                        len: data.len(),
+                    });
 
-                    if streams.contains_token(dst_addr) {
-                        if let Err(e) = streams.write(dst_addr, &read_buffer[0..read_len]) {
-                            warn!("Connection terminated ({e})");
-                            let msg = e.into();
-                            if let Err(e) = streams.write_message(TUNNEL_STREAM.0, event.token().0, msg) {
-                                error!("unable to write message for {} ({e})", event.token().0);
-                                return Err(e);
-                            }
+                    if CONTROL_ADDRESS == p.addr {
+                        control_message(&p, &data, streams, config, &mut session)?;
+                        continue;
+                    }
+
+                    let dst_addr = p.addr;
+
+                    if PacketMessage::Connect == p.msg {
+                        let info = ConnectInfo::decode(&data)?;
+                        let channel = info.channel as usize;
+                        let label = session.hello.label(channel).to_string();
+
+                        //
+                        // whatever the server allows, more connections than
+                        // this box can carry would take the whole tunnel down
+                        //
+                        if let Some(max) = config.max_connections
+                            && connection_count(streams, &session) >= max
+                        {
+                            debug!("[{label}] at capacity ({max} connections), refusing token={dst_addr}");
+                            session.refused += 1;
+                            log_refused(config, &mut session, Instant::now());
+                            let reason = RefuseReason::CapacityExceeded.as_str().as_bytes();
+                            streams.write_message_data(
+                                TUNNEL_STREAM.0,
+                                dst_addr,
+                                PacketMessage::ConnectionRefused,
+                                reason,
+                            )?;
+                            streams.release_address(dst_addr);
+                            continue;
                         }
-                    } else {
+
+                        let server = match endpoints.get(&label) {
+                            Some(v) => v.first(Instant::now()),
+                            None => {
+                                warn!("[{label}] no endpoint for the forward, refusing token={dst_addr}");
+                                let reason = RefuseReason::NoEndpoint.as_str().as_bytes();
+                                streams.write_message_data(
+                                    TUNNEL_STREAM.0,
+                                    dst_addr,
+                                    PacketMessage::ConnectionRefused,
+                                    reason,
+                                )?;
+                                streams.release_address(dst_addr);
+                                continue;
+                            }
+                        };
+
                         //
                         // Connect the server
                         //
-                        info!("{dst_addr} is not connected to {server}");
+                        info!("[{label}] token={dst_addr} from {} connecting to {server}", info.peer);
 
-                        let addr = server.parse()?;
+                        let connected = match config.protocol {
+                            Protocol::Udp => udp_connect(poll, dst_addr, server, &mut session),
+                            Protocol::Tcp => tcp_connect(streams, dst_addr, server, &info, config),
+                        };
+
+                        // that connection only, the others and the session stay
+                        if let Err(e) = connected {
+                            warn!("[{label}] unable to connect token={dst_addr} to {server} ({e})");
+                            streams.write_message(TUNNEL_STREAM.0, dst_addr, PacketMessage::ConnectionRefused)?;
+                            streams.release_address(dst_addr);
+                            continue;
+                        }
+
+                        session.channels.insert(dst_addr, channel);
+                        continue;
+                    }
+
+                    if PacketMessage::Datagram == p.msg {
+                        if let Some((socket, last_seen)) = session.udp.get_mut(&dst_addr) {
+                            *last_seen = Instant::now();
+
+                            if let Err(e) = socket.send(&data) {
+                                // same as the network dropping it
+                                debug!("send() failure for token={dst_addr} ({e})");
+                            }
+                        }
+                        continue;
+                    }
 
-                        let mut sstream = TcpStream::connect(addr)?;
+                    let label = match session.channels.get(&dst_addr) {
+                        Some(c) => session.hello.label(*c),
+                        None => "?",
+                    };
 
-                        poll.registry().register(
-                            &mut sstream,
-                            Token(dst_addr),
-                            Interest::READABLE | Interest::WRITABLE,
-                        )?;
+                    info!("[{label}] token={dst_addr} bytes={}", data.len());
 
-                        let mut client = ClientStream::new(sstream)?;
+                    if !streams.contains_token(dst_addr) {
+                        debug!("[{label}] dropping data for unknown token={dst_addr}");
+                        continue;
+                    }
 
-                        client.push_data(&read_buffer[0..read_len]);
-                        streams.add(dst_addr, client);
+                    // the server is told by write()
+                    if let Err(e) = streams.write(dst_addr, &data) {
+                        warn!("Connection terminated ({e})");
                     }
                 }
+
+                if eof {
+                    return Err(Error::Eof);
+                }
             } else if TUNNEL_STREAM == event.token() && event.is_writable() {
                 if let Err(e) = streams.flush(TUNNEL_STREAM.0) {
                     error!("flush failure for {} {e}", TUNNEL_STREAM.0);
                     return Err(e);
                 }
-            } else if event.is_readable() {
-                loop {
-                    let read_len = match streams.read(event.token().0, &mut read_buffer) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            warn!("Connection terminated ({e})");
-                            let msg = e.into();
-                            if let Err(e) = streams.write_message(TUNNEL_STREAM.0, event.token().0, msg) {
-                                error!("unable to write message for {} ({e})", event.token().0);
-                                return Err(e);
+            } else if session.udp.contains_key(&event.token().0) {
+                udp_read(event.token().0, streams, &mut session, &mut datagram)?;
+            } else if !streams.contains_token(event.token().0) {
+                // removed earlier in this batch
+                debug!("event for unknown token={}", event.token().0);
+            } else if (event.is_error() || event.is_read_closed() && event.is_write_closed())
+                && streams.socket_error(event.token().0)?
+            {
+                // reset, closed and the server told
+            } else {
+                if event.is_readable() {
+                    loop {
+                        let read_len = match streams.read(event.token().0, &mut read_buffer) {
+                            Ok(ReadOutcome::Data(v)) => v,
+                            Ok(ReadOutcome::WouldBlock) => break,
+                            Ok(ReadOutcome::Eof) => {
+                                streams.write_message(TUNNEL_STREAM.0, event.token().0, PacketMessage::CloseWrite)?;
+                                break;
                             }
-                            break;
-                        }
-                    };
+                            // closed and the server told by read()
+                            Err(e) => {
+                                warn!("Connection terminated ({e})");
+                                break;
+                            }
+                        };
 
-                    if 0 == read_len {
-                        break;
+                        streams.write_packet(TUNNEL_STREAM.0, event.token().0, &read_buffer[0..read_len])?;
                     }
+                }
 
-                    streams.write_packet(TUNNEL_STREAM.0, event.token().0, &read_buffer[0..read_len])?;
+                //
+                // the endpoint went away, the session stays
+                //
+                if event.is_writable()
+                    && streams.contains_token(event.token().0)
+                    && let Err(e) = streams.flush(event.token().0)
+                {
+                    warn!("flush failure for {} {e}", event.token().0);
                 }
-            } else if event.is_writable()
-                && let Err(e) = streams.flush(event.token().0)
-            {
-                error!("flush failure for {} {e}", event.token().0);
-                return Err(e);
             }
         }
+
+        // last word about the addresses removed above
+        streams.send_releases(TUNNEL_STREAM.0)?;
+    }
+}
+
+//
+// How long to wait before the next attempt, None to give up. Never more than
+// `max`, --reconnect-max
+//
+fn reconnect_policy(delay: Duration, max: Duration, goodbye: Option<GoodbyeReason>) -> Option<Duration> {
+    let delay = match goodbye {
+        Some(GoodbyeReason::AuthRevoked) => None,
+        // the server crashing on its config, no point hammering it
+        Some(GoodbyeReason::ConfigError) => Some(delay * CONFIG_ERROR_BACKOFF),
+        Some(GoodbyeReason::Internal) => Some(delay * INTERNAL_BACKOFF),
+        Some(GoodbyeReason::Busy) => Some(delay * BUSY_BACKOFF),
+        Some(GoodbyeReason::Replaced) => Some(delay * REPLACED_BACKOFF),
+        _ => Some(delay),
+    };
+
+    delay.map(|v| v.min(max))
+}
+
+//
+// The connection to a tunnel server, through the proxy if there's one
+//
+fn tunnel_connect(config: &ClientConfig, first_hop: &mut Resolved, tunnel: &str) -> Result<TcpStream> {
+    let mut stream = connect_first(first_hop.addrs(Instant::now()), &config.tunnel_bind, CONNECT_TIMEOUT)?;
+
+    if let Some(proxy) = &config.proxy {
+        proxy.handshake(&mut stream, tunnel, CONNECT_TIMEOUT)?;
     }
+    stream.set_nonblocking(true)?;
+
+    Ok(TcpStream::from_std(stream))
 }
 
-pub fn client_main(tunnel: &str, server: &str, reconnect_delay: u64) -> Result<()> {
-    info!("connecting to: {tunnel}");
-    let tunnel_addr = tunnel.parse()?;
+pub fn client_main(config: &ClientConfig) -> Result<()> {
+    let tunnels: Vec<&String> = std::iter::once(&config.tunnel).chain(&config.tunnel_failover).collect();
+
+    // the proxy resolves the tunnel's name when there's one
+    let mut first_hops = Vec::new();
+
+    for tunnel in &tunnels {
+        let hop = match &config.proxy {
+            Some(proxy) => {
+                info!("tunnel server: {tunnel} through {proxy}");
+                Resolved::new("--proxy", proxy.server())?
+            }
+            None => {
+                info!("tunnel server: {tunnel}");
+                Resolved::new("--tunnel-address", tunnel)?
+            }
+        };
+        first_hops.push(hop);
+    }
+
+    // the one that worked last, tried first
+    let mut last = 0;
+
+    let mut endpoints = Endpoints::resolve(config)?;
+
+    let watchdog = Watchdog::new();
+    watchdog.spawn(config.watchdog_timeout);
+
+    let webhook = Webhook::spawn(config.webhook.as_ref(), "client")?;
+
+    let mut backoff = Backoff::new(
+        config.reconnect_delay,
+        config.reconnect_max.unwrap_or(DEF_RECONNECT_MAX),
+    );
+
+    // connect failures and short sessions, in a row
+    let mut failures = 0;
+
+    let mut unreachable = RepeatLog::new(REPEAT_SUMMARY);
 
     loop {
-        match TcpStream::connect(tunnel_addr) {
+        watchdog.ping();
+
+        let mut goodbye = None;
+
+        //
+        // One pass over the list, the backoff is for when none of them
+        // answered
+        //
+        let mut connected = Err(Error::from(std::io::Error::from(std::io::ErrorKind::NotConnected)));
+
+        for i in std::iter::once(last).chain((0..tunnels.len()).filter(|i| *i != last)) {
+            connected = tunnel_connect(config, &mut first_hops[i], tunnels[i]);
+
+            match &connected {
+                Ok(_) => {
+                    last = i;
+                    break;
+                }
+                Err(e) if tunnels.len() > 1 => warn!("{}: {e}", tunnels[i]),
+                Err(_) => {}
+            }
+        }
+
+        match connected {
             Ok(v) => {
-                let ret = read_loop(v, server);
+                if let Some(line) = unreachable.on_success() {
+                    info!("tunnel server reachable, {line}");
+                }
+                info!("attached to {}", tunnels[last]);
+
+                let started = Instant::now();
+                let ret = read_loop(v, tunnels[last], config, &mut endpoints, &watchdog, &webhook);
+                backoff.on_session(started.elapsed());
+
+                failures = match started.elapsed() >= STABLE_CONNECTION {
+                    true => 0,
+                    false => failures + 1,
+                };
 
-                if let Err(e) = ret {
-                    info!("client disconnected. ({e})");
+                if let Err(Error::Goodbye { reason, .. }) = &ret {
+                    goodbye = Some(*reason);
+                }
+
+                // only sent if the session made it to the Hello
+                match &ret {
+                    Ok(_) => webhook.notify(EventKind::TunnelDown, "closed"),
+                    Err(e) => webhook.notify(EventKind::TunnelDown, e),
+                }
+
+                match ret {
+                    Ok(_) => {}
+                    Err(Error::Internal { payload }) => {
+                        error!("session aborted by a panic ({payload}), panics={}", panic_count())
+                    }
+                    // the next session would end the same way
+                    Err(e) if ErrorClass::Fatal == e.class() => {
+                        error!("client disconnected. ({e}), not reconnecting");
+                        return Err(e);
+                    }
+                    Err(e) => info!("client disconnected. ({e})"),
                 }
             }
+            // bad credentials, a local address that isn't there
+            Err(e) if ErrorClass::Fatal == e.class() => {
+                error!("{e}, not reconnecting");
+                return Err(e);
+            }
             Err(e) => {
-                error!("{e}");
+                if let Some(line) = unreachable.on_failure(&e.to_string(), Instant::now()) {
+                    error!("{line}");
+                }
+                failures += 1;
+            }
+        }
+
+        if let Some(max) = config.max_reconnect_attempts
+            && failures >= max
+        {
+            error!("{failures} failed attempts in a row, giving up");
+            return Err(Error::TooManyFailures { failures });
+        }
+
+        // the server's reasons go by the base delay, the rest backs off
+        let delay = match goodbye {
+            None => Some(backoff.next_delay()),
+            v => reconnect_policy(
+                config.reconnect_delay,
+                config.reconnect_max.unwrap_or(DEF_RECONNECT_MAX),
+                v,
+            ),
+        };
+
+        match delay {
+            Some(v) => {
+                info!("reconnecting in {} ms", v.as_millis());
+                watchdog.sleep(v)
+            }
+            None => {
+                error!("the server revoked this client, not reconnecting");
+                return Err(Error::Goodbye {
+                    reason: GoodbyeReason::AuthRevoked,
+                    message: "not reconnecting".to_string(),
+                });
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::Shutdown,
+        thread::sleep,
+    };
+
+    use super::*;
+    use crate::{
+        packet::HEADER_SIZE,
+        test_util::{TEST_TIMEOUT, connect_retry, endpoint, free_port, start_tunnel, start_tunnel_with},
+        tunnel_server::{Forward, ServerConfig, bind_forward, server_main},
+    };
+
+    #[test]
+    fn response_after_half_close() {
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"request").unwrap();
+        internet.shutdown(Shutdown::Write).unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        //
+        // the endpoint only sees EOF once the CloseWrite made it through
+        //
+        let mut request = Vec::new();
+        local.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"request");
+
+        sleep(Duration::from_millis(100));
+        local.write_all(b"response").unwrap();
+        drop(local);
+
+        let mut response = Vec::new();
+        internet.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"response");
+    }
+
+    fn read_line(stream: &mut std::net::TcpStream) -> String {
+        let mut line = Vec::new();
+        let mut byte: [u8; 1] = [0; 1];
+
+        while !line.ends_with(b"\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+
+        String::from_utf8(line).unwrap()
+    }
+
+    #[test]
+    fn proxy_protocol() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let config = ClientConfig {
+            proxy_protocol: true,
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, Default::default(), config);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"hello").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let peer = internet.local_addr().unwrap();
+        let exposed = internet.peer_addr().unwrap();
+
+        let expected = format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            peer.ip(),
+            exposed.ip(),
+            peer.port(),
+            exposed.port()
+        );
+
+        assert_eq!(read_line(&mut local), expected);
+
+        let mut data: [u8; 5] = [0; 5];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hello");
+    }
+
+    #[test]
+    fn no_proxy_protocol_by_default() {
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"hello").unwrap();
+        internet.shutdown(Shutdown::Write).unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data = Vec::new();
+        local.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn path_probe() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let config = ClientConfig {
+            path_probe: true,
+            probe_clamp: true,
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, Default::default(), config);
+
+        //
+        // probes and data share the tunnel
+        //
+        let mut internet = connect_retry(&tunnel.server);
+        let data = vec![0x42; 256 * 1024];
+        internet.write_all(&data).unwrap();
+        internet.shutdown(Shutdown::Write).unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut received = Vec::new();
+        local.read_to_end(&mut received).unwrap();
+        assert!(received == data);
+    }
+
+    #[test]
+    fn motd_suppressed() {
+        let config = ClientConfig {
+            motd: true,
+            ..Default::default()
+        };
+        assert!(client_hello(&config).has_feature(FEATURE_BANNER));
+
+        // --no-motd, the server never sends it
+        assert!(!client_hello(&ClientConfig::default()).has_feature(FEATURE_BANNER));
+    }
+
+    #[test]
+    fn max_rate() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let config = ClientConfig {
+            max_rate: Some(64 * 1024),
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, Default::default(), config);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"go").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        // unread data would turn the close into a reset
+        let mut go: [u8; 2] = [0; 2];
+        local.read_exact(&mut go).unwrap();
+
+        //
+        // 128KB at 64KB/s from the client side, about 2s
+        //
+        let data = vec![0x42; 128 * 1024];
+        let start = Instant::now();
+
+        local.write_all(&data).unwrap();
+        drop(local);
+
+        let mut received = Vec::new();
+        internet.read_to_end(&mut received).unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(received.len(), data.len());
+        assert!(elapsed > Duration::from_millis(1500), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(6), "{elapsed:?}");
+    }
+
+    #[test]
+    fn no_dial_for_dead_tokens() {
+        const CLIENTS: usize = 100;
+
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"up").unwrap();
+        drop(listener.accept().unwrap());
+        drop(internet);
+
+        for _ in 0..CLIENTS {
+            drop(std::net::TcpStream::connect(&tunnel.server).unwrap());
+        }
+
+        //
+        // each one gets dialed at most once, whatever state its token was in
+        // when the events came
+        //
+        listener.set_nonblocking(true).unwrap();
+
+        let mut dials = 0;
+        let start = Instant::now();
+
+        while start.elapsed() < Duration::from_secs(2) {
+            match listener.accept() {
+                Ok(_) => dials += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => sleep(Duration::from_millis(10)),
+                Err(e) => panic!("{e}"),
+            }
+        }
+
+        assert!(dials <= CLIENTS, "{dials} dials for {CLIENTS} connections");
+
+        // and the session is still fine
+        listener.set_nonblocking(false).unwrap();
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"after").unwrap();
+
+        // skipping a straggler from the churn, if any
+        loop {
+            let (mut local, _) = listener.accept().unwrap();
+            local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+            let mut data: [u8; 5] = [0; 5];
+            if local.read_exact(&mut data).is_ok() {
+                assert_eq!(&data, b"after");
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let delay = Duration::from_millis(500);
+        let max = DEF_RECONNECT_MAX;
+
+        assert_eq!(reconnect_policy(delay, max, None), Some(delay));
+        assert_eq!(reconnect_policy(delay, max, Some(GoodbyeReason::Operator)), Some(delay));
+        assert_eq!(
+            reconnect_policy(delay, max, Some(GoodbyeReason::Internal)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            reconnect_policy(delay, max, Some(GoodbyeReason::ConfigError)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(reconnect_policy(delay, max, Some(GoodbyeReason::AuthRevoked)), None);
+        assert_eq!(
+            reconnect_policy(delay, max, Some(GoodbyeReason::Busy)),
+            Some(Duration::from_secs(5))
+        );
+
+        // --reconnect-delay 3000 on a config-error, capped
+        assert_eq!(
+            reconnect_policy(Duration::from_secs(3), max, Some(GoodbyeReason::ConfigError)),
+            Some(max)
+        );
+    }
+
+    fn send_frame(stream: &mut std::net::TcpStream, addr: Address, msg: PacketMessage, data: &[u8]) {
+        let mut frame = Vec::new();
+        Packet::new(addr, msg, data.len() as u16).encode(&mut frame).unwrap();
+        frame.extend_from_slice(data);
+        stream.write_all(&frame).unwrap();
+    }
+
+    fn recv_frame(stream: &mut std::net::TcpStream) -> (Packet, Vec<u8>) {
+        let mut hdr: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        stream.read_exact(&mut hdr).unwrap();
+
+        let (p, _) = Packet::from_buffer(&hdr).unwrap();
+        let mut data = vec![0; p.data_len as usize];
+        stream.read_exact(&mut data).unwrap();
+
+        (p, data)
+    }
+
+    //
+    // Plays the server, which allows more than the client can carry
+    //
+    #[test]
+    fn refused_above_capacity() {
+        let (_listener, endpoint_addr) = endpoint();
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            server: endpoint_addr,
+            reconnect_delay: Duration::from_millis(50),
+            max_connections: Some(1),
+            ..Default::default()
+        };
+
+        std::thread::spawn(move || client_main(&config));
+
+        let (mut server, _) = tunnel.accept().unwrap();
+        server.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let hello = Hello {
+            forwards: vec!["test".to_string()],
+            max_connections: Some(100),
+            ..Default::default()
+        };
+        send_frame(&mut server, CONTROL_ADDRESS, PacketMessage::Hello, &hello.encode());
+
+        let (p, data) = recv_frame(&mut server);
+        assert_eq!(p.msg, PacketMessage::Hello);
+        assert_eq!(Hello::decode(&data).unwrap().max_connections, Some(1));
+
+        let info = ConnectInfo {
+            peer: "127.0.0.1:1000".parse().unwrap(),
+            local: "127.0.0.1:2000".parse().unwrap(),
+            channel: 0,
+        };
+
+        for addr in [4, 5] {
+            send_frame(&mut server, addr, PacketMessage::Connect, &info.encode().unwrap());
+        }
+
+        loop {
+            let (p, data) = recv_frame(&mut server);
+
+            if PacketMessage::ConnectionRefused == p.msg {
+                assert_eq!(p.addr, 5);
+                assert_eq!(RefuseReason::decode(&data), Some(RefuseReason::CapacityExceeded));
+                break;
+            }
+        }
+    }
+
+    //
+    // Many frames in one segment all go out on that readable event, and an
+    // endpoint that can't be reached costs its connection, not the session
+    //
+    #[test]
+    fn pipelined_frames() {
+        const FRAMES: usize = 50;
+
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            reconnect_delay: Duration::from_millis(50),
+            connect_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        config.endpoints.insert("test".to_string(), endpoint_addr);
+        config.endpoints.insert("bad".to_string(), "192.0.2.1:22".to_string());
+
+        std::thread::spawn(move || client_main(&config));
+
+        let (mut server, _) = tunnel.accept().unwrap();
+        server.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let hello = Hello {
+            forwards: vec!["test".to_string(), "bad".to_string()],
+            ..Default::default()
+        };
+        send_frame(&mut server, CONTROL_ADDRESS, PacketMessage::Hello, &hello.encode());
+        assert_eq!(recv_frame(&mut server).0.msg, PacketMessage::Hello);
+
+        let mut info = ConnectInfo {
+            peer: "127.0.0.1:1000".parse().unwrap(),
+            local: "127.0.0.1:2000".parse().unwrap(),
+            channel: 1,
+        };
+        send_frame(&mut server, 5, PacketMessage::Connect, &info.encode().unwrap());
+
+        // one write
+        info.channel = 0;
+        let mut batch = Vec::new();
+        let connect = info.encode().unwrap();
+        Packet::new(4, PacketMessage::Connect, connect.len() as u16)
+            .encode(&mut batch)
+            .unwrap();
+        batch.extend_from_slice(&connect);
+        for _ in 0..FRAMES {
+            Packet::new_data(4, 10).encode(&mut batch).unwrap();
+            batch.extend_from_slice(b"0123456789");
+        }
+
+        let start = Instant::now();
+        server.write_all(&batch).unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+        let mut data = vec![0; FRAMES * 10];
+        local.read_exact(&mut data).unwrap();
+        assert!(start.elapsed() < TICK_INTERVAL, "{:?}", start.elapsed());
+
+        loop {
+            let (p, _) = recv_frame(&mut server);
+            assert_ne!(p.msg, PacketMessage::Hello);
+
+            if PacketMessage::ConnectionRefused == p.msg {
+                assert_eq!(p.addr, 5);
+                break;
+            }
+        }
+
+        // same session
+        send_frame(&mut server, 4, PacketMessage::Data, b"x");
+        let mut data: [u8; 1] = [0; 1];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"x");
+    }
+
+    //
+    // The endpoint hangs up while the server still sends for it, what comes
+    // after is dropped, never dialed again
+    //
+    #[test]
+    fn data_after_endpoint_eof() {
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            server: endpoint_addr,
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        std::thread::spawn(move || client_main(&config));
+
+        let (mut server, _) = tunnel.accept().unwrap();
+        server.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let hello = Hello {
+            forwards: vec!["test".to_string()],
+            ..Default::default()
+        };
+        send_frame(&mut server, CONTROL_ADDRESS, PacketMessage::Hello, &hello.encode());
+        assert_eq!(recv_frame(&mut server).0.msg, PacketMessage::Hello);
+
+        let info = ConnectInfo {
+            peer: "127.0.0.1:1000".parse().unwrap(),
+            local: "127.0.0.1:2000".parse().unwrap(),
+            channel: 0,
+        };
+        send_frame(&mut server, 4, PacketMessage::Connect, &info.encode().unwrap());
+
+        let (local, _) = listener.accept().unwrap();
+        drop(local);
+
+        loop {
+            let (p, _) = recv_frame(&mut server);
+            if 4 == p.addr && PacketMessage::Data != p.msg {
+                break;
+            }
+        }
+
+        for _ in 0..10 {
+            send_frame(&mut server, 4, PacketMessage::Data, b"late");
+        }
+
+        // still the same session and nobody dialed the endpoint again
+        send_frame(&mut server, CONTROL_ADDRESS, PacketMessage::Probe, &[]);
+        loop {
+            let (p, _) = recv_frame(&mut server);
+            assert_ne!(p.msg, PacketMessage::Hello);
+            if PacketMessage::ProbeReply == p.msg {
+                break;
+            }
+        }
+
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn endpoint_routes() {
+        let now = Instant::now();
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        let mut config = ClientConfig {
+            server: "127.0.0.1:80".to_string(),
+            ..Default::default()
+        };
+        config.endpoints.insert("ssh".to_string(), "127.0.0.1:22".to_string());
+
+        let mut endpoints = Endpoints::resolve(&config).unwrap();
+        assert_eq!(endpoints.get("ssh").unwrap().first(now), addr("127.0.0.1:22"));
+        // --server-address, the default route
+        assert_eq!(endpoints.get("web").unwrap().first(now), addr("127.0.0.1:80"));
+
+        config.server.clear();
+        let mut endpoints = Endpoints::resolve(&config).unwrap();
+        assert!(endpoints.get("web").is_none());
+    }
+
+    //
+    // Host names for the endpoints, a bad one stops the client right away
+    //
+    #[test]
+    fn endpoint_names() {
+        let (listener, addr) = endpoint();
+        let port = addr.rsplit_once(':').unwrap().1;
+
+        let tunnel = start_tunnel(&format!("localhost:{port}"));
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+        let mut data: [u8; 1] = [0; 1];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"x");
+
+        let mut config = ClientConfig {
+            tunnel: tunnel.tunnel,
+            ..Default::default()
+        };
+        config.endpoints.insert("test".to_string(), "pvpn.invalid:22".to_string());
+
+        match client_main(&config) {
+            Err(Error::Unresolved { flag, .. }) => assert_eq!(flag, "--endpoint"),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    //
+    // A blackholed endpoint ( RFC 5737 ), the internet peer isn't left
+    // waiting on the OS connect timeout
+    //
+    #[test]
+    fn endpoint_connect_timeout() {
+        let client_config = ClientConfig {
+            connect_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with("192.0.2.1:22", Default::default(), client_config);
+
+        let mut internet = connect_retry(&tunnel.server);
+        let start = Instant::now();
+        internet.write_all(b"x").unwrap();
+
+        let mut data: [u8; 1] = [0; 1];
+        assert!(matches!(internet.read(&mut data), Ok(0) | Err(_)));
+        assert!(start.elapsed() < CONNECT_TIMEOUT);
+    }
+
+    //
+    // A server that hangs up right away, then none at all, both count
+    //
+    #[test]
+    fn max_reconnect_attempts() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tunnel = listener.local_addr().unwrap().to_string();
+
+        let accepted = std::thread::spawn(move || {
+            listener.set_nonblocking(true).unwrap();
+            let start = Instant::now();
+            let mut accepted = 0;
+
+            while start.elapsed() < Duration::from_secs(2) {
+                match listener.accept() {
+                    Ok(_) => accepted += 1,
+                    Err(_) => sleep(Duration::from_millis(5)),
+                }
+            }
+            accepted
+        });
+
+        let config = ClientConfig {
+            tunnel,
+            reconnect_delay: Duration::from_millis(10),
+            max_reconnect_attempts: Some(3),
+            ..Default::default()
+        };
+
+        match client_main(&config) {
+            Err(Error::TooManyFailures { failures }) => assert_eq!(failures, 3),
+            v => panic!("{v:?}"),
+        }
+        assert_eq!(accepted.join().unwrap(), 3);
+
+        // nothing listening anymore
+        match client_main(&config) {
+            Err(Error::TooManyFailures { failures }) => assert_eq!(failures, 3),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    #[test]
+    fn local_bind() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let client_config = ClientConfig {
+            endpoint_bind: LocalBind {
+                addr: Some("127.0.0.2".parse().unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&endpoint_addr, Default::default(), client_config);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+
+        let (_local, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip().to_string(), "127.0.0.2");
+
+        // the tunnel
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            tunnel_bind: LocalBind {
+                addr: Some("127.0.0.3".parse().unwrap()),
+                ..Default::default()
+            },
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        std::thread::spawn(move || client_main(&config));
+
+        let (_server, peer) = tunnel.accept().unwrap();
+        assert_eq!(peer.ip().to_string(), "127.0.0.3");
+    }
+
+    //
+    // A server that went quiet, a NAT that dropped the mapping. Keepalives
+    // first, then the client gives up on it and reconnects
+    //
+    #[test]
+    fn server_silent() {
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            reconnect_delay: Duration::from_millis(10),
+            tunnel_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        std::thread::spawn(move || client_main(&config));
+
+        let (mut server, _) = tunnel.accept().unwrap();
+        server.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let hello = Hello {
+            forwards: vec!["test".to_string()],
+            ..Default::default()
+        };
+        send_frame(&mut server, CONTROL_ADDRESS, PacketMessage::Hello, &hello.encode());
+
+        let start = Instant::now();
+
+        loop {
+            let (p, _) = recv_frame(&mut server);
+            if PacketMessage::Probe == p.msg {
+                break;
+            }
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // never answered
+        tunnel.set_nonblocking(true).unwrap();
+        while tunnel.accept().is_err() {
+            assert!(start.elapsed() < TEST_TIMEOUT);
+            sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+
+    //
+    // The first server of the list is down, the client settles on the next
+    //
+    #[test]
+    fn tunnel_failover() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let socket = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let internet = socket.local_addr().unwrap().to_string();
+        let forwards = vec![Forward {
+            label: "test".to_string(),
+            socket,
+        }];
+
+        let backup = format!("127.0.0.1:{}", free_port());
+        let server_config = ServerConfig {
+            tunnel: backup.clone(),
+            ..Default::default()
+        };
+        std::thread::spawn(move || server_main(&server_config, forwards));
+
+        let config = ClientConfig {
+            tunnel: format!("127.0.0.1:{}", free_port()),
+            tunnel_failover: vec![backup],
+            server: endpoint_addr,
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        std::thread::spawn(move || client_main(&config));
+
+        let mut internet = connect_retry(&internet);
+        internet.write_all(b"hello").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut buf = [0; 5];
+        local.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        local.write_all(b"world").unwrap();
+        internet.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    //
+    // What a reconnect can't fix ends client_main, not an endless loop
+    //
+    #[test]
+    fn fatal_errors() {
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let start = Instant::now();
+
+        let config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            server: "pvpn.invalid:22".to_string(),
+            ..Default::default()
+        };
+        match client_main(&config) {
+            Err(Error::Unresolved { flag, .. }) => assert_eq!(flag, "--server-address"),
+            v => panic!("{v:?}"),
+        }
+
+        // not an address of this host, failed on the first attempt
+        let config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            tunnel_bind: LocalBind {
+                addr: Some("192.0.2.1".parse().unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        match client_main(&config) {
+            Err(e @ Error::LocalBindFailed { .. }) => assert_eq!(e.class(), ErrorClass::Fatal),
+            v => panic!("{v:?}"),
+        }
+
+        assert!(start.elapsed() < TEST_TIMEOUT);
+    }
+
+    #[test]
+    fn max_endpoint_connections() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let client_config = ClientConfig {
+            max_connections: Some(2),
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&endpoint_addr, Default::default(), client_config);
+
+        let mut internet: Vec<std::net::TcpStream> = (0..5)
+            .map(|_| {
+                let mut v = connect_retry(&tunnel.server);
+                v.write_all(b"x").unwrap();
+                v
+            })
+            .collect();
+
+        let mut locals = Vec::new();
+        for _ in 0..2 {
+            locals.push(listener.accept().unwrap());
+        }
+
+        // the others fail right away, these two stay up
+        let start = Instant::now();
+        let mut failed = 0;
+
+        for v in internet.iter_mut() {
+            v.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+
+            let mut data = [0; 1];
+            match v.read(&mut data) {
+                Ok(0) => failed += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => failed += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                v => panic!("{v:?}"),
             }
         }
+        assert_eq!(failed, 3);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    //
+    // The tunnel goes away: what the endpoint was sent reaches it before an
+    // orderly close, and the next session starts from nothing
+    //
+    #[test]
+    fn fresh_session() {
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let config = ClientConfig {
+            tunnel: tunnel.local_addr().unwrap().to_string(),
+            server: endpoint_addr,
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        std::thread::spawn(move || client_main(&config));
+
+        let hello = Hello {
+            forwards: vec!["test".to_string()],
+            ..Default::default()
+        };
+        let info = ConnectInfo {
+            peer: "127.0.0.1:1000".parse().unwrap(),
+            local: "127.0.0.1:2000".parse().unwrap(),
+            channel: 0,
+        };
+
+        let (mut server, _) = tunnel.accept().unwrap();
+        server.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+        send_frame(&mut server, CONTROL_ADDRESS, PacketMessage::Hello, &hello.encode());
+        assert_eq!(recv_frame(&mut server).0.msg, PacketMessage::Hello);
+
+        send_frame(&mut server, 4, PacketMessage::Connect, &info.encode().unwrap());
+        send_frame(&mut server, 4, PacketMessage::Data, b"before");
+        drop(server);
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data = Vec::new();
+        local.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"before");
+
+        // address 4 means nothing to the new session until it's connected
+        let (mut server, _) = tunnel.accept().unwrap();
+        server.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+        send_frame(&mut server, CONTROL_ADDRESS, PacketMessage::Hello, &hello.encode());
+        assert_eq!(recv_frame(&mut server).0.msg, PacketMessage::Hello);
+
+        send_frame(&mut server, 4, PacketMessage::Data, b"stale");
+        send_frame(&mut server, 4, PacketMessage::Connect, &info.encode().unwrap());
+        send_frame(&mut server, 4, PacketMessage::Data, b"after");
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data = [0; 5];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"after");
 
-        sleep(Duration::from_millis(reconnect_delay));
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
     }
 }
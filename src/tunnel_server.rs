@@ -1,184 +1,4963 @@
-use log::{error, info, warn};
+use log::{Level, debug, error, info, log_enabled, warn};
 use mio::{
-    Events, Interest, Poll, Token,
-    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Registry, Token,
+    event::{Event, Source},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    io::ErrorKind,
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
-use std::io::ErrorKind;
 
 use crate::{
-    error::{Error, Result},
-    packet::PacketMessage,
-    streams::{BUFFER_SIZE, ClientStream, TokenStreams},
+    access_log::AccessLog,
+    acl::{Acl, Gate},
+    api::{ForwardStatus, Status, TunnelStatus},
+    churn::{ChurnConfig, ChurnDetector, ChurnEvent},
+    clock::{ClockEvent, ClockSample, ResumeDetector},
+    control::{Control, DEF_OVERRIDE_TTL, Override, Target},
+    error::{Error, ErrorClass, Result},
+    handshake::{
+        FEATURE_BANNER, FEATURE_RELEASE, FEATURE_STATS, Goodbye, GoodbyeReason, Hello, load_motd, queue_goodbye,
+        send_goodbye, session_max_connections, validate_label,
+    },
+    logging::{REPEAT_SUMMARY, RepeatLog},
+    overload::{Overload, OverloadEvent},
+    packet::{Address, CONTROL_ADDRESS, ConnectInfo, Packet, PacketMessage},
+    ratelimit::{AcceptRate, REFILL_INTERVAL},
+    resource::{Usage, check_fds, fd_count, last_fd_check},
+    signals::take_sighup,
+    stats::{PeerStats, STATS_INTERVAL},
+    streams::{
+        ClientStream, CloseReason, DRAIN_TIMEOUT, HALF_CLOSE_TIMEOUT, ReadOutcome, TEARDOWN_FLUSH, TICK_INTERVAL,
+        TokenStreams,
+    },
+    timers::{Housekeeping, Timers},
+    udp::{DEF_UDP_TIMEOUT, MAX_DATAGRAM, Protocol, UdpFlows},
+    unwind::{catch_session, failpoint, panic_count, stall_point},
+    watchdog::{Activity, Watchdog},
+    webhook::{EventKind, Webhook, WebhookConfig},
 };
 
 // Ports that the client side conected to
 const TUNNEL_PORT: Token = Token(1);
 // Stream between the client and the server
 const TUNNEL_STREAM: Token = Token(2);
-// Internet exposed port
-const INTERNET_PORT: Token = Token(3);
+// Internet exposed ports, one per forward, past the u16 address space so they
+// never collide with a stream
+const FIRST_LISTENER: usize = 0x1_0000;
+// --max-clients, each client's streams in their own u16 range past those
+const FIRST_CLIENT: usize = 0x100_0000;
+const CLIENT_TOKENS: usize = 0x1_0000;
+// and how often a client that went away is looked at until it's drained
+const TEARDOWN_POLL: Duration = Duration::from_millis(10);
+// Out of fds, accepting again right away would only spin
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(250);
+// Connections waiting for a tunnel, per forward, the others wait in the backlog
+const PARK_LIMIT: usize = 128;
+// and for that long at most
+const PARK_TIMEOUT: Duration = Duration::from_secs(30);
+// After a session that failed, a client stuck in a loop doesn't spin us too
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+// A session that lasted that long clears --max-tunnel-failures
+const STABLE_SESSION: Duration = Duration::from_secs(60);
 
-fn tunnel_accept(tunnel: &str) -> Result<TcpStream> {
-    let mut poll = Poll::new()?;
-    let mut events = Events::with_capacity(128);
+pub enum ForwardSocket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+    // closed by remove-forward, the slot keeps the others' channel
+    Removed,
+}
 
-    let tunnel_addr = tunnel.parse()?;
-    let mut tunnel_listener = TcpListener::bind(tunnel_addr)?;
+impl ForwardSocket {
+    //
+    // A listening socket someone else bound, systemd's
+    //
+    pub fn from_fd(fd: OwnedFd, protocol: Protocol) -> Result<Self> {
+        let socket = match protocol {
+            Protocol::Tcp => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                ForwardSocket::Tcp(TcpListener::from_std(listener))
+            }
+            Protocol::Udp => {
+                let socket = std::net::UdpSocket::from(fd);
+                socket.set_nonblocking(true)?;
+                ForwardSocket::Udp(UdpSocket::from_std(socket))
+            }
+        };
+        Ok(socket)
+    }
 
-    poll.registry()
-        .register(&mut tunnel_listener, TUNNEL_PORT, Interest::READABLE)?;
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let addr = match self {
+            ForwardSocket::Tcp(v) => v.local_addr()?,
+            ForwardSocket::Udp(v) => v.local_addr()?,
+            ForwardSocket::Removed => return Err(std::io::Error::from(ErrorKind::NotConnected).into()),
+        };
+        Ok(addr)
+    }
+}
 
-    poll.poll(&mut events, None)?;
+impl Source for ForwardSocket {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> std::io::Result<()> {
+        match self {
+            ForwardSocket::Tcp(v) => v.register(registry, token, interests),
+            ForwardSocket::Udp(v) => v.register(registry, token, interests),
+            ForwardSocket::Removed => Ok(()),
+        }
+    }
 
-    for event in events.iter() {
-        if TUNNEL_PORT == event.token() {
-            //
-            // This is the pvpn client connecting
-            //
-            let (tstream, iaddr) = tunnel_listener.accept()?;
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> std::io::Result<()> {
+        match self {
+            ForwardSocket::Tcp(v) => v.reregister(registry, token, interests),
+            ForwardSocket::Udp(v) => v.reregister(registry, token, interests),
+            ForwardSocket::Removed => Ok(()),
+        }
+    }
 
-            info!("tunnel connected: {:?}", iaddr);
-            return Ok(tstream);
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        match self {
+            ForwardSocket::Tcp(v) => v.deregister(registry),
+            ForwardSocket::Udp(v) => v.deregister(registry),
+            ForwardSocket::Removed => Ok(()),
         }
     }
+}
 
-    Err(Error::ClientNotFound)
+//
+// An internet facing socket and the name of the service it exposes, the
+// client picks the endpoint by that name
+//
+pub struct Forward {
+    pub label: String,
+    pub socket: ForwardSocket,
 }
 
-fn tunnel_handler(mut tstream: TcpStream, server: &str) -> Result<()> {
-    info!("starting internet listener on {server}");
+struct Listener {
+    forward: Forward,
+    // per forward, outlives the sessions
+    churn: ChurnDetector,
+    // out of fds, nothing is accepted before then
+    accept_paused: Option<Instant>,
+    // logged when it started, until an accept works again
+    fd_exhausted: bool,
+    // accepted while there was no tunnel
+    parked: VecDeque<(TcpStream, SocketAddr, Instant)>,
+    // --allow-cidr and --deny-cidr
+    gate: Gate,
+}
 
-    let mut poll = Poll::new()?;
+impl Listener {
+    fn new(forward: Forward, config: &ServerConfig) -> Self {
+        Self {
+            forward,
+            churn: ChurnDetector::new(config.churn.clone(), Instant::now()),
+            accept_paused: None,
+            fd_exhausted: false,
+            parked: VecDeque::new(),
+            gate: Gate::new(config.acl.clone(), config.accept_rate),
+        }
+    }
 
-    let mut events = Events::with_capacity(128);
+    //
+    // How long before accept_forward() does anything
+    //
+    fn accept_wait(&self, now: Instant) -> Duration {
+        match self.accept_paused {
+            Some(until) => until.saturating_duration_since(now),
+            None => Duration::ZERO,
+        }
+    }
+}
 
-    let server_addr = server.parse()?;
+//
+// --lazy-listen, a forward bound for the time of a session. Same address and
+// port every time, the one main() bound first
+//
+#[derive(Debug, Clone)]
+struct LazyForward {
+    label: String,
+    addr: SocketAddr,
+    protocol: Protocol,
+}
 
-    let mut server_listener = TcpListener::bind(server_addr)?;
+impl LazyForward {
+    fn new(forward: &Forward) -> Result<Self> {
+        let protocol = match forward.socket {
+            ForwardSocket::Udp(_) => Protocol::Udp,
+            _ => Protocol::Tcp,
+        };
 
-    let mut token_id: usize = 4;
+        Ok(Self {
+            label: forward.label.clone(),
+            addr: forward.socket.local_addr()?,
+            protocol,
+        })
+    }
 
-    let mut streams = TokenStreams::new();
+    fn bind(&self) -> Result<Forward> {
+        let host = self.addr.ip().to_string();
 
-    let mut read_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        Ok(Forward {
+            label: self.label.clone(),
+            socket: bind_forward(&host, &[self.addr.port()], self.protocol)?,
+        })
+    }
+}
 
-    poll.registry()
-        .register(&mut tstream, TUNNEL_STREAM, Interest::READABLE | Interest::WRITABLE)?;
+//
+// The client said hello, the internet ports open
+//
+fn listen_lazy(poll: &Poll, listeners: &mut Vec<Listener>, lazy: &[LazyForward], config: &ServerConfig) -> Result<()> {
+    for lf in lazy {
+        let mut listener = Listener::new(lf.bind()?, config);
 
-    poll.registry().register(
-        &mut server_listener,
-        INTERNET_PORT,
-        Interest::READABLE | Interest::WRITABLE,
-    )?;
+        let token = Token(FIRST_LISTENER + listeners.len());
+        poll.registry()
+            .register(&mut listener.forward.socket, token, Interest::READABLE)?;
 
-    streams.add(TUNNEL_STREAM.0, ClientStream::new(tstream)?);
+        info!("[{}] internet listener on {}", lf.label, lf.addr);
+        listeners.push(listener);
+    }
 
-    info!("-----------------------------SERVER-----------------------------");
+    Ok(())
+}
 
-    loop {
-        poll.poll(&mut events, None)?;
+//
+// What the internet connections get between two tunnels
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WhenDown {
+    // parked until the next tunnel, PARK_LIMIT and PARK_TIMEOUT
+    #[default]
+    Queue,
+    // accepted and closed right away
+    Refuse,
+}
 
-        for event in events.iter() {
-            if INTERNET_PORT == event.token() {
-                //
-                //
-                //
-                let (mut istream, iaddr) = server_listener.accept()?;
-                info!("internet connected: {:?} (token={token_id})", iaddr);
+impl FromStr for WhenDown {
+    type Err = String;
 
-                let token = Token(token_id);
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(WhenDown::Queue),
+            "refuse" => Ok(WhenDown::Refuse),
+            _ => Err("expecting queue or refuse".to_string()),
+        }
+    }
+}
 
-                poll.registry()
-                    .register(&mut istream, token, Interest::READABLE | Interest::WRITABLE)?;
+impl Display for WhenDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WhenDown::Queue => write!(f, "queue"),
+            WhenDown::Refuse => write!(f, "refuse"),
+        }
+    }
+}
 
-                let iclient = ClientStream::new(istream)?;
-                streams.add(token.0, iclient);
+fn is_fd_exhaustion(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
 
-                token_id += 1;
-            } else if TUNNEL_STREAM == event.token() && event.is_readable() {
-                // it's fatal if we the tunnel read fails
-
-                streams.flush_read(TUNNEL_STREAM.0, &mut read_buffer)?;
-
-                loop {
-                    match streams.read_packet(&mut read_buffer) {
-                        Ok((read_len, dst_addr)) => {
-                            if let Err(e) = streams.write(dst_addr, &read_buffer[0..read_len]) {
-                                warn!("Connection terminated ({e})");
-                                let msg = e.into();
-                                if let Err(e) = streams.write_message(TUNNEL_STREAM.0, event.token().0, msg) {
-                                    error!("unable to write message for {} ({e})", event.token().0);
-                                    return Err(e);
-                                }
-                            }
-                        }
-                        Err(Error::Empty) => {
-                            // not a failure case
-                            break;
-                        }
-                        Err(Error::NotEnoughData) => {
-                            // not a failure case
-                            break;
-                        }
-                        Err(Error::Eof) => {
-                            break;
-                        }
-                        Err(e) => {
-                            error!("{e}");
-                            return Err(e);
-                        }
-                    }
-                }
-            } else if TUNNEL_STREAM == event.token() && event.is_writable() {
-                if let Err(e) = streams.flush(TUNNEL_STREAM.0) {
-                    error!("flush failure for {} {e}", TUNNEL_STREAM.0);
-                    return Err(e);
-                }
-            } else if event.is_readable() {
-                loop {
-                    match streams.read(event.token().0, &mut read_buffer) {
-                        Ok(0) => break,
-                        Ok(v) => {
-                            info!("read {v} bytes from internet {:?}", event.token());
-                            streams.write_packet(TUNNEL_STREAM.0, event.token().0, &read_buffer[0..v])?;
-                        }
-                        Err(e) => {
-                            info!("{e}");
-                            streams.write_message(TUNNEL_STREAM.0, event.token().0, PacketMessage::Disconnected)?;
-                            break;
-                        }
-                    }
+fn listener_index(token: Token, listeners: &[Listener]) -> Option<usize> {
+    let idx = token.0.checked_sub(FIRST_LISTENER)?;
+
+    match idx < listeners.len() {
+        true => Some(idx),
+        false => None,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    // internet facing address, the port is picked by bind_internet()
+    pub server_address: String,
+    // address the pvpn client connects to
+    pub tunnel: String,
+    // 0 disables the watchdog
+    pub watchdog_timeout: Duration,
+    // banner file, reloaded on SIGHUP
+    pub motd_path: Option<PathBuf>,
+    // its content, sent to the clients that ask for it
+    pub motd: Option<String>,
+    pub churn: ChurnConfig,
+    // idle UDP mappings are dropped after that, DEF_UDP_TIMEOUT if None
+    pub udp_timeout: Option<Duration>,
+    // idle TCP connections are closed after that, never if None
+    pub idle_timeout: Option<Duration>,
+    // SO_KEEPALIVE idle time of the tunnel and the connections, off if None
+    pub tcp_keepalive: Option<Duration>,
+    // drop the tunnel when nothing was read from it for that long, 0 disables
+    pub tunnel_timeout: Duration,
+    pub webhook: Option<WebhookConfig>,
+    // bytes per second written to the tunnel
+    pub max_rate: Option<u64>,
+    // per stream data waiting for its socket, DEF_MAX_BUFFERED if None
+    pub max_buffered: Option<usize>,
+    // bytes read from a socket at once, BUFFER_SIZE if None
+    pub buffer_size: Option<usize>,
+    // operator commands, see control.rs
+    pub control_socket: Option<PathBuf>,
+    // overrides go away after that, DEF_OVERRIDE_TTL if None
+    pub override_ttl: Option<Duration>,
+    // complain when a session leaves fds behind
+    pub check_fds: bool,
+    // internet connections carried at once, the client may ask for less.
+    // No limit if None
+    pub max_connections: Option<usize>,
+    // connections past max_connections wait in the backlog, closed if false
+    pub queue_when_full: bool,
+    // tunnel clients served at once, 0 and 1 are one at a time
+    pub max_clients: usize,
+    // internet connections while there's no tunnel
+    pub when_down: WhenDown,
+    // internet peers let in, everyone if empty
+    pub acl: Acl,
+    // a new client replaces the current one, refused as busy otherwise
+    pub tunnel_takeover: bool,
+    // JSON lines, one per internet connection
+    pub access_log: Option<PathBuf>,
+    // listening already, the tunnel address isn't bound then ( systemd )
+    pub tunnel_fd: Option<Arc<OwnedFd>>,
+    // new internet connections per source address, no limit if None
+    pub accept_rate: Option<AcceptRate>,
+    // the internet ports are only open while a client is attached
+    pub lazy_listen: bool,
+    // failed sessions in a row before giving up, never if None
+    pub max_tunnel_failures: Option<usize>,
+}
+
+//
+// "8080,8081,8090-8099,0" : ports to try in order, 0 ( any port ) can only
+// be the last resort
+//
+pub fn parse_port_list(spec: &str) -> Result<Vec<u16>> {
+    let invalid = || Error::InvalidPortList { spec: spec.to_string() };
+
+    let mut ports = Vec::new();
+
+    for item in spec.split(',') {
+        let item = item.trim();
+
+        if ports.contains(&0) {
+            // 0 has to be the last one
+            return Err(invalid());
+        }
+
+        match item.split_once('-') {
+            Some((start, end)) => {
+                let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+                let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+
+                if 0 == start || start > end {
+                    return Err(invalid());
                 }
-            } else if event.is_writable() {
+
+                ports.extend(start..=end);
+            }
+            None => ports.push(item.parse().map_err(|_| invalid())?),
+        }
+    }
+
+    Ok(ports)
+}
+
+//
+// "8080,8081:web" : port list and service name of a --forward
+//
+pub fn parse_forward(spec: &str) -> Result<(Vec<u16>, String)> {
+    let (ports, label) = match spec.rsplit_once(':') {
+        Some(v) => v,
+        None => return Err(Error::InvalidForward { spec: spec.to_string() }),
+    };
+
+    validate_label(label)?;
+
+    Ok((parse_port_list(ports)?, label.to_string()))
+}
+
+//
+// Binds the first port of the list that is available. Binding is the check,
+// there's no window between testing a port and using it
+//
+pub fn bind_internet(address: &str, ports: &[u16]) -> Result<TcpListener> {
+    bind_first(address, ports, TcpListener::bind)
+}
+
+pub fn bind_forward(address: &str, ports: &[u16], protocol: Protocol) -> Result<ForwardSocket> {
+    match protocol {
+        Protocol::Tcp => Ok(ForwardSocket::Tcp(bind_internet(address, ports)?)),
+        Protocol::Udp => Ok(ForwardSocket::Udp(bind_first(address, ports, UdpSocket::bind)?)),
+    }
+}
+
+//
+// Where the clients connect, handed over or bound to config.tunnel
+//
+fn bind_tunnel(config: &ServerConfig) -> Result<TcpListener> {
+    let fd = match &config.tunnel_fd {
+        Some(v) => v.try_clone()?,
+        None => return Ok(TcpListener::bind(config.tunnel.parse()?)?),
+    };
+
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+
+    Ok(TcpListener::from_std(listener))
+}
+
+//
+// "::1" and 8080 make "[::1]:8080", a bracketed or v4 host is used as is
+//
+pub fn host_port(host: &str, port: u16) -> String {
+    match host.contains(':') && !host.starts_with('[') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    }
+}
+
+//
+// [::]:port for the v6 peers only, next to a v4 socket on the same port
+// ( --dual-stack ). Plain binds of :: take the v4 peers too on Linux
+//
+pub fn bind_v6_only(port: u16, protocol: Protocol) -> Result<ForwardSocket> {
+    let kind = match protocol {
+        Protocol::Tcp => libc::SOCK_STREAM,
+        Protocol::Udp => libc::SOCK_DGRAM,
+    };
+
+    let fd = unsafe { libc::socket(libc::AF_INET6, kind, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let on: libc::c_int = 1;
+    let set = |level, name| {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                level,
+                name,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+
+    set(libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?;
+    // same as std's listeners
+    if Protocol::Tcp == protocol {
+        set(libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    }
+
+    let mut sin6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sin6.sin6_port = port.to_be();
+
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &sin6 as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    };
+    if 0 != ret {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let socket = match protocol {
+        Protocol::Tcp => {
+            if 0 != unsafe { libc::listen(fd.as_raw_fd(), 128) } {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true)?;
+            ForwardSocket::Tcp(TcpListener::from_std(listener))
+        }
+        Protocol::Udp => {
+            let socket = std::net::UdpSocket::from(fd);
+            socket.set_nonblocking(true)?;
+            ForwardSocket::Udp(UdpSocket::from_std(socket))
+        }
+    };
+
+    Ok(socket)
+}
+
+fn bind_first<T, F>(address: &str, ports: &[u16], bind: F) -> Result<T>
+where
+    F: Fn(SocketAddr) -> std::io::Result<T>,
+{
+    let mut last_error = Error::InvalidPortList { spec: String::new() };
+
+    for port in ports {
+        let addr = host_port(address, *port).parse()?;
+
+        match bind(addr) {
+            Ok(v) => return Ok(v),
+            Err(e) if e.kind() == ErrorKind::AddrInUse || e.kind() == ErrorKind::PermissionDenied => {
+                info!("unable to bind {addr} ({e})");
+                last_error = e.into();
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(last_error)
+}
+
+//
+// Between two tunnels, parks or refuses what the listeners accept
+//
+fn park_connections(listener: &mut Listener, when_down: WhenDown) {
+    let label = &listener.forward.label;
+
+    let tcp_listener = match &listener.forward.socket {
+        ForwardSocket::Tcp(v) => v,
+        _ => return,
+    };
+
+    while let Some((_, iaddr, since)) = listener.parked.front()
+        && since.elapsed() >= PARK_TIMEOUT
+    {
+        debug!("[{label}] no tunnel for {}s, dropping {iaddr}", PARK_TIMEOUT.as_secs());
+        listener.parked.pop_front();
+    }
+
+    while listener.parked.len() < PARK_LIMIT {
+        let (istream, iaddr) = match tcp_listener.accept() {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                debug!("[{label}] accept failure ({e})");
+                break;
+            }
+        };
+
+        if !listener.gate.admits(label, iaddr) {
+            continue;
+        }
+
+        match when_down {
+            WhenDown::Queue => {
+                debug!("[{label}] no tunnel, parking {iaddr}");
+                listener.parked.push_back((istream, iaddr, Instant::now()));
+            }
+            WhenDown::Refuse => debug!("[{label}] no tunnel, refusing {iaddr}"),
+        }
+    }
+}
+
+fn tunnel_accept(
+    tunnel_listener: &mut TcpListener,
+    listeners: &mut Vec<Listener>,
+    config: &ServerConfig,
+    watchdog: &Watchdog,
+    mut control: Option<&mut Control>,
+) -> Result<TcpStream> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(128);
+
+    poll.registry().register(tunnel_listener, TUNNEL_PORT, Interest::READABLE)?;
+
+    for (i, l) in listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(&mut l.forward.socket, Token(FIRST_LISTENER + i), Interest::READABLE)?;
+    }
+
+    if let Some(c) = control.as_deref_mut() {
+        c.register(poll.registry())?;
+    }
+
+    let ret = loop {
+        match poll.poll(&mut events, Some(TICK_INTERVAL)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e.into()),
+        }
+
+        watchdog.ping();
+
+        if let Some(c) = control.as_deref_mut() {
+            c.expire();
+        }
+
+        // every time, the full ones wouldn't tell again
+        for l in listeners.iter_mut() {
+            park_connections(l, config.when_down);
+        }
+
+        let mut accepted = None;
+
+        for event in events.iter() {
+            if let Some(c) = control.as_deref_mut()
+                && c.owns(event.token())
+            {
+                // no tunnel to drop
+                let mut target = ControlTarget {
+                    registry: poll.registry(),
+                    listeners,
+                    streams: None,
+                    config,
+                    max_connections: None,
+                };
+                c.on_event(poll.registry(), event.token(), &mut target);
+                continue;
+            }
+
+            if TUNNEL_PORT == event.token() {
                 //
-                // writable... feels like we should use this
+                // This is the pvpn client connecting
                 //
-                if let Err(e) = streams.flush(event.token().0) {
-                    error!("flush({}) => {e}", event.token().0)
-                }
+                accepted = Some(tunnel_listener.accept());
+            }
+        }
+
+        match accepted {
+            Some(Ok((tstream, iaddr))) => {
+                info!("tunnel connected: {:?}", iaddr);
+                break Ok(tstream);
             }
+            Some(Err(e)) => break Err(e.into()),
+            None => {}
         }
+    };
+
+    poll.registry().deregister(tunnel_listener)?;
+
+    for l in listeners.iter_mut() {
+        poll.registry().deregister(&mut l.forward.socket)?;
+    }
+
+    if let Some(c) = control {
+        c.deregister(poll.registry())?;
     }
+
+    ret
 }
 
-pub fn server_main(server: &str, tunnel: &str) -> Result<()> {
-    loop {
-        let tstream = tunnel_accept(tunnel)?;
-
-        match tunnel_handler(tstream, server) {
-            Ok(_) => info!("tunnel disconnected"),
-            Err(Error::Eof) => info!("tunnel disconnected (EOF)"),
-            Err(Error::Io(e)) => match e.kind() {
-                ErrorKind::AddrInUse => {
-                    //
-                    // this one is fatal because it'll never work
-                    //
-                    error!("tunnel error: {}", e);
-                    break Err(e.into());
-                }
-                _ => error!("tunnel error: {}", e),
-            },
-            Err(e) => error!("tunnel error: {}", e),
+//
+// What the control socket's status command returns, None between sessions
+//
+fn server_status(streams: Option<&TokenStreams>, max_connections: Option<usize>) -> Status {
+    Status {
+        role: "server".to_string(),
+        tunnel: streams.map(|s| TunnelStatus {
+            backlog: s.tun_backlog(),
+            ..s.tunnel_stats().into()
+        }),
+        streams: match streams {
+            Some(s) => s.buffer_stats().iter().map(|b| b.into()).collect(),
+            None => Vec::new(),
+        },
+        panics: panic_count() as usize,
+        overrides: Vec::new(),
+        max_connections,
+    }
+}
+
+//
+// The control socket's view of a loop, streams is None between sessions
+//
+struct ControlTarget<'a> {
+    registry: &'a Registry,
+    listeners: &'a mut Vec<Listener>,
+    streams: Option<&'a TokenStreams>,
+    config: &'a ServerConfig,
+    max_connections: Option<usize>,
+}
+
+impl Target for ControlTarget<'_> {
+    fn status(&self) -> Status {
+        server_status(self.streams, self.max_connections)
+    }
+
+    fn stats(&self) -> Option<PeerStats> {
+        self.streams.map(|s| s.totals())
+    }
+
+    fn forwards(&self) -> Vec<ForwardStatus> {
+        self.listeners
+            .iter()
+            .filter_map(|l| {
+                let protocol = match l.forward.socket {
+                    ForwardSocket::Tcp(_) => Protocol::Tcp,
+                    ForwardSocket::Udp(_) => Protocol::Udp,
+                    ForwardSocket::Removed => return None,
+                };
+
+                Some(ForwardStatus {
+                    label: l.forward.label.clone(),
+                    addr: l.forward.socket.local_addr().ok()?.to_string(),
+                    protocol: protocol.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    //
+    // Same address and protocol as the others, labelled with its port. Not in
+    // the Hello the client has, the default endpoint gets it
+    //
+    fn add_forward(&mut self, port: u16) -> Result<SocketAddr> {
+        if self.config.lazy_listen {
+            return Err(Error::ForwardsFixed);
+        }
+
+        let live = self.listeners.iter().find_map(|l| match l.forward.socket {
+            ForwardSocket::Tcp(_) => Some((l.forward.socket.local_addr().ok()?, Protocol::Tcp)),
+            ForwardSocket::Udp(_) => Some((l.forward.socket.local_addr().ok()?, Protocol::Udp)),
+            ForwardSocket::Removed => None,
+        });
+
+        let (host, protocol) = match live {
+            Some((addr, protocol)) => (addr.ip().to_string(), protocol),
+            None => (self.config.server_address.clone(), Protocol::Tcp),
+        };
+
+        let socket = bind_forward(&host, &[port], protocol)?;
+        let addr = socket.local_addr()?;
+
+        let forward = Forward {
+            label: addr.port().to_string(),
+            socket,
+        };
+
+        let mut listener = Listener::new(forward, self.config);
+
+        let token = Token(FIRST_LISTENER + self.listeners.len());
+        self.registry
+            .register(&mut listener.forward.socket, token, Interest::READABLE)?;
+
+        info!("[{}] internet listener on {addr}", listener.forward.label);
+        self.listeners.push(listener);
+
+        Ok(addr)
+    }
+
+    //
+    // The port closes, what it forwards already goes on
+    //
+    fn remove_forward(&mut self, port: u16) -> Result<()> {
+        if self.config.lazy_listen {
+            return Err(Error::ForwardsFixed);
+        }
+
+        let l = self
+            .listeners
+            .iter_mut()
+            .find(|l| matches!(l.forward.socket.local_addr(), Ok(a) if a.port() == port))
+            .ok_or(Error::UnknownForward { port })?;
+
+        self.registry.deregister(&mut l.forward.socket)?;
+        l.forward.socket = ForwardSocket::Removed;
+        l.parked.clear();
+
+        info!("[{}] internet listener removed", l.forward.label);
+        Ok(())
+    }
+}
+
+//
+// A session's streams, set up from the config
+//
+fn session_streams(registry: Registry, config: &ServerConfig) -> TokenStreams {
+    let mut streams = TokenStreams::new();
+
+    streams.set_registry(registry);
+    streams.set_max_rate(config.max_rate);
+    streams.set_keepalive(config.tcp_keepalive);
+    // one slow internet peer doesn't get the whole tunnel buffered for it
+    streams.set_backpressure(true);
+    if let Some(max) = config.max_buffered {
+        streams.set_max_buffered(max);
+    }
+    if let Some(size) = config.buffer_size {
+        streams.set_buffer_size(size);
+    }
+    if let Some(path) = &config.access_log {
+        match AccessLog::open(path, "server") {
+            Ok(v) => streams.set_access_log(Some(v)),
+            Err(e) => warn!("unable to open the access log {} ({e})", path.display()),
+        }
+    }
+
+    streams
+}
+
+//
+// What the client is told first, the forwards numbered by their channel
+//
+fn server_hello(listeners: &[Listener], lazy: &[LazyForward], config: &ServerConfig) -> Result<Hello> {
+    let mut hello = Hello::default();
+
+    for l in listeners {
+        // a removed one still numbers the channels
+        hello.forwards.push(l.forward.label.clone());
+
+        if let ForwardSocket::Removed = l.forward.socket {
+            continue;
+        }
+
+        hello.port.get_or_insert(l.forward.socket.local_addr()?.port());
+    }
+
+    for lf in lazy {
+        hello.forwards.push(lf.label.clone());
+        hello.port.get_or_insert(lf.addr.port());
+    }
+
+    hello.features.push(FEATURE_STATS.to_string());
+    hello.features.push(FEATURE_RELEASE.to_string());
+    hello.max_connections = config.max_connections;
+
+    Ok(hello)
+}
+
+//
+// The internet ports, polled along with the session's streams
+//
+fn register_listeners(registry: &Registry, listeners: &mut [Listener]) -> Result<()> {
+    for (i, l) in listeners.iter_mut().enumerate() {
+        if let ForwardSocket::Removed = l.forward.socket {
+            continue;
+        }
+
+        info!(
+            "[{}] internet listener on {}",
+            l.forward.label,
+            l.forward.socket.local_addr()?
+        );
+
+        registry.register(&mut l.forward.socket, Token(FIRST_LISTENER + i), Interest::READABLE)?;
+    }
+
+    Ok(())
+}
+
+fn tunnel_handler(
+    tstream: TcpStream,
+    listeners: &mut Vec<Listener>,
+    config: &ServerConfig,
+    env: SessionEnv,
+) -> Result<()> {
+    let SessionEnv {
+        watchdog,
+        webhook,
+        mut control,
+        mut tunnel_port,
+        lazy,
+    } = env;
+
+    let mut poll = Poll::new()?;
+
+    let mut streams = session_streams(poll.registry().try_clone()?, config);
+
+    let hello = server_hello(listeners, lazy, config)?;
+
+    register_listeners(poll.registry(), listeners)?;
+
+    if let Some(c) = control.as_deref_mut() {
+        c.register(poll.registry())?;
+    }
+
+    if let Some(port) = tunnel_port.as_deref_mut() {
+        poll.registry().register(&mut port.listener, TUNNEL_PORT, Interest::READABLE)?;
+    }
+
+    streams.add_tunnel(TUNNEL_STREAM.0, ClientStream::new(tstream)?)?;
+
+    streams.write_control(TUNNEL_STREAM.0, PacketMessage::Hello, &hello.encode())?;
+
+    info!("-----------------------------SERVER-----------------------------");
+
+    let mut overload = Overload::new();
+
+    let ret = catch_session(|| {
+        let env = SessionEnv {
+            watchdog,
+            webhook,
+            control: control.as_deref_mut(),
+            tunnel_port: tunnel_port.as_deref_mut(),
+            lazy,
+        };
+        handler_loop(&mut poll, listeners, &mut streams, config, env, &mut overload)
+    });
+
+    if let Err(e) = &ret {
+        send_goodbye(&mut streams, TUNNEL_STREAM.0, e);
+    }
+
+    // whatever is still open won't hear from the peer again
+    let left = streams.flush_all(TEARDOWN_FLUSH);
+    if 0 != left {
+        warn!("{left} bytes for the connections never went out");
+    }
+    streams.close_all(CloseReason::TunnelLost);
+
+    info!("session summary: {}", streams.tunnel_stats());
+    info!("session streams: {}", streams.totals());
+    info!("session resources: {} connections={}", Usage::sample(), streams.len());
+    info!("loop iterations: {}", overload.histogram());
+
+    // the listeners outlive the session's poll
+    for l in listeners.iter_mut() {
+        poll.registry().deregister(&mut l.forward.socket)?;
+    }
+
+    // unless bound for it, closed until the next client
+    if !lazy.is_empty() {
+        listeners.clear();
+    }
+
+    if let Some(c) = control {
+        c.deregister(poll.registry())?;
+    }
+
+    if let Some(port) = tunnel_port {
+        poll.registry().deregister(&mut port.listener)?;
+    }
+
+    ret
+}
+
+//
+// Tunnel level messages from the client
+//
+fn control_message(
+    p: &Packet,
+    data: &[u8],
+    streams: &mut TokenStreams,
+    config: &ServerConfig,
+    peer: &mut Hello,
+) -> Result<()> {
+    match p.msg {
+        PacketMessage::Probe => streams.write_control(TUNNEL_STREAM.0, PacketMessage::ProbeReply, data)?,
+        // keepalive answer, reading it was the point
+        PacketMessage::ProbeReply => {}
+        PacketMessage::Stats => info!("client stats: {}", PeerStats::decode(data)?),
+        PacketMessage::Goodbye => {
+            let goodbye = Goodbye::decode(data)?;
+            warn!("the client is leaving: {goodbye}");
+            return Err(Error::Goodbye {
+                reason: goodbye.reason,
+                message: goodbye.message,
+            });
+        }
+        PacketMessage::Hello => {
+            let hello = Hello::decode(data)?;
+            info!("client parameters: {hello}");
+
+            if let Some(mtu) = hello.mtu {
+                streams.set_mtu(mtu);
+            }
+
+            if hello.max_connections.is_some() {
+                peer.max_connections = hello.max_connections;
+
+                match session_max_connections(config.max_connections, peer.max_connections) {
+                    Some(v) => info!("session parameters: max_connections={v}"),
+                    None => info!("session parameters: max_connections=unlimited"),
+                }
+            }
+
+            // a later Hello only carries what changed
+            for feature in &hello.features {
+                if !peer.has_feature(feature) {
+                    peer.features.push(feature.clone());
+                }
+            }
+
+            if let Some(motd) = &config.motd
+                && hello.has_feature(FEATURE_BANNER)
+            {
+                streams.write_control(TUNNEL_STREAM.0, PacketMessage::Banner, motd.as_bytes())?;
+            }
+        }
+        _ => warn!("unexpected control message {}", p.msg),
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Capacity {
+    // connections carried at once, the tunnel aside
+    max: Option<usize>,
+    // the others wait in the backlog instead of being closed
+    queue: bool,
+}
+
+//
+// Accepts everything pending on the forward's listener, each connection is
+// announced to the client with a Connect carrying the forward's channel.
+// True when there may be more, out of budget or backing off
+//
+fn accept_forward(
+    channel: usize,
+    listener: &mut Listener,
+    streams: &mut TokenStreams,
+    channels: &mut HashMap<Address, usize>,
+    budget: usize,
+    capacity: Capacity,
+) -> Result<bool> {
+    let label = &listener.forward.label;
+
+    let tcp_listener = match &listener.forward.socket {
+        ForwardSocket::Tcp(v) => v,
+        _ => return Ok(false),
+    };
+
+    if !listener.accept_wait(Instant::now()).is_zero() {
+        return Ok(true);
+    }
+
+    listener.accept_paused = None;
+
+    for _ in 0..budget {
+        //
+        // left in the backlog, looked at again once some had a chance to
+        // close
+        //
+        if capacity.queue
+            && let Some(max) = capacity.max
+            && streams.len().saturating_sub(1) >= max
+        {
+            debug!("[{label}] at capacity ({max} connections), queueing");
+            listener.accept_paused = Some(Instant::now() + ACCEPT_BACKOFF);
+            return Ok(true);
+        }
+
+        let (istream, iaddr) = match tcp_listener.accept() {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            //
+            // the connections wait in the backlog, the streams already
+            // there don't need new fds
+            //
+            Err(e) if is_fd_exhaustion(&e) => {
+                if !listener.fd_exhausted {
+                    warn!("[{label}] accept failure ({e}), pausing the accepts");
+                    listener.fd_exhausted = true;
+                }
+                listener.accept_paused = Some(Instant::now() + ACCEPT_BACKOFF);
+                return Ok(true);
+            }
+            // gone before it was accepted, the next one may be fine
+            Err(e) if matches!(e.kind(), ErrorKind::ConnectionAborted | ErrorKind::Interrupted) => {
+                debug!("[{label}] accept failure ({e})");
+                continue;
+            }
+            // the session and its streams don't depend on the listener
+            Err(e) => {
+                warn!("[{label}] accept failure ({e})");
+                return Ok(false);
+            }
+        };
+
+        if listener.fd_exhausted {
+            info!("[{label}] accepting again");
+            listener.fd_exhausted = false;
+        }
+
+        if !listener.gate.admits(label, iaddr) {
+            continue;
+        }
+
+        listener.churn.on_accept(Instant::now());
+
+        if listener.churn.should_throttle() {
+            debug!("[{label}] churn protection, dropping {iaddr}");
+            drop(istream);
+            continue;
+        }
+
+        forward_connection(channel, label, istream, iaddr, streams, channels, capacity.max)?;
+    }
+
+    // out of budget, there may be more
+    Ok(true)
+}
+
+//
+// What came in while there was no tunnel, as long as the session has room
+//
+fn forward_parked(
+    listeners: &mut [Listener],
+    streams: &mut TokenStreams,
+    channels: &mut HashMap<Address, usize>,
+    capacity: Capacity,
+) -> Result<()> {
+    for (idx, l) in listeners.iter_mut().enumerate() {
+        let label = l.forward.label.as_str();
+
+        while let Some((_, _, since)) = l.parked.front() {
+            if since.elapsed() < PARK_TIMEOUT
+                && capacity.queue
+                && let Some(max) = capacity.max
+                && streams.len().saturating_sub(1) >= max
+            {
+                break;
+            }
+
+            if let Some((istream, iaddr, since)) = l.parked.pop_front()
+                && since.elapsed() < PARK_TIMEOUT
+            {
+                forward_connection(idx, label, istream, iaddr, streams, channels, capacity.max)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//
+// An accepted internet connection to the client
+//
+fn forward_connection(
+    channel: usize,
+    label: &str,
+    istream: TcpStream,
+    iaddr: SocketAddr,
+    streams: &mut TokenStreams,
+    channels: &mut HashMap<Address, usize>,
+    max_connections: Option<usize>,
+) -> Result<()> {
+    // the tunnel doesn't count
+    if let Some(max) = max_connections
+        && streams.len().saturating_sub(1) >= max
+    {
+        info!("[{label}] at capacity ({max} connections), dropping {iaddr}");
+        return Ok(());
+    }
+
+    let info = ConnectInfo {
+        peer: iaddr,
+        local: istream.local_addr()?,
+        channel: channel.try_into()?,
+    };
+
+    let mut iclient = ClientStream::new(istream)?;
+    iclient.set_peer(iaddr);
+
+    let addr = match streams.allocate_address() {
+        Some(v) => v,
+        None => {
+            warn!("[{label}] out of tunnel addresses, dropping {iaddr}");
+            return Ok(());
+        }
+    };
+
+    info!("[{label}] internet connected: {:?} (token={addr})", iaddr);
+
+    streams.add(addr, iclient)?;
+    channels.insert(addr, channel);
+
+    streams.write_message_data(TUNNEL_STREAM.0, addr, PacketMessage::Connect, &info.encode()?)?;
+
+    Ok(())
+}
+
+//
+// Internet data to the tunnel, up to `budget` reads. Returns true when the
+// budget ran out before the socket did
+//
+fn internet_read(
+    addr: Address,
+    label: &str,
+    streams: &mut TokenStreams,
+    read_buffer: &mut [u8],
+    budget: usize,
+) -> Result<bool> {
+    for _ in 0..budget {
+        match streams.read(addr, read_buffer) {
+            Ok(ReadOutcome::WouldBlock) => return Ok(false),
+            Ok(ReadOutcome::Data(v)) => {
+                info!("[{label}] token={addr} read bytes={v} from internet");
+                streams.write_packet(TUNNEL_STREAM.0, addr, &read_buffer[0..v])?;
+            }
+            Ok(ReadOutcome::Eof) => {
+                info!("[{label}] token={addr} EOF from internet");
+                streams.write_message(TUNNEL_STREAM.0, addr, PacketMessage::CloseWrite)?;
+                return Ok(false);
+            }
+            // closed and the client told by read()
+            Err(e) => {
+                info!("{e}");
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+//
+// Every datagram pending on the forward's socket goes through the tunnel on
+// its own, a new remote address is announced with a Connect first
+//
+fn udp_forward(
+    channel: usize,
+    listener: &mut Listener,
+    streams: &mut TokenStreams,
+    flows: &mut UdpFlows,
+    datagram: &mut [u8],
+    max_connections: Option<usize>,
+) -> Result<()> {
+    let label = &listener.forward.label;
+
+    let socket = match &listener.forward.socket {
+        ForwardSocket::Udp(v) => v,
+        _ => return Ok(()),
+    };
+
+    loop {
+        let (len, peer) = match socket.recv_from(datagram) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if len > streams.mtu() {
+            // can't be split without losing the boundary
+            warn!(
+                "[{label}] dropping {len} bytes datagram from {peer}, above {}",
+                streams.mtu()
+            );
+            continue;
+        }
+
+        let addr = match flows.lookup(channel, &peer) {
+            Some(v) => v,
+            None => {
+                if !listener.gate.admits(label, peer) {
+                    continue;
+                }
+
+                listener.churn.on_accept(Instant::now());
+
+                if listener.churn.should_throttle() {
+                    debug!("[{label}] churn protection, dropping {peer}");
+                    continue;
+                }
+
+                if let Some(max) = max_connections
+                    && flows.len() >= max
+                {
+                    debug!("[{label}] at capacity ({max} peers), dropping {peer}");
+                    continue;
+                }
+
+                let addr = match streams.allocate_address() {
+                    Some(v) => v,
+                    None => {
+                        warn!("[{label}] out of tunnel addresses, dropping {peer}");
+                        continue;
+                    }
+                };
+
+                info!("[{label}] new udp peer: {peer} (token={addr})");
+
+                let info = ConnectInfo {
+                    peer,
+                    local: socket.local_addr()?,
+                    channel: channel.try_into()?,
+                };
+
+                flows.insert(addr, channel, peer);
+                streams.write_message_data(TUNNEL_STREAM.0, addr, PacketMessage::Connect, &info.encode()?)?;
+
+                addr
+            }
+        };
+
+        flows.touch(addr);
+        streams.write_message_data(TUNNEL_STREAM.0, addr, PacketMessage::Datagram, &datagram[0..len])?;
+    }
+}
+
+//
+// Reply from the endpoint, back to the peer that owns the address
+//
+fn udp_to_internet(addr: Address, data: &[u8], listeners: &[Listener], flows: &mut UdpFlows) {
+    let flow = match flows.touch(addr) {
+        Some(v) => v,
+        None => {
+            debug!("dropping datagram for unknown token={addr}");
+            return;
+        }
+    };
+
+    if let ForwardSocket::Udp(socket) = &listeners[flow.channel].forward.socket
+        && let Err(e) = socket.send_to(data, flow.peer)
+    {
+        // same as the network dropping it
+        debug!("send_to({}) failed ({e})", flow.peer);
+    }
+}
+
+//
+// What the session reports to or takes orders from, outlives it
+//
+struct SessionEnv<'a> {
+    watchdog: &'a Watchdog,
+    webhook: &'a Webhook,
+    control: Option<&'a mut Control>,
+    // where the next client shows up, one client at a time
+    tunnel_port: Option<&'a mut TunnelPort>,
+    // bound once the client said hello, empty without --lazy-listen
+    lazy: &'a [LazyForward],
+}
+
+//
+// The tunnel listener, also polled while a session runs. The new client
+// either replaces the current one ( --tunnel-takeover ) or is told the
+// server is busy
+//
+struct TunnelPort {
+    listener: TcpListener,
+    takeover: bool,
+    // the client that took over, served next
+    next: Option<TcpStream>,
+}
+
+impl TunnelPort {
+    //
+    // True once a client is waiting to take over
+    //
+    fn on_readable(&mut self) -> bool {
+        loop {
+            let (tstream, taddr) = match self.listener.accept() {
+                Ok(v) => v,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("tunnel accept failure ({e})");
+                    break;
+                }
+            };
+
+            match self.takeover {
+                true => {
+                    info!("tunnel connected: {taddr:?}, replacing the current one");
+                    self.next = Some(tstream);
+                }
+                false => {
+                    info!("tunnel connected: {taddr:?}, already serving a client");
+                    refuse_tunnel(tstream, GoodbyeReason::Busy, "another client is connected");
+                }
+            }
+        }
+
+        self.next.is_some()
+    }
+}
+
+//
+// A Goodbye before anything else, the client waits for the server's Hello
+// so there's nothing to read that would turn the close into a reset
+//
+fn refuse_tunnel(mut tstream: TcpStream, reason: GoodbyeReason, message: &str) {
+    let goodbye = Goodbye {
+        reason,
+        message: message.to_string(),
+    }
+    .encode();
+
+    let mut frame = Vec::new();
+
+    let ret = Packet::new(CONTROL_ADDRESS, PacketMessage::Goodbye, goodbye.len() as u16)
+        .encode(&mut frame)
+        .and_then(|_| {
+            frame.extend_from_slice(&goodbye);
+            // an empty socket buffer, all of it fits
+            Ok(std::io::Write::write_all(&mut tstream, &frame)?)
+        });
+
+    if let Err(e) = ret {
+        debug!("unable to send the goodbye ({e})");
+    }
+
+    let _ = tstream.shutdown(std::net::Shutdown::Write);
+}
+
+fn overridden(control: &Option<&mut Control>, o: Override) -> bool {
+    match control {
+        Some(c) => c.is_active(o),
+        None => false,
+    }
+}
+
+//
+// What expires with time in a session's streams and UDP flows, only the
+// bookkeeping when the timers are held back
+//
+fn session_tick(
+    streams: &mut TokenStreams,
+    channels: &mut HashMap<Address, usize>,
+    flows: &mut UdpFlows,
+    config: &ServerConfig,
+    timers: bool,
+) -> Result<()> {
+    if timers {
+        streams.prune_half_closed(HALF_CLOSE_TIMEOUT)?;
+        streams.prune_draining(DRAIN_TIMEOUT);
+
+        if let Some(max_idle) = config.idle_timeout {
+            streams.prune_idle(max_idle)?;
+        }
+    }
+
+    channels.retain(|addr, _| streams.contains_token(*addr));
+
+    if timers {
+        for addr in flows.reap(config.udp_timeout.unwrap_or(DEF_UDP_TIMEOUT)) {
+            debug!("udp flow token={addr} expired");
+            streams.release_address(addr);
+        }
+    }
+
+    Ok(())
+}
+
+fn listeners_tick(listeners: &mut [Listener], config: &ServerConfig, webhook: &Webhook, now: Instant) {
+    for l in listeners.iter_mut() {
+        l.gate.tick(&l.forward.label, now);
+
+        if let Some(e) = l.churn.tick(now) {
+            warn!("[{}] {e} factor={}", l.forward.label, config.churn.factor);
+
+            let kind = match e {
+                ChurnEvent::Spike { .. } => EventKind::ChurnSpike,
+                ChurnEvent::Normal { .. } => EventKind::ChurnNormal,
+            };
+            webhook.notify(kind, &l.forward.label);
+        }
+    }
+}
+
+//
+// --tunnel-timeout, the client is dropped once silent for that long. An idle
+// tunnel is healthy as long as the client echoes the probes
+//
+fn check_silence(
+    streams: &mut TokenStreams,
+    last_read: Instant,
+    last_keepalive: &mut Instant,
+    config: &ServerConfig,
+    stalled: Duration,
+    keepalives: bool,
+) -> Result<()> {
+    // not the client's fault if the loop was too busy to read
+    let silent = last_read.elapsed().saturating_sub(stalled);
+
+    if silent > config.tunnel_timeout {
+        warn!(
+            "nothing from the client for {} s, dropping the tunnel",
+            silent.as_secs()
+        );
+        return Err(Error::TunnelTimeout);
+    }
+
+    let interval = config.tunnel_timeout / 3;
+    if silent > interval && last_keepalive.elapsed() > interval && keepalives {
+        *last_keepalive = Instant::now();
+        streams.write_control(TUNNEL_STREAM.0, PacketMessage::Probe, &[])?;
+    }
+
+    Ok(())
+}
+
+fn stream_label<'a>(addr: Address, listeners: &'a [Listener], channels: &HashMap<Address, usize>) -> &'a str {
+    match channels.get(&addr) {
+        Some(c) => listeners[*c].forward.label.as_str(),
+        None => "?",
+    }
+}
+
+//
+// Readiness of one of the session's internet connections. True when reads
+// were cut short by the budget
+//
+fn internet_event(
+    addr: Address,
+    event: &Event,
+    listeners: &[Listener],
+    channels: &HashMap<Address, usize>,
+    streams: &mut TokenStreams,
+    read_buffer: &mut [u8],
+    budget: usize,
+) -> Result<bool> {
+    // removed earlier in this batch
+    if !streams.contains_token(addr) {
+        debug!("event for unknown token={addr}");
+        return Ok(false);
+    }
+
+    if (event.is_error() || event.is_read_closed() && event.is_write_closed()) && streams.socket_error(addr)? {
+        // reset, closed and the client told
+        return Ok(false);
+    }
+
+    let mut more = false;
+
+    if event.is_readable() {
+        let label = stream_label(addr, listeners, channels);
+        more = internet_read(addr, label, streams, read_buffer, budget)?;
+    }
+
+    if event.is_writable()
+        && streams.contains_token(addr)
+        && let Err(e) = streams.flush(addr)
+    {
+        warn!("flush({addr}) => {e}");
+    }
+
+    Ok(more)
+}
+
+//
+// What the tunnel read holds, true when the client's Hello was among it
+//
+fn tunnel_frames(
+    streams: &mut TokenStreams,
+    listeners: &[Listener],
+    flows: &mut UdpFlows,
+    config: &ServerConfig,
+    peer: &mut Hello,
+    watchdog: &Watchdog,
+) -> Result<bool> {
+    let mut hello = false;
+
+    loop {
+        match streams.read_packet() {
+            Ok((p, data)) => {
+                watchdog.record(Activity::Frame {
+                    msg: p.msg,
+                    addr: p.addr,
+                    len: data.len(),
+                });
+
+                if CONTROL_ADDRESS == p.addr {
+                    control_message(&p, &data, streams, config, peer)?;
+                    hello |= PacketMessage::Hello == p.msg;
+                    continue;
+                }
+
+                if PacketMessage::Datagram == p.msg {
+                    udp_to_internet(p.addr, &data, listeners, flows);
+                    continue;
+                }
+
+                if PacketMessage::Data != p.msg {
+                    warn!("unexpected {} from the client", p.msg);
+                    continue;
+                }
+
+                // the client is told by write()
+                if let Err(e) = streams.write(p.addr, &data) {
+                    warn!("Connection terminated ({e})");
+                }
+            }
+            Err(Error::Empty) => {
+                // not a failure case
+                break;
+            }
+            Err(Error::NotEnoughData) => {
+                // not a failure case
+                break;
+            }
+            Err(Error::Eof) => {
+                break;
+            }
+            Err(e) => {
+                error!("{e}");
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(hello)
+}
+
+fn handler_loop(
+    poll: &mut Poll,
+    listeners: &mut Vec<Listener>,
+    streams: &mut TokenStreams,
+    config: &ServerConfig,
+    env: SessionEnv,
+    overload: &mut Overload,
+) -> Result<()> {
+    let SessionEnv {
+        watchdog,
+        webhook,
+        mut control,
+        mut tunnel_port,
+        lazy,
+    } = env;
+
+    let mut events = Events::with_capacity(128);
+
+    // forward of each stream, for the logs
+    let mut channels: HashMap<Address, usize> = HashMap::new();
+
+    let mut flows = UdpFlows::new();
+    let mut datagram = vec![0; MAX_DATAGRAM];
+
+    let mut read_buffer = vec![0; streams.buffer_size()];
+
+    let mut housekeeping = Timers::new();
+    housekeeping.every(Housekeeping::Tick, TICK_INTERVAL, Instant::now());
+    housekeeping.every(Housekeeping::Stats, STATS_INTERVAL, Instant::now());
+
+    // what the client said about itself
+    let mut peer = Hello::default();
+    // what the process held when the session started
+    let baseline = Usage::sample();
+
+    // last time the client was heard of
+    let mut last_read = Instant::now();
+    let mut last_keepalive = Instant::now();
+
+    // read from the tunnel, handled after the other events
+    let mut tunnel_input = false;
+    let mut tunnel_eof = false;
+
+    // cut short by the overload budgets, resumed on the next iteration
+    let mut deferred_accepts: Vec<usize> = Vec::new();
+    let mut deferred_reads: Vec<Address> = Vec::new();
+
+    let session_label = match (listeners.first(), lazy.first()) {
+        (Some(l), _) => l.forward.label.clone(),
+        (None, Some(lf)) => lf.label.clone(),
+        (None, None) => String::new(),
+    };
+
+    failpoint(&session_label);
+
+    let mut clock = ResumeDetector::new();
+
+    loop {
+        // a listener backing off isn't ready before then
+        let accept_wait = deferred_accepts
+            .iter()
+            .map(|idx| listeners[*idx].accept_wait(Instant::now()))
+            .min();
+
+        // until the next housekeeping deadline at most
+        let wait = housekeeping.wait(Instant::now(), TICK_INTERVAL);
+
+        let timeout = if !deferred_reads.is_empty() {
+            Duration::ZERO
+        } else if let Some(accept) = accept_wait {
+            accept.min(wait)
+        } else if streams.is_throttled() {
+            REFILL_INTERVAL.min(wait)
+        } else {
+            wait
+        };
+
+        match poll.poll(&mut events, Some(timeout)) {
+            Ok(_) => {}
+            // signals
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        let iteration = Instant::now();
+
+        if streams.is_throttled() {
+            streams.flush(TUNNEL_STREAM.0)?;
+        }
+
+        watchdog.ping();
+        watchdog.set_streams(streams.len());
+
+        match clock.on_sample(ClockSample::now()) {
+            Some(e @ ClockEvent::Resume { .. }) => {
+                warn!("{e}");
+                // the client answers if the tunnel survived
+                streams.write_control(TUNNEL_STREAM.0, PacketMessage::Probe, &[])?;
+                last_keepalive = Instant::now();
+            }
+            Some(e) => warn!("{e}"),
+            None => {}
+        }
+
+        // lowest of ours and the client's
+        let max_connections = session_max_connections(config.max_connections, peer.max_connections);
+        let capacity = Capacity {
+            max: max_connections,
+            queue: config.queue_when_full,
+        };
+
+        forward_parked(listeners, streams, &mut channels, capacity)?;
+
+        let due = housekeeping.due(iteration);
+
+        if due.contains(&Housekeeping::Tick) {
+            let now = iteration;
+
+            if let Some(c) = control.as_deref_mut() {
+                c.expire();
+            }
+
+            // nothing expires because the host slept
+            let timers = !overridden(&control, Override::PauseTimers) && !clock.in_grace(now);
+
+            session_tick(streams, &mut channels, &mut flows, config, timers)?;
+
+            listeners_tick(listeners, config, webhook, now);
+
+            // not read meanwhile, the client isn't silent
+            if streams.is_congested() {
+                last_read = now;
+            }
+
+            if timers && !config.tunnel_timeout.is_zero() {
+                let keepalives = !overridden(&control, Override::StopKeepalives);
+                check_silence(
+                    streams,
+                    last_read,
+                    &mut last_keepalive,
+                    config,
+                    overload.stalled(),
+                    keepalives,
+                )?;
+            }
+        }
+
+        if due.contains(&Housekeeping::Stats) {
+            let usage = Usage::sample();
+            match usage.fd_leak(&baseline, streams.len()) {
+                true => warn!("resources: {usage} connections={} fd leak?", streams.len()),
+                false => info!("resources: {usage} connections={}", streams.len()),
+            }
+
+            if log_enabled!(Level::Debug) {
+                for info in streams.snapshot() {
+                    debug!("stream {info}");
+                }
+            }
+
+            if peer.has_feature(FEATURE_STATS) {
+                streams.write_control(TUNNEL_STREAM.0, PacketMessage::Stats, &streams.totals().encode()?)?;
+            }
+        }
+
+        for event in events.iter() {
+            watchdog.record(Activity::Event {
+                token: event.token().0,
+                readable: event.is_readable(),
+                writable: event.is_writable(),
+            });
+
+            if let Some(c) = control.as_deref_mut()
+                && c.owns(event.token())
+            {
+                let mut target = ControlTarget {
+                    registry: poll.registry(),
+                    listeners,
+                    streams: Some(streams),
+                    config,
+                    max_connections,
+                };
+                if c.on_event(poll.registry(), event.token(), &mut target) {
+                    return Err(Error::TunnelDropped);
+                }
+                continue;
+            }
+
+            if TUNNEL_PORT == event.token()
+                && let Some(port) = tunnel_port.as_deref_mut()
+                && port.on_readable()
+            {
+                return Err(Error::TunnelReplaced);
+            }
+
+            if let Some(idx) = listener_index(event.token(), listeners) {
+                let l = &mut listeners[idx];
+
+                match l.forward.socket {
+                    // left in the backlog, picked up once the override ends
+                    ForwardSocket::Tcp(_) if overridden(&control, Override::StopAccepting) => {}
+                    ForwardSocket::Tcp(_) => {
+                        let budget = overload.accept_budget();
+                        if accept_forward(idx, l, streams, &mut channels, budget, capacity)?
+                            && !deferred_accepts.contains(&idx)
+                        {
+                            deferred_accepts.push(idx);
+                        }
+                    }
+                    ForwardSocket::Udp(_) => udp_forward(idx, l, streams, &mut flows, &mut datagram, max_connections)?,
+                    ForwardSocket::Removed => {}
+                }
+            } else if TUNNEL_STREAM == event.token() && event.is_readable() {
+                //
+                // a failed tunnel read is fatal, the frames that came with an
+                // EOF are handled first, the peer's last Data may be among them
+                //
+                tunnel_eof = match streams.flush_read(TUNNEL_STREAM.0) {
+                    Err(Error::Eof) => true,
+                    ret => {
+                        ret?;
+                        false
+                    }
+                };
+                tunnel_input = true;
+                last_read = Instant::now();
+                overload.reset_stalled();
+
+                // the rest of the batch is for a session that's over
+                if tunnel_eof {
+                    break;
+                }
+            } else if TUNNEL_STREAM == event.token() && event.is_writable() {
+                if let Err(e) = streams.flush(TUNNEL_STREAM.0) {
+                    error!("flush failure for {} {e}", TUNNEL_STREAM.0);
+                    return Err(e);
+                }
+            } else {
+                let addr = event.token().0;
+                let budget = overload.read_budget();
+
+                if internet_event(addr, event, listeners, &channels, streams, &mut read_buffer, budget)?
+                    && !deferred_reads.contains(&addr)
+                {
+                    deferred_reads.push(addr);
+                }
+            }
+        }
+
+        //
+        // what came from the tunnel, once the events are handled. A congested
+        // local socket holds it back, it's picked up again once that drained
+        //
+        if std::mem::take(&mut tunnel_input) || streams.take_resumed() {
+            if tunnel_frames(streams, listeners, &mut flows, config, &mut peer, watchdog)? && listeners.is_empty() {
+                listen_lazy(poll, listeners, lazy, config)?;
+            }
+
+            if tunnel_eof {
+                return Err(Error::Eof);
+            }
+        }
+
+        //
+        // undo what the operator overrides held back
+        //
+        if let Some(c) = control.as_deref_mut() {
+            for o in c.overrides.take_ended() {
+                match o {
+                    Override::StopAccepting => {
+                        deferred_accepts = (0..listeners.len()).collect();
+                    }
+                    // the client can't be blamed for the paused time
+                    Override::PauseTimers => last_read = Instant::now(),
+                    Override::StopKeepalives => {}
+                }
+            }
+        }
+
+        //
+        // what the budgets left over from the previous iterations, edge
+        // triggered sockets won't tell again
+        //
+        if overridden(&control, Override::StopAccepting) {
+            deferred_accepts.clear();
+        }
+
+        for idx in std::mem::take(&mut deferred_accepts) {
+            let budget = overload.accept_budget();
+            let l = &mut listeners[idx];
+            if accept_forward(idx, l, streams, &mut channels, budget, capacity)? {
+                deferred_accepts.push(idx);
+            }
+        }
+
+        for addr in std::mem::take(&mut deferred_reads) {
+            if !streams.contains_token(addr) {
+                continue;
+            }
+
+            let label = stream_label(addr, listeners, &channels);
+
+            if internet_read(addr, label, streams, &mut read_buffer, overload.read_budget())? {
+                deferred_reads.push(addr);
+            }
+        }
+
+        stall_point(&session_label);
+
+        match overload.on_iteration(iteration.elapsed()) {
+            Some(e @ OverloadEvent::Start { .. }) => warn!("{e} streams={}", streams.len()),
+            Some(e @ OverloadEvent::End) => info!("{e}"),
+            None => {}
+        }
+    }
+}
+
+//
+// only the handshakes that follow see the new banner
+//
+fn reload_motd(config: &mut ServerConfig) {
+    if take_sighup()
+        && let Some(path) = &config.motd_path
+    {
+        match load_motd(path) {
+            Ok(v) => {
+                info!("reloaded {}", path.display());
+                config.motd = Some(v);
+            }
+            Err(e) => error!("unable to reload {} ({e}), keeping the previous one", path.display()),
+        }
+    }
+}
+
+//
+// How a session that didn't take the server down ended
+//
+#[derive(Debug, Clone, Copy)]
+struct SessionEnd {
+    // on a retryable error, the operator's doing isn't one
+    failed: bool,
+    lasted: Duration,
+}
+
+//
+// --max-tunnel-failures, the sessions in a row that failed
+//
+#[derive(Debug)]
+struct FailureBudget {
+    max: Option<usize>,
+    failures: usize,
+}
+
+impl FailureBudget {
+    fn new(max: Option<usize>) -> Self {
+        Self { max, failures: 0 }
+    }
+
+    fn on_end(&mut self, end: SessionEnd) -> Result<()> {
+        if !end.failed || end.lasted >= STABLE_SESSION {
+            self.failures = 0;
+            return Ok(());
+        }
+
+        self.failures += 1;
+
+        match self.max {
+            Some(max) if self.failures >= max => {
+                error!("{} tunnel failures in a row, giving up", self.failures);
+                Err(Error::TooManyFailures {
+                    failures: self.failures,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+//
+// One client, from its connection to its disconnection. Only errors that
+// would end the next session the same way are returned
+//
+fn run_session(
+    tstream: TcpStream,
+    listeners: &mut Vec<Listener>,
+    config: &ServerConfig,
+    env: SessionEnv,
+) -> Result<SessionEnd> {
+    let webhook = env.webhook;
+    let start = Instant::now();
+
+    match tstream.peer_addr() {
+        Ok(v) => webhook.notify(EventKind::TunnelUp, v),
+        Err(_) => webhook.notify(EventKind::TunnelUp, "?"),
+    }
+
+    let fds = match config.check_fds {
+        true => fd_count(),
+        false => None,
+    };
+
+    let ret = tunnel_handler(tstream, listeners, config, env);
+
+    if config.check_fds && !check_fds(fds) {
+        error!("session left fds behind {:?}", last_fd_check());
+    }
+
+    match &ret {
+        Ok(_) => webhook.notify(EventKind::TunnelDown, "closed"),
+        Err(e) => webhook.notify(EventKind::TunnelDown, e),
+    }
+
+    Ok(SessionEnd {
+        failed: session_failed(ret)?,
+        lasted: start.elapsed(),
+    })
+}
+
+//
+// Whether the session ended on a failure, only errors that would end the
+// next session the same way are returned
+//
+fn session_failed(ret: Result<()>) -> Result<bool> {
+    let failed = match ret {
+        Ok(_) => {
+            info!("tunnel disconnected");
+            false
+        }
+        Err(Error::TunnelDropped) => {
+            info!("tunnel dropped by the operator");
+            false
+        }
+        Err(Error::TunnelReplaced) => {
+            info!("tunnel replaced by a new client");
+            false
+        }
+        // whatever the reason, the client's word ends its own session only
+        Err(Error::Goodbye { reason, .. }) => {
+            info!("tunnel closed by the client ({reason})");
+            false
+        }
+        // the client hung up, the way they leave
+        Err(Error::Eof) => {
+            info!("tunnel disconnected (EOF)");
+            false
+        }
+        Err(Error::TunnelTimeout) => {
+            info!("tunnel disconnected (timeout)");
+            true
+        }
+        Err(Error::Internal { payload }) => {
+            error!("session aborted by a panic ({payload}), panics={}", panic_count());
+            true
+        }
+        // it'll never work, a broken config rather than a flapping network
+        Err(e) if ErrorClass::Fatal == e.class() => {
+            error!("tunnel error: {e} ({})", e.class());
+            return Err(e);
+        }
+        Err(e) => {
+            error!("tunnel error: {e} ({})", e.class());
+            true
+        }
+    };
+
+    Ok(failed)
+}
+
+//
+// --max-clients, one of the clients multi_client_main() serves. Its streams
+// are registered from its slot's token base on, the same loop polls them all
+//
+struct ClientSession {
+    streams: TokenStreams,
+    taddr: SocketAddr,
+    // what the client said about itself
+    peer: Hello,
+    // forward of each stream, for the logs
+    channels: HashMap<Address, usize>,
+    flows: UdpFlows,
+    read_buffer: Vec<u8>,
+    start: Instant,
+    // last time the client was heard of
+    last_read: Instant,
+    last_keepalive: Instant,
+    // read from the tunnel, handled after the other events
+    tunnel_input: bool,
+    tunnel_eof: bool,
+    // cut short by the read budget, resumed on the next iteration
+    deferred_reads: Vec<Address>,
+    // the session ended then, what it still writes goes out before the close
+    closing: Option<Instant>,
+}
+
+impl ClientSession {
+    fn new(
+        slot: usize,
+        tstream: TcpStream,
+        taddr: SocketAddr,
+        listeners: &[Listener],
+        config: &ServerConfig,
+        registry: &Registry,
+    ) -> Result<Self> {
+        let mut streams = session_streams(registry.try_clone()?, config);
+        streams.set_token_base(FIRST_CLIENT + slot * CLIENT_TOKENS);
+
+        streams.add_tunnel(TUNNEL_STREAM.0, ClientStream::new(tstream)?)?;
+
+        let hello = server_hello(listeners, &[], config)?;
+        streams.write_control(TUNNEL_STREAM.0, PacketMessage::Hello, &hello.encode())?;
+
+        let read_buffer = vec![0; streams.buffer_size()];
+
+        Ok(Self {
+            streams,
+            taddr,
+            peer: Hello::default(),
+            channels: HashMap::new(),
+            flows: UdpFlows::new(),
+            read_buffer,
+            start: Instant::now(),
+            last_read: Instant::now(),
+            last_keepalive: Instant::now(),
+            tunnel_input: false,
+            tunnel_eof: false,
+            deferred_reads: Vec::new(),
+            closing: None,
+        })
+    }
+
+    fn capacity(&self, config: &ServerConfig) -> Capacity {
+        Capacity {
+            max: session_max_connections(config.max_connections, self.peer.max_connections),
+            queue: config.queue_when_full,
+        }
+    }
+
+    fn has_room(&self, config: &ServerConfig) -> bool {
+        match self.capacity(config).max {
+            // the tunnel doesn't count
+            Some(max) => self.streams.len().saturating_sub(1) < max,
+            None => true,
+        }
+    }
+
+    //
+    // Before the events, what the previous iteration or the sleep left over
+    //
+    fn on_iteration(&mut self, listeners: &mut [Listener], config: &ServerConfig, resumed: bool) -> Result<()> {
+        if self.streams.is_throttled() {
+            self.streams.flush(TUNNEL_STREAM.0)?;
+        }
+
+        if resumed {
+            // the client answers if the tunnel survived
+            self.streams.write_control(TUNNEL_STREAM.0, PacketMessage::Probe, &[])?;
+            self.last_keepalive = Instant::now();
+        }
+
+        let capacity = self.capacity(config);
+        forward_parked(listeners, &mut self.streams, &mut self.channels, capacity)
+    }
+
+    fn on_tick(&mut self, config: &ServerConfig, stalled: Duration, timers: bool) -> Result<()> {
+        session_tick(&mut self.streams, &mut self.channels, &mut self.flows, config, timers)?;
+
+        // not read meanwhile, the client isn't silent
+        if self.streams.is_congested() {
+            self.last_read = Instant::now();
+        }
+
+        if timers && !config.tunnel_timeout.is_zero() {
+            check_silence(
+                &mut self.streams,
+                self.last_read,
+                &mut self.last_keepalive,
+                config,
+                stalled,
+                true,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn on_stats(&mut self) -> Result<()> {
+        if log_enabled!(Level::Debug) {
+            for info in self.streams.snapshot() {
+                debug!("{} stream {info}", self.taddr);
+            }
+        }
+
+        if self.peer.has_feature(FEATURE_STATS) {
+            let totals = self.streams.totals().encode()?;
+            self.streams.write_control(TUNNEL_STREAM.0, PacketMessage::Stats, &totals)?;
+        }
+
+        Ok(())
+    }
+
+    fn on_event(
+        &mut self,
+        addr: Address,
+        event: &Event,
+        listeners: &[Listener],
+        overload: &mut Overload,
+    ) -> Result<()> {
+        if TUNNEL_STREAM.0 != addr {
+            let budget = overload.read_budget();
+
+            if internet_event(
+                addr,
+                event,
+                listeners,
+                &self.channels,
+                &mut self.streams,
+                &mut self.read_buffer,
+                budget,
+            )? && !self.deferred_reads.contains(&addr)
+            {
+                self.deferred_reads.push(addr);
+            }
+        } else if event.is_readable() {
+            // the frames that came with an EOF are handled first
+            self.tunnel_eof = match self.streams.flush_read(TUNNEL_STREAM.0) {
+                Err(Error::Eof) => true,
+                ret => {
+                    ret?;
+                    false
+                }
+            };
+            self.tunnel_input = true;
+            self.last_read = Instant::now();
+            overload.reset_stalled();
+        } else if event.is_writable() {
+            self.streams.flush(TUNNEL_STREAM.0)?;
+        }
+
+        Ok(())
+    }
+
+    fn on_frames(&mut self, listeners: &[Listener], config: &ServerConfig, watchdog: &Watchdog) -> Result<()> {
+        failpoint(&self.taddr.to_string());
+
+        if std::mem::take(&mut self.tunnel_input) || self.streams.take_resumed() {
+            tunnel_frames(
+                &mut self.streams,
+                listeners,
+                &mut self.flows,
+                config,
+                &mut self.peer,
+                watchdog,
+            )?;
+
+            if self.tunnel_eof {
+                return Err(Error::Eof);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_deferred(&mut self, listeners: &[Listener], budget: usize) -> Result<()> {
+        for addr in std::mem::take(&mut self.deferred_reads) {
+            if !self.streams.contains_token(addr) {
+                continue;
+            }
+
+            let label = stream_label(addr, listeners, &self.channels);
+
+            if internet_read(addr, label, &mut self.streams, &mut self.read_buffer, budget)? {
+                self.deferred_reads.push(addr);
+            }
+        }
+
+        Ok(())
+    }
+
+    //
+    // The session is over, the Goodbye is queued and nothing more is read.
+    // The loop keeps flushing until drained()
+    //
+    fn close(&mut self, e: &Error) {
+        queue_goodbye(&mut self.streams, TUNNEL_STREAM.0, e);
+        self.deferred_reads.clear();
+        self.closing = Some(Instant::now());
+    }
+
+    //
+    // A closing session's writes, without waiting. True once they're out or
+    // TEARDOWN_FLUSH is over
+    //
+    fn drained(&mut self) -> bool {
+        let since = match self.closing {
+            Some(v) => v,
+            None => return false,
+        };
+
+        // a tunnel that failed has nothing more to send
+        let tunnel = match self.streams.flush(TUNNEL_STREAM.0) {
+            Ok(_) => self.streams.buffered_len(TUNNEL_STREAM.0).unwrap_or_default(),
+            Err(e) => {
+                debug!("unable to send the goodbye ({e})");
+                0
+            }
+        };
+        let left = self.streams.flush_all(Duration::ZERO);
+
+        if 0 == tunnel + left {
+            return true;
+        }
+
+        if since.elapsed() >= TEARDOWN_FLUSH {
+            if 0 != left {
+                warn!("{left} bytes for the connections never went out");
+            }
+            return true;
+        }
+
+        false
+    }
+
+    //
+    // The client's connections are closed, the other clients' go on
+    //
+    fn reap(mut self) {
+        self.streams.close_all(CloseReason::TunnelLost);
+
+        info!("{} session summary: {}", self.taddr, self.streams.tunnel_stats());
+        info!("{} session streams: {}", self.taddr, self.streams.totals());
+    }
+}
+
+fn client_token(token: Token) -> Option<(usize, Address)> {
+    let offset = token.0.checked_sub(FIRST_CLIENT)?;
+    Some((offset / CLIENT_TOKENS, offset % CLIENT_TOKENS))
+}
+
+//
+// The clients still served, not the ones closing or whose session ended
+// earlier in the iteration
+//
+fn serving(clients: &[Option<ClientSession>], ended: &[(usize, Error)]) -> Vec<usize> {
+    (0..clients.len())
+        .filter(|i| matches!(&clients[*i], Some(c) if c.closing.is_none()))
+        .filter(|i| !ended.iter().any(|(e, _)| e == i))
+        .collect()
+}
+
+//
+// The clients waiting on the tunnel listener, each in a free slot. True when
+// one attached
+//
+fn attach_clients(
+    tunnel_listener: &TcpListener,
+    clients: &mut [Option<ClientSession>],
+    listeners: &[Listener],
+    config: &mut ServerConfig,
+    registry: &Registry,
+    webhook: &Webhook,
+) -> bool {
+    let mut attached = false;
+
+    loop {
+        let (tstream, taddr) = match tunnel_listener.accept() {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("tunnel accept failure ({e})");
+                break;
+            }
+        };
+
+        // a closing one keeps its slot until it's drained
+        let slot = match clients.iter().position(Option::is_none) {
+            Some(v) => v,
+            None => {
+                warn!("{} clients already connected, refusing {taddr:?}", clients.len());
+                refuse_tunnel(tstream, GoodbyeReason::Busy, "too many clients");
+                continue;
+            }
+        };
+
+        info!("tunnel connected: {taddr:?}");
+
+        reload_motd(config);
+
+        match catch_session(|| ClientSession::new(slot, tstream, taddr, listeners, config, registry)) {
+            Ok(c) => {
+                // the first one up, the others only add to it
+                if serving(clients, &[]).is_empty() {
+                    webhook.notify(EventKind::TunnelUp, taddr);
+                }
+                clients[slot] = Some(c);
+                attached = true;
+            }
+            Err(e) => error!("unable to serve {taddr:?} ({e})"),
+        }
+    }
+
+    attached
+}
+
+//
+// The next client in turn with room for a connection, or the next one at all
+// once they're full, its capacity queues or drops it
+//
+fn next_client(
+    clients: &[Option<ClientSession>],
+    next: usize,
+    config: &ServerConfig,
+    ended: &[(usize, Error)],
+) -> Option<usize> {
+    let mut live = serving(clients, ended);
+    live.sort_by_key(|i| (*i + clients.len() - next) % clients.len());
+
+    live.iter()
+        .find(|i| clients[**i].as_ref().is_some_and(|c| c.has_room(config)))
+        .or(live.first())
+        .copied()
+}
+
+//
+// accept_forward() one connection at a time, each to the next client. Same
+// return, true when there may be more
+//
+fn accept_round_robin(
+    idx: usize,
+    listeners: &mut [Listener],
+    clients: &mut [Option<ClientSession>],
+    next: &mut usize,
+    config: &ServerConfig,
+    budget: usize,
+    ended: &mut Vec<(usize, Error)>,
+) -> bool {
+    for _ in 0..budget {
+        let i = match next_client(clients, *next, config, ended) {
+            Some(v) => v,
+            None => return false,
+        };
+        *next = (i + 1) % clients.len();
+
+        let c = match clients[i].as_mut() {
+            Some(v) => v,
+            None => return false,
+        };
+
+        let l = &mut listeners[idx];
+        let capacity = c.capacity(config);
+
+        match catch_session(|| accept_forward(idx, l, &mut c.streams, &mut c.channels, 1, capacity)) {
+            // queueing or out of fds, backing off
+            Ok(true) if !l.accept_wait(Instant::now()).is_zero() => return true,
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(e) => {
+                ended.push((i, e));
+                return true;
+            }
+        }
+    }
+
+    true
+}
+
+//
+// The server is going away on `e`, the clients are told and their
+// connections get what's left before they close
+//
+fn shutdown(clients: &mut [Option<ClientSession>], e: &Error) {
+    for c in clients.iter_mut().flatten() {
+        if c.closing.is_none() {
+            c.close(e);
+        }
+    }
+
+    loop {
+        let mut pending = 0;
+
+        for c in clients.iter_mut().flatten() {
+            if !c.drained() {
+                pending += 1;
+            }
+        }
+
+        if 0 == pending {
+            break;
+        }
+
+        thread::sleep(TEARDOWN_POLL);
+    }
+
+    for c in clients.iter_mut().filter_map(Option::take) {
+        c.reap();
+    }
+}
+
+//
+// --max-clients: the clients share the loop, the tunnel listener stays
+// registered in it. Each has its own TokenStreams ( tunnel, credit, mtu,
+// stats ) registered past the others, a connection goes to the next client
+// with room and stays with it. A client that goes away closes its own
+// connections, the next ones go to the others. The UDP flows all go through
+// the client attached the longest
+//
+fn multi_client_main(
+    config: &mut ServerConfig,
+    mut listeners: Vec<Listener>,
+    watchdog: &Watchdog,
+    webhook: &Webhook,
+) -> Result<()> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(128);
+
+    let mut tunnel_listener = bind_tunnel(config)?;
+    poll.registry()
+        .register(&mut tunnel_listener, TUNNEL_PORT, Interest::READABLE)?;
+
+    register_listeners(poll.registry(), &mut listeners)?;
+
+    let mut clients: Vec<Option<ClientSession>> = (0..config.max_clients).map(|_| None).collect();
+    // the round-robin's next client
+    let mut next = 0;
+
+    let mut datagram = vec![0; MAX_DATAGRAM];
+
+    let mut housekeeping = Timers::new();
+    housekeeping.every(Housekeeping::Tick, TICK_INTERVAL, Instant::now());
+    housekeeping.every(Housekeeping::Stats, STATS_INTERVAL, Instant::now());
+
+    let mut budget = FailureBudget::new(config.max_tunnel_failures);
+    let mut overload = Overload::new();
+    let mut clock = ResumeDetector::new();
+    let baseline = Usage::sample();
+
+    // cut short by the overload budget, resumed on the next iteration
+    let mut deferred_accepts: Vec<usize> = Vec::new();
+
+    info!("-----------------------------SERVER-----------------------------");
+
+    let e = loop {
+        // a listener backing off isn't ready before then
+        let accept_wait = deferred_accepts
+            .iter()
+            .map(|idx| listeners[*idx].accept_wait(Instant::now()))
+            .min();
+
+        // until the next housekeeping deadline at most
+        let wait = housekeeping.wait(Instant::now(), TICK_INTERVAL);
+
+        let timeout = if clients.iter().flatten().any(|c| !c.deferred_reads.is_empty()) {
+            Duration::ZERO
+        } else if let Some(accept) = accept_wait {
+            accept.min(wait)
+        } else if clients.iter().flatten().any(|c| c.closing.is_some()) {
+            TEARDOWN_POLL.min(wait)
+        } else if clients.iter().flatten().any(|c| c.streams.is_throttled()) {
+            REFILL_INTERVAL.min(wait)
+        } else {
+            wait
+        };
+
+        match poll.poll(&mut events, Some(timeout)) {
+            Ok(_) => {}
+            // signals
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break e.into(),
+        }
+
+        let iteration = Instant::now();
+
+        //
+        // the clients whose session is over, each error stays with its
+        // client. A panic too, caught around everything done for one
+        //
+        let mut ended: Vec<(usize, Error)> = Vec::new();
+
+        let connections: usize = clients.iter().flatten().map(|c| c.streams.len()).sum();
+
+        watchdog.ping();
+        watchdog.set_streams(connections);
+
+        let resumed = match clock.on_sample(ClockSample::now()) {
+            Some(e @ ClockEvent::Resume { .. }) => {
+                warn!("{e}");
+                true
+            }
+            Some(e) => {
+                warn!("{e}");
+                false
+            }
+            None => false,
+        };
+
+        let live = serving(&clients, &ended);
+
+        match live.is_empty() {
+            false => {
+                for i in live {
+                    if let Some(c) = clients[i].as_mut()
+                        && let Err(e) = catch_session(|| c.on_iteration(&mut listeners, config, resumed))
+                    {
+                        ended.push((i, e));
+                    }
+                }
+            }
+            // every time, the full ones wouldn't tell again
+            true => {
+                for l in listeners.iter_mut() {
+                    park_connections(l, config.when_down);
+                }
+            }
+        }
+
+        let due = housekeeping.due(iteration);
+
+        if due.contains(&Housekeeping::Tick) {
+            // nothing expires because the host slept
+            let timers = !clock.in_grace(iteration);
+
+            listeners_tick(&mut listeners, config, webhook, iteration);
+
+            for i in serving(&clients, &ended) {
+                if let Some(c) = clients[i].as_mut()
+                    && let Err(e) = catch_session(|| c.on_tick(config, overload.stalled(), timers))
+                {
+                    ended.push((i, e));
+                }
+            }
+        }
+
+        if due.contains(&Housekeeping::Stats) {
+            let usage = Usage::sample();
+            let attached = clients.iter().flatten().count();
+            match usage.fd_leak(&baseline, connections) {
+                true => warn!("resources: {usage} clients={attached} connections={connections} fd leak?"),
+                false => info!("resources: {usage} clients={attached} connections={connections}"),
+            }
+
+            for i in serving(&clients, &ended) {
+                if let Some(c) = clients[i].as_mut()
+                    && let Err(e) = catch_session(|| c.on_stats())
+                {
+                    ended.push((i, e));
+                }
+            }
+        }
+
+        for event in events.iter() {
+            watchdog.record(Activity::Event {
+                token: event.token().0,
+                readable: event.is_readable(),
+                writable: event.is_writable(),
+            });
+
+            if TUNNEL_PORT == event.token() {
+                //
+                // what's in the backlog didn't wait for a client, and won't
+                // tell again
+                //
+                if attach_clients(
+                    &tunnel_listener,
+                    &mut clients,
+                    &listeners,
+                    config,
+                    poll.registry(),
+                    webhook,
+                ) {
+                    deferred_accepts = (0..listeners.len()).collect();
+                }
+                continue;
+            }
+
+            if let Some(idx) = listener_index(event.token(), &listeners) {
+                match listeners[idx].forward.socket {
+                    // parked above while there's no client
+                    ForwardSocket::Tcp(_) => {
+                        let budget = overload.accept_budget();
+                        if accept_round_robin(idx, &mut listeners, &mut clients, &mut next, config, budget, &mut ended)
+                            && !deferred_accepts.contains(&idx)
+                        {
+                            deferred_accepts.push(idx);
+                        }
+                    }
+                    ForwardSocket::Udp(_) => {
+                        let oldest = serving(&clients, &ended)
+                            .into_iter()
+                            .min_by_key(|i| clients[*i].as_ref().map(|c| c.start));
+
+                        if let Some(i) = oldest
+                            && let Some(c) = clients[i].as_mut()
+                        {
+                            let max = c.capacity(config).max;
+                            let l = &mut listeners[idx];
+                            let ret =
+                                catch_session(|| udp_forward(idx, l, &mut c.streams, &mut c.flows, &mut datagram, max));
+                            if let Err(e) = ret {
+                                ended.push((i, e));
+                            }
+                        }
+                    }
+                    ForwardSocket::Removed => {}
+                }
+                continue;
+            }
+
+            let (i, addr) = match client_token(event.token()) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let c = match clients.get_mut(i).and_then(Option::as_mut) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            //
+            // a closing client is only written to, the rest of the batch is
+            // for a session that's over
+            //
+            if c.closing.is_some() {
+                if event.is_writable() && c.streams.contains_token(addr) {
+                    let _ = c.streams.flush(addr);
+                }
+                continue;
+            }
+
+            if ended.iter().any(|(e, _)| *e == i) {
+                continue;
+            }
+
+            if let Err(e) = catch_session(|| c.on_event(addr, event, &listeners, &mut overload)) {
+                ended.push((i, e));
+            }
+        }
+
+        //
+        // what came from the tunnels, once the events are handled
+        //
+        for i in serving(&clients, &ended) {
+            if let Some(c) = clients[i].as_mut()
+                && let Err(e) = catch_session(|| c.on_frames(&listeners, config, watchdog))
+            {
+                ended.push((i, e));
+            }
+        }
+
+        //
+        // what the budgets left over from the previous iterations, edge
+        // triggered sockets won't tell again
+        //
+        if !serving(&clients, &ended).is_empty() {
+            for idx in std::mem::take(&mut deferred_accepts) {
+                let budget = overload.accept_budget();
+                if accept_round_robin(idx, &mut listeners, &mut clients, &mut next, config, budget, &mut ended) {
+                    deferred_accepts.push(idx);
+                }
+            }
+        }
+
+        for i in serving(&clients, &ended) {
+            if let Some(c) = clients[i].as_mut()
+                && let Err(e) = catch_session(|| c.read_deferred(&listeners, overload.read_budget()))
+            {
+                ended.push((i, e));
+            }
+        }
+
+        let mut gave_up = None;
+
+        for (i, e) in ended {
+            // the first error a session ran into ended it
+            let c = match clients[i].as_mut() {
+                Some(v) if v.closing.is_none() => v,
+                _ => continue,
+            };
+
+            info!("tunnel {} ended", c.taddr);
+            c.close(&e);
+            let lasted = c.start.elapsed();
+
+            // the last one down, the others kept it up
+            if serving(&clients, &[]).is_empty() {
+                webhook.notify(EventKind::TunnelDown, &e);
+            }
+
+            // a Fatal one too, it's about that client's session only
+            let end = SessionEnd {
+                failed: session_failed(Err(e)).unwrap_or(true),
+                lasted,
+            };
+
+            if let Err(e) = budget.on_end(end) {
+                gave_up = Some(e);
+                break;
+            }
+        }
+
+        if let Some(e) = gave_up {
+            break e;
+        }
+
+        for slot in clients.iter_mut() {
+            if let Some(c) = slot
+                && catch_session(|| Ok(c.drained())).unwrap_or(true)
+                && let Some(c) = slot.take()
+            {
+                c.reap();
+            }
+        }
+
+        match overload.on_iteration(iteration.elapsed()) {
+            Some(e @ OverloadEvent::Start { .. }) => warn!("{e} streams={connections}"),
+            Some(e @ OverloadEvent::End) => info!("{e}"),
+            None => {}
+        }
+    };
+
+    shutdown(&mut clients, &e);
+
+    Err(e)
+}
+
+pub fn server_main(config: &ServerConfig, forwards: Vec<Forward>) -> Result<()> {
+    let watchdog = Watchdog::new();
+    watchdog.spawn(config.watchdog_timeout);
+
+    let webhook = Webhook::spawn(config.webhook.as_ref(), "server")?;
+
+    let mut config = config.clone();
+
+    let mut listeners: Vec<Listener> = forwards.into_iter().map(|forward| Listener::new(forward, &config)).collect();
+
+    if config.max_clients > 1 {
+        return multi_client_main(&mut config, listeners, &watchdog, &webhook);
+    }
+
+    // closed now, bound again for each session
+    let lazy: Vec<LazyForward> = match config.lazy_listen {
+        true => listeners
+            .drain(..)
+            .map(|l| LazyForward::new(&l.forward))
+            .collect::<Result<_>>()?,
+        false => Vec::new(),
+    };
+
+    let mut control = match &config.control_socket {
+        Some(path) => Some(Control::bind(path, config.override_ttl.unwrap_or(DEF_OVERRIDE_TTL))?),
+        None => None,
+    };
+
+    let mut port = TunnelPort {
+        listener: bind_tunnel(&config)?,
+        takeover: config.tunnel_takeover,
+        next: None,
+    };
+
+    let mut budget = FailureBudget::new(config.max_tunnel_failures);
+
+    let mut accept_errors = RepeatLog::new(REPEAT_SUMMARY);
+
+    loop {
+        let tstream = match port.next.take() {
+            Some(v) => v,
+            None => match tunnel_accept(&mut port.listener, &mut listeners, &config, &watchdog, control.as_mut()) {
+                Ok(v) => {
+                    if let Some(line) = accept_errors.on_success() {
+                        info!("tunnel accepted, {line}");
+                    }
+                    v
+                }
+                Err(e) if ErrorClass::Fatal == e.class() => return Err(e),
+                Err(e) => {
+                    let what = format!("tunnel accept error: {e} ({})", e.class());
+                    if let Some(line) = accept_errors.on_failure(&what, Instant::now()) {
+                        error!("{line}");
+                    }
+                    budget.on_end(SessionEnd {
+                        failed: true,
+                        lasted: Duration::ZERO,
+                    })?;
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+            },
+        };
+
+        reload_motd(&mut config);
+
+        let env = SessionEnv {
+            watchdog: &watchdog,
+            webhook: &webhook,
+            control: control.as_mut(),
+            tunnel_port: Some(&mut port),
+            lazy: &lazy,
+        };
+
+        let end = run_session(tstream, &mut listeners, &config, env)?;
+        budget.on_end(end)?;
+
+        if end.failed {
+            thread::sleep(RETRY_DELAY);
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::{self, Read, Write},
+        thread::sleep,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        streams::WINDOW_SIZE,
+        test_util::{
+            TEST_TIMEOUT, connect_retry, endpoint, free_port, start_services, start_tunnel, start_tunnel_with,
+        },
+        tunnel_client::ClientConfig,
+        unwind::{arm_failpoint, arm_stall},
+    };
+
+    #[test]
+    fn port_list() {
+        assert_eq!(parse_port_list("8080").unwrap(), vec![8080]);
+        assert_eq!(
+            parse_port_list("8080,8081,8090-8093,0").unwrap(),
+            vec![8080, 8081, 8090, 8091, 8092, 8093, 0]
+        );
+
+        assert!(parse_port_list("").is_err());
+        assert!(parse_port_list("8080,").is_err());
+        assert!(parse_port_list("8099-8090").is_err());
+        assert!(parse_port_list("0,8080").is_err());
+        assert!(parse_port_list("0-10").is_err());
+        assert!(parse_port_list("65536").is_err());
+    }
+
+    #[test]
+    fn address_allocation() {
+        let mut streams = TokenStreams::new();
+
+        // never CONTROL_ADDRESS nor the server tokens
+        let first = streams.allocate_address().unwrap();
+        assert!(first > TUNNEL_STREAM.0);
+        assert_eq!(streams.allocate_address(), Some(first + 1));
+    }
+
+    #[test]
+    fn bind_fallback() {
+        let blocker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = blocker.local_addr().unwrap().port();
+        let free = crate::test_util::free_port();
+
+        let listener = bind_internet("127.0.0.1", &[taken, free]).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), free);
+
+        // last resort
+        let listener = bind_internet("127.0.0.1", &[taken, 0]).unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), taken);
+
+        match bind_internet("127.0.0.1", &[taken]) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::AddrInUse),
+            _ => panic!("expecting AddrInUse"),
+        }
+    }
+
+    #[test]
+    fn slow_endpoint_bounded() {
+        const TOTAL: usize = 64 * 1024 * 1024;
+        // kernel buffers on both ends of the path can absorb a few MB each
+        const BOUND: usize = 32 * 1024 * 1024;
+
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.set_nonblocking(true).unwrap();
+
+        let chunk = vec![0x41; 64 * 1024];
+        let mut sent = 0;
+        let mut stalled = Instant::now();
+
+        //
+        // the endpoint never reads, push until the sender stays blocked
+        //
+        while sent < TOTAL && stalled.elapsed() < Duration::from_secs(1) {
+            match internet.write(&chunk) {
+                Ok(v) => {
+                    sent += v;
+                    stalled = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => sleep(Duration::from_millis(5)),
+                Err(e) => panic!("{e}"),
+            }
+        }
+
+        assert!(sent > WINDOW_SIZE, "sent={sent}");
+        assert!(sent < BOUND, "sent={sent}");
+
+        //
+        // everything that was accepted must make it to the endpoint
+        //
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0;
+
+        while received < sent {
+            let len = local.read(&mut buf).unwrap();
+            assert_ne!(len, 0);
+            assert!(buf[..len].iter().all(|b| *b == 0x41));
+            received += len;
+        }
+
+        assert_eq!(received, sent);
+    }
+
+    //
+    // The internet side resets mid-transfer, the endpoint connection goes
+    // away right after instead of lingering until a timeout
+    //
+    #[test]
+    fn internet_reset_closes_endpoint() {
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        let mut internet = connect_retry(&tunnel.server);
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut writer = local.try_clone().unwrap();
+        std::thread::spawn(move || {
+            let chunk = vec![0x41; 64 * 1024];
+            while writer.write_all(&chunk).is_ok() {}
+        });
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0;
+
+        while received < 1024 * 1024 {
+            let len = internet.read(&mut buf).unwrap();
+            assert_ne!(len, 0);
+            received += len;
+        }
+
+        crate::test_util::reset(internet);
+
+        let start = Instant::now();
+
+        match local.read(&mut buf) {
+            Ok(0) => {}
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+            other => panic!("{other:?}"),
+        }
+
+        assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn slow_internet_reader_intact() {
+        use std::hash::{DefaultHasher, Hasher};
+
+        const TOTAL: usize = 8 * 1024 * 1024;
+
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        let payload: Vec<u8> = (0..TOTAL).map(|i| (i * 31 + i / 251) as u8).collect();
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&payload);
+        let expected = hasher.finish();
+
+        let mut internet = connect_retry(&tunnel.server);
+        let (mut local, _) = listener.accept().unwrap();
+
+        std::thread::spawn(move || {
+            local.write_all(&payload).unwrap();
+        });
+
+        //
+        // let the server's writes to the internet hit WouldBlock, then drain
+        // in small bites
+        //
+        sleep(Duration::from_millis(500));
+
+        let mut hasher = DefaultHasher::new();
+        let mut buf = [0; 4096];
+        let mut received = 0;
+        let mut reads = 0;
+
+        while received < TOTAL {
+            let len = internet.read(&mut buf).unwrap();
+            assert_ne!(len, 0);
+            hasher.write(&buf[..len]);
+            received += len;
+
+            reads += 1;
+            if 0 == reads % 64 {
+                sleep(Duration::from_millis(10));
+            }
+        }
+
+        assert_eq!(received, TOTAL);
+        assert_eq!(hasher.finish(), expected);
+    }
+
+    #[test]
+    fn session_panic_recovers() {
+        let (listener, endpoint_addr) = endpoint();
+
+        arm_failpoint("panic-once");
+
+        let before = panic_count();
+        let tunnel = start_services(
+            &[("panic-once", &endpoint_addr)],
+            Default::default(),
+            Default::default(),
+        );
+
+        //
+        // the first session dies, the listener has to be released for the
+        // next one to register it again
+        //
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"hello").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 5] = [0; 5];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hello");
+
+        assert!(panic_count() > before);
+    }
+
+    #[test]
+    fn goodbye_slows_reconnect() {
+        let (listener, endpoint_addr) = endpoint();
+
+        arm_failpoint("goodbye");
+
+        let client_config = ClientConfig {
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        let tunnel = start_services(&[("goodbye", &endpoint_addr)], Default::default(), client_config);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"hello").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 5] = [0; 5];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hello");
+
+        //
+        // the panic was announced, the client waited 10x its usual delay
+        // instead of coming straight back
+        //
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{elapsed:?}");
+    }
+
+    //
+    // One after the other, every address goes back through the Released
+    // round-trip before it's handed out again
+    //
+    #[test]
+    fn sequential_connections_soak() {
+        const CONNECTIONS: usize = 20_000;
+
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        for i in 0..CONNECTIONS {
+            let mut internet = connect_retry(&tunnel.server);
+            internet.write_all(&[i as u8]).unwrap();
+
+            let (mut local, _) = listener.accept().unwrap();
+            local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+            let mut data: [u8; 1] = [0; 1];
+            local.read_exact(&mut data).unwrap();
+            assert_eq!(data[0], i as u8, "connection {i}");
+        }
+    }
+
+    #[test]
+    fn parse_forwards() {
+        assert_eq!(parse_forward("2222:ssh").unwrap(), (vec![2222], "ssh".to_string()));
+        assert_eq!(
+            parse_forward("8080,8081:web").unwrap(),
+            (vec![8080, 8081], "web".to_string())
+        );
+
+        assert!(parse_forward("2222").is_err());
+        assert!(parse_forward("2222:").is_err());
+        assert!(parse_forward("x:ssh").is_err());
+    }
+
+    #[test]
+    fn accept_burst() {
+        const CONNECTIONS: u16 = 500;
+
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        drop(connect_retry(&tunnel.server));
+        let (first, _) = listener.accept().unwrap();
+        drop(first);
+
+        let endpoint = std::thread::spawn(move || {
+            let mut ids = HashSet::new();
+            let mut locals = Vec::new();
+
+            while ids.len() < CONNECTIONS as usize {
+                let (mut local, _) = listener.accept().unwrap();
+                local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+                let mut id: [u8; 2] = [0; 2];
+                local.read_exact(&mut id).unwrap();
+                ids.insert(u16::from_le_bytes(id));
+                locals.push(local);
+            }
+
+            ids
+        });
+
+        let internet: Vec<std::net::TcpStream> = (0..CONNECTIONS)
+            .map(|id| {
+                let mut c = std::net::TcpStream::connect(&tunnel.server).unwrap();
+                c.write_all(&id.to_le_bytes()).unwrap();
+                c
+            })
+            .collect();
+
+        assert_eq!(endpoint.join().unwrap().len(), CONNECTIONS as usize);
+        drop(internet);
+    }
+
+    //
+    // Lowers RLIMIT_NOFILE so nothing below it is free, the whole process is
+    // affected so it runs alone in a child
+    //
+    #[test]
+    fn fd_exhaustion() {
+        use std::os::fd::FromRawFd;
+
+        const FD_CHILD: &str = "PVPN_FD_EXHAUSTION_CHILD";
+        const FLOOD: usize = 20;
+
+        if std::env::var_os(FD_CHILD).is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tunnel_server::tests::fd_exhaustion", "--test-threads=1"])
+                .env(FD_CHILD, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let (listener, endpoint_addr) = endpoint();
+        let tunnel = start_tunnel(&endpoint_addr);
+
+        let mut internet = connect_retry(&tunnel.server);
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let echo = |internet: &mut std::net::TcpStream, local: &mut std::net::TcpStream| {
+            let mut data: [u8; 4] = [0; 4];
+            internet.write_all(b"ping").unwrap();
+            local.read_exact(&mut data).unwrap();
+            local.write_all(b"pong").unwrap();
+            internet.read_exact(&mut data).unwrap();
+            assert_eq!(&data, b"pong");
+        };
+
+        echo(&mut internet, &mut local);
+
+        // the flood's sockets exist before the limit, connect() needs no fd
+        let flood: Vec<libc::c_int> = (0..FLOOD)
+            .map(|_| unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) })
+            .collect();
+        assert!(flood.iter().all(|fd| *fd >= 0));
+
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        assert_eq!(0, unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) });
+
+        // the lowest free fd, nothing below is
+        let lowest = unsafe { libc::dup(0) };
+        unsafe { libc::close(lowest) };
+
+        let lowered = libc::rlimit {
+            rlim_cur: lowest as libc::rlim_t,
+            ..limit
+        };
+        assert_eq!(0, unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) });
+
+        let server: std::net::SocketAddrV4 = tunnel.server.parse().unwrap();
+        let sin = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: server.port().to_be(),
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(*server.ip()).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+
+        for fd in &flood {
+            let ret = unsafe {
+                libc::connect(
+                    *fd,
+                    &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            };
+            assert_eq!(ret, 0);
+        }
+
+        // accept() fails meanwhile, the session and its stream stay
+        sleep(ACCEPT_BACKOFF * 2);
+        echo(&mut internet, &mut local);
+
+        assert_eq!(0, unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) });
+
+        // the backlog is picked up once the fds are back
+        let flood: Vec<std::net::TcpStream> = flood
+            .into_iter()
+            .map(|fd| unsafe { std::net::TcpStream::from_raw_fd(fd) })
+            .collect();
+
+        listener.set_nonblocking(false).unwrap();
+        for _ in 0..FLOOD {
+            drop(listener.accept().unwrap());
+        }
+
+        echo(&mut internet, &mut local);
+        drop(flood);
+    }
+
+    //
+    // Unnamed forwards, the client picks the endpoint by internet port
+    //
+    #[test]
+    fn two_port_forwards() {
+        let (a, a_addr) = endpoint();
+        let (b, b_addr) = endpoint();
+
+        let tunnel = start_services(&[("", &a_addr), ("", &b_addr)], Default::default(), Default::default());
+
+        for (listener, server) in [(a, &tunnel.servers[0]), (b, &tunnel.servers[1])] {
+            let mut internet = connect_retry(server);
+            internet.write_all(server.as_bytes()).unwrap();
+
+            let (mut local, _) = listener.accept().unwrap();
+            local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+            let mut data = vec![0; server.len()];
+            local.read_exact(&mut data).unwrap();
+            assert_eq!(data, server.as_bytes());
+        }
+    }
+
+    #[test]
+    fn two_services() {
+        let (web, web_addr) = endpoint();
+        let (ssh, ssh_addr) = endpoint();
+
+        let tunnel = start_services(
+            &[("web", &web_addr), ("ssh", &ssh_addr)],
+            Default::default(),
+            Default::default(),
+        );
+
+        //
+        // both at once, each has to land on its own endpoint
+        //
+        let mut web_client = connect_retry(&tunnel.servers[0]);
+        let mut ssh_client = connect_retry(&tunnel.servers[1]);
+
+        web_client.write_all(b"GET /").unwrap();
+        ssh_client.write_all(b"SSH-2.0").unwrap();
+
+        let (mut web_local, _) = web.accept().unwrap();
+        let (mut ssh_local, _) = ssh.accept().unwrap();
+        web_local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+        ssh_local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 5] = [0; 5];
+        web_local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"GET /");
+
+        let mut data: [u8; 7] = [0; 7];
+        ssh_local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"SSH-2.0");
+
+        web_local.write_all(b"200").unwrap();
+        ssh_local.write_all(b"banner").unwrap();
+
+        let mut data: [u8; 3] = [0; 3];
+        web_client.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"200");
+
+        let mut data: [u8; 6] = [0; 6];
+        ssh_client.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"banner");
+    }
+
+    #[test]
+    fn udp_datagrams() {
+        let echo = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buf = vec![0; MAX_DATAGRAM];
+            while let Ok((len, peer)) = echo.recv_from(&mut buf) {
+                echo.send_to(&buf[0..len], peer).unwrap();
+            }
+        });
+
+        let config = ClientConfig {
+            protocol: Protocol::Udp,
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&echo_addr, Default::default(), config);
+
+        let internet = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        internet.connect(&tunnel.server).unwrap();
+        internet.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let mut buf = vec![0; MAX_DATAGRAM];
+
+        //
+        // the first ones may be sent before the tunnel is up
+        //
+        let start = std::time::Instant::now();
+        loop {
+            internet.send(b"ping").unwrap();
+            if internet.recv(&mut buf).is_ok() {
+                break;
+            }
+            assert!(start.elapsed() < TEST_TIMEOUT);
+        }
+
+        // drain the late echoes
+        internet.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        while let Ok(len) = internet.recv(&mut buf) {
+            assert_eq!(len, 4);
+        }
+        internet.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        //
+        // boundaries preserved, each one comes back whole and alone
+        //
+        for size in [1, 100, 1400, 9000, 16 * 1024] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            internet.send(&data).unwrap();
+
+            let len = internet.recv(&mut buf).unwrap();
+            assert_eq!(&buf[0..len], &data[..]);
+        }
+    }
+
+    #[test]
+    fn tunnel_timeout() {
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            tunnel_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap(),
+        };
+
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        //
+        // a client that never answers the keepalives, like a dead host would
+        //
+        let mut client = connect_retry(&tunnel);
+        let start = std::time::Instant::now();
+
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).unwrap();
+        assert!(start.elapsed() < TEST_TIMEOUT);
+
+        // and the server takes the next one
+        connect_retry(&tunnel);
+    }
+
+    #[test]
+    fn idle_tunnel_kept() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let config = ServerConfig {
+            tunnel_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, config, Default::default());
+
+        // make sure the tunnel is up before idling
+        drop(connect_retry(&tunnel.server));
+        let _ = listener.accept().unwrap();
+
+        sleep(Duration::from_secs(5));
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"still there").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 11] = [0; 11];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"still there");
+    }
+
+    #[test]
+    fn slow_iteration_not_a_dead_tunnel() {
+        let (listener, endpoint_addr) = endpoint();
+
+        let config = ServerConfig {
+            tunnel_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        let tunnel = start_services(&[("stall", &endpoint_addr)], config, Default::default());
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"before").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 6] = [0; 6];
+        local.read_exact(&mut data).unwrap();
+
+        //
+        // one iteration longer than the timeout, the client didn't go
+        // anywhere so the session has to survive it
+        //
+        arm_stall("stall", Duration::from_secs(3));
+        sleep(Duration::from_secs(5));
+
+        internet.write_all(b"after").unwrap();
+
+        let mut data: [u8; 5] = [0; 5];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"after");
+    }
+
+    fn control_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pvpn-{}-{name}.sock", std::process::id()))
+    }
+
+    //
+    // The socket only exists once server_main is running
+    //
+    fn ctl(path: &std::path::Path, line: &str) -> String {
+        let start = std::time::Instant::now();
+
+        loop {
+            match crate::control::command(path, line) {
+                Ok(v) => return v,
+                Err(e) => {
+                    if start.elapsed() > TEST_TIMEOUT {
+                        panic!("unable to reach {} ({e})", path.display());
+                    }
+                    sleep(Duration::from_millis(20));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stop_accepting_override() {
+        let (listener, endpoint_addr) = endpoint();
+        let path = control_path("accepting");
+
+        let config = ServerConfig {
+            control_socket: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, config, Default::default());
+
+        drop(connect_retry(&tunnel.server));
+        let _ = listener.accept().unwrap();
+
+        assert_eq!(ctl(&path, "stop-accepting"), "ok");
+        assert!(ctl(&path, "status").contains(r#""overrides":[{"name":"stop-accepting""#));
+
+        // the kernel completes the handshake, pvpn doesn't pick it up
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"held").unwrap();
+
+        listener.set_nonblocking(true).unwrap();
+        sleep(Duration::from_millis(500));
+        assert_eq!(listener.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        listener.set_nonblocking(false).unwrap();
+
+        assert_eq!(ctl(&path, "clear stop-accepting"), "ok");
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 4] = [0; 4];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"held");
+    }
+
+    #[test]
+    fn runtime_forwards() {
+        let (listener, endpoint_addr) = endpoint();
+        let path = control_path("forwards");
+
+        let config = ServerConfig {
+            control_socket: Some(path.clone()),
+            ..Default::default()
+        };
+        // where what the Hello doesn't name goes
+        let client_config = ClientConfig {
+            server: endpoint_addr.clone(),
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, config, client_config);
+
+        drop(connect_retry(&tunnel.server));
+        let _ = listener.accept().unwrap();
+
+        let answer = ctl(&path, "add-forward 0");
+        let added = answer.strip_prefix("ok ").unwrap().to_string();
+        let port = added.parse::<SocketAddr>().unwrap().port();
+
+        let list = ctl(&path, "list");
+        assert!(
+            list.contains(&format!(r#""label":"{port}","addr":"{added}","protocol":"tcp""#)),
+            "{list}"
+        );
+        assert!(list.contains(r#""label":"test""#), "{list}");
+
+        let mut internet = connect_retry(&added);
+        internet.write_all(b"new").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 3] = [0; 3];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"new");
+
+        assert!(ctl(&path, "stats").starts_with("streams="));
+
+        // the port closes, the connection it carried doesn't
+        assert_eq!(ctl(&path, &format!("remove-forward {port}")), "ok");
+        assert!(std::net::TcpStream::connect(&added).is_err());
+        assert!(!ctl(&path, "list").contains(&added));
+
+        internet.write_all(b"old").unwrap();
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"old");
+
+        assert!(ctl(&path, &format!("remove-forward {port}")).starts_with("error UnknownForward"));
+
+        // the original forward is still on its channel
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"one").unwrap();
+        let (mut local, _) = listener.accept().unwrap();
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"one");
+    }
+
+    #[test]
+    fn drop_tunnel_override() {
+        let (listener, endpoint_addr) = endpoint();
+        let path = control_path("drop");
+
+        let config = ServerConfig {
+            control_socket: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, config, Default::default());
+
+        let mut internet = connect_retry(&tunnel.server);
+        let _ = listener.accept().unwrap();
+
+        assert_eq!(ctl(&path, "drop-tunnel"), "ok");
+
+        // the session's connections go with it
+        let mut data = Vec::new();
+        let _ = internet.read_to_end(&mut data);
+
+        // and the client comes back
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"again").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 5] = [0; 5];
+        local.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"again");
+    }
+
+    #[test]
+    fn fds_back_to_baseline() {
+        let (listener, endpoint_addr) = endpoint();
+        let path = control_path("fds");
+
+        let config = ServerConfig {
+            control_socket: Some(path.clone()),
+            check_fds: true,
+            ..Default::default()
+        };
+
+        let tunnel = start_tunnel_with(&endpoint_addr, config, Default::default());
+
+        let mut pairs = Vec::new();
+        for _ in 0..100 {
+            let mut internet = connect_retry(&tunnel.server);
+            internet.write_all(b"x").unwrap();
+            let (local, _) = listener.accept().unwrap();
+            pairs.push((internet, local));
+        }
+        drop(pairs);
+
+        assert_eq!(ctl(&path, "drop-tunnel"), "ok");
+
+        let start = std::time::Instant::now();
+        let before = loop {
+            if let Some((before, _)) = last_fd_check() {
+                break before;
+            }
+            assert!(start.elapsed() < TEST_TIMEOUT);
+            sleep(Duration::from_millis(20));
+        };
+
+        // the other tests come and go, a leak stays
+        while fd_count().unwrap() > before + crate::resource::FD_SLACK {
+            assert!(start.elapsed() < TEST_TIMEOUT, "fds never went back to {before}");
+            sleep(Duration::from_millis(20));
+        }
+    }
+
+    //
+    // Server with a control socket and a client that never answers
+    //
+    fn silent_client(name: &str, tunnel_timeout: Duration, override_ttl: Duration) -> (PathBuf, String) {
+        let tunnel = format!("127.0.0.1:{}", free_port());
+        let path = control_path(name);
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            tunnel_timeout,
+            control_socket: Some(path.clone()),
+            override_ttl: Some(override_ttl),
+            ..Default::default()
+        };
+
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap(),
+        };
+
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        (path, tunnel)
+    }
+
+    #[test]
+    fn pause_timers_override() {
+        let ttl = Duration::from_secs(3);
+        let (path, tunnel) = silent_client("timers", Duration::from_secs(1), ttl);
+
+        // set between sessions, the next one inherits it
+        assert_eq!(ctl(&path, "pause-timers"), "ok");
+        let start = std::time::Instant::now();
+
+        let mut client = connect_retry(&tunnel);
+
+        // the timeout only kicks in once the override expired
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).unwrap();
+        assert!(start.elapsed() >= ttl);
+        assert!(start.elapsed() < TEST_TIMEOUT);
+    }
+
+    #[test]
+    fn stop_keepalives_override() {
+        let (path, tunnel) = silent_client("keepalives", Duration::from_secs(3), DEF_OVERRIDE_TTL);
+
+        assert_eq!(ctl(&path, "stop-keepalives"), "ok");
+
+        let mut client = connect_retry(&tunnel);
+
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).unwrap();
+
+        // dropped by the timeout without ever being probed
+        let mut offset = 0;
+        while offset < data.len() {
+            let (p, hdr_len) = Packet::from_buffer(&data[offset..]).unwrap();
+            assert_ne!(p.msg, PacketMessage::Probe);
+            offset += hdr_len + p.data_len as usize;
+        }
+    }
+
+    #[test]
+    fn max_connections_negotiated() {
+        // (server, client, session)
+        let cases = [(5, 2, 2), (1, 5, 1)];
+
+        for (server, client, expected) in cases {
+            let (listener, endpoint_addr) = endpoint();
+            let path = control_path(&format!("capacity-{server}-{client}"));
+
+            let server_config = ServerConfig {
+                control_socket: Some(path.clone()),
+                max_connections: Some(server),
+                ..Default::default()
+            };
+            let client_config = ClientConfig {
+                max_connections: Some(client),
+                ..Default::default()
+            };
+
+            let tunnel = start_tunnel_with(&endpoint_addr, server_config, client_config);
+
+            // the client's Hello made it
+            let negotiated = format!(r#""max_connections":{expected}"#);
+            let start = std::time::Instant::now();
+            while !ctl(&path, "status").contains(&negotiated) {
+                assert!(start.elapsed() < TEST_TIMEOUT);
+                sleep(Duration::from_millis(20));
+            }
+
+            let mut carried = Vec::new();
+            for _ in 0..expected {
+                let mut internet = connect_retry(&tunnel.server);
+                internet.write_all(b"x").unwrap();
+                carried.push((internet, listener.accept().unwrap()));
+            }
+
+            let mut internet = connect_retry(&tunnel.server);
+            let mut data: [u8; 1] = [0; 1];
+            let ret = internet.read(&mut data);
+            assert!(
+                matches!(&ret, Ok(0)) || matches!(&ret, Err(e) if e.kind() == io::ErrorKind::ConnectionReset),
+                "{ret:?}"
+            );
+
+            listener.set_nonblocking(true).unwrap();
+            assert_eq!(listener.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        }
+    }
+
+    //
+    // One byte back per connection, closed once the other side is
+    //
+    fn echo_endpoint() -> String {
+        let (listener, addr) = endpoint();
+
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let mut data: [u8; 1] = [0; 1];
+                    if stream.read_exact(&mut data).is_ok() && stream.write_all(&data).is_ok() {
+                        let _ = stream.read(&mut data);
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn echoed(stream: &mut std::net::TcpStream) -> bool {
+        let mut data: [u8; 1] = [0; 1];
+        matches!(stream.read(&mut data), Ok(1))
+    }
+
+    #[test]
+    fn two_clients() {
+        const CONNECTIONS: usize = 16;
+
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap().to_string();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            max_clients: 2,
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        let mut client_config = ClientConfig {
+            tunnel: tunnel.clone(),
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        client_config.endpoints.insert("test".to_string(), echo_endpoint());
+        std::thread::spawn(move || crate::tunnel_client::client_main(&client_config));
+
+        // only the real client so far
+        let mut internet = connect_retry(&server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+        drop(internet);
+
+        //
+        // a client that never answers, its Hello says the session is up
+        //
+        let mut silent = connect_retry(&tunnel);
+        let mut data: [u8; 1] = [0; 1];
+        silent.read_exact(&mut data).unwrap();
+
+        let connections: Vec<std::net::TcpStream> = (0..CONNECTIONS)
+            .map(|_| {
+                let mut internet = connect_retry(&server);
+                internet.write_all(b"x").unwrap();
+                internet
+            })
+            .collect();
+
+        // no echo from the silent client's
+        let mut silent_ones = Vec::new();
+        for mut internet in connections {
+            internet.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            if !echoed(&mut internet) {
+                silent_ones.push(internet);
+            }
+        }
+        assert!(
+            !silent_ones.is_empty() && silent_ones.len() < CONNECTIONS,
+            "{}",
+            silent_ones.len()
+        );
+
+        // closed along with it
+        drop(silent);
+
+        for mut internet in silent_ones {
+            internet.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+            let ret = internet.read(&mut data);
+            assert!(
+                matches!(&ret, Ok(0)) || matches!(&ret, Err(e) if e.kind() == io::ErrorKind::ConnectionReset),
+                "{ret:?}"
+            );
+        }
+
+        // the new ones all go to the one left
+        for _ in 0..4 {
+            let mut internet = connect_retry(&server);
+            internet.write_all(b"x").unwrap();
+            assert!(echoed(&mut internet));
+        }
+    }
+
+    //
+    // A panic in one client's session only ends that one, the other client's
+    // connections go on
+    //
+    #[test]
+    fn client_panic_isolated() {
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap().to_string();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            max_clients: 2,
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(server_main(&config, vec![forward])).unwrap());
+
+        let mut client_config = ClientConfig {
+            tunnel: tunnel.clone(),
+            ..Default::default()
+        };
+        client_config.endpoints.insert("test".to_string(), echo_endpoint());
+        std::thread::spawn(move || crate::tunnel_client::client_main(&client_config));
+
+        let mut internet = connect_retry(&server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+
+        let mut broken = connect_retry(&tunnel);
+        wait_frame(&mut broken, PacketMessage::Hello);
+
+        let before = panic_count();
+        arm_failpoint(&broken.local_addr().unwrap().to_string());
+
+        let mut frame = Vec::new();
+        Packet::new(CONTROL_ADDRESS, PacketMessage::Probe, 0)
+            .encode(&mut frame)
+            .unwrap();
+        broken.write_all(&frame).unwrap();
+
+        assert_eq!(wait_goodbye(&mut broken).reason, GoodbyeReason::Internal);
+        assert!(panic_count() > before);
+
+        // still open, and the server still there
+        internet.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut data: [u8; 1] = [0; 1];
+        let ret = internet.read(&mut data);
+        assert!(
+            matches!(&ret, Err(e) if e.kind() == io::ErrorKind::WouldBlock),
+            "{ret:?}"
+        );
+        assert!(rx.try_recv().is_err());
+
+        let mut internet = connect_retry(&server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+    }
+
+    #[test]
+    fn connection_during_gap() {
+        let cases = [
+            (WhenDown::Queue, 1),
+            (WhenDown::Refuse, 1),
+            (WhenDown::Queue, 2),
+            (WhenDown::Refuse, 2),
+        ];
+
+        for (when_down, max_clients) in cases {
+            let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+            let server = forward.local_addr().unwrap().to_string();
+            let tunnel = format!("127.0.0.1:{}", free_port());
+
+            let config = ServerConfig {
+                tunnel: tunnel.clone(),
+                when_down,
+                max_clients,
+                ..Default::default()
+            };
+            let forward = Forward {
+                label: "test".to_string(),
+                socket: forward,
+            };
+            std::thread::spawn(move || server_main(&config, vec![forward]));
+
+            // a first client comes and goes
+            let mut first = connect_retry(&tunnel);
+            let mut data: [u8; 1] = [0; 1];
+            first.read_exact(&mut data).unwrap();
+            drop(first);
+            sleep(Duration::from_millis(200));
+
+            let mut internet = connect_retry(&server);
+            internet.write_all(b"x").unwrap();
+
+            let mut client_config = ClientConfig {
+                tunnel,
+                ..Default::default()
+            };
+            client_config.endpoints.insert("test".to_string(), echo_endpoint());
+            std::thread::spawn(move || crate::tunnel_client::client_main(&client_config));
+
+            match when_down {
+                WhenDown::Queue => assert!(echoed(&mut internet)),
+                WhenDown::Refuse => {
+                    let ret = internet.read(&mut data);
+                    assert!(
+                        matches!(&ret, Ok(0)) || matches!(&ret, Err(e) if e.kind() == io::ErrorKind::ConnectionReset),
+                        "{ret:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn queued_when_full() {
+        const MAX: usize = 5;
+
+        let server_config = ServerConfig {
+            max_connections: Some(MAX),
+            queue_when_full: true,
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&echo_endpoint(), server_config, Default::default());
+
+        let open = |count| -> Vec<std::net::TcpStream> {
+            (0..count)
+                .map(|_| {
+                    let mut internet = connect_retry(&tunnel.server);
+                    internet.write_all(b"x").unwrap();
+                    internet
+                })
+                .collect()
+        };
+
+        let mut connections = open(MAX);
+        for internet in connections.iter_mut() {
+            assert!(echoed(internet));
+        }
+
+        let mut queued = open(3 * MAX);
+
+        // still waiting, not closed
+        for internet in queued.iter_mut() {
+            internet.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            let mut data: [u8; 1] = [0; 1];
+            let e = internet.read(&mut data).unwrap_err();
+            assert!(
+                matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut),
+                "{e}"
+            );
+        }
+
+        // the next ones in once those are gone
+        drop(connections);
+
+        for internet in queued.iter_mut().take(MAX) {
+            internet.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+            assert!(echoed(internet));
+        }
+    }
+
+    #[test]
+    fn denied_peer() {
+        let server_config = ServerConfig {
+            acl: crate::acl::Acl {
+                allow: vec!["127.0.0.0/8".parse().unwrap()],
+                deny: vec!["127.0.0.2".parse().unwrap()],
+            },
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&echo_endpoint(), server_config, Default::default());
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+
+        let mut internet = crate::test_util::connect_from("127.0.0.2".parse().unwrap(), &tunnel.server);
+        internet.write_all(b"x").unwrap();
+        let mut data: [u8; 1] = [0; 1];
+        let ret = internet.read(&mut data);
+        assert!(
+            matches!(&ret, Ok(0)) || matches!(&ret, Err(e) if e.kind() == io::ErrorKind::ConnectionReset),
+            "{ret:?}"
+        );
+    }
+
+    fn recv_frame(stream: &mut std::net::TcpStream) -> (Packet, Vec<u8>) {
+        let mut hdr: [u8; crate::packet::HEADER_SIZE] = [0; crate::packet::HEADER_SIZE];
+        stream.read_exact(&mut hdr).unwrap();
+
+        let (p, _) = Packet::from_buffer(&hdr).unwrap();
+        let mut data = vec![0; p.data_len as usize];
+        stream.read_exact(&mut data).unwrap();
+
+        (p, data)
+    }
+
+    //
+    // Frames from the server until a Goodbye
+    //
+    fn wait_goodbye(stream: &mut std::net::TcpStream) -> Goodbye {
+        loop {
+            let (p, data) = recv_frame(stream);
+
+            if p.msg == PacketMessage::Goodbye {
+                return Goodbye::decode(&data).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn second_client_busy() {
+        let tunnel = start_tunnel(&echo_endpoint());
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+
+        let mut second = connect_retry(&tunnel.tunnel);
+        assert_eq!(wait_goodbye(&mut second).reason, GoodbyeReason::Busy);
+
+        let mut data: [u8; 1] = [0; 1];
+        assert_eq!(second.read(&mut data).unwrap(), 0);
+
+        // the first one is still there
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+    }
+
+    #[test]
+    fn tunnel_takeover() {
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap().to_string();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            tunnel_takeover: true,
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        // half-open as far as the server can tell, never answers
+        let mut stale = connect_retry(&tunnel);
+        assert_eq!(recv_frame(&mut stale).0.msg, PacketMessage::Hello);
+
+        let mut pinned = connect_retry(&server);
+        pinned.write_all(b"x").unwrap();
+
+        let mut client_config = ClientConfig {
+            tunnel,
+            ..Default::default()
+        };
+        client_config.endpoints.insert("test".to_string(), echo_endpoint());
+        std::thread::spawn(move || crate::tunnel_client::client_main(&client_config));
+
+        assert_eq!(wait_goodbye(&mut stale).reason, GoodbyeReason::Replaced);
+
+        // closed with the old tunnel
+        let mut data: [u8; 1] = [0; 1];
+        let ret = pinned.read(&mut data);
+        assert!(
+            matches!(&ret, Ok(0)) || matches!(&ret, Err(e) if e.kind() == io::ErrorKind::ConnectionReset),
+            "{ret:?}"
+        );
+
+        let mut internet = connect_retry(&server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+    }
+
+    #[test]
+    fn rapid_tunnel_sessions() {
+        const SESSIONS: usize = 100;
+
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap().to_string();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        //
+        // a session that didn't see the previous EOF yet answers busy
+        //
+        let start = std::time::Instant::now();
+        let mut sessions = 0;
+
+        while sessions < SESSIONS {
+            assert!(start.elapsed() < TEST_TIMEOUT, "{sessions} sessions");
+
+            let mut stream = connect_retry(&tunnel);
+            if PacketMessage::Hello == recv_frame(&mut stream).0.msg {
+                sessions += 1;
+            }
+        }
+
+        // still serving
+        let mut client_config = ClientConfig {
+            tunnel,
+            ..Default::default()
+        };
+        client_config.endpoints.insert("test".to_string(), echo_endpoint());
+        std::thread::spawn(move || crate::tunnel_client::client_main(&client_config));
+
+        let mut internet = connect_retry(&server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+    }
+
+    #[test]
+    fn host_ports() {
+        assert_eq!(host_port("::1", 8080), "[::1]:8080");
+        assert_eq!(host_port("[::1]", 8080), "[::1]:8080");
+        assert_eq!(host_port("127.0.0.1", 8080), "127.0.0.1:8080");
+        assert_eq!(host_port("example.com", 8080), "example.com:8080");
+    }
+
+    #[test]
+    fn dual_stack() {
+        let v4 = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind_v6_only(port, Protocol::Tcp).unwrap();
+
+        let forwards = vec![
+            Forward {
+                label: "test".to_string(),
+                socket: v4,
+            },
+            Forward {
+                label: "test".to_string(),
+                socket: v6,
+            },
+        ];
+
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let server_config = ServerConfig {
+            tunnel: tunnel.clone(),
+            ..Default::default()
+        };
+        let mut client_config = ClientConfig {
+            tunnel,
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        client_config.endpoints.insert("test".to_string(), echo_endpoint());
+
+        thread::spawn(move || server_main(&server_config, forwards));
+        thread::spawn(move || crate::tunnel_client::client_main(&client_config));
+
+        for addr in [format!("127.0.0.1:{port}"), format!("[::1]:{port}")] {
+            let mut internet = connect_retry(&addr);
+            internet.write_all(b"x").unwrap();
+            assert!(echoed(&mut internet), "{addr}");
+        }
+    }
+
+    //
+    // Closed on both ends, the endpoint connection goes with the internet one
+    //
+    #[test]
+    fn idle_timeout() {
+        let (listener, addr) = endpoint();
+
+        let server_config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&addr, server_config, Default::default());
+
+        let mut internet = connect_retry(&tunnel.server);
+        // idle from the last byte on, no sooner than that
+        let start = std::time::Instant::now();
+        internet.write_all(b"x").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        local.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+        let mut data: [u8; 1] = [0; 1];
+        local.read_exact(&mut data).unwrap();
+
+        assert_eq!(internet.read(&mut data).unwrap(), 0);
+        assert_eq!(local.read(&mut data).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    //
+    // The housekeeping tick still runs while another connection keeps the
+    // loop busy, the idle one goes on time
+    //
+    #[test]
+    fn housekeeping_under_load() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let (listener, addr) = endpoint();
+
+        let server_config = ServerConfig {
+            idle_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&addr, server_config, Default::default());
+
+        // echoed back as fast as it comes
+        let mut busy = connect_retry(&tunnel.server);
+        busy.write_all(b"x").unwrap();
+        let (local, _) = listener.accept().unwrap();
+        thread::spawn(move || io::copy(&mut &local, &mut &local));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut writer = busy.try_clone().unwrap();
+        let running = stop.clone();
+        let pump = thread::spawn(move || {
+            let chunk = vec![0x55; 16 * 1024];
+            while !running.load(Ordering::Relaxed) {
+                writer.write_all(&chunk).unwrap();
+            }
+        });
+        let mut reader = busy.try_clone().unwrap();
+        thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+
+        let mut idle = connect_retry(&tunnel.server);
+        idle.write_all(b"x").unwrap();
+        let (mut idle_local, _) = listener.accept().unwrap();
+        let mut data: [u8; 1] = [0; 1];
+        idle_local.read_exact(&mut data).unwrap();
+
+        let start = std::time::Instant::now();
+        idle.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+        assert_eq!(idle.read(&mut data).unwrap(), 0);
+
+        // the timeout and a tick or two late at most
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1) + 3 * TICK_INTERVAL, "{elapsed:?}");
+
+        stop.store(true, Ordering::Relaxed);
+        pump.join().unwrap();
+    }
+
+    //
+    // The internet peer resets with the endpoint's upload still queued on
+    // the server, the client closes the endpoint connection
+    //
+    #[test]
+    fn internet_gone_mid_upload() {
+        let (listener, addr) = endpoint();
+        let tunnel = start_tunnel(&addr);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+
+        let (mut local, _) = listener.accept().unwrap();
+        let mut data: [u8; 1] = [0; 1];
+        local.read_exact(&mut data).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        thread::spawn(move || {
+            let chunk = vec![0x55; 64 * 1024];
+            let ret = loop {
+                if let Err(e) = local.write_all(&chunk) {
+                    break e.kind();
+                }
+            };
+            tx.send(ret).unwrap();
+        });
+
+        // some of it made it, the rest is queued somewhere
+        let mut buf = vec![0; 64 * 1024];
+        internet.read_exact(&mut buf).unwrap();
+        crate::test_util::reset(internet);
+
+        let kind = rx.recv_timeout(TEST_TIMEOUT).unwrap();
+        assert!(
+            matches!(kind, io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset),
+            "{kind:?}"
+        );
+    }
+
+    #[test]
+    fn access_log() {
+        let dir = std::env::temp_dir();
+        let server_log = dir.join(format!("pvpn-access-server-{}.log", std::process::id()));
+        let client_log = dir.join(format!("pvpn-access-client-{}.log", std::process::id()));
+
+        let server_config = ServerConfig {
+            access_log: Some(server_log.clone()),
+            ..Default::default()
+        };
+        let client_config = ClientConfig {
+            access_log: Some(client_log.clone()),
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&echo_endpoint(), server_config, client_config);
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+
+        let peer = internet.local_addr().unwrap().to_string();
+        drop(internet);
+
+        for (path, role) in [(&server_log, "server"), (&client_log, "client")] {
+            let start = std::time::Instant::now();
+
+            let line = loop {
+                if let Ok(v) = std::fs::read_to_string(path)
+                    && !v.is_empty()
+                {
+                    break v;
+                }
+                assert!(start.elapsed() < TEST_TIMEOUT, "nothing in the {role} access log");
+                sleep(Duration::from_millis(20));
+            };
+            std::fs::remove_file(path).unwrap();
+
+            let record: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+
+            assert_eq!(record["role"], role);
+            assert_eq!(record["peer"], peer.as_str());
+            assert_eq!(
+                record["bytes_in"].as_u64().unwrap() + record["bytes_out"].as_u64().unwrap(),
+                2
+            );
+        }
+    }
+
+    //
+    // Frames until one is `msg`
+    //
+    fn wait_frame(stream: &mut std::net::TcpStream, msg: PacketMessage) -> (Packet, Vec<u8>) {
+        loop {
+            let (p, data) = recv_frame(stream);
+
+            if p.msg == msg {
+                return (p, data);
+            }
+        }
+    }
+
+    //
+    // The client's last Data still reaches the internet peer, then it's
+    // closed. The next session starts with none of the old streams
+    //
+    #[test]
+    fn tunnel_eof_teardown() {
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap().to_string();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        let mut client = connect_retry(&tunnel);
+        wait_frame(&mut client, PacketMessage::Hello);
+
+        let mut internet = connect_retry(&server);
+        let (connect, _) = wait_frame(&mut client, PacketMessage::Connect);
+
+        let mut frame = Vec::new();
+        Packet::new_data(connect.addr, 5).encode(&mut frame).unwrap();
+        frame.extend_from_slice(b"reply");
+        client.write_all(&frame).unwrap();
+        drop(client);
+
+        let mut received = Vec::new();
+        internet.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"reply");
+
+        //
+        // a session that didn't see the previous EOF yet answers busy
+        //
+        let mut client = loop {
+            let mut stream = connect_retry(&tunnel);
+            if PacketMessage::Hello == recv_frame(&mut stream).0.msg {
+                break stream;
+            }
+        };
+
+        let _internet = connect_retry(&server);
+        wait_frame(&mut client, PacketMessage::Connect);
+    }
+
+    //
+    // Listening sockets bound by someone else, as systemd hands them over
+    //
+    #[test]
+    fn inherited_listeners() {
+        let tunnel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let internet = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let tunnel_addr = tunnel.local_addr().unwrap().to_string();
+        let server = internet.local_addr().unwrap().to_string();
+
+        let config = ServerConfig {
+            // never bound
+            tunnel: "192.0.2.1:1".to_string(),
+            tunnel_fd: Some(Arc::new(tunnel.into())),
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: ForwardSocket::from_fd(internet.into(), Protocol::Tcp).unwrap(),
+        };
+        thread::spawn(move || server_main(&config, vec![forward]));
+
+        let mut client_config = ClientConfig {
+            tunnel: tunnel_addr,
+            reconnect_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        client_config.endpoints.insert("test".to_string(), echo_endpoint());
+        thread::spawn(move || crate::tunnel_client::client_main(&client_config));
+
+        let mut internet = connect_retry(&server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+    }
+
+    //
+    // One address opening connections in a loop, another one unaffected
+    //
+    #[test]
+    fn accept_rate() {
+        const BURST: usize = 3;
+
+        let server_config = ServerConfig {
+            accept_rate: Some(AcceptRate {
+                rate: 1,
+                burst: BURST as u64,
+            }),
+            ..Default::default()
+        };
+        let tunnel = start_tunnel_with(&echo_endpoint(), server_config, Default::default());
+
+        // the tunnel is up
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+        drop(internet);
+
+        let noisy: std::net::Ipv4Addr = "127.0.0.2".parse().unwrap();
+
+        let mut echoes = 0;
+        for _ in 0..20 {
+            let mut internet = crate::test_util::connect_from(noisy, &tunnel.server);
+            internet.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+
+            if internet.write_all(b"x").is_ok() && echoed(&mut internet) {
+                echoes += 1;
+            }
+        }
+
+        // the burst, maybe one more if a second went by
+        assert!((BURST..=BURST + 1).contains(&echoes), "{echoes}");
+
+        let mut internet = connect_retry(&tunnel.server);
+        internet.write_all(b"x").unwrap();
+        assert!(echoed(&mut internet));
+    }
+
+    //
+    // Connection refused within TEST_TIMEOUT
+    //
+    fn wait_closed(addr: &str) {
+        let start = std::time::Instant::now();
+
+        while std::net::TcpStream::connect(addr).is_ok() {
+            assert!(start.elapsed() < TEST_TIMEOUT, "{addr} still open");
+            sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn lazy_listen() {
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap().to_string();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            lazy_listen: true,
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        // before, nobody there
+        wait_closed(&server);
+
+        let mut client = connect_retry(&tunnel);
+        let (_, data) = wait_frame(&mut client, PacketMessage::Hello);
+        assert_eq!(Hello::decode(&data).unwrap().forwards, vec!["test".to_string()]);
+
+        // not before the client's hello
+        sleep(Duration::from_millis(100));
+        assert!(std::net::TcpStream::connect(&server).is_err());
+
+        let hello = Hello::default().encode();
+        let mut frame = Vec::new();
+        Packet::new(CONTROL_ADDRESS, PacketMessage::Hello, hello.len() as u16)
+            .encode(&mut frame)
+            .unwrap();
+        frame.extend_from_slice(&hello);
+        client.write_all(&frame).unwrap();
+
+        // during
+        let _internet = connect_retry(&server);
+        wait_frame(&mut client, PacketMessage::Connect);
+
+        // after
+        drop(client);
+        wait_closed(&server);
+
+        // same port for the next one, a session that didn't see the EOF
+        // yet answers busy
+        let mut client = loop {
+            let mut stream = connect_retry(&tunnel);
+            if PacketMessage::Hello == recv_frame(&mut stream).0.msg {
+                break stream;
+            }
+        };
+        client.write_all(&frame).unwrap();
+
+        let _internet = connect_retry(&server);
+        wait_frame(&mut client, PacketMessage::Connect);
+    }
+
+    //
+    // Clients that reset right after the Hello, the server gives up after
+    // --max-tunnel-failures of them
+    //
+    #[test]
+    fn failure_budget() {
+        // one client at a time or several, the loop gives up all the same
+        for max_clients in [1, 2] {
+            let tunnel = format!("127.0.0.1:{}", free_port());
+
+            let config = ServerConfig {
+                tunnel: tunnel.clone(),
+                max_tunnel_failures: Some(2),
+                max_clients,
+                ..Default::default()
+            };
+            let forward = Forward {
+                label: "test".to_string(),
+                socket: bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap(),
+            };
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || tx.send(server_main(&config, vec![forward])).unwrap());
+
+            for _ in 0..2 {
+                // busy until the previous reset was seen
+                let client = loop {
+                    let mut stream = connect_retry(&tunnel);
+                    if PacketMessage::Hello == recv_frame(&mut stream).0.msg {
+                        break stream;
+                    }
+                };
+                crate::test_util::reset(client);
+            }
+
+            match rx.recv_timeout(TEST_TIMEOUT).unwrap() {
+                Err(Error::TooManyFailures { failures }) => assert_eq!(failures, 2),
+                other => panic!("expecting TooManyFailures, got {other:?}"),
+            }
+        }
+    }
+
+    //
+    // Whatever reason its Goodbye gives, a client only ends its own session,
+    // not a failure either
+    //
+    #[test]
+    fn client_goodbye() {
+        for max_clients in [1, 2] {
+            let tunnel = format!("127.0.0.1:{}", free_port());
+
+            let config = ServerConfig {
+                tunnel: tunnel.clone(),
+                max_tunnel_failures: Some(1),
+                max_clients,
+                ..Default::default()
+            };
+            let forward = Forward {
+                label: "test".to_string(),
+                socket: bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap(),
+            };
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || tx.send(server_main(&config, vec![forward])).unwrap());
+
+            let mut client = connect_retry(&tunnel);
+            wait_frame(&mut client, PacketMessage::Hello);
+
+            let goodbye = Goodbye {
+                reason: GoodbyeReason::AuthRevoked,
+                message: String::new(),
+            }
+            .encode();
+            let mut frame = Vec::new();
+            Packet::new(CONTROL_ADDRESS, PacketMessage::Goodbye, goodbye.len() as u16)
+                .encode(&mut frame)
+                .unwrap();
+            frame.extend_from_slice(&goodbye);
+            client.write_all(&frame).unwrap();
+
+            // closed on the server's side
+            let mut data = Vec::new();
+            client.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+            let _ = client.read_to_end(&mut data);
+
+            let mut next = connect_retry(&tunnel);
+            wait_frame(&mut next, PacketMessage::Hello);
+
+            assert!(rx.recv_timeout(Duration::from_millis(200)).is_err(), "{max_clients}");
+        }
+    }
+
+    //
+    // The lazy port was taken meanwhile, no client will ever do better
+    //
+    #[test]
+    fn fatal_session_error() {
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            lazy_listen: true,
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(server_main(&config, vec![forward])).unwrap());
+
+        let mut client = connect_retry(&tunnel);
+        wait_frame(&mut client, PacketMessage::Hello);
+
+        let _blocker = std::net::TcpListener::bind(server).unwrap();
+
+        let hello = Hello::default().encode();
+        let mut frame = Vec::new();
+        Packet::new(CONTROL_ADDRESS, PacketMessage::Hello, hello.len() as u16)
+            .encode(&mut frame)
+            .unwrap();
+        frame.extend_from_slice(&hello);
+        client.write_all(&frame).unwrap();
+
+        match rx.recv_timeout(TEST_TIMEOUT).unwrap() {
+            Err(e) => assert_eq!(e.class(), ErrorClass::Fatal, "{e}"),
+            Ok(()) => panic!("expecting an error"),
+        }
+    }
+
+    //
+    // A client ignoring the window floods a slow internet peer, the server
+    // stops reading the tunnel instead of buffering it all or dropping the
+    // connection, the fast one gets its share once the slow one caught up
+    //
+    #[test]
+    fn congested_internet_peer() {
+        const SLOW_TOTAL: usize = 8 * 1024 * 1024;
+        const FAST_TOTAL: usize = 1024 * 1024;
+        const CHUNK: usize = 32 * 1024;
+
+        let forward = bind_forward("127.0.0.1", &[0], Protocol::Tcp).unwrap();
+        let server = forward.local_addr().unwrap().to_string();
+        let tunnel = format!("127.0.0.1:{}", free_port());
+
+        let config = ServerConfig {
+            tunnel: tunnel.clone(),
+            // way below what the slow one is sent
+            max_buffered: Some(256 * 1024),
+            ..Default::default()
+        };
+        let forward = Forward {
+            label: "test".to_string(),
+            socket: forward,
+        };
+        std::thread::spawn(move || server_main(&config, vec![forward]));
+
+        let mut client = connect_retry(&tunnel);
+        wait_frame(&mut client, PacketMessage::Hello);
+
+        let slow = connect_retry(&server);
+        let (slow_connect, _) = wait_frame(&mut client, PacketMessage::Connect);
+        let fast = connect_retry(&server);
+        let (fast_connect, _) = wait_frame(&mut client, PacketMessage::Connect);
+
+        let mut writer = client.try_clone().unwrap();
+        thread::spawn(move || {
+            for (addr, total) in [(slow_connect.addr, SLOW_TOTAL), (fast_connect.addr, FAST_TOTAL)] {
+                for _ in 0..total / CHUNK {
+                    let mut frame = Vec::new();
+                    Packet::new_data(addr, CHUNK as u16).encode(&mut frame).unwrap();
+                    frame.extend_from_slice(&[0x55; CHUNK]);
+                    writer.write_all(&frame).unwrap();
+                }
+            }
+        });
+
+        // whatever the server says back
+        thread::spawn(move || io::copy(&mut client, &mut io::sink()));
+
+        let reader = |mut stream: std::net::TcpStream, total: usize, pause: Duration| {
+            thread::spawn(move || {
+                stream.set_read_timeout(Some(TEST_TIMEOUT)).unwrap();
+                let mut buf = vec![0; 16 * 1024];
+                let mut received = 0;
+                while received < total {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(v) => received += v,
+                    }
+                    sleep(pause);
+                }
+                received
+            })
+        };
+
+        let slow = reader(slow, SLOW_TOTAL, Duration::from_millis(1));
+        let fast = reader(fast, FAST_TOTAL, Duration::ZERO);
+
+        assert_eq!(slow.join().unwrap(), SLOW_TOTAL);
+        assert_eq!(fast.join().unwrap(), FAST_TOTAL);
+    }
+
+    //
+    // Nothing listens on the endpoint, the internet peer gets a RST as soon
+    // as the client reports it instead of hanging
+    //
+    #[test]
+    fn endpoint_refused() {
+        let tunnel = start_tunnel(&format!("127.0.0.1:{}", free_port()));
+
+        let mut internet = connect_retry(&tunnel.server);
+        let start = Instant::now();
+        internet.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+
+        let mut buf = [0; 16];
+        match internet.read(&mut buf) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            v => panic!("{v:?}"),
         }
+        assert!(start.elapsed() < Duration::from_millis(500), "{:?}", start.elapsed());
     }
 }
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::packet::Address;
+
+// Mappings without traffic for that long are dropped
+pub const DEF_UDP_TIMEOUT: Duration = Duration::from_secs(60);
+// Largest UDP payload
+pub const MAX_DATAGRAM: usize = 65535;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err("expecting tcp or udp".to_string()),
+        }
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+pub struct UdpFlow {
+    // forward the datagrams came through
+    pub channel: usize,
+    pub peer: SocketAddr,
+    last_seen: Instant,
+}
+
+//
+// Server side, there's no connection so every remote address of a forward
+// gets its own tunnel address until it goes quiet
+//
+#[derive(Default)]
+pub struct UdpFlows {
+    by_peer: HashMap<(usize, SocketAddr), Address>,
+    flows: HashMap<Address, UdpFlow>,
+}
+
+impl UdpFlows {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn lookup(&self, channel: usize, peer: &SocketAddr) -> Option<Address> {
+        self.by_peer.get(&(channel, *peer)).copied()
+    }
+
+    pub fn insert(&mut self, addr: Address, channel: usize, peer: SocketAddr) {
+        self.by_peer.insert((channel, peer), addr);
+        self.flows.insert(
+            addr,
+            UdpFlow {
+                channel,
+                peer,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    pub fn contains(&self, addr: Address) -> bool {
+        self.flows.contains_key(&addr)
+    }
+
+    //
+    // Returns the flow and marks it as active
+    //
+    pub fn touch(&mut self, addr: Address) -> Option<&UdpFlow> {
+        let flow = self.flows.get_mut(&addr)?;
+        flow.last_seen = Instant::now();
+        Some(flow)
+    }
+
+    //
+    // Drops the flows idle for longer than `timeout`, returns their addresses
+    //
+    pub fn reap(&mut self, timeout: Duration) -> Vec<Address> {
+        let idle: Vec<Address> = self
+            .flows
+            .iter()
+            .filter(|(_, f)| f.last_seen.elapsed() > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &idle {
+            if let Some(flow) = self.flows.remove(addr) {
+                self.by_peer.remove(&(flow.channel, flow.peer));
+            }
+        }
+
+        idle
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flows() {
+        let mut flows = UdpFlows::new();
+        let peer: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+
+        flows.insert(4, 0, peer);
+        flows.insert(5, 1, peer);
+
+        // same peer on two forwards, two flows
+        assert_eq!(flows.lookup(0, &peer), Some(4));
+        assert_eq!(flows.lookup(1, &peer), Some(5));
+        assert_eq!(flows.touch(5).unwrap().channel, 1);
+
+        assert!(flows.reap(Duration::from_secs(60)).is_empty());
+
+        let mut idle = flows.reap(Duration::ZERO);
+        idle.sort();
+        assert_eq!(idle, vec![4, 5]);
+        assert!(flows.is_empty());
+        assert_eq!(flows.lookup(0, &peer), None);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::error;
+
+use crate::error::{Error, Result};
+
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+pub fn panic_count() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+//
+// Logs the panic message and a backtrace ( RUST_BACKTRACE ) at error level
+// instead of the default stderr dump
+//
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::capture();
+        error!("panic: {info}\n{backtrace}");
+    }));
+}
+
+fn payload_string(payload: Box<dyn Any + Send>) -> String {
+    if let Some(v) = payload.downcast_ref::<&str>() {
+        return v.to_string();
+    }
+
+    match payload.downcast::<String>() {
+        Ok(v) => *v,
+        Err(_) => "unknown panic payload".to_string(),
+    }
+}
+
+//
+// Runs one session, a panic is turned into Error::Internal so the caller
+// goes through its normal teardown. Everything the closure touches is
+// rebuilt per session so it's fine to assert unwind safety
+//
+pub fn catch_session<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => v,
+        Err(payload) => {
+            PANICS.fetch_add(1, Ordering::Relaxed);
+            Err(Error::Internal {
+                payload: payload_string(payload),
+            })
+        }
+    }
+}
+
+//
+// Test only panic injection, armed with the label of the session to break.
+// Fires once
+//
+#[cfg(test)]
+static ARMED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(test)]
+pub fn arm_failpoint(name: &str) {
+    ARMED.lock().unwrap().push(name.to_string());
+}
+
+#[cfg(test)]
+pub fn failpoint(name: &str) {
+    let mut armed = ARMED.lock().unwrap();
+
+    if let Some(pos) = armed.iter().position(|v| v == name) {
+        armed.remove(pos);
+        drop(armed);
+        panic!("failpoint {name}");
+    }
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+pub fn failpoint(_name: &str) {}
+
+//
+// Same for slow iterations, the armed session sleeps once
+//
+#[cfg(test)]
+static STALLS: std::sync::Mutex<Vec<(String, std::time::Duration)>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(test)]
+pub fn arm_stall(name: &str, duration: std::time::Duration) {
+    STALLS.lock().unwrap().push((name.to_string(), duration));
+}
+
+#[cfg(test)]
+pub fn stall_point(name: &str) {
+    let mut armed = STALLS.lock().unwrap();
+
+    if let Some(pos) = armed.iter().position(|(v, _)| v == name) {
+        let (_, duration) = armed.remove(pos);
+        drop(armed);
+        std::thread::sleep(duration);
+    }
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+pub fn stall_point(_name: &str) {}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_to_error() {
+        arm_failpoint("unwind-test");
+
+        let before = panic_count();
+
+        let ret: Result<()> = catch_session(|| {
+            failpoint("unwind-test");
+            Ok(())
+        });
+
+        match ret {
+            Err(Error::Internal { payload }) => assert_eq!(payload, "failpoint unwind-test"),
+            _ => panic!("expecting Internal"),
+        }
+
+        assert!(panic_count() > before);
+
+        // one shot
+        assert!(
+            catch_session(|| {
+                failpoint("unwind-test");
+                Ok(())
+            })
+            .is_ok()
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use crate::packet::{Address, PacketMessage};
+
+// Exit code used when the event loop is wedged so supervisors can tell
+pub const EXIT_WATCHDOG: i32 = 3;
+pub const DEF_WATCHDOG_TIMEOUT: u64 = 180;
+
+const HISTORY_LEN: usize = 64;
+// Watchdog::sleep() pings that often
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Activity {
+    Event {
+        token: usize,
+        readable: bool,
+        writable: bool,
+    },
+    Frame {
+        msg: PacketMessage,
+        addr: Address,
+        len: usize,
+    },
+}
+
+impl Display for Activity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Activity::Event {
+                token,
+                readable,
+                writable,
+            } => write!(f, "event token={token} readable={readable} writable={writable}"),
+            Activity::Frame { msg, addr, len } => write!(f, "frame msg={msg} token={addr} len={len}"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct History {
+    last_event: Option<Activity>,
+    frames: VecDeque<Activity>,
+}
+
+struct Inner {
+    start: Instant,
+    // milliseconds since start
+    last_ping: AtomicU64,
+    streams: AtomicUsize,
+    history: Mutex<History>,
+}
+
+//
+// Cheap handle the event loops ping, a background thread aborts the process
+// if the pings stop
+//
+#[derive(Clone)]
+pub struct Watchdog {
+    inner: Arc<Inner>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        let inner = Inner {
+            start: Instant::now(),
+            last_ping: AtomicU64::new(0),
+            streams: AtomicUsize::new(0),
+            history: Mutex::new(History::default()),
+        };
+
+        Self { inner: Arc::new(inner) }
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.inner.start.elapsed().as_millis() as u64
+    }
+
+    pub fn ping(&self) {
+        self.inner.last_ping.store(self.now_ms(), Ordering::Relaxed);
+    }
+
+    //
+    // A wait the loop means, a reconnect delay, not a wedged loop
+    //
+    pub fn sleep(&self, duration: Duration) {
+        let end = Instant::now() + duration;
+
+        loop {
+            self.ping();
+
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return;
+            }
+
+            sleep(left.min(SLEEP_SLICE));
+        }
+    }
+
+    pub fn set_streams(&self, count: usize) {
+        self.inner.streams.store(count, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, activity: Activity) {
+        let mut history = match self.inner.history.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+
+        match activity {
+            Activity::Event { .. } => history.last_event = Some(activity),
+            Activity::Frame { .. } => {
+                if history.frames.len() == HISTORY_LEN {
+                    history.frames.pop_front();
+                }
+                history.frames.push_back(activity);
+            }
+        }
+    }
+
+    pub fn dump(&self) -> String {
+        let history = match self.inner.history.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+
+        let silent = self.now_ms().saturating_sub(self.inner.last_ping.load(Ordering::Relaxed));
+
+        let mut out = format!(
+            "no ping for {silent} ms, streams={}",
+            self.inner.streams.load(Ordering::Relaxed)
+        );
+
+        match &history.last_event {
+            Some(v) => out.push_str(&format!("\n  last {v}")),
+            None => out.push_str("\n  no event processed"),
+        }
+
+        for frame in &history.frames {
+            out.push_str(&format!("\n  {frame}"));
+        }
+
+        out
+    }
+
+    fn expired(&self, timeout: Duration) -> bool {
+        let silent = self.now_ms().saturating_sub(self.inner.last_ping.load(Ordering::Relaxed));
+        silent > timeout.as_millis() as u64
+    }
+
+    //
+    // Runs `action` with the state dump once the loop stopped pinging for
+    // `timeout`
+    //
+    pub fn spawn_with<F>(&self, timeout: Duration, action: F)
+    where
+        F: FnOnce(String) + Send + 'static,
+    {
+        let watchdog = self.clone();
+        let check = (timeout / 4).max(Duration::from_millis(10));
+
+        self.ping();
+
+        thread::spawn(move || {
+            loop {
+                sleep(check);
+
+                if watchdog.expired(timeout) {
+                    action(watchdog.dump());
+                    return;
+                }
+            }
+        });
+    }
+
+    //
+    // A zero timeout disables the watchdog
+    //
+    pub fn spawn(&self, timeout: Duration) {
+        if timeout.is_zero() {
+            return;
+        }
+
+        self.spawn_with(timeout, |dump| {
+            error!("event loop wedged, aborting: {dump}");
+            std::process::exit(EXIT_WATCHDOG);
+        });
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// TEST
+////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn wedged_loop() {
+        let watchdog = Watchdog::new();
+        let (tx, rx) = mpsc::channel();
+
+        watchdog.spawn_with(Duration::from_millis(100), move |dump| tx.send(dump).unwrap());
+
+        for _ in 0..10 {
+            watchdog.ping();
+            sleep(Duration::from_millis(20));
+        }
+
+        // still alive while pinging
+        assert!(rx.try_recv().is_err());
+
+        watchdog.set_streams(3);
+        watchdog.record(Activity::Event {
+            token: 5,
+            readable: true,
+            writable: false,
+        });
+        watchdog.record(Activity::Frame {
+            msg: PacketMessage::Data,
+            addr: 5,
+            len: 42,
+        });
+
+        // stop pinging
+        let dump = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(dump.contains("streams=3"));
+        assert!(dump.contains("last event token=5 readable=true writable=false"));
+        assert!(dump.contains("frame msg=Data token=5 len=42"));
+    }
+
+    #[test]
+    fn sleep_pings() {
+        let watchdog = Watchdog::new();
+        let (tx, rx) = mpsc::channel();
+
+        watchdog.spawn_with(Duration::from_millis(100), move |dump| tx.send(dump).unwrap());
+
+        watchdog.sleep(Duration::from_millis(400));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let watchdog = Watchdog::new();
+
+        for i in 0..HISTORY_LEN * 2 {
+            watchdog.record(Activity::Frame {
+                msg: PacketMessage::Data,
+                addr: i,
+                len: 0,
+            });
+        }
+
+        let dump = watchdog.dump();
+        assert_eq!(dump.matches("frame").count(), HISTORY_LEN);
+        assert!(dump.contains(&format!("token={} ", HISTORY_LEN * 2 - 1)));
+    }
+
+    #[test]
+    fn ping_after_now() {
+        let watchdog = Watchdog::new();
+
+        // a ping from the loop between reading now and last_ping
+        let later = watchdog.now_ms() + 1000;
+        watchdog.inner.last_ping.store(later, Ordering::Relaxed);
+
+        assert!(!watchdog.expired(Duration::ZERO));
+        assert!(watchdog.dump().starts_with("no ping for 0 ms"));
+    }
+}